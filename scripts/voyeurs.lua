@@ -0,0 +1,152 @@
+-- Companion script that voyeurs loads into mpv.
+-- Key bindings are handed to voyeurs through user-data properties, which it observes over ipc.
+-- They can be rebound in input.conf, e.g. `Ctrl+l script-binding voyeurs/reaction-laugh`
+
+local reactions = {
+    { name = "laugh", key = "Alt+1", emoji = "😂" },
+    { name = "love", key = "Alt+2", emoji = "❤️" },
+    { name = "wow", key = "Alt+3", emoji = "😮" },
+    { name = "sad", key = "Alt+4", emoji = "😢" },
+}
+
+for _, reaction in ipairs(reactions) do
+    mp.add_key_binding(reaction.key, "reaction-" .. reaction.name, function()
+        mp.set_property("user-data/voyeurs/reaction", reaction.emoji)
+    end)
+end
+
+-- Sync status indicator, voyeurs keeps the drift and connection health up to date
+local status = mp.create_osd_overlay("ass-events")
+local status_visible = false
+-- ASS colors are BGR
+local colors = { green = "00FF00", yellow = "00FFFF", red = "0000FF" }
+
+local function draw_status()
+    local health = mp.get_property("user-data/voyeurs/health", "")
+    local drift = mp.get_property("user-data/voyeurs/drift", "-")
+    if not status_visible or colors[health] == nil then
+        status:remove()
+        return
+    end
+    status.data = string.format("{\\an9}{\\1c&H%s&}●{\\1c&HFFFFFF&} %s", colors[health], drift)
+    status:update()
+end
+
+mp.observe_property("user-data/voyeurs/health", "string", draw_status)
+mp.observe_property("user-data/voyeurs/drift", "string", draw_status)
+mp.add_key_binding("Alt+s", "toggle-status", function()
+    status_visible = not status_visible
+    draw_status()
+end)
+
+-- Who's in the session, whether they're ready, how far off they are and the last few chat
+-- lines, kept up to date by voyeurs and shown until toggled off again
+local panel = mp.create_osd_overlay("ass-events")
+panel.z = 2
+local panel_visible = false
+
+local function draw_panel()
+    local data = mp.get_property("user-data/voyeurs/panel", "")
+    if not panel_visible or data == "" then
+        panel:remove()
+        return
+    end
+    panel.data = "{\\an4\\fs22}" .. data
+    panel:update()
+end
+
+mp.observe_property("user-data/voyeurs/panel", "string", draw_panel)
+mp.add_key_binding("Alt+p", "toggle-panel", function()
+    panel_visible = not panel_visible
+    draw_panel()
+end)
+
+-- Everything voyeurs has to say, with each name in its own color. voyeurs sends the
+-- messages already formatted as ASS, after how many milliseconds they should go away.
+-- They get an overlay of their own instead of show-text, placed with voyeurs' --osd-* options
+-- or script-opts/voyeurs.conf
+local options = { position = "top-left", font_size = 0, box = false }
+require("mp.options").read_options(options, "voyeurs")
+local alignments = {
+    ["top-left"] = 7, top = 8, ["top-right"] = 9,
+    ["bottom-left"] = 1, bottom = 2, ["bottom-right"] = 3,
+}
+
+local messages = mp.create_osd_overlay("ass-events")
+messages.z = 1
+messages.compute_bounds = options.box
+local backdrop = mp.create_osd_overlay("ass-events")
+local lines = {}
+local BOX_PADDING = 8
+
+local function draw_backdrop(bounds)
+    if not options.box or bounds == nil or bounds.x0 == nil then
+        backdrop:remove()
+        return
+    end
+    local x0, y0 = bounds.x0 - BOX_PADDING, bounds.y0 - BOX_PADDING
+    local x1, y1 = bounds.x1 + BOX_PADDING, bounds.y1 + BOX_PADDING
+    backdrop.data = string.format(
+        "{\\an7\\pos(0,0)\\bord0\\shad0\\1c&H000000&\\1a&H60&\\p1}m %d %d l %d %d l %d %d l %d %d{\\p0}",
+        x0, y0, x1, y0, x1, y1, x0, y1
+    )
+    backdrop:update()
+end
+
+local function draw_messages()
+    local now = mp.get_time()
+    local texts = {}
+    for i = #lines, 1, -1 do
+        if lines[i].expires <= now then
+            table.remove(lines, i)
+        end
+    end
+    for _, line in ipairs(lines) do
+        table.insert(texts, line.text)
+    end
+    if #texts == 0 then
+        messages:remove()
+        backdrop:remove()
+        return
+    end
+    local style = "{\\an" .. (alignments[options.position] or 7)
+    if options.font_size > 0 then
+        style = style .. "\\fs" .. options.font_size
+    end
+    messages.data = style .. "}" .. table.concat(texts, "\\N")
+    draw_backdrop(messages:update())
+end
+
+mp.observe_property("user-data/voyeurs/osd", "string", function(_, message)
+    local duration, text = (message or ""):match("^(%d+) (.*)$")
+    if duration == nil then
+        return
+    end
+    -- cleared, so that the same message twice in a row shows up twice
+    mp.set_property("user-data/voyeurs/osd", "")
+    duration = tonumber(duration) / 1000
+    table.insert(lines, { text = text, expires = mp.get_time() + duration })
+    mp.add_timeout(duration, draw_messages)
+    draw_messages()
+end)
+
+-- Bookmarks are named after the position, voyeurs shares them with everyone
+mp.add_key_binding("Alt+b", "bookmark", function()
+    local position = mp.get_property_osd("time-pos")
+    if position ~= nil then
+        mp.set_property("user-data/voyeurs/bookmark", position)
+    end
+end)
+
+-- Back in sync with the host, or everyone back in sync with us when hosting
+mp.add_key_binding("Alt+r", "resync", function()
+    mp.set_property("user-data/voyeurs/resync", "yes")
+end)
+
+-- Our copy is slightly out of step with everyone else's, like subtitle delay but for the file
+mp.add_key_binding("Alt+[", "offset-earlier", function()
+    mp.set_property("user-data/voyeurs/nudge", "-0.5")
+end, { repeatable = true })
+mp.add_key_binding("Alt+]", "offset-later", function()
+    mp.set_property("user-data/voyeurs/nudge", "0.5")
+end, { repeatable = true })
@@ -0,0 +1,178 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+// Failed handshakes an address gets within the window before being banned
+const MAX_STRIKES: usize = 5;
+const STRIKE_WINDOW: Duration = Duration::from_secs(10 * 60);
+pub const BAN_DURATION: Duration = Duration::from_secs(15 * 60);
+
+/// An address range, like 192.168.1.0/24 or 100.64.0.0/10
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // a dual-stack listener sees ipv4 peers as ::ffff:a.b.c.d
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                masked(u32::from(net).into(), self.prefix, 32)
+                    == masked(u32::from(ip).into(), self.prefix, 32)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                masked(net.into(), self.prefix, 128) == masked(ip.into(), self.prefix, 128)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn masked(bits: u128, prefix: u8, width: u8) -> u128 {
+    match width - prefix {
+        0 => bits,
+        host if host >= 128 => 0,
+        host => bits >> host,
+    }
+}
+
+/// clap value parser for a CIDR, a bare address being a range of one
+pub fn parse_cidr(s: &str) -> Result<Cidr, String> {
+    let (addr, prefix) = match s.split_once('/') {
+        Some((addr, prefix)) => (addr, Some(prefix)),
+        None => (s, None),
+    };
+    let addr: IpAddr = addr
+        .parse()
+        .map_err(|_| format!("{addr} isn't an ip address"))?;
+    let width = if addr.is_ipv4() { 32 } else { 128 };
+    let prefix = match prefix {
+        Some(prefix) => prefix
+            .parse()
+            .ok()
+            .filter(|prefix| *prefix <= width)
+            .ok_or(format!("the prefix length has to be between 0 and {width}"))?,
+        None => width,
+    };
+    Ok(Cidr { addr, prefix })
+}
+
+/// Who may connect to the server, checked before anything is read from a connection
+#[derive(Debug, Clone, Default)]
+pub struct Acl {
+    /// when not empty, nobody outside these ranges gets in
+    pub allow: Vec<Cidr>,
+    /// always refused, even when also allowed
+    pub deny: Vec<Cidr>,
+}
+
+impl Acl {
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|cidr| cidr.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(ip))
+    }
+}
+
+/// Temporarily refuses addresses that keep failing the handshake, like scanners
+#[derive(Debug, Default)]
+pub struct Bans {
+    strikes: HashMap<IpAddr, Vec<Instant>>,
+    /// banned addresses, until when
+    banned: HashMap<IpAddr, Instant>,
+}
+
+impl Bans {
+    pub fn is_banned(&mut self, ip: IpAddr, now: Instant) -> bool {
+        let ip = ip.to_canonical();
+        match self.banned.get(&ip) {
+            Some(until) if *until > now => true,
+            Some(_) => {
+                self.banned.remove(&ip);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Count a failed handshake against `ip`, returns whether that got it banned
+    pub fn strike(&mut self, ip: IpAddr, now: Instant) -> bool {
+        let ip = ip.to_canonical();
+        // forget old strikes, of this address and of the ones that gave up
+        self.strikes.retain(|_, strikes| {
+            strikes.retain(|t| now.duration_since(*t) < STRIKE_WINDOW);
+            !strikes.is_empty()
+        });
+        let strikes = self.strikes.entry(ip).or_default();
+        strikes.push(now);
+        if strikes.len() < MAX_STRIKES {
+            return false;
+        }
+        self.strikes.remove(&ip);
+        self.banned.insert(ip, now + BAN_DURATION);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::acl::{parse_cidr, Acl, Bans, BAN_DURATION, MAX_STRIKES, STRIKE_WINDOW};
+    use std::{net::IpAddr, time::Instant};
+
+    #[test]
+    fn test_parse_cidr() {
+        assert!(parse_cidr("192.168.1.0/24").is_ok());
+        assert!(parse_cidr("fd7a:115c:a1e0::/48").is_ok());
+        assert!(parse_cidr("10.0.0.1").is_ok());
+        assert!(parse_cidr("10.0.0.0/33").is_err());
+        assert!(parse_cidr("friends").is_err());
+    }
+
+    #[test]
+    fn test_acl() {
+        let acl = Acl {
+            allow: vec![parse_cidr("100.64.0.0/10").unwrap()],
+            deny: vec![parse_cidr("100.100.100.100").unwrap()],
+        };
+        assert!(acl.allows("100.101.2.3".parse().unwrap()));
+        assert!(acl.allows("::ffff:100.101.2.3".parse().unwrap()));
+        assert!(!acl.allows("100.100.100.100".parse().unwrap()));
+        assert!(!acl.allows("8.8.8.8".parse().unwrap()));
+        assert!(!acl.allows("::1".parse().unwrap()));
+
+        let acl = Acl {
+            allow: vec![],
+            deny: vec![parse_cidr("0.0.0.0/0").unwrap()],
+        };
+        assert!(!acl.allows("1.2.3.4".parse().unwrap()));
+        assert!(acl.allows("::1".parse().unwrap()));
+        assert!(Acl::default().allows("1.2.3.4".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_bans() {
+        let mut bans = Bans::default();
+        let scanner: IpAddr = "203.0.113.7".parse().unwrap();
+        let now = Instant::now();
+        for _ in 1..MAX_STRIKES {
+            assert!(!bans.strike(scanner, now));
+        }
+        // strikes too far apart don't add up
+        assert!(!bans.strike(scanner, now + STRIKE_WINDOW));
+        assert!(!bans.is_banned(scanner, now + STRIKE_WINDOW));
+
+        let now = now + STRIKE_WINDOW * 2;
+        for _ in 1..MAX_STRIKES {
+            bans.strike(scanner, now);
+        }
+        assert!(bans.strike(scanner, now));
+        assert!(bans.is_banned(scanner, now));
+        assert!(!bans.is_banned("203.0.113.8".parse().unwrap(), now));
+        assert!(!bans.is_banned(scanner, now + BAN_DURATION));
+    }
+}
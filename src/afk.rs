@@ -0,0 +1,50 @@
+use std::{sync::Arc, time::Duration};
+use tokio::{sync::Mutex, time::interval};
+
+use crate::{
+    i18n::Msg,
+    mpv_event_handler::ready,
+    osd::{notify, Category},
+    player::PlayerBackend,
+    proto::*,
+    Settings, Shared,
+};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Marks the local user as away and no longer ready once they have been paused without
+/// touching mpv for `timeout`. Coming back is detected by the mpv event handler, which
+/// clears the flag on the next input.
+pub async fn watch_idle(
+    player: Box<dyn PlayerBackend>,
+    state: Arc<Mutex<Shared>>,
+    timeout: Duration,
+    settings: Settings,
+) {
+    let mut ticker = interval(CHECK_INTERVAL);
+    loop {
+        ticker.tick().await;
+        let mut s = state.lock().await;
+        if s.away || s.last_activity.elapsed() < timeout {
+            continue;
+        }
+        if !player.is_paused().unwrap_or_default() {
+            continue;
+        }
+        s.away = true;
+        notify(
+            &*player,
+            &settings.osd,
+            Category::Presence,
+            &Msg::MarkedAway.to_string(),
+        )
+        .unwrap();
+        s.broadcast(VoyeursCommand::Away(true)).await;
+        // Back at the keyboard they still have to say they're ready, unless they never do
+        if s.is_ready && !settings.auto_ready {
+            s.is_ready = false;
+            let unready = ready(&s, &settings, false);
+            s.broadcast(unready).await;
+        }
+    }
+}
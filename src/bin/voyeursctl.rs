@@ -0,0 +1,54 @@
+use clap::Parser;
+use std::path::PathBuf;
+
+/// Control a running voyeurs started with --control-socket
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+    /// socket voyeurs listens on, by default voyeurs.sock in $XDG_RUNTIME_DIR
+    #[arg(long, value_name = "PATH")]
+    socket: Option<PathBuf>,
+
+    /// peers, info, invite [MINUTES], code, stats, kick NAME, ban NAME, role NAME ROLE, chat TEXT, whisper NAME TEXT, rename NAME, pause, play, seek TIME, resync, sync, nudge SECS, load URL, skip START-END, lock, unlock or connect ADDRESS
+    #[arg(required = true, num_args = 1..)]
+    command: Vec<String>,
+}
+
+#[cfg(unix)]
+fn main() {
+    use std::{
+        env,
+        io::{self, Read, Write},
+        os::unix::net::UnixStream,
+        process::exit,
+    };
+
+    let args = Cli::parse();
+    // the same default as voyeurs
+    let socket = args.socket.unwrap_or_else(|| {
+        env::var_os("XDG_RUNTIME_DIR")
+            .map_or_else(env::temp_dir, PathBuf::from)
+            .join("voyeurs.sock")
+    });
+    let mut stream = UnixStream::connect(&socket).unwrap_or_else(|e| {
+        eprintln!("couldn't reach voyeurs at {}: {e}", socket.display());
+        exit(1)
+    });
+    writeln!(stream, "{}", args.command.join(" ")).expect("Couldn't send the command");
+    let mut reply = String::new();
+    stream
+        .read_to_string(&mut reply)
+        .expect("Couldn't read the reply");
+    if reply.starts_with("error: ") {
+        eprint!("{reply}");
+        exit(1)
+    }
+    io::stdout().write_all(reply.as_bytes()).unwrap();
+}
+
+#[cfg(not(unix))]
+fn main() {
+    Cli::parse();
+    eprintln!("voyeursctl needs unix sockets");
+    std::process::exit(1)
+}
@@ -0,0 +1,83 @@
+/// Named positions in the file, shared by everyone in the session
+#[derive(Debug, Clone, Default)]
+pub struct Bookmarks(Vec<(String, f64)>);
+
+impl Bookmarks {
+    /// Add a bookmark, moving the one with the same name if there is one
+    pub fn set(&mut self, name: String, time: f64) {
+        match self.0.iter_mut().find(|(n, _)| *n == name) {
+            Some(bookmark) => bookmark.1 = time,
+            None => self.0.push((name, time)),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<f64> {
+        self.0.iter().find(|(n, _)| n == name).map(|(_, t)| *t)
+    }
+
+    /// In the order they were added
+    pub fn iter(&self) -> impl Iterator<Item = (&str, f64)> {
+        self.0.iter().map(|(n, t)| (n.as_str(), *t))
+    }
+}
+
+/// A position as players show it, like 1:02:03 or 4:05
+pub fn format_time(t: f64) -> String {
+    let secs = t.max(0.0) as u64;
+    let (h, m, s) = (secs / 3600, secs / 60 % 60, secs % 60);
+    if h > 0 {
+        format!("{h}:{m:02}:{s:02}")
+    } else {
+        format!("{m}:{s:02}")
+    }
+}
+
+/// The other way around: 1:02:03, 4:05 or just seconds, like 245.5
+pub fn parse_time(time: &str) -> Option<f64> {
+    let mut parts = time.trim().rsplit(':');
+    let secs: f64 = parts.next()?.parse().ok()?;
+    let colons = time.matches(':').count();
+    // More than hours, or seconds running over into the minutes
+    if colons > 2 || (colons > 0 && secs >= 60.0) || secs.is_nan() || secs < 0.0 {
+        return None;
+    }
+    let mut total = secs;
+    for (part, unit) in parts.zip([60, 3600]) {
+        total += (part.parse::<u64>().ok()? * unit) as f64;
+    }
+    Some(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bookmarks::{format_time, parse_time, Bookmarks};
+
+    #[test]
+    fn test_bookmarks() {
+        let mut bookmarks = Bookmarks::default();
+        bookmarks.set("intro".to_owned(), 10.0);
+        bookmarks.set("twist".to_owned(), 5400.0);
+        bookmarks.set("intro".to_owned(), 12.0);
+        assert_eq!(
+            bookmarks.iter().collect::<Vec<_>>(),
+            [("intro", 12.0), ("twist", 5400.0)]
+        );
+    }
+
+    #[test]
+    fn test_format_time() {
+        assert_eq!(format_time(3723.4), "1:02:03");
+        assert_eq!(format_time(245.0), "4:05");
+        assert_eq!(format_time(-1.0), "0:00");
+    }
+
+    #[test]
+    fn test_parse_time() {
+        assert_eq!(parse_time("1:02:03"), Some(3723.0));
+        assert_eq!(parse_time("01:30"), Some(90.0));
+        assert_eq!(parse_time("245.5"), Some(245.5));
+        assert_eq!(parse_time("1:75"), None);
+        assert_eq!(parse_time("1:2:3:4"), None);
+        assert_eq!(parse_time("soon"), None);
+    }
+}
@@ -1,191 +1,1281 @@
-use mpvipc::Mpv;
-use mpvipc::*;
-use std::{collections::VecDeque, net::SocketAddr, sync::Arc};
-use tokio::{net::TcpStream, sync::Mutex};
-use url::Url;
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{self, AsyncRead, AsyncWrite},
+    sync::{Mutex, OwnedSemaphorePermit},
+    time::{self, timeout_at},
+};
 
+#[cfg(feature = "notify")]
+use crate::desktop;
 use crate::{
+    bookmarks::format_time,
+    filename,
+    i18n::Msg,
+    identity,
+    invite::Invite,
+    mpv_event_handler::hold_for_next_entry,
+    osd::{notify, notify_from, Category},
+    outbox::{self, write_packets},
+    player::PlayerBackend,
     proto::*,
+    record::Direction,
+    session::SessionEvent,
+    simulate::Simulated,
+    telemetry::{follow_live_edge, RESTART_TOLERANCE, RESYNC_COOLDOWN},
     time::{get_timestamp, get_weighted_latency, MAX_QUEUE_LATENCY},
-    Peer, Settings, Shared,
+    username, Departed, HandshakeState, Peer, Settings, Shared, SyncClass,
 };
 
+const REACTION_COOLDOWN: Duration = Duration::from_secs(1);
+const AUDIO_DURATION_TOLERANCE: f64 = 2.0;
+// How often a seek is tried while the file loads, and how long apart: a stream can
+// take a few seconds
+const SEEK_TRIES: u32 = 50;
+const SEEK_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
 pub async fn handle_connection(
-    mut mpv: Mpv,
+    mut player: Box<dyn PlayerBackend>,
     addr: SocketAddr,
-    stream: TcpStream,
-    state: Arc<Mutex<Shared>>,
+    mut stream: impl AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    // the first room's on a server with --rooms, until the peer picks another
+    mut state: Arc<Mutex<Shared>>,
     settings: Settings,
+    // Held by connections that haven't completed the handshake yet
+    mut pending: Option<OwnedSemaphorePermit>,
+    // The only username the peer may join with, from its client certificate
+    certified: Option<String>,
 ) {
-    println!("accepted connection");
-    let (rx, tx) = stream.into_split();
-    let reader = PacketReader::new(rx);
+    let deadline = time::Instant::now() + settings.handshake_timeout;
+
+    // Drop anything that isn't voyeurs before it can get near the shared state
+    let preamble = match timeout_at(deadline, exchange_preamble(&mut stream)).await {
+        Ok(preamble) => preamble,
+        Err(_) => Err("timed out waiting for the preamble".into()),
+    };
+    if let Err(e) = preamble {
+        let mut s = state.lock().await;
+        s.log(format!("dropped connection from {addr}: {e}"));
+        s.strike(addr);
+        return;
+    }
+
+    let (rx, tx) = io::split(stream);
+    let mut reader = PacketReader::new(rx, settings.limits.clone(), settings.psk.clone());
+    // Writing happens on a task of its own, the shared state only ever queues packets
+    let (outbox, queue) = outbox::channel(settings.psk.clone(), settings.limits.max_packet);
+    let mut writer = match settings.simulate {
+        Some(conditions) => tokio::spawn(write_packets(queue, Simulated::new(tx, conditions))),
+        None => tokio::spawn(write_packets(queue, tx)),
+    };
+    let (mut writing, mut slow) = (true, false);
     state.lock().await.peers.insert(
         addr,
         Peer {
-            tx,
+            tx: outbox,
+            writer: writer.abort_handle(),
+            // The server we connected to doesn't have to introduce itself
+            handshake: if settings.is_serving {
+                HandshakeState::AwaitingHello
+            } else {
+                HandshakeState::Joined
+            },
             username: Default::default(),
             ready: false,
+            away: false,
             latency: VecDeque::with_capacity(MAX_QUEUE_LATENCY),
+            last_reaction: None,
+            extensions: vec![],
+            drift: None,
+            last_position: None,
+            last_resync: None,
+            recheck: false,
+            key: None,
+            vouched: false,
+            join_code: None,
+            role: if settings.is_serving {
+                Role::Viewer
+            } else {
+                Role::Host
+            },
         },
     );
+    state
+        .lock()
+        .await
+        .log(format!("accepted connection from {addr}"));
 
-    if !settings.is_serving {
-        if settings.accept_source {
-            state
-                .lock()
-                .await
-                .send(addr, VoyeursCommand::GetStreamName)
-                .await
-        } else {
-            state
-                .lock()
-                .await
-                .send(
-                    addr,
-                    VoyeursCommand::NewConnection(settings.username.to_string()),
-                )
-                .await
-        }
+    // The server speaks first, with something for clients to prove who they are with
+    let challenge = identity::challenge();
+    if settings.is_serving {
+        state
+            .lock()
+            .await
+            .send(addr, VoyeursCommand::Challenge(challenge))
+            .await
     }
 
     loop {
-        match reader.read_packet().await {
+        // A peer we can't write to any more is as gone as one we can't read from, and
+        // goes the same way without holding up anybody else
+        let read = async {
+            tokio::select! {
+                read = reader.read_packet() => read,
+                written = &mut writer, if writing => {
+                    writing = false;
+                    match written {
+                        Ok(Err(e)) => Err(e.into()),
+                        // stopped by Shared::drop_slow
+                        Err(e) if e.is_cancelled() => {
+                            slow = true;
+                            Err("couldn't keep up".into())
+                        }
+                        // closed on our end, kicked or moving to another server
+                        _ => Err("closed the connection".into()),
+                    }
+                }
+            }
+        };
+        let read = if pending.is_some() {
+            match timeout_at(deadline, read).await {
+                Ok(read) => read,
+                Err(_) => {
+                    let mut s = state.lock().await;
+                    s.evict(addr, None).await;
+                    s.log(format!("handshake with {addr} timed out"));
+                    s.strike(addr);
+                    break;
+                }
+            }
+        } else {
+            read.await
+        };
+
+        match read {
             Ok(packet) => {
                 let mut s = state.lock().await;
+                // Kicked while this was on its way, it doesn't get a say any more
+                if !s.peers.contains_key(&addr) {
+                    break;
+                }
+                s.record(Direction::In, addr, &packet);
+                s.count(Direction::In, addr, &packet.command, reader.last_size);
+
+                if s.peers[&addr].handshake != HandshakeState::Joined
+                    && !matches!(
+                        packet.command,
+                        VoyeursCommand::NewConnection(_)
+                            | VoyeursCommand::GetStreamName
+                            | VoyeursCommand::Room(_)
+                            | VoyeursCommand::Identity { .. }
+                            | VoyeursCommand::Password(_)
+                            | VoyeursCommand::Invite { .. }
+                            | VoyeursCommand::JoinCode(_)
+                            | VoyeursCommand::Auth(_)
+                    )
+                {
+                    s.log(format!(
+                        "ignoring {:?} from {addr}, it hasn't completed the handshake",
+                        packet.command
+                    ));
+                    continue;
+                }
 
-                let t_delta = get_timestamp() - packet.timestamp;
+                let t_delta = get_timestamp().saturating_sub(packet.timestamp);
                 let latency_vec = &mut s.peers.get_mut(&addr).unwrap().latency;
                 if latency_vec.len() == MAX_QUEUE_LATENCY {
                     latency_vec.pop_back();
                 }
                 latency_vec.push_front(t_delta);
 
-                println!(
-                    "Avg Latency : {}ms , Current Latency: {}",
-                    get_weighted_latency(latency_vec),
-                    t_delta
-                );
+                let avg_latency = get_weighted_latency(latency_vec);
+                // Clients only hear from the host, who doesn't send its name
+                let who = if settings.is_serving {
+                    s.peers[&addr].username.clone()
+                } else {
+                    Msg::Host.to_string()
+                };
+                if s.peers[&addr].handshake == HandshakeState::Joined {
+                    s.stats.latency(&who, t_delta);
+                }
+                s.debug(|| {
+                    format!(
+                        "{addr} -> {:?} (latency {t_delta}ms, avg {avg_latency}ms)",
+                        packet.command
+                    )
+                });
 
-                match packet.command {
+                // Who paused, resumed or seeked: the server knows who sent it, clients hear
+                // it from the server. Our own, coming back, need no telling
+                // Where everyone else is, on our file's timeline
+                let (command, actor) =
+                    match packet.command.retimed(|time| s.timeline.to_local(time)) {
+                        VoyeursCommand::ReadyFrom { username, ready } if !settings.is_serving => {
+                            if let Some(peer) =
+                                s.roster.iter_mut().find(|peer| peer.username == username)
+                            {
+                                peer.ready = ready;
+                            }
+                            let actor = (username != s.username(&settings)).then_some(username);
+                            (VoyeursCommand::Ready(ready), actor)
+                        }
+                        VoyeursCommand::SeekFrom { username, time } if !settings.is_serving => {
+                            let actor = (username != s.username(&settings)).then_some(username);
+                            (VoyeursCommand::Seek(time), actor)
+                        }
+                        VoyeursCommand::ReadyFrom { ready, .. } | VoyeursCommand::Ready(ready)
+                            if settings.is_serving =>
+                        {
+                            (VoyeursCommand::Ready(ready), Some(who.clone()))
+                        }
+                        VoyeursCommand::SeekFrom { time, .. } | VoyeursCommand::Seek(time)
+                            if settings.is_serving =>
+                        {
+                            (VoyeursCommand::Seek(time), Some(who.clone()))
+                        }
+                        command => (command, None),
+                    };
+                match command {
+                    // What we keep to ourselves with --no-sync
+                    VoyeursCommand::Ready(_) if !settings.syncs(SyncClass::Pause) => {}
+                    VoyeursCommand::Seek(_) if !settings.syncs(SyncClass::Seek) => {}
+                    VoyeursCommand::PlaylistPos(_) if !settings.syncs(SyncClass::Playlist) => {}
+                    // While locked, peers following the host don't get a say, unless they moderate
+                    VoyeursCommand::Ready(p)
+                        if settings.is_serving
+                            && s.locked
+                            && s.peers[&addr].role < Role::Moderator
+                            && (settings.standalone || !p) =>
+                    {
+                        s.debug(|| "ignoring it, playback is locked".to_owned());
+                    }
                     VoyeursCommand::Ready(p) => {
                         if settings.standalone {
-                            if mpv.get_property::<bool>("pause").unwrap() == p {
+                            if player.is_paused().unwrap() == p {
+                                if !p {
+                                    s.stats.paused_by(&who);
+                                }
                                 s.ignore_next = true;
-                                mpv.set_property("pause", !p).unwrap();
+                                player.set_paused(!p).unwrap();
+                                attribute(&*player, &mut s, &settings, actor.as_deref(), !p);
                             }
                             if settings.is_serving {
-                                s.broadcast(VoyeursCommand::Ready(p)).await;
+                                s.announce(if p {
+                                    SessionEvent::Resume
+                                } else {
+                                    SessionEvent::Pause(who.clone())
+                                });
+                                let relayed = VoyeursCommand::ReadyFrom {
+                                    username: who,
+                                    ready: p,
+                                };
+                                s.broadcast(relayed).await;
                             }
                         } else {
                             s.peers.get_mut(&addr).unwrap().ready = p;
+                            if settings.is_serving {
+                                s.broadcast_peers(&settings.username).await;
+                            }
                             match p {
                                 false => {
-                                    if !mpv.get_property::<bool>("pause").unwrap() {
+                                    if !player.is_paused().unwrap() {
+                                        s.stats.paused_by(&who);
                                         s.ignore_next = true;
-                                        mpv.set_property("pause", true).unwrap();
+                                        player.set_paused(true).unwrap();
+                                        let actor = actor.as_deref();
+                                        attribute(&*player, &mut s, &settings, actor, true);
                                     }
                                     if settings.is_serving {
-                                        s.broadcast_excluding(VoyeursCommand::Ready(false), addr)
-                                            .await;
+                                        s.announce(SessionEvent::Pause(who.clone()));
+                                        let relayed = VoyeursCommand::ReadyFrom {
+                                            username: who,
+                                            ready: false,
+                                        };
+                                        s.broadcast_excluding(relayed, addr).await;
                                     }
                                 }
                                 true => {
-                                    if dbg!(s.is_ready) && dbg!(s.peers.values().all(|r| r.ready)) {
-                                        if mpv.get_property::<bool>("pause").unwrap() {
-                                            s.ignore_next = true;
-                                            mpv.set_property("pause", false).unwrap();
+                                    if s.is_ready && s.peers_ready() {
+                                        if player.is_paused().unwrap() {
+                                            // The host tells us when to start, with ResumeAt
+                                            let waits = !settings.is_serving
+                                                && s.peers[&addr]
+                                                    .extensions
+                                                    .iter()
+                                                    .any(|name| name == PRECISE);
+                                            if !waits {
+                                                s.ignore_next = true;
+                                                player.set_paused(false).unwrap();
+                                            }
+                                            let actor = actor.as_deref();
+                                            attribute(&*player, &mut s, &settings, actor, false);
                                         }
 
                                         if settings.is_serving {
-                                            s.broadcast(VoyeursCommand::Ready(true)).await;
+                                            s.announce(SessionEvent::Resume);
+                                            let relayed = VoyeursCommand::ReadyFrom {
+                                                username: who,
+                                                ready: true,
+                                            };
+                                            s.broadcast(relayed).await;
+                                            let position = player.position().unwrap_or_default();
+                                            s.schedule_resume(position).await;
                                         }
                                     }
                                 }
                             }
                         }
                     }
-                    VoyeursCommand::Seek(t) => {
-                        let current_time: f64 =
-                            mpv.get_property("playback-time").unwrap_or_default();
-                        if t != current_time {
+                    VoyeursCommand::GetState if settings.is_serving => {
+                        if let Some(time) = player.position() {
+                            let paused = player.is_paused().unwrap_or(true);
+                            s.send(addr, VoyeursCommand::State { time, paused }).await;
+                        }
+                    }
+                    VoyeursCommand::State { time, paused } if !settings.is_serving => {
+                        s.stats.position(&who, time, paused, Instant::now());
+                        // Where the host is by now
+                        let remote = if paused {
+                            time
+                        } else {
+                            time + t_delta as f64 / 1000.0
+                        };
+                        let local = player.position();
+                        let peer = s.peers.get_mut(&addr).unwrap();
+                        peer.drift = local.map(|local| local - remote);
+                        peer.last_position = Some(Instant::now());
+
+                        // We asked to go right there
+                        if std::mem::take(&mut s.resyncing) {
                             s.ignore_next = true;
+                            player.seek(remote, settings.exact_seek).unwrap();
+                            let username = s.username(&settings).to_owned();
+                            s.log(
+                                Msg::Resyncing {
+                                    username: &username,
+                                    off: local.map_or(0.0, |local| local - remote),
+                                }
+                                .to_string(),
+                            );
+                        }
+                    }
+                    // Only the host has the say on where everyone should be
+                    VoyeursCommand::GetState | VoyeursCommand::State { .. } => {}
+                    VoyeursCommand::ServerInfo(_) if settings.is_serving => {}
+                    VoyeursCommand::ServerInfo(info) => {
+                        s.log(format!("the server runs {info}"));
+                        s.server_info = Some(info);
+                    }
+                    VoyeursCommand::ResumeAt { .. } if settings.is_serving => {
+                        s.debug(|| "ignoring it, only the host schedules resumes".to_owned());
+                    }
+                    VoyeursCommand::ResumeAt { time: position, at } => {
+                        // Playing with the host from now on, ready or not
+                        s.peers.get_mut(&addr).unwrap().ready = true;
+                        s.is_ready = true;
+                        let ahead = at as i64 - get_timestamp() as i64;
+                        s.ignore_next = true;
+                        if !player.is_paused().unwrap() {
+                            // Playing already, over to where the host is right now
+                            player.seek(position - ahead as f64 / 1000.0, true).unwrap();
+                        } else {
+                            // Past the moment already if the packet took longer than the lead
+                            let late = (-ahead).max(0) as f64 / 1000.0;
+                            player.seek(position + late, true).unwrap();
+                            drop(s);
+                            time::sleep(Duration::from_millis(ahead.max(0) as u64)).await;
+                            let mut s = state.lock().await;
+                            s.ignore_next = true;
+                            player.set_paused(false).unwrap();
+                        }
+                    }
+                    // Turned into a Ready or a Seek above
+                    VoyeursCommand::ReadyFrom { .. } | VoyeursCommand::SeekFrom { .. } => {}
+                    VoyeursCommand::Away(a) => {
+                        let peer = s.peers.get_mut(&addr).unwrap();
+                        peer.away = a;
+                        let name = if settings.is_serving {
+                            peer.username.clone()
+                        } else {
+                            Msg::Host.to_string()
+                        };
+                        let line = if a {
+                            Msg::Away(&name)
+                        } else {
+                            Msg::Back(&name)
+                        }
+                        .to_string();
+                        notify_from(&*player, &settings.osd, Category::Presence, &name, &line)
+                            .unwrap();
+                        s.log(line);
+                        if settings.is_serving {
+                            s.broadcast_peers(&settings.username).await;
+                        } else if let Some(host) =
+                            s.roster.iter_mut().find(|peer| peer.role == Role::Host)
+                        {
+                            host.away = a;
+                        }
 
-                            // If the file isn't loaded yet, the seek will fail
-                            while mpv.seek(t, SeekOptions::Absolute).is_err() {}
+                        // The peer we were waiting on went away, so the others can go on
+                        if a && !settings.standalone && s.is_ready && s.peers_ready() {
+                            if player.is_paused().unwrap() {
+                                s.ignore_next = true;
+                                player.set_paused(false).unwrap();
+                            }
+                            if settings.is_serving {
+                                s.announce(SessionEvent::Resume);
+                                s.broadcast(VoyeursCommand::Ready(true)).await;
+                                let position = player.position().unwrap_or_default();
+                                s.schedule_resume(position).await;
+                            }
+                        }
+                    }
+                    VoyeursCommand::Chat { mut from, text } => {
+                        if settings.is_serving {
+                            // Don't let peers speak for somebody else
+                            from = s.peers[&addr].username.clone();
+                            let chat = VoyeursCommand::Chat {
+                                from: from.clone(),
+                                text: text.clone(),
+                            };
+                            s.broadcast_excluding(chat, addr).await;
+                        }
+                        let line = format!("{from}: {text}");
+                        notify_from(&*player, &settings.osd, Category::Chat, &from, &line).unwrap();
+                        s.remember_chat(from.clone(), line.clone());
+                        s.announce(SessionEvent::Chat { from, text });
+                        #[cfg(feature = "notify")]
+                        desktop::notify(&settings, &line);
+                        s.log(line);
+                    }
+                    VoyeursCommand::Whisper { to, text } => {
+                        if !settings.is_serving {
+                            continue;
+                        }
+                        let from = s.peers[&addr].username.clone();
+                        // Passed along without a look, unless it's for the host
+                        if to != settings.username {
+                            if let Err(e) = s.whisper(from, to, text, true).await {
+                                s.send(addr, VoyeursCommand::ServerMessage(e)).await;
+                            }
+                            continue;
+                        }
+                        let line = Msg::Whisper {
+                            from: &from,
+                            text: &text,
+                        }
+                        .to_string();
+                        notify_from(&*player, &settings.osd, Category::Chat, &from, &line).unwrap();
+                        s.remember_chat(from, line.clone());
+                        #[cfg(feature = "notify")]
+                        desktop::notify(&settings, &line);
+                        s.log(line);
+                    }
+                    VoyeursCommand::WhisperFrom { from, text } => {
+                        if settings.is_serving {
+                            continue;
+                        }
+                        let line = Msg::Whisper {
+                            from: &from,
+                            text: &text,
+                        }
+                        .to_string();
+                        notify_from(&*player, &settings.osd, Category::Chat, &from, &line).unwrap();
+                        s.remember_chat(from, line.clone());
+                        #[cfg(feature = "notify")]
+                        desktop::notify(&settings, &line);
+                        s.log(line);
+                    }
+                    VoyeursCommand::Rename(username) if !settings.is_serving => {
+                        // What the server took, maybe cleaned up
+                        s.renamed = Some(username);
+                    }
+                    VoyeursCommand::Rename(username) => {
+                        let old = s.peers[&addr].username.clone();
+                        let others = s
+                            .joined_peers()
+                            .filter(|(a, _)| **a != addr)
+                            .map(|(_, p)| p.username.as_str());
+                        let renamed = username::validate(&username, others).and_then(|username| {
+                            match &certified {
+                                Some(name) if *name != username => Err(RejectReason::NotCertified),
+                                _ => Ok(username),
+                            }
+                        });
+                        let username = match renamed {
+                            Ok(username) if username != old => username,
+                            Ok(_) => continue,
+                            Err(reason) => {
+                                let line = format!("couldn't rename you: {reason}");
+                                s.send(addr, VoyeursCommand::ServerMessage(line)).await;
+                                continue;
+                            }
+                        };
+                        if s.typing.remove(&old) {
+                            s.typing.insert(username.clone());
+                        }
+                        s.peers.get_mut(&addr).unwrap().username = username.clone();
+                        let line = Msg::Renamed {
+                            from: &old,
+                            to: &username,
+                        }
+                        .to_string();
+                        notify(&*player, &settings.osd, Category::Presence, &line).unwrap();
+                        s.log(line.clone());
+                        s.send(addr, VoyeursCommand::Rename(username)).await;
+                        s.broadcast(VoyeursCommand::ServerMessage(line)).await;
+                        s.broadcast_peers(&settings.username).await;
+                    }
+                    VoyeursCommand::Typing(typing) => {
+                        if !settings.is_serving {
+                            continue;
+                        }
+                        let from = s.peers[&addr].username.clone();
+                        if typing {
+                            s.typing.insert(from.clone());
+                        } else {
+                            s.typing.remove(&from);
+                        }
+                        s.broadcast_excluding(VoyeursCommand::TypingFrom { from, typing }, addr)
+                            .await;
+                    }
+                    VoyeursCommand::TypingFrom { from, typing } => {
+                        if settings.is_serving {
+                            continue;
+                        }
+                        if typing {
+                            s.typing.insert(from);
+                        } else {
+                            s.typing.remove(&from);
+                        }
+                    }
+                    VoyeursCommand::Reaction(emoji) => {
+                        if settings.is_serving {
+                            let peer = s.peers.get_mut(&addr).unwrap();
+                            if peer
+                                .last_reaction
+                                .is_some_and(|last| last.elapsed() < REACTION_COOLDOWN)
+                            {
+                                continue;
+                            }
+                            peer.last_reaction = Some(Instant::now());
+                            s.broadcast_excluding(VoyeursCommand::Reaction(emoji.clone()), addr)
+                                .await;
+                        }
+                        notify(&*player, &settings.osd, Category::Reaction, &emoji).unwrap();
+                    }
+                    // Live streams are kept together by LiveLag instead
+                    VoyeursCommand::Seek(_) if settings.live => {}
+                    // Put them back where the host is instead
+                    VoyeursCommand::Seek(_)
+                        if settings.is_serving
+                            && s.locked
+                            && s.peers[&addr].role < Role::Moderator =>
+                    {
+                        let current_time = player.position().unwrap_or_default();
+                        s.send(addr, VoyeursCommand::Seek(current_time)).await;
+                    }
+                    VoyeursCommand::Seek(t) => {
+                        if !settings.plugins.is_empty() {
+                            // Nobody waits on the lock while the plugins make up their minds
+                            drop(s);
+                            let allowed = settings.plugins.pre_seek(actor.as_deref(), t).await;
+                            s = state.lock().await;
+                            // Kicked meanwhile
+                            if !s.peers.contains_key(&addr) {
+                                break;
+                            }
+                            if !allowed {
+                                s.log(format!(
+                                    "a plugin kept us from seeking to {}",
+                                    format_time(t)
+                                ));
+                                if settings.is_serving {
+                                    let current_time = player.position().unwrap_or_default();
+                                    s.send(addr, VoyeursCommand::Seek(current_time)).await;
+                                }
+                                continue;
+                            }
+                        }
+                        // It kept playing while the seek was on its way
+                        let t = if settings.precise && !player.is_paused().unwrap() {
+                            t + t_delta as f64 / 1000.0
+                        } else {
+                            t
+                        };
+                        let current_time = player.position().unwrap_or_default();
+                        if t != current_time {
+                            // If the file isn't loaded yet, the seek fails for a while.
+                            // One that keeps failing has lost the player
+                            let mut tries = 1;
+                            let seeked = loop {
+                                s.ignore_next = true;
+                                let seeked = player.seek(t, settings.exact_seek);
+                                if seeked.is_ok() || tries == SEEK_TRIES {
+                                    break seeked;
+                                }
+                                // No seek is coming back from the player to ignore
+                                s.ignore_next = false;
+                                tries += 1;
+                                // Nobody waits on the lock while the file loads
+                                drop(s);
+                                time::sleep(SEEK_RETRY_INTERVAL).await;
+                                s = state.lock().await;
+                                if !s.peers.contains_key(&addr) {
+                                    break seeked;
+                                }
+                            };
+                            // Kicked meanwhile
+                            if !s.peers.contains_key(&addr) {
+                                break;
+                            }
+                            if let Err(e) = seeked {
+                                s.ignore_next = false;
+                                s.log(format!("couldn't seek to {}: {e}", format_time(t)));
+                                // Back to where we are, so that they don't stay off it
+                                if settings.is_serving {
+                                    let current_time = player.position().unwrap_or_default();
+                                    s.send(addr, VoyeursCommand::Seek(current_time)).await;
+                                }
+                                continue;
+                            }
+                            s.announce(SessionEvent::Seek {
+                                by: actor.clone(),
+                                time: t,
+                            });
 
+                            // Makes accidental scrubs obvious, and who to blame
+                            if let Some(actor) = &actor {
+                                let line = Msg::Jumped {
+                                    name: actor,
+                                    time: t,
+                                    off: t - current_time,
+                                }
+                                .to_string();
+                                notify(&*player, &settings.osd, Category::Presence, &line).unwrap();
+                                s.log(line);
+                            }
                             if settings.is_serving {
-                                s.broadcast_excluding(VoyeursCommand::Seek(t), addr).await;
+                                let relayed = VoyeursCommand::SeekFrom {
+                                    username: who,
+                                    time: t,
+                                };
+                                s.broadcast_excluding(relayed, addr).await;
                             }
                         }
                     }
                     VoyeursCommand::NewConnection(username) => {
-                        if !username.chars().all(char::is_alphanumeric) {
-                            break;
+                        if s.peers[&addr].handshake != HandshakeState::AwaitingHello {
+                            continue;
                         }
+                        let others = s
+                            .joined_peers()
+                            .filter(|(a, _)| **a != addr)
+                            .map(|(_, p)| p.username.as_str());
+                        let username = username::validate(&username, others)
+                            .and_then(|username| match &certified {
+                                Some(name) if *name != username => Err(RejectReason::NotCertified),
+                                _ => Ok(username),
+                            })
+                            .and_then(|username| {
+                                trust(&*player, &mut s, &settings, addr, &username)?;
+                                Ok(username)
+                            });
+                        let username = match username {
+                            Ok(username) => username,
+                            Err(reason) => {
+                                s.log(format!("rejected {addr}: {reason}"));
+                                s.evict(addr, Some(reason)).await;
+                                break;
+                            }
+                        };
 
-                        mpv.pause().unwrap();
-                        mpv.run_command_raw(
-                            "show-text",
-                            &[format!("{username}: connected").as_str(), "2000"],
+                        if !settings.plugins.is_empty() {
+                            drop(s);
+                            let joined = settings.plugins.post_join(&username).await;
+                            s = state.lock().await;
+                            if !s.peers.contains_key(&addr) {
+                                break;
+                            }
+                            if let Err(reason) = joined {
+                                s.log(format!("a plugin turned {username} away: {reason}"));
+                                s.evict(addr, Some(RejectReason::Kicked)).await;
+                                break;
+                            }
+                            // Somebody else may have joined as them in the meantime
+                            let others = s
+                                .joined_peers()
+                                .filter(|(a, _)| **a != addr)
+                                .map(|(_, p)| p.username.as_str());
+                            if let Err(reason) = username::validate(&username, others) {
+                                s.log(format!("rejected {addr}: {reason}"));
+                                s.evict(addr, Some(reason)).await;
+                                break;
+                            }
+                        }
+
+                        // Back within the grace period: as if it never left
+                        let returning = s.departed.remove(&username);
+                        let line = if returning.is_some() {
+                            Msg::Rejoined(&username).to_string()
+                        } else {
+                            player.set_paused(true).unwrap();
+                            s.announce(SessionEvent::Join(username.clone()));
+                            Msg::Connected(&username).to_string()
+                        };
+                        notify_from(
+                            &*player,
+                            &settings.osd,
+                            Category::Presence,
+                            &username,
+                            &line,
                         )
                         .unwrap();
+                        #[cfg(feature = "notify")]
+                        desktop::notify(&settings, &line);
+                        s.log(line);
 
-                        let filename = mpv.get_property("filename").unwrap_or_default();
-                        let duration = mpv.get_property("duration").unwrap_or_default();
-                        let pause: bool = mpv.get_property("pause").unwrap_or_default();
-                        let current_time = mpv.get_property("playback-time").unwrap_or_default();
-                        s.peers.get_mut(&addr).unwrap().username = username;
+                        let filename = player.filename().unwrap_or_default();
+                        let duration = player.duration().unwrap_or_default();
+                        let size = player.file_size();
+                        let pause = player.is_paused().unwrap_or_default();
+                        let current_time = player.position().unwrap_or_default();
+                        let peer = s.peers.get_mut(&addr).unwrap();
+                        peer.username = username;
+                        peer.handshake = HandshakeState::Joined;
+                        if let Some(departed) = &returning {
+                            peer.ready = departed.ready;
+                            peer.away = departed.away;
+                            // Anyone can take a name, only the same key gets the role back.
+                            // Unless the admin password just made them more
+                            if departed.key.is_some() && departed.key == peer.key {
+                                peer.role = peer.role.max(departed.role);
+                            }
+                            peer.latency = departed.latency.clone();
+                        }
+                        // What to --ban-key them by
+                        if let Some(key) = &peer.key {
+                            let line = format!(
+                                "{} holds key {} ({})",
+                                peer.username,
+                                identity::encode_key(key),
+                                identity::fingerprint(key)
+                            );
+                            s.log(line);
+                        }
+                        pending = None;
+                        let filename = if settings.anonymous {
+                            filename::hashed(&filename)
+                        } else {
+                            filename
+                        };
                         s.send(addr, VoyeursCommand::Filename(filename)).await;
                         s.send(addr, VoyeursCommand::Duration(duration)).await;
+                        // Streams don't have one
+                        if let Some(size) = size {
+                            s.send(addr, VoyeursCommand::FileSize(size)).await;
+                        }
                         s.send(addr, VoyeursCommand::Seek(current_time)).await;
                         s.send(addr, VoyeursCommand::Ready(!pause)).await;
+                        s.send(
+                            addr,
+                            VoyeursCommand::Extensions(settings.extensions.clone()),
+                        )
+                        .await;
+                        let info = s.server_info();
+                        s.send(addr, VoyeursCommand::ServerInfo(info)).await;
+                        if let (Some(motd), None) = (&settings.motd, &returning) {
+                            s.send(addr, VoyeursCommand::ServerMessage(motd.clone()))
+                                .await;
+                        }
+                        if !s.segments.is_empty() {
+                            let segments = s.segments.clone();
+                            s.send(addr, VoyeursCommand::SkipSegments(segments)).await;
+                        }
+                        if s.locked {
+                            s.send(addr, VoyeursCommand::Lock(true)).await;
+                        }
+                        // Bookmarks made before they joined
+                        let bookmarks: Vec<_> = s
+                            .bookmarks
+                            .iter()
+                            .map(|(name, time)| VoyeursCommand::Bookmark {
+                                name: name.to_owned(),
+                                time,
+                            })
+                            .collect();
+                        for bookmark in bookmarks {
+                            s.send(addr, bookmark).await;
+                        }
+                        s.broadcast_peers(&settings.username).await;
                     }
-                    VoyeursCommand::GetStreamName => {
+                    VoyeursCommand::Challenge(challenge) => {
                         if settings.is_serving {
-                            // Check if path is a valid URL
-                            // TODO: the correct way to check this is by using stream-open-filename and parsing its data
+                            continue;
+                        }
+                        // Before anything else, the server answers from the room
+                        if let Some(room) = &settings.room {
+                            s.send(addr, VoyeursCommand::Room(room.clone())).await
+                        }
+                        if let Some(identity) = &settings.identity {
+                            let identity = VoyeursCommand::Identity {
+                                key: identity.public(),
+                                sig: identity.sign(&challenge),
+                            };
+                            s.send(addr, identity).await
+                        }
+                        if let Some(password) = &settings.password {
+                            let answer = password.vouch(&challenge);
+                            s.send(addr, VoyeursCommand::Password(answer)).await
+                        }
+                        if let Some(invite) = &settings.invite {
+                            s.send(addr, invite.command()).await
+                        }
+                        if let Some(admin) = &settings.admin_password {
+                            let answer = admin.vouch(&challenge);
+                            s.send(addr, VoyeursCommand::Auth(answer)).await
+                        }
+                        if let Some(code) = &settings.join_code {
+                            s.send(addr, VoyeursCommand::JoinCode(code.clone())).await
+                        }
+                        if settings.accept_source {
+                            s.send(addr, VoyeursCommand::GetStreamName).await
+                        } else {
+                            introduce(&mut s, addr, &settings).await
+                        }
+                    }
+                    VoyeursCommand::Identity { key, sig } => {
+                        if !settings.is_serving || s.peers[&addr].key.is_some() {
+                            continue;
+                        }
+                        if !identity::verify(&key, &challenge, &sig) {
+                            s.log(format!(
+                                "dropped connection from {addr}: bad identity signature"
+                            ));
+                            s.evict(addr, None).await;
+                            s.strike(addr);
+                            break;
+                        }
+                        if settings.banned_keys.contains(&key) || s.banned_keys.contains(&key) {
+                            let fingerprint = identity::fingerprint(&key);
+                            s.log(format!("rejected {addr}: key {fingerprint} is banned"));
+                            s.evict(addr, Some(RejectReason::Kicked)).await;
+                            break;
+                        }
+                        s.peers.get_mut(&addr).unwrap().key = Some(key);
+                    }
+                    VoyeursCommand::Password(answer) => {
+                        if !settings.is_serving {
+                            continue;
+                        }
+                        let vouched = settings
+                            .password
+                            .as_ref()
+                            .is_some_and(|password| password.is_vouched_by(&challenge, &answer));
+                        // Guessing counts like any other failed handshake
+                        if settings.password.is_some() && !vouched {
+                            s.log(format!("{addr} got the password wrong"));
+                            s.strike(addr);
+                        }
+                        s.peers.get_mut(&addr).unwrap().vouched = vouched;
+                    }
+                    VoyeursCommand::Auth(answer) => {
+                        let Some(admin) = settings.admin_password.as_ref() else {
+                            continue;
+                        };
+                        if !settings.is_serving {
+                            continue;
+                        }
+                        if !admin.is_vouched_by(&challenge, &answer) {
+                            s.log(format!("{addr} got the admin password wrong"));
+                            s.strike(addr);
+                            continue;
+                        }
+                        // Whoever knows the admin password needn't know the room's
+                        let peer = s.peers.get_mut(&addr).unwrap();
+                        peer.vouched = true;
+                        peer.role = peer.role.max(Role::Moderator);
+                        if peer.handshake == HandshakeState::Joined {
+                            let line = Msg::NowRole {
+                                username: Some(&peer.username),
+                                role: peer.role,
+                            }
+                            .to_string();
+                            s.broadcast_peers(&settings.username).await;
+                            s.log(line);
+                        }
+                    }
+                    VoyeursCommand::JoinCode(code) => {
+                        if settings.is_serving && settings.invite_only {
+                            s.peers.get_mut(&addr).unwrap().join_code = Some(code);
+                        }
+                    }
+                    VoyeursCommand::Invite { expires, sig } => {
+                        let Some(identity) = settings.identity.as_ref() else {
+                            continue;
+                        };
+                        if !settings.is_serving || !settings.invite_only {
+                            continue;
+                        }
+                        let invite = Invite { expires, sig };
+                        match invite.check(identity, get_timestamp() / 1000) {
+                            Ok(()) => s.peers.get_mut(&addr).unwrap().vouched = true,
+                            Err(reason) => {
+                                s.log(format!("rejected {addr}: {reason}"));
+                                if reason == RejectReason::NotTrusted {
+                                    s.strike(addr);
+                                }
+                                s.evict(addr, Some(reason)).await;
+                                break;
+                            }
+                        }
+                    }
+                    VoyeursCommand::Room(name) => {
+                        if !settings.is_serving
+                            || s.peers[&addr].handshake != HandshakeState::AwaitingHello
+                        {
+                            continue;
+                        }
+                        let Some(room) = settings.rooms.get(&name) else {
+                            s.log(format!("rejected {addr}: {}", RejectReason::NoSuchRoom));
+                            s.evict(addr, Some(RejectReason::NoSuchRoom)).await;
+                            break;
+                        };
+                        // The peer moves over, with the room's player from now on
+                        if !Arc::ptr_eq(&room.state, &state) {
+                            let Some(peer) = s.peers.remove(&addr) else {
+                                break;
+                            };
+                            drop(s);
+                            room.state.lock().await.peers.insert(addr, peer);
+                            state = Arc::clone(&room.state);
+                            player = room.player.connect();
+                        }
+                    }
+                    VoyeursCommand::Extensions(names) => {
+                        let peer = s.peers.get_mut(&addr).unwrap();
+                        peer.extensions = names
+                            .into_iter()
+                            .filter(|name| settings.extensions.contains(name))
+                            .collect();
+                    }
+                    VoyeursCommand::Position { time, paused } => {
+                        s.stats.position(&who, time, paused, Instant::now());
+                        let local = player.position();
+                        // Where the peer is by now, after the report spent some time on the wire
+                        let remote = if paused {
+                            time
+                        } else {
+                            time + t_delta as f64 / 1000.0
+                        };
+                        let peer = s.peers.get_mut(&addr).unwrap();
+                        peer.drift = local.map(|local| local - remote);
+                        peer.last_position = Some(Instant::now());
+
+                        // Coming back from a restart a bit off is too little to notice
+                        // until it adds up, so it's corrected right away
+                        let restarted = std::mem::take(&mut peer.recheck);
+                        if let (true, Some(drift), Some(local)) = (restarted, peer.drift, local) {
+                            if drift.abs() > RESTART_TOLERANCE {
+                                let (username, off) = if settings.is_serving {
+                                    peer.last_resync = Some(Instant::now());
+                                    let username = peer.username.clone();
+                                    let target = local + t_delta as f64 / 1000.0;
+                                    s.send(addr, VoyeursCommand::Seek(target)).await;
+                                    (username, -drift)
+                                } else {
+                                    s.ignore_next = true;
+                                    player.seek(local - drift, settings.exact_seek).unwrap();
+                                    (s.username(&settings).to_owned(), drift)
+                                };
+                                s.log(
+                                    Msg::Resyncing {
+                                        username: &username,
+                                        off,
+                                    }
+                                    .to_string(),
+                                );
+                                continue;
+                            }
+                        }
 
-                            let mut streamname =
-                                mpv.get_property_string("path").unwrap_or_default();
-                            if Url::parse(&streamname).is_err() {
-                                streamname = "".to_owned();
+                        // Pull peers that drifted too far back in line
+                        let (Some(max), Some(drift), Some(local)) =
+                            (settings.max_desync, peer.drift, local)
+                        else {
+                            continue;
+                        };
+                        let cooled_down = peer
+                            .last_resync
+                            .is_none_or(|t| t.elapsed() > RESYNC_COOLDOWN);
+                        if settings.is_serving && drift.abs() > max && cooled_down {
+                            peer.last_resync = Some(Instant::now());
+                            let username = peer.username.clone();
+                            let playing = !player.is_paused().unwrap_or(true);
+                            let target = if playing {
+                                local + t_delta as f64 / 1000.0
+                            } else {
+                                local
+                            };
+                            s.send(addr, VoyeursCommand::Seek(target)).await;
+                            s.log(
+                                Msg::Resyncing {
+                                    username: &username,
+                                    off: -drift,
+                                }
+                                .to_string(),
+                            );
+                        }
+                    }
+                    VoyeursCommand::LiveLag(lag) => {
+                        let ahead = player.live_lag().map(|own| lag - own);
+                        let peer = s.peers.get_mut(&addr).unwrap();
+                        peer.drift = ahead;
+                        peer.last_position = Some(Instant::now());
+
+                        // Clients follow the host
+                        if let (true, false, Some(ahead)) =
+                            (settings.live, settings.is_serving, ahead)
+                        {
+                            if let Err(e) = follow_live_edge(&*player, ahead) {
+                                s.log(format!("couldn't follow the live edge: {e}"));
+                            }
+                        }
+                    }
+                    VoyeursCommand::PlaylistPos(pos) => {
+                        // Without --audio-only, only the host moves the playlist
+                        if !settings.audio_only && settings.is_serving {
+                            continue;
+                        }
+                        if !settings.audio_only {
+                            hold_for_next_entry(&*player, &mut s, &settings);
+                        }
+                        if player.playlist_pos() != Some(pos) {
+                            // Held clients don't pass the move on, there's nothing to ignore
+                            let ignoring = s.ignore_next;
+                            if settings.audio_only {
+                                s.ignore_next = true;
+                            }
+                            if player.set_playlist_pos(pos).is_err() {
+                                s.ignore_next = ignoring;
+                                let line = Msg::NoPlaylistEntry(pos + 1).to_string();
+                                notify(&*player, &settings.osd, Category::Warning, &line).unwrap();
+                                s.log(line);
+                                continue;
+                            }
+                        }
+                        if !settings.audio_only && settings.auto_ready {
+                            s.send(addr, VoyeursCommand::Ready(true)).await;
+                        }
+                        if settings.is_serving {
+                            s.broadcast_excluding(VoyeursCommand::PlaylistPos(pos), addr)
+                                .await;
+                        }
+                    }
+                    VoyeursCommand::Extension { code, args } => {
+                        let shared = &s.peers[&addr].extensions;
+                        let Some(plugin) = settings.plugins.handling(code, shared) else {
+                            // Skip them instead of dropping a peer that runs a fork
+                            let line = format!(
+                                "skipping extension command {code:#04x} from {addr}, shared extensions: {shared:?}",
+                            );
+                            s.log(line);
+                            continue;
+                        };
+                        drop(s);
+                        let shown = plugin.command(&who, code, &args).await;
+                        s = state.lock().await;
+                        if !s.peers.contains_key(&addr) {
+                            break;
+                        }
+                        if let Some(line) = shown {
+                            notify(&*player, &settings.osd, Category::Chat, &line).unwrap();
+                            s.log(line);
+                        }
+                        // The other peers with the plugin get it too
+                        if settings.is_serving {
+                            let name = plugin.name().to_owned();
+                            let others: Vec<_> = s
+                                .joined_peers()
+                                .filter(|(a, p)| **a != addr && p.extensions.contains(&name))
+                                .map(|(a, _)| *a)
+                                .collect();
+                            for other in others {
+                                let command = VoyeursCommand::Extension {
+                                    code,
+                                    args: args.clone(),
+                                };
+                                s.send(other, command).await;
                             }
+                        }
+                    }
+                    VoyeursCommand::Rejected(reason) => {
+                        let line = Msg::Rejected(reason).to_string();
+                        notify(&*player, &settings.osd, Category::Warning, &line).unwrap();
+                        s.log(line);
+                        // Knocking again would get the same answer
+                        s.goodbye = true;
+                        break;
+                    }
+                    // Peers just disconnect, only the host's goodbye means anything
+                    VoyeursCommand::Goodbye if settings.is_serving => {}
+                    VoyeursCommand::Goodbye => {
+                        let line = Msg::Goodbye.to_string();
+                        notify(&*player, &settings.osd, Category::Warning, &line).unwrap();
+                        s.log(line);
+                        s.goodbye = true;
+                        break;
+                    }
+                    VoyeursCommand::ServerMessage(text) => {
+                        if settings.is_serving {
+                            continue;
+                        }
+                        notify(&*player, &settings.osd, Category::Chat, &text).unwrap();
+                        s.log(format!("server: {text}"));
+                    }
+                    VoyeursCommand::Bookmark { name, time } => {
+                        s.bookmarks.set(name.clone(), time);
+                        if settings.is_serving {
+                            let bookmark = VoyeursCommand::Bookmark {
+                                name: name.clone(),
+                                time,
+                            };
+                            s.broadcast_excluding(bookmark, addr).await;
+                        }
+                        let line = Msg::Bookmark { name: &name, time }.to_string();
+                        notify(&*player, &settings.osd, Category::Chat, &line).unwrap();
+                        s.log(line);
+                    }
+                    VoyeursCommand::SkipSegments(segments) => {
+                        // The host has them already
+                        if settings.is_serving {
+                            continue;
+                        }
+                        if !segments.is_empty() {
+                            s.log(format!("skipping {} segments", segments.len()));
+                        }
+                        s.segments = segments;
+                    }
+                    VoyeursCommand::Peers(peers) => {
+                        if settings.is_serving {
+                            continue;
+                        }
+                        let role = peers
+                            .iter()
+                            .find(|peer| peer.username == s.username(&settings))
+                            .map_or(Role::Viewer, |peer| peer.role);
+                        if role != s.role {
+                            s.role = role;
+                            let line = Msg::NowRole {
+                                username: None,
+                                role,
+                            }
+                            .to_string();
+                            notify(&*player, &settings.osd, Category::Presence, &line).unwrap();
+                            s.log(line);
+                        }
+                        s.typing
+                            .retain(|typing| peers.iter().any(|peer| peer.username == *typing));
+                        s.roster = peers;
+                    }
+                    VoyeursCommand::Kick(username) => {
+                        if !settings.is_serving {
+                            continue;
+                        }
+                        let by = &s.peers[&addr];
+                        let (by, allowed) = (by.username.clone(), by.role >= Role::Moderator);
+                        let target = s
+                            .joined_peers()
+                            .find(|(_, peer)| peer.username == username)
+                            .map(|(addr, peer)| (*addr, peer.role));
+                        match target {
+                            // Moderators keep the viewers in line, not each other
+                            Some((target, Role::Viewer)) if allowed => {
+                                s.log(format!("{by} kicked {username}"));
+                                if let Some(username) = s.kick(target).await {
+                                    leave(&*player, &mut s, &settings, username).await;
+                                }
+                            }
+                            _ => s.debug(|| format!("{by} can't kick {username}")),
+                        }
+                    }
+                    VoyeursCommand::Lock(locked) => {
+                        if settings.is_serving {
+                            continue;
+                        }
+                        s.locked = locked;
+                        let line = Msg::Locked(locked).to_string();
+                        notify(&*player, &settings.osd, Category::Warning, &line).unwrap();
+                        s.log(line);
+                    }
+                    VoyeursCommand::GetStreamName => {
+                        if settings.is_serving {
+                            // Empty when we are playing a local file
+                            let streamname = player.stream_source().unwrap_or_default();
                             s.send(addr, VoyeursCommand::StreamName(streamname)).await;
                         }
                     }
                     VoyeursCommand::StreamName(stream) => {
                         if settings.accept_source {
                             if stream.is_empty() {
-                                println!("Server is not streaming from a valid url")
+                                s.log(Msg::NotStreaming.to_string())
                             }
-                            mpv.run_command(MpvCommand::LoadFile {
-                                file: stream.to_string(),
-                                option: PlaylistAddOptions::Replace,
-                            })
-                            .unwrap();
-                            while !matches!(mpv.event_listen().unwrap(), Event::FileLoaded) {}
-                            s.send(
-                                addr,
-                                VoyeursCommand::NewConnection(settings.username.to_string()),
-                            )
-                            .await
+                            // Same file, from somewhere closer
+                            let stream = match settings.rewrites.apply(&stream) {
+                                Some(rewritten) => {
+                                    s.log(format!("playing {rewritten} instead of {stream}"));
+                                    rewritten
+                                }
+                                None => stream,
+                            };
+                            player.load(&stream).unwrap();
+                            introduce(&mut s, addr, &settings).await
                         }
                     }
                     VoyeursCommand::Filename(f) => {
-                        if f != mpv.get_property::<String>("filename").unwrap_or_default() {
-                            mpv.run_command_raw(
-                                "show-text",
-                                &["filename does not match with server's filename", "2000"],
+                        let own = player.filename().unwrap_or_default();
+                        if !filename::matches(&own, &f, settings.filename_match) {
+                            notify(
+                                &*player,
+                                &settings.osd,
+                                Category::Warning,
+                                &Msg::FilenameMismatch.to_string(),
                             )
                             .unwrap();
                         }
                     }
                     VoyeursCommand::Duration(t) => {
-                        if t != mpv.get_property::<f64>("duration").unwrap_or_default() {
-                            mpv.run_command_raw(
-                                "show-text",
-                                &["duration does not match with server's duration", "2000"],
+                        // Tracks of gapless albums are often cut slightly differently
+                        let tolerance = if settings.audio_only {
+                            settings.duration_tolerance.max(AUDIO_DURATION_TOLERANCE)
+                        } else {
+                            settings.duration_tolerance
+                        };
+                        let off = player.duration().unwrap_or_default() - t;
+                        if off.abs() > tolerance {
+                            notify(
+                                &*player,
+                                &settings.osd,
+                                Category::Warning,
+                                &Msg::DurationMismatch(off).to_string(),
+                            )
+                            .unwrap();
+                        }
+                    }
+                    // Differently cut tracks differ in size too, the duration is enough there
+                    VoyeursCommand::FileSize(_) if settings.audio_only => {}
+                    VoyeursCommand::FileSize(size) => {
+                        // Nothing to compare a stream with
+                        if player.file_size().is_some_and(|own| own != size) {
+                            notify(
+                                &*player,
+                                &settings.osd,
+                                Category::Warning,
+                                &Msg::FileSizeMismatch.to_string(),
                             )
                             .unwrap();
                         }
@@ -194,15 +1284,247 @@ pub async fn handle_connection(
             }
             Err(_) => {
                 let mut s = state.lock().await;
-                let peer = s.peers.remove(&addr).unwrap();
-                mpv.run_command_raw(
-                    "show-text",
-                    &[format!("{} : disconnected", peer.username).as_str(), "2000"],
+                let Some(peer) = s.evict(addr, None).await else {
+                    break;
+                };
+                if peer.handshake != HandshakeState::Joined {
+                    s.log(Msg::Disconnected(&peer.username).to_string());
+                    s.strike(addr);
+                    break;
+                }
+                let grace = settings.reconnect_grace;
+                if slow || !settings.is_serving || grace.is_zero() {
+                    leave(&*player, &mut s, &settings, peer.username).await;
+                    break;
+                }
+
+                // Give it a chance to come back before telling everyone it left
+                let line = Msg::Lost(&peer.username).to_string();
+                notify_from(
+                    &*player,
+                    &settings.osd,
+                    Category::Presence,
+                    &peer.username,
+                    &line,
                 )
                 .unwrap();
-                peer.tx.forget();
+                s.log(line);
+                let left = Instant::now();
+                let departed = Departed {
+                    ready: peer.ready,
+                    away: peer.away,
+                    role: peer.role,
+                    key: peer.key,
+                    latency: peer.latency,
+                    left,
+                };
+                s.departed.insert(peer.username.clone(), departed);
+                drop(s);
+                tokio::spawn(async move {
+                    time::sleep(grace).await;
+                    let mut s = state.lock().await;
+                    // Still gone, and not gone again since
+                    if s.departed
+                        .get(&peer.username)
+                        .is_some_and(|d| d.left == left)
+                    {
+                        s.departed.remove(&peer.username);
+                        leave(&*player, &mut s, &settings, peer.username).await;
+                    }
+                });
                 break;
             }
         };
     }
 }
+
+/// Say who paused or resumed the video, when somebody else did
+fn attribute(
+    player: &dyn PlayerBackend,
+    s: &mut Shared,
+    settings: &Settings,
+    actor: Option<&str>,
+    paused: bool,
+) {
+    let Some(actor) = actor else {
+        return;
+    };
+    let line = if paused {
+        Msg::PausedBy(actor)
+    } else {
+        Msg::ResumedBy(actor)
+    }
+    .to_string();
+    notify(player, &settings.osd, Category::Presence, &line).unwrap();
+    s.log(line);
+}
+
+/// Whether the server lets the peer in: trusted keys always get in, and with a password,
+/// --invite-only or --trusted-keys the others need the password, an invite, a join code
+/// or the admin password. A new key let in that way is shown and remembered, for the host
+/// to check with whoever it should belong to
+fn trust(
+    player: &dyn PlayerBackend,
+    s: &mut Shared,
+    settings: &Settings,
+    addr: SocketAddr,
+    username: &str,
+) -> Result<(), RejectReason> {
+    // Back within the grace period with the key it had, its code was used up letting it in
+    let peer = &s.peers[&addr];
+    let returning = s
+        .departed
+        .get(username)
+        .is_some_and(|departed| departed.key.is_some() && departed.key == peer.key);
+    if returning && peer.join_code.is_some() {
+        s.peers.get_mut(&addr).unwrap().vouched = true;
+    }
+    // Redeemed now that we know who for, unless the password or an invite let them in
+    let peer = &s.peers[&addr];
+    if let (Some(code), false) = (peer.join_code.clone(), peer.vouched) {
+        let redeemed = s.join_codes.redeem(&code, Instant::now());
+        if !redeemed {
+            s.log(format!("{addr} gave a join code that isn't good"));
+            s.strike(addr);
+        }
+        s.peers.get_mut(&addr).unwrap().vouched = redeemed;
+    }
+    let peer = &s.peers[&addr];
+    let trusted = match (&settings.trusted_keys, &peer.key) {
+        (Some(list), Some(key)) => list.name(key).is_some(),
+        _ => false,
+    };
+    if trusted {
+        return Ok(());
+    }
+    let guarded =
+        settings.password.is_some() || settings.invite_only || settings.trusted_keys.is_some();
+    if guarded && !peer.vouched {
+        return Err(RejectReason::NotTrusted);
+    }
+    // Vouched for, a new key is remembered so that it needn't be again
+    match (&settings.trusted_keys, peer.key) {
+        (Some(list), Some(key)) => {
+            if let Err(e) = list.add(&key, username) {
+                s.log(format!("couldn't save {username}'s key: {e}"));
+            }
+            let line = Msg::NewKey {
+                username,
+                key: &identity::fingerprint(&key),
+            }
+            .to_string();
+            notify(player, &settings.osd, Category::Warning, &line).unwrap();
+            s.log(line);
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Tell everyone a peer is gone for good
+pub async fn leave(
+    player: &dyn PlayerBackend,
+    s: &mut Shared,
+    settings: &Settings,
+    username: String,
+) {
+    let line = Msg::Disconnected(&username).to_string();
+    notify_from(player, &settings.osd, Category::Presence, &username, &line).unwrap();
+    #[cfg(feature = "notify")]
+    desktop::notify(settings, &line);
+    s.typing.remove(&username);
+    s.announce(SessionEvent::Leave(username));
+    s.log(line.clone());
+    // the peer itself is gone by now, this is for the ones still here
+    s.broadcast(VoyeursCommand::ServerMessage(line)).await;
+    if settings.is_serving {
+        s.broadcast_peers(&settings.username).await;
+    }
+    if settings.pause_when_empty
+        && s.joined_peers().next().is_none()
+        && !player.is_paused().unwrap_or(true)
+    {
+        // Whoever joins next gets the usual wait for everyone to be ready
+        s.ignore_next = true;
+        player.set_paused(true).unwrap();
+        s.is_ready = settings.auto_ready;
+        let line = Msg::PausedEmpty.to_string();
+        notify(player, &settings.osd, Category::Presence, &line).unwrap();
+        s.log(line);
+    }
+}
+
+async fn introduce(s: &mut Shared, addr: SocketAddr, settings: &Settings) {
+    s.send(
+        addr,
+        VoyeursCommand::NewConnection(s.username(settings).to_owned()),
+    )
+    .await;
+    s.send(
+        addr,
+        VoyeursCommand::Extensions(settings.extensions.clone()),
+    )
+    .await;
+    if settings.auto_ready {
+        s.send(addr, VoyeursCommand::Ready(true)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::proto::{Role, VoyeursCommand};
+    use crate::selftest::{assert_ignored, eventually, join_deaf, Node};
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_rejoin_role() {
+        let server = Node::new(true, "host");
+        let addr = server.serve().await;
+        let bob = Node::new(false, "bob");
+        let bob_conn = bob.connect(addr).await;
+        assert!(eventually(|| server.joined_peers() == Some(1)).await);
+
+        // a moderator without an identity key, its role can't follow its name
+        for peer in server.state.lock().await.peers.values_mut() {
+            peer.role = Role::Moderator;
+        }
+        // dropping both halves of the connection is what closes it
+        bob_conn.abort();
+        bob.state.lock().await.peers.clear();
+        assert!(eventually(|| server.joined_peers() == Some(0)).await);
+
+        // back within the grace period, as a viewer
+        let bob = Node::new(false, "bob");
+        bob.connect(addr).await;
+        let rejoined = eventually(|| {
+            server
+                .state
+                .try_lock()
+                .is_ok_and(|s| s.departed.is_empty() && s.joined_peers().count() == 1)
+        })
+        .await;
+        assert!(rejoined);
+        let s = server.state.lock().await;
+        assert!(s.joined_peers().all(|(_, peer)| peer.role == Role::Viewer));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_moderator_kick() {
+        let server = Node::new(true, "host");
+        let addr = server.serve().await;
+        let alice = Node::new(false, "alice");
+        alice.connect(addr).await;
+        assert!(eventually(|| server.joined_peers() == Some(1)).await);
+        let mallory = join_deaf(addr, "mallory").await;
+        assert!(eventually(|| server.joined_peers() == Some(2)).await);
+
+        for peer in server.state.lock().await.peers.values_mut() {
+            if peer.username == "alice" {
+                peer.role = Role::Moderator;
+            }
+        }
+        let kick = VoyeursCommand::Kick("mallory".to_owned());
+        alice.state.lock().await.broadcast(kick).await;
+        assert!(eventually(|| server.joined_peers() == Some(1)).await);
+        assert_ignored(&server, mallory, "mallory").await;
+    }
+}
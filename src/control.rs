@@ -0,0 +1,559 @@
+use std::{
+    env,
+    fs::{self, Permissions},
+    io,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::UnixListener,
+    sync::Mutex,
+    time::timeout,
+};
+
+use crate::{
+    bookmarks::{format_time, parse_time},
+    client_message_handler::leave,
+    i18n::Msg,
+    identity,
+    invite::{self, Invite},
+    player::PlayerBackend,
+    proto::*,
+    source,
+    sponsorblock::parse_segment,
+    time::{get_timestamp, get_weighted_latency},
+    username, HandshakeState, Settings, Shared,
+};
+
+// How long voyeursctl gets to send its command
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Where the control socket goes when no path is given, voyeursctl looks there too
+pub fn default_socket() -> PathBuf {
+    env::var_os("XDG_RUNTIME_DIR")
+        .map_or_else(env::temp_dir, PathBuf::from)
+        .join("voyeurs.sock")
+}
+
+/// Take commands from voyeursctl, one per connection, answering with a line of text
+pub async fn serve(
+    path: PathBuf,
+    player: Box<dyn PlayerBackend>,
+    state: Arc<Mutex<Shared>>,
+    settings: Settings,
+) {
+    // left behind by a voyeurs that didn't exit cleanly
+    let _ = fs::remove_file(&path);
+    let listener = match bind_private(&path) {
+        Ok(listener) => listener,
+        Err(e) => {
+            state.lock().await.log(format!(
+                "couldn't open the control socket {}: {e}",
+                path.display()
+            ));
+            return;
+        }
+    };
+    let player = Arc::new(Mutex::new(player));
+    while let Ok((stream, _)) = listener.accept().await {
+        let (player, state, settings) = (Arc::clone(&player), Arc::clone(&state), settings.clone());
+        // One that never sends its command mustn't hold up the others
+        tokio::spawn(async move {
+            let (rx, mut tx) = stream.into_split();
+            let (mut rx, mut line) = (BufReader::new(rx), String::new());
+            let read = timeout(REQUEST_TIMEOUT, rx.read_line(&mut line));
+            if !matches!(read.await, Ok(Ok(_))) {
+                return;
+            }
+            let mut player = player.lock().await;
+            let mut s = state.lock().await;
+            s.debug(|| format!("control: {}", line.trim()));
+            let reply = match execute(&mut **player, &mut s, &settings, line.trim()).await {
+                Ok(reply) => reply,
+                Err(e) => format!("error: {e}"),
+            };
+            drop(s);
+            let _ = tx.write_all(format!("{reply}\n").as_bytes()).await;
+        });
+    }
+}
+
+/// Bind the socket where nobody else can reach it and make it ours alone, and only then
+/// move it to `path`. Only we get to control the session, even when the socket is in /tmp
+fn bind_private(path: &Path) -> io::Result<UnixListener> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    // made 0700, and on the same filesystem for the rename
+    let dir = tempfile::Builder::new()
+        .prefix(".voyeurs-")
+        .tempdir_in(parent)?;
+    let private = dir.path().join("voyeurs.sock");
+    let listener = UnixListener::bind(&private)?;
+    fs::set_permissions(&private, Permissions::from_mode(0o600))?;
+    fs::rename(&private, path)?;
+    Ok(listener)
+}
+
+pub async fn execute(
+    player: &mut dyn PlayerBackend,
+    s: &mut Shared,
+    settings: &Settings,
+    line: &str,
+) -> Result<String, String> {
+    let (command, arg) = line.split_once(' ').unwrap_or((line, ""));
+    let arg = arg.trim();
+    match command {
+        // Everyone in the session, not just the host we're connected to
+        "peers" if !settings.is_serving && !s.roster.is_empty() => {
+            let peers: Vec<_> = s
+                .roster
+                .iter()
+                .map(|peer| {
+                    let status = match (peer.ready, peer.away) {
+                        _ if s.typing.contains(&peer.username) => "typing",
+                        (_, true) => "away",
+                        (true, _) => "ready",
+                        (false, _) => "not ready",
+                    };
+                    format!("{}\t{}\t{status}", peer.username, peer.role)
+                })
+                .collect();
+            Ok(peers.join("\n"))
+        }
+        "peers" => {
+            let peers: Vec<_> = s
+                .joined_peers()
+                .map(|(addr, peer)| {
+                    let latency = if peer.latency.is_empty() {
+                        0
+                    } else {
+                        get_weighted_latency(&peer.latency)
+                    };
+                    let drift = peer.drift.map_or("-".to_owned(), |d| format!("{d:+.2}s"));
+                    format!(
+                        "{}\t{}\t{addr}\t{}\t{latency}ms\t{drift}\t{}",
+                        peer.username,
+                        peer.role,
+                        match (peer.ready, peer.away) {
+                            _ if s.typing.contains(&peer.username) => "typing",
+                            (_, true) => "away",
+                            (true, _) => "ready",
+                            (false, _) => "not ready",
+                        },
+                        peer.key.as_ref().map_or("-".to_owned(), identity::fingerprint)
+                    )
+                })
+                .collect();
+            Ok(peers.join("\n"))
+        }
+        // The host checks it too
+        "kick" if !settings.is_serving && s.role >= Role::Moderator => {
+            s.broadcast(VoyeursCommand::Kick(arg.to_owned())).await;
+            Ok(format!("asked the host to kick {arg}"))
+        }
+        "kick" if !settings.is_serving => Err("only the host and moderators can kick".to_owned()),
+        "ban" if !settings.is_serving => Err("only the host can ban".to_owned()),
+        "kick" | "ban" => {
+            let addr = s
+                .joined_peers()
+                .find(|(_, peer)| peer.username == arg)
+                .map(|(addr, _)| *addr)
+                .ok_or(format!("nobody called {arg} is here"))?;
+            // Whoever holds the key stays out, under any username
+            let reply = match (command, s.peers[&addr].key) {
+                ("kick", _) => format!("kicked {arg}"),
+                (_, Some(key)) => {
+                    s.banned_keys.insert(key);
+                    let key = identity::encode_key(&key);
+                    format!("banned {arg}, --ban-key {key} keeps them out after a restart too")
+                }
+                (_, None) => return Err(format!("{arg} has no key to ban, kick them instead")),
+            };
+            s.log(reply.clone());
+            if let Some(username) = s.kick(addr).await {
+                leave(player, s, settings, username).await;
+            }
+            Ok(reply)
+        }
+        "role" if !settings.is_serving => Err("only the host can give roles".to_owned()),
+        "role" => {
+            let (username, role) = arg
+                .rsplit_once(' ')
+                .ok_or("usage: role NAME viewer|moderator".to_owned())?;
+            let role = match role {
+                "viewer" => Role::Viewer,
+                "moderator" => Role::Moderator,
+                _ => return Err(format!("{role:?} isn't viewer or moderator")),
+            };
+            let peer = s
+                .peers
+                .values_mut()
+                .find(|peer| peer.handshake == HandshakeState::Joined && peer.username == username)
+                .ok_or(format!("nobody called {username} is here"))?;
+            peer.role = role;
+            s.broadcast_peers(&settings.username).await;
+            let line = Msg::NowRole {
+                username: Some(username),
+                role,
+            }
+            .to_string();
+            s.log(line.clone());
+            Ok(line)
+        }
+        "invite" if !settings.is_serving => Err("only the host can invite".to_owned()),
+        "invite" => {
+            let identity = settings
+                .identity
+                .as_ref()
+                .filter(|_| settings.invite_only)
+                .ok_or("start the server with --invite-only to hand out invites".to_owned())?;
+            let minutes = match arg {
+                "" => invite::DEFAULT_MINUTES,
+                minutes => minutes
+                    .parse()
+                    .map_err(|_| "usage: invite [MINUTES], like invite 240".to_owned())?,
+            };
+            let invite = Invite::mint(identity, get_timestamp() / 1000, minutes);
+            s.log(format!("minted an invite good for {minutes} minutes"));
+            Ok(invite.link(&settings.public_address))
+        }
+        "code" if !settings.is_serving => Err("only the host can hand out join codes".to_owned()),
+        "code" if !settings.invite_only => {
+            Err("start the server with --invite-only to hand out join codes".to_owned())
+        }
+        "code" => {
+            let code = s.join_codes.mint(Instant::now());
+            s.log(format!("minted join code {code}"));
+            Ok(format!("{code}, good for one person"))
+        }
+        "info" if settings.is_serving => Ok(s.server_info().to_string()),
+        "info" => s
+            .server_info
+            .as_ref()
+            .map(|info| format!("{info}, as of joining"))
+            .ok_or("not connected to a server".to_owned()),
+        "stats" => {
+            let traffic = s.traffic.as_ref().ok_or("start voyeurs with --stats".to_owned())?;
+            Ok(traffic.summary().join("\n"))
+        }
+        "chat" if arg.is_empty() => Err("usage: chat TEXT".to_owned()),
+        "chat" => {
+            s.chat(s.username(settings).to_owned(), arg.to_owned())
+                .await?;
+            Ok("sent".to_owned())
+        }
+        "rename" if settings.is_serving => Err("the host goes by its --username".to_owned()),
+        "rename" => {
+            let username = username::sanitize(arg).map_err(|reason| reason.to_string())?;
+            s.broadcast(VoyeursCommand::Rename(username.clone())).await;
+            Ok(format!("asked the host to call you {username}"))
+        }
+        "whisper" => {
+            let (to, text) = arg
+                .split_once(' ')
+                .ok_or("usage: whisper NAME TEXT".to_owned())?;
+            let (to, text) = (to.to_owned(), text.trim().to_owned());
+            let line = format!("to {to}: {text}");
+            s.whisper(s.username(settings).to_owned(), to, text, settings.is_serving)
+                .await?;
+            s.log(line);
+            Ok("sent".to_owned())
+        }
+        // Like pressing space in the player, everyone follows
+        "pause" | "play" => {
+            let paused = command == "pause";
+            player.set_paused(paused).map_err(|e| e.to_string())?;
+            Ok(if paused { "paused" } else { "playing" }.to_owned())
+        }
+        "seek" => {
+            let time = parse_time(arg).ok_or("usage: seek TIME, like seek 1:30".to_owned())?;
+            player
+                .seek(time, settings.exact_seek)
+                .map_err(|e| e.to_string())?;
+            Ok(format!("at {}", format_time(time)))
+        }
+        // Back where the host is, like Alt+r
+        "resync" if !settings.is_serving => {
+            s.query_state(true).await;
+            Ok("asked the host where it is".to_owned())
+        }
+        // Everyone goes where we are, like after seeking in the player
+        "resync" => {
+            let position = player.position().ok_or("nothing is playing".to_owned())?;
+            s.broadcast(VoyeursCommand::Seek(position)).await;
+            Ok(format!("everyone is at {}", format_time(position)))
+        }
+        "nudge" => {
+            let by = arg
+                .parse()
+                .map_err(|_| "usage: nudge SECS, like nudge -0.5".to_owned())?;
+            Ok(s.nudge(player, by, settings.exact_seek))
+        }
+        "sync" if !settings.is_serving => Err("only the host can sync everyone".to_owned()),
+        "sync" => {
+            let position = player.position().ok_or("nothing is playing".to_owned())?;
+            let paused = player.is_paused().unwrap_or(true);
+            s.sync_everyone(&settings.username, position, paused).await;
+            let line = format!("everyone is at {}", format_time(position));
+            s.log(line.clone());
+            Ok(line)
+        }
+        "load" if arg.is_empty() => Err("usage: load URL".to_owned()),
+        "load" => {
+            player.load(arg).map_err(|e| e.to_string())?;
+            // Clients started with --source follow, a path on our disk is of no use to them
+            if settings.is_serving && source::shareable(arg, None, None).is_some() {
+                s.broadcast(VoyeursCommand::StreamName(arg.to_owned()))
+                    .await;
+            }
+            Ok(format!("loaded {arg}"))
+        }
+        "connect" if settings.is_serving => Err("only clients can switch servers".to_owned()),
+        "connect" if arg.is_empty() => Err("usage: connect ADDRESS".to_owned()),
+        "connect" => {
+            let switch = s.switch.clone().ok_or("not connected to a server")?;
+            let _ = switch.send(arg.to_owned());
+            // The connection ends once the server sees it closing, then the next one starts
+            for peer in s.peers.values_mut() {
+                peer.tx.close();
+            }
+            Ok(format!("switching to {arg}"))
+        }
+        "lock" | "unlock" if !settings.is_serving => {
+            Err("only the host can lock playback".to_owned())
+        }
+        "lock" | "unlock" => {
+            s.locked = command == "lock";
+            s.broadcast(VoyeursCommand::Lock(s.locked)).await;
+            let line = Msg::Locked(s.locked).to_string();
+            s.log(line.clone());
+            Ok(line)
+        }
+        "skip" if !settings.is_serving => Err("only the host can add skips".to_owned()),
+        "skip" => {
+            let (start, end) = parse_segment(arg)?;
+            s.skips.push((start, end));
+            s.segments.push((start, end));
+            s.segments.sort_by(|a, b| a.0.total_cmp(&b.0));
+            let segments = s.segments.clone();
+            s.broadcast(VoyeursCommand::SkipSegments(segments)).await;
+            Ok(format!(
+                "everyone skips {} to {}",
+                format_time(start),
+                format_time(end)
+            ))
+        }
+        _ => Err(format!(
+            "unknown command {command:?}, try peers, info, invite, code, stats, kick, ban, role, chat, pause, play, seek, resync, sync, nudge, load, skip, lock, unlock or connect"
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::control::{bind_private, execute};
+    use crate::player::{FakePlayer, PlayerBackend};
+    use crate::proto::Role;
+    use crate::selftest::{assert_ignored, eventually, join_deaf, Node};
+    use crate::{Settings, Shared};
+    use std::{fs, os::unix::fs::PermissionsExt};
+
+    #[tokio::test]
+    async fn test_bind_private() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("voyeurs.sock");
+        let _listener = bind_private(&path).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        // the private directory it was made in is gone
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_execute() {
+        let mut player = FakePlayer::default();
+        let mut s = Shared::new();
+        s.tui = true;
+        let settings = Settings {
+            is_serving: true,
+            username: "host".to_owned(),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            execute(&mut player, &mut s, &settings, "peers").await,
+            Ok("".to_owned())
+        );
+        assert_eq!(
+            execute(&mut player, &mut s, &settings, "chat hi all").await,
+            Ok("sent".to_owned())
+        );
+        // peers would hang up over it
+        let long = "a".repeat(2000);
+        let too_long =
+            Err("The chat message is 2000 bytes long, more than the limit of 1024".to_owned());
+        let chat = format!("chat {long}");
+        assert_eq!(
+            execute(&mut player, &mut s, &settings, &chat).await,
+            too_long
+        );
+        let whisper = format!("whisper bob {long}");
+        assert_eq!(
+            execute(&mut player, &mut s, &settings, &whisper).await,
+            too_long
+        );
+        assert!(execute(&mut player, &mut s, &settings, "rename hostess")
+            .await
+            .is_err());
+        // nobody to whisper to yet
+        assert!(execute(&mut player, &mut s, &settings, "whisper bob hi")
+            .await
+            .is_err());
+        assert!(execute(&mut player, &mut s, &settings, "whisper bob")
+            .await
+            .is_err());
+        assert!(execute(&mut player, &mut s, &settings, "kick bob")
+            .await
+            .is_err());
+        assert!(execute(&mut player, &mut s, &settings, "ban bob")
+            .await
+            .is_err());
+        assert!(execute(&mut player, &mut s, &settings, "stats")
+            .await
+            .is_err());
+        // nothing to sign it with without --invite-only, nor a point to codes
+        assert!(execute(&mut player, &mut s, &settings, "invite")
+            .await
+            .is_err());
+        assert!(execute(&mut player, &mut s, &settings, "code")
+            .await
+            .is_err());
+        assert!(execute(&mut player, &mut s, &settings, "dance")
+            .await
+            .is_err());
+
+        assert_eq!(
+            execute(&mut player, &mut s, &settings, "pause").await,
+            Ok("paused".to_owned())
+        );
+        assert_eq!(player.is_paused().ok(), Some(true));
+        assert_eq!(
+            execute(&mut player, &mut s, &settings, "seek 1:30").await,
+            Ok("at 1:30".to_owned())
+        );
+        assert!(execute(&mut player, &mut s, &settings, "seek soon")
+            .await
+            .is_err());
+        assert_eq!(
+            execute(&mut player, &mut s, &settings, "play").await,
+            Ok("playing".to_owned())
+        );
+        assert_eq!(
+            execute(&mut player, &mut s, &settings, "resync").await,
+            Ok("everyone is at 1:30".to_owned())
+        );
+        assert_eq!(
+            execute(&mut player, &mut s, &settings, "sync").await,
+            Ok("everyone is at 1:30".to_owned())
+        );
+        assert!(s.is_ready);
+
+        assert_eq!(
+            execute(&mut player, &mut s, &settings, "skip 0:00-1:30").await,
+            Ok("everyone skips 0:00 to 1:30".to_owned())
+        );
+        assert_eq!(s.segments, [(0.0, 90.0)]);
+        assert!(execute(&mut player, &mut s, &settings, "skip 2:00")
+            .await
+            .is_err());
+
+        assert!(execute(&mut player, &mut s, &settings, "lock")
+            .await
+            .is_ok());
+        assert!(s.locked);
+        assert!(execute(&mut player, &mut s, &settings, "unlock")
+            .await
+            .is_ok());
+        assert!(!s.locked);
+
+        // the player comes along, from 1:30
+        assert!(execute(&mut player, &mut s, &settings, "nudge -0.5")
+            .await
+            .is_ok());
+        assert_eq!(player.position(), Some(89.5));
+        assert!(execute(&mut player, &mut s, &settings, "nudge")
+            .await
+            .is_err());
+        assert!(
+            execute(&mut player, &mut s, &settings, "connect other:8998")
+                .await
+                .is_err()
+        );
+
+        assert!(
+            execute(&mut player, &mut s, &settings, "role bob moderator")
+                .await
+                .is_err()
+        );
+        assert!(execute(&mut player, &mut s, &settings, "role bob admin")
+            .await
+            .is_err());
+        // clients leave kicking to the host, if it lets them
+        let client = Settings {
+            username: "alice".to_owned(),
+            ..Default::default()
+        };
+        assert!(execute(&mut player, &mut s, &client, "kick bob")
+            .await
+            .is_err());
+        s.role = Role::Moderator;
+        assert_eq!(
+            execute(&mut player, &mut s, &client, "kick bob").await,
+            Ok("asked the host to kick bob".to_owned())
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_kick() {
+        let server = Node::new(true, "host");
+        let addr = server.serve().await;
+        let mallory = join_deaf(addr, "mallory").await;
+        assert!(eventually(|| server.joined_peers() == Some(1)).await);
+
+        let (mut player, settings) = (server.player.clone(), server.settings.clone());
+        let mut s = server.state.lock().await;
+        let kicked = execute(&mut player, &mut s, &settings, "kick mallory").await;
+        assert_eq!(kicked, Ok("kicked mallory".to_owned()));
+        assert!(s.peers.is_empty());
+        drop(s);
+        // it didn't take the hint, and has no say any more
+        assert_ignored(&server, mallory, "mallory").await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_ban() {
+        let server = Node::new(true, "host");
+        let addr = server.serve().await;
+        let mallory = join_deaf(addr, "mallory").await;
+        assert!(eventually(|| server.joined_peers() == Some(1)).await);
+
+        let (mut player, settings) = (server.player.clone(), server.settings.clone());
+        let mut s = server.state.lock().await;
+        // as if it had signed the challenge
+        let peer = s.peers.values_mut().next().unwrap();
+        peer.key = Some([7; 32]);
+        assert!(execute(&mut player, &mut s, &settings, "ban mallory")
+            .await
+            .is_ok());
+        assert!(s.banned_keys.contains(&[7; 32]));
+        assert!(s.peers.is_empty());
+        drop(s);
+        // out right away, not once it leaves by itself
+        assert_ignored(&server, mallory, "mallory").await;
+    }
+}
@@ -0,0 +1,74 @@
+use std::{env, net, os::unix::io::FromRawFd, process, sync::Arc};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::Mutex,
+};
+
+use crate::Shared;
+
+// Where systemd puts the first socket it passes, SD_LISTEN_FDS_START
+const LISTEN_FDS_START: i32 = 3;
+
+/// Whether systemd passed us a socket, from LISTEN_PID and LISTEN_FDS
+fn socket_activated(listen_pid: Option<&str>, listen_fds: Option<&str>, pid: u32) -> bool {
+    let pid_matches = listen_pid.and_then(|p| p.parse().ok()) == Some(pid);
+    let fds: u32 = listen_fds.and_then(|n| n.parse().ok()).unwrap_or(0);
+    pid_matches && fds >= 1
+}
+
+/// The listener systemd passed us with socket activation, if it did. Call it before any
+/// other thread runs, it clears the variables systemd set
+pub fn inherited_listener() -> Option<net::TcpListener> {
+    let listen_pid = env::var("LISTEN_PID").ok();
+    let listen_fds = env::var("LISTEN_FDS").ok();
+    // the player doesn't get to think the socket is for it
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    if !socket_activated(listen_pid.as_deref(), listen_fds.as_deref(), process::id()) {
+        return None;
+    }
+    // SAFETY: systemd hands the fd over to us, and nothing else in voyeurs uses it
+    let listener = unsafe { net::TcpListener::from_raw_fd(LISTEN_FDS_START) };
+    listener.set_nonblocking(true).ok()?;
+    Some(listener)
+}
+
+/// End the session on SIGTERM like /quit does, saying goodbye and removing the pid file
+pub async fn quit_on_sigterm(state: Arc<Mutex<Shared>>) {
+    let Ok(mut terms) = signal(SignalKind::terminate()) else {
+        return;
+    };
+    if terms.recv().await.is_some() {
+        let mut s = state.lock().await;
+        s.log("got SIGTERM, quitting");
+        s.say_goodbye().await;
+        s.end_session()
+    }
+}
+
+/// Reopen the log file on SIGHUP, so that logrotate can move it away
+pub async fn reopen_logs_on_sighup(state: Arc<Mutex<Shared>>) {
+    let Ok(mut hangups) = signal(SignalKind::hangup()) else {
+        return;
+    };
+    while hangups.recv().await.is_some() {
+        let mut s = state.lock().await;
+        match s.logger.reopen() {
+            Ok(()) => s.log("reopened the log file"),
+            Err(e) => s.log(format!("couldn't reopen the log file: {e}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::daemon::socket_activated;
+
+    #[test]
+    fn test_socket_activated() {
+        assert!(socket_activated(Some("42"), Some("1"), 42));
+        assert!(!socket_activated(Some("41"), Some("1"), 42));
+        assert!(!socket_activated(Some("42"), Some("0"), 42));
+        assert!(!socket_activated(None, None, 42));
+    }
+}
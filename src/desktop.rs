@@ -0,0 +1,21 @@
+use notify_rust::Notification;
+use std::thread;
+
+use crate::Settings;
+
+/// Pop `text` up on the desktop too, for when the player is fullscreen on another
+/// screen or minimized
+pub fn notify(settings: &Settings, text: &str) {
+    if !settings.desktop_notifications {
+        return;
+    }
+    let text = text.to_owned();
+    // Talking to the notification daemon blocks, and it may not even be running
+    thread::spawn(move || {
+        let _ = Notification::new()
+            .appname("voyeurs")
+            .summary("voyeurs")
+            .body(&text)
+            .show();
+    });
+}
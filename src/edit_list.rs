@@ -0,0 +1,124 @@
+use std::{error::Error, fs, path::Path};
+
+use crate::sponsorblock::{parse_segment, Segment};
+
+type EditListError = Box<dyn Error + Sync + Send>;
+
+/// How our file's timeline lines up with the session's, for a cut of the movie that
+/// isn't the one everyone else has, like a TV broadcast against the bluray. Stretches
+/// of the session's timeline each sit some seconds later or earlier in our file, and
+/// whatever isn't in one of them sits `offset` seconds away
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EditList {
+    offset: f64,
+    edits: Vec<(Segment, f64)>,
+}
+
+impl EditList {
+    /// The same file, `offset` seconds ahead of everyone else's
+    pub fn offset(offset: f64) -> EditList {
+        EditList {
+            offset,
+            edits: vec![],
+        }
+    }
+
+    /// Read the stretches from a file with a `START-END OFFSET` per line, START-END in
+    /// the session's timeline and OFFSET how many seconds ahead of it our file is there
+    pub fn load(path: &Path, offset: f64) -> Result<EditList, EditListError> {
+        parse(&fs::read_to_string(path)?, offset)
+    }
+
+    /// Move our whole file `by` seconds further ahead, returning how far ahead it is now
+    /// where the stretches don't say otherwise
+    pub fn nudge(&mut self, by: f64) -> f64 {
+        self.offset += by;
+        for (_, offset) in &mut self.edits {
+            *offset += by;
+        }
+        self.offset
+    }
+
+    /// Where `time` in the session is in our file
+    pub fn to_local(&self, time: f64) -> f64 {
+        let offset = self
+            .edits
+            .iter()
+            .find(|((start, end), _)| (*start..*end).contains(&time))
+            .map_or(self.offset, |(_, offset)| *offset);
+        time + offset
+    }
+
+    /// Where `time` in our file is in the session
+    pub fn to_session(&self, time: f64) -> f64 {
+        let offset = self
+            .edits
+            .iter()
+            .find(|((start, end), offset)| (start + offset..end + offset).contains(&time))
+            .map_or(self.offset, |(_, offset)| *offset);
+        time - offset
+    }
+}
+
+fn parse(edits: &str, offset: f64) -> Result<EditList, EditListError> {
+    let mut parsed: Vec<(Segment, f64)> = vec![];
+    for (n, line) in edits.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (range, by) = line
+            .split_once(char::is_whitespace)
+            .ok_or_else(|| format!("line {} isn't START-END OFFSET", n + 1))?;
+        let range = parse_segment(range).map_err(|e| format!("line {}: {e}", n + 1))?;
+        let by: f64 = by
+            .trim()
+            .parse()
+            .map_err(|_| format!("line {}: {by:?} isn't a number of seconds", n + 1))?;
+        if parsed
+            .iter()
+            .any(|((start, end), _)| range.0 < *end && *start < range.1)
+        {
+            return Err(format!("line {} overlaps a stretch before it", n + 1).into());
+        }
+        parsed.push((range, by));
+    }
+    Ok(EditList {
+        offset,
+        edits: parsed,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::edit_list::{parse, EditList};
+
+    #[test]
+    fn test_edit_list() {
+        // the broadcast cut has a minute more of recap up front and loses a scene at 1:00:00
+        let edits = parse(
+            "# bluray to TV\n\
+             0:00-1:00:00 60\n\
+             1:02:00-2:00:00 -60\n",
+            0.0,
+        )
+        .unwrap();
+        assert_eq!(edits.to_local(30.0), 90.0);
+        assert_eq!(edits.to_local(3720.0), 3660.0);
+        // past the end of the stretches, the default offset
+        assert_eq!(edits.to_local(7300.0), 7300.0);
+        for time in [30.0, 3720.0, 7300.0] {
+            assert_eq!(edits.to_session(edits.to_local(time)), time);
+        }
+
+        let mut plain = EditList::offset(-3.5);
+        assert_eq!(plain.to_local(10.0), 6.5);
+        assert_eq!(plain.to_session(6.5), 10.0);
+        assert_eq!(plain.nudge(0.5), -3.0);
+        assert_eq!(plain.to_local(10.0), 7.0);
+
+        assert!(parse("0:00-1:00", 0.0).is_err());
+        assert!(parse("0:00-1:00 later", 0.0).is_err());
+        assert!(parse("0:00-1:00 5\n0:30-2:00 6", 0.0).is_err());
+    }
+}
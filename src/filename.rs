@@ -0,0 +1,158 @@
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+
+/// How alike our filename has to be to the server's not to warn about it
+#[derive(Debug, Clone, Copy, PartialEq, Default, clap::ValueEnum)]
+pub enum FilenameMatch {
+    /// byte for byte
+    Exact,
+    /// the same once case, separators, tags and the release group are dropped
+    Normalized,
+    /// mostly the same words, and exactly the same numbers
+    #[default]
+    Fuzzy,
+}
+
+// What a hashed filename starts with, hashes aren't valid filenames on their own
+const HASHED: &str = "sha256:";
+
+// Share of words the names need in common to match with fuzzy
+const FUZZY_THRESHOLD: f64 = 0.5;
+
+// Words release names are padded with, that don't tell the files apart
+const NOISE: &[&str] = &[
+    "480p", "576p", "720p", "1080p", "1080i", "2160p", "4k", "uhd", "hdr", "hdr10", "dv", "sdr",
+    "x264", "x265", "h264", "h265", "hevc", "avc", "av1", "xvid", "10bit", "8bit", "web", "dl",
+    "webdl", "webrip", "bluray", "bdrip", "brrip", "dvdrip", "hdtv", "remux", "aac", "ac3", "eac3",
+    "dts", "ddp", "flac", "opus", "atmos", "proper", "repack",
+];
+
+/// What an anonymous server sends instead of its filename. It's the normalized name that
+/// gets hashed, so it still matches names that differ by tags or case
+pub fn hashed(name: &str) -> String {
+    format!("{HASHED}{:x}", Sha256::digest(normalize(name).join(" ")))
+}
+
+/// Whether `ours` is close enough to the server's `theirs`
+pub fn matches(ours: &str, theirs: &str, strictness: FilenameMatch) -> bool {
+    // Nothing fuzzy can be done with a hash
+    if theirs.starts_with(HASHED) {
+        return hashed(ours) == theirs;
+    }
+    match strictness {
+        FilenameMatch::Exact => ours == theirs,
+        FilenameMatch::Normalized => normalize(ours) == normalize(theirs),
+        FilenameMatch::Fuzzy => {
+            let (ours, theirs) = (normalize(ours), normalize(theirs));
+            // A different episode or year is a different file, however alike the rest
+            let numbers = |words: &[String]| -> HashSet<String> {
+                words
+                    .iter()
+                    .filter(|w| w.chars().any(|c| c.is_ascii_digit()))
+                    .cloned()
+                    .collect()
+            };
+            numbers(&ours) == numbers(&theirs) && similarity(&ours, &theirs) >= FUZZY_THRESHOLD
+        }
+    }
+}
+
+/// The words of a filename that say what it is, lowercase
+fn normalize(name: &str) -> Vec<String> {
+    let stem = match name.rsplit_once('.') {
+        Some((stem, ext))
+            if ext.len() <= 4
+                && ext.chars().all(char::is_alphanumeric)
+                && ext.chars().any(char::is_alphabetic) =>
+        {
+            stem
+        }
+        _ => name,
+    };
+    // Show.S01E01.1080p.WEB-DL-GROUP, the group comes after the last dash
+    let stem = match stem.rsplit_once('-') {
+        Some((rest, group))
+            if !group.is_empty()
+                && group.chars().all(char::is_alphanumeric)
+                && rest.contains('.') =>
+        {
+            rest
+        }
+        _ => stem,
+    };
+    without_tags(stem)
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .filter(|word| !NOISE.contains(&word.as_str()))
+        .collect()
+}
+
+// [Group] and (tags) are never part of the title
+fn without_tags(name: &str) -> String {
+    let mut depth = 0usize;
+    name.chars()
+        .filter(|&c| {
+            match c {
+                '[' | '(' => depth += 1,
+                ']' | ')' => {
+                    depth = depth.saturating_sub(1);
+                    return false;
+                }
+                _ => {}
+            }
+            depth == 0
+        })
+        .collect()
+}
+
+/// Words in common over words in either, 1 for two empty names
+fn similarity(a: &[String], b: &[String]) -> f64 {
+    let a: HashSet<_> = a.iter().collect();
+    let b: HashSet<_> = b.iter().collect();
+    let union = a.union(&b).count();
+    if union == 0 {
+        return 1.0;
+    }
+    a.intersection(&b).count() as f64 / union as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::filename::{hashed, matches, normalize, FilenameMatch};
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(
+            normalize("Show.S01E01.1080p.WEB-DL-NTb.mkv"),
+            ["show", "s01e01"]
+        );
+        assert_eq!(
+            normalize("[SubsPlease] Show - 03 (1080p) [ABCD1234].mkv"),
+            ["show", "03"]
+        );
+        assert_eq!(normalize("show s01e01.mkv"), ["show", "s01e01"]);
+        assert_eq!(normalize("Film.Title.2019"), ["film", "title", "2019"]);
+    }
+
+    #[test]
+    fn test_matches() {
+        let a = "Show.S01E01.1080p.mkv";
+        let b = "show s01e01.mkv";
+        assert!(!matches(a, b, FilenameMatch::Exact));
+        assert!(matches(a, b, FilenameMatch::Normalized));
+        assert!(matches(a, b, FilenameMatch::Fuzzy));
+
+        let extended = "The Show S01E01 Extended.mkv";
+        assert!(!matches(a, extended, FilenameMatch::Normalized));
+        assert!(matches(a, extended, FilenameMatch::Fuzzy));
+
+        // Same show, another episode
+        assert!(!matches(a, "Show.S01E02.1080p.mkv", FilenameMatch::Fuzzy));
+        assert!(!matches(a, "Other.S01E01.mkv", FilenameMatch::Fuzzy));
+
+        let theirs = hashed("[Group] Show - S01E01 (1080p).mkv");
+        assert!(matches(a, &theirs, FilenameMatch::Fuzzy));
+        assert!(!matches("Show.S01E02.mkv", &theirs, FilenameMatch::Fuzzy));
+    }
+}
@@ -0,0 +1,188 @@
+use std::{
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::player::{PlayerBackend, PlayerError, PlayerEvent};
+
+// Nothing pushes events, changes are found by polling like with vlc
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The player of a --controller: nothing is shown or played, it only keeps time along with
+/// the session so that pausing and seeking from the terminal or voyeursctl reach the others.
+///
+/// Clones share the same clock, like connections to the same mpv would.
+#[derive(Clone)]
+pub struct Headless {
+    clock: Arc<Mutex<Clock>>,
+    // what the last poll saw, every clone polls on its own
+    last: (bool, u64),
+}
+
+struct Clock {
+    paused: bool,
+    /// where playback was at `since`
+    position: f64,
+    since: Instant,
+    speed: f64,
+    /// how many seeks there were, so that a poll notices one to the same spot
+    seeks: u64,
+    quit: bool,
+}
+
+impl Clock {
+    fn position(&self) -> f64 {
+        if self.paused {
+            self.position
+        } else {
+            self.position + self.since.elapsed().as_secs_f64() * self.speed
+        }
+    }
+}
+
+impl Default for Headless {
+    fn default() -> Headless {
+        Headless {
+            clock: Arc::new(Mutex::new(Clock {
+                paused: true,
+                position: 0.0,
+                since: Instant::now(),
+                speed: 1.0,
+                seeks: 0,
+                quit: false,
+            })),
+            last: (true, 0),
+        }
+    }
+}
+
+impl PlayerBackend for Headless {
+    fn is_paused(&self) -> Result<bool, PlayerError> {
+        Ok(self.clock.lock().unwrap().paused)
+    }
+
+    fn set_paused(&self, paused: bool) -> Result<(), PlayerError> {
+        let mut clock = self.clock.lock().unwrap();
+        clock.position = clock.position();
+        clock.since = Instant::now();
+        clock.paused = paused;
+        Ok(())
+    }
+
+    fn seek(&self, t: f64, _exact: bool) -> Result<(), PlayerError> {
+        let mut clock = self.clock.lock().unwrap();
+        clock.position = t.max(0.0);
+        clock.since = Instant::now();
+        clock.seeks += 1;
+        Ok(())
+    }
+
+    fn position(&self) -> Option<f64> {
+        Some(self.clock.lock().unwrap().position())
+    }
+
+    fn duration(&self) -> Option<f64> {
+        None
+    }
+
+    fn file_size(&self) -> Option<u64> {
+        None
+    }
+
+    fn live_lag(&self) -> Option<f64> {
+        None
+    }
+
+    fn set_speed(&self, speed: f64) -> Result<(), PlayerError> {
+        let mut clock = self.clock.lock().unwrap();
+        clock.position = clock.position();
+        clock.since = Instant::now();
+        clock.speed = speed;
+        Ok(())
+    }
+
+    fn filename(&self) -> Option<String> {
+        None
+    }
+
+    fn path(&self) -> Option<String> {
+        None
+    }
+
+    fn playlist_pos(&self) -> Option<u64> {
+        None
+    }
+
+    fn playlist_count(&self) -> Option<u64> {
+        None
+    }
+
+    fn set_playlist_pos(&self, _pos: u64) -> Result<(), PlayerError> {
+        Err("a controller has no playlist".into())
+    }
+
+    fn load(&mut self, _url: &str) -> Result<(), PlayerError> {
+        Err("a controller can't play anything".into())
+    }
+
+    // Chat and the rest already go to the log
+    fn show_text(&self, _text: &str, _duration: u32) -> Result<(), PlayerError> {
+        Ok(())
+    }
+
+    fn quit(&self) {
+        self.clock.lock().unwrap().quit = true;
+    }
+
+    fn observe(&mut self) -> Result<(), PlayerError> {
+        Ok(())
+    }
+
+    fn next_event(&mut self) -> Result<PlayerEvent, PlayerError> {
+        loop {
+            let (paused, seeks, quit) = {
+                let clock = self.clock.lock().unwrap();
+                (clock.paused, clock.seeks, clock.quit)
+            };
+            if quit {
+                return Ok(PlayerEvent::Shutdown);
+            }
+            let (was_paused, last_seeks) = std::mem::replace(&mut self.last, (paused, seeks));
+            if paused != was_paused {
+                return Ok(PlayerEvent::Pause(paused));
+            }
+            if seeks != last_seeks {
+                return Ok(PlayerEvent::Seeking(false));
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::headless::Headless;
+    use crate::player::{PlayerBackend, PlayerEvent};
+
+    #[test]
+    fn test_headless() {
+        let mut player = Headless::default();
+        let other = player.clone();
+        assert_eq!(player.is_paused().ok(), Some(true));
+        assert_eq!(player.position(), Some(0.0));
+
+        other.set_paused(false).unwrap();
+        assert_eq!(player.next_event().ok(), Some(PlayerEvent::Pause(false)));
+        other.seek(90.0, true).unwrap();
+        assert_eq!(player.next_event().ok(), Some(PlayerEvent::Seeking(false)));
+        assert!(player.position().unwrap() >= 90.0);
+
+        other.set_paused(true).unwrap();
+        let position = player.position();
+        assert_eq!(player.next_event().ok(), Some(PlayerEvent::Pause(true)));
+        assert_eq!(player.position(), position);
+        other.quit();
+        assert_eq!(player.next_event().ok(), Some(PlayerEvent::Shutdown));
+    }
+}
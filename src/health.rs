@@ -0,0 +1,189 @@
+use serde_json::{json, Map, Value};
+use std::time::{Duration, Instant};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpListener,
+    time::{interval, timeout},
+};
+
+use crate::{player::PlayerBackend, proto::ServerInfo, rooms::Room};
+
+// How long a health check gets to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Answer load balancers and container orchestrators at `GET /healthz` on `address`,
+/// with what the server runs and who's in each of `rooms`, the first being the server's own
+pub async fn serve(address: String, rooms: Vec<Room>) {
+    let listener = match TcpListener::bind(&address).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            rooms[0]
+                .state
+                .lock()
+                .await
+                .log(format!("couldn't serve health checks on {address}: {e}"));
+            return;
+        }
+    };
+    while let Ok((stream, _)) = listener.accept().await {
+        let rooms = rooms.clone();
+        tokio::spawn(async move {
+            let (rx, mut tx) = stream.into_split();
+            let mut rx = BufReader::new(rx);
+            let mut request = String::new();
+            // The headers don't matter, but the client expects them read
+            let read = timeout(REQUEST_TIMEOUT, async {
+                rx.read_line(&mut request).await?;
+                let mut header = String::new();
+                while rx.read_line(&mut header).await? > 2 {
+                    header.clear();
+                }
+                Ok::<_, std::io::Error>(())
+            });
+            if !matches!(read.await, Ok(Ok(()))) {
+                return;
+            }
+            let mut infos = vec![];
+            for room in &rooms {
+                infos.push(room.state.lock().await.server_info());
+            }
+            let _ = tx.write_all(respond(&request, &infos).as_bytes()).await;
+        });
+    }
+}
+
+/// The HTTP response to `request`, its first line
+fn respond(request: &str, rooms: &[ServerInfo]) -> String {
+    let mut words = request.split_whitespace();
+    let (status, body) = match (words.next(), words.next()) {
+        (Some("GET"), Some("/healthz")) => ("200 OK", report(rooms)),
+        _ => ("404 Not Found", json!({"status": "not found"})),
+    };
+    let body = format!("{body}\n");
+    format!(
+        "HTTP/1.1 {status}\r\n\
+         Content-Type: application/json\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n\
+         {body}",
+        body.len()
+    )
+}
+
+fn report(rooms: &[ServerInfo]) -> Value {
+    let server = &rooms[0];
+    let mut report = json!({
+        "status": "ok",
+        "version": server.version,
+        "protocol": server.protocol,
+        "uptime": server.uptime,
+        "peers": rooms.iter().map(|room| room.peers).sum::<u32>(),
+    });
+    if !server.room.is_empty() {
+        let peers: Map<String, Value> = rooms
+            .iter()
+            .map(|room| (room.room.clone(), json!(room.peers)))
+            .collect();
+        report["rooms"] = Value::Object(peers);
+    }
+    report
+}
+
+/// How long a room has gone without peers
+#[derive(Debug, Default)]
+struct Vacancy {
+    since: Option<Instant>,
+    unloaded: bool,
+}
+
+impl Vacancy {
+    /// Whether the room has been empty for `timeout`, as of `now`
+    fn update(&mut self, empty: bool, now: Instant, timeout: Duration) -> bool {
+        if !empty {
+            *self = Vacancy::default();
+            return false;
+        }
+        now.duration_since(*self.since.get_or_insert(now)) >= timeout
+    }
+}
+
+/// Shut the server down once nobody has been in any of `rooms` for `timeout`, the first
+/// being the server's own. Until then, a room left empty that long unloads its file
+pub async fn watch_empty(rooms: Vec<(Room, Box<dyn PlayerBackend>)>, timeout: Duration) {
+    let minutes = timeout.as_secs() / 60;
+    let mut vacancies: Vec<Vacancy> = rooms.iter().map(|_| Vacancy::default()).collect();
+    let mut ticker = interval(CHECK_INTERVAL);
+    loop {
+        ticker.tick().await;
+        let now = Instant::now();
+        let mut idle = vec![];
+        for ((room, _), vacancy) in rooms.iter().zip(&mut vacancies) {
+            let empty = room.state.lock().await.joined_peers().next().is_none();
+            idle.push(vacancy.update(empty, now, timeout));
+        }
+        if idle.iter().all(|idle| *idle) {
+            let mut s = rooms[0].0.state.lock().await;
+            s.log(format!(
+                "nobody has been around for {minutes} minutes, shutting down"
+            ));
+            s.end_session();
+        }
+        for (((room, player), vacancy), idle) in rooms.iter().zip(&mut vacancies).zip(idle) {
+            if !idle || vacancy.unloaded {
+                continue;
+            }
+            vacancy.unloaded = true;
+            player.stop();
+            room.state.lock().await.log(format!(
+                "nobody has been in the room for {minutes} minutes, unloaded the file"
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::health::{respond, Vacancy};
+    use crate::proto::ServerInfo;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_respond() {
+        let info = |room: &str, peers| ServerInfo {
+            version: "0.1.0".to_owned(),
+            protocol: 3,
+            uptime: 600,
+            room: room.to_owned(),
+            peers,
+        };
+        let ok = respond("GET /healthz HTTP/1.1\r\n", &[info("", 2)]);
+        assert!(ok.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(ok.ends_with(
+            "\r\n\r\n{\"peers\":2,\"protocol\":3,\"status\":\"ok\",\"uptime\":600,\"version\":\"0.1.0\"}\n"
+        ));
+        let rooms = respond(
+            "GET /healthz HTTP/1.1\r\n",
+            &[info("anime", 2), info("film", 1)],
+        );
+        assert!(rooms.contains("\"peers\":3"));
+        assert!(rooms.contains("\"rooms\":{\"anime\":2,\"film\":1}"));
+        assert!(respond("GET / HTTP/1.1\r\n", &[info("", 0)]).starts_with("HTTP/1.1 404"));
+        assert!(respond("", &[info("", 0)]).starts_with("HTTP/1.1 404"));
+    }
+
+    #[test]
+    fn test_vacancy() {
+        let start = Instant::now();
+        let at = |mins: u64| start + Duration::from_secs(mins * 60);
+        let timeout = Duration::from_secs(10 * 60);
+        let mut vacancy = Vacancy::default();
+        assert!(!vacancy.update(true, at(0), timeout));
+        assert!(!vacancy.update(true, at(9), timeout));
+        assert!(vacancy.update(true, at(10), timeout));
+        // someone came by, the count starts over
+        assert!(!vacancy.update(false, at(11), timeout));
+        assert!(!vacancy.update(true, at(12), timeout));
+        assert!(vacancy.update(true, at(22), timeout));
+    }
+}
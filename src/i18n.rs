@@ -0,0 +1,438 @@
+use std::{env, fmt, sync::OnceLock};
+
+use crate::{
+    bookmarks::format_time,
+    proto::{RejectReason, Role},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum Locale {
+    En,
+    It,
+}
+
+static LOCALE: OnceLock<Locale> = OnceLock::new();
+
+impl Locale {
+    /// Pick the locale the same way gettext would, falling back to english
+    pub fn from_env() -> Locale {
+        let lang = ["LC_ALL", "LC_MESSAGES", "LANG"]
+            .into_iter()
+            .filter_map(|var| env::var(var).ok())
+            .find(|lang| !lang.is_empty())
+            .unwrap_or_default();
+        if lang.starts_with("it") {
+            Locale::It
+        } else {
+            Locale::En
+        }
+    }
+}
+
+/// Set the locale for the rest of the session, only the first call counts
+pub fn set_locale(locale: Locale) {
+    let _ = LOCALE.set(locale);
+}
+
+/// Everything voyeurs tells the user, in every language it speaks
+#[derive(Debug, Clone, Copy)]
+pub enum Msg<'a> {
+    Welcome,
+    Connected(&'a str),
+    Disconnected(&'a str),
+    /// the connection dropped, but the peer may still come back
+    Lost(&'a str),
+    Rejoined(&'a str),
+    Away(&'a str),
+    Back(&'a str),
+    Renamed {
+        from: &'a str,
+        to: &'a str,
+    },
+    /// a chat line only we got
+    Whisper {
+        from: &'a str,
+        text: &'a str,
+    },
+    /// how clients refer to the server
+    Host,
+    MarkedAway,
+    /// the host gave us, or the peer named, another role
+    NowRole {
+        username: Option<&'a str>,
+        role: Role,
+    },
+    /// with --pause-when-empty
+    PausedEmpty,
+    NotReady,
+    /// seconds we are ahead of the host, past the alert threshold
+    Drifting(f64),
+    /// seconds our file is ahead of everyone else's, after nudging it
+    Offset(f64),
+    /// whether only the host controls playback from now on
+    Locked(bool),
+    /// we tried to pause or seek while locked
+    HostOnly,
+    /// the host quit on purpose, so there's nothing to rejoin
+    Goodbye,
+    /// the server went away without saying goodbye
+    Rejoining,
+    Rejected(RejectReason),
+    FilenameMismatch,
+    /// seconds our file is longer than the server's
+    DurationMismatch(f64),
+    FileSizeMismatch,
+    /// the host moved to an entry past the end of our playlist, counting from 1
+    NoPlaylistEntry(u64),
+    NotStreaming,
+    Resyncing {
+        username: &'a str,
+        off: f64,
+    },
+    /// a key the host hadn't seen yet, trusted from now on
+    NewKey {
+        username: &'a str,
+        key: &'a str,
+    },
+    StartingServer(&'a str),
+    Connecting(&'a str),
+    PausedBy(&'a str),
+    ResumedBy(&'a str),
+    Resumed,
+    NowPlaying(&'a str),
+    Bookmark {
+        name: &'a str,
+        time: f64,
+    },
+    /// the first line of the end of session summary, seconds watched over seconds spent
+    Summary {
+        watched: f64,
+        over: f64,
+    },
+    /// how many pauses there were, and `by` whom
+    Pauses {
+        count: u32,
+        by: &'a str,
+    },
+    Buffered {
+        name: &'a str,
+        time: f64,
+    },
+    /// milliseconds, added to the peer's Buffered line
+    AverageLatency(u64),
+    /// somebody seeked to `time`, `off` seconds from where we were
+    Jumped {
+        name: &'a str,
+        time: f64,
+        off: f64,
+    },
+}
+
+impl Msg<'_> {
+    fn render(&self, locale: Locale, f: &mut fmt::Formatter) -> fmt::Result {
+        match locale {
+            Locale::En => self.english(f),
+            Locale::It => self.italian(f),
+        }
+    }
+
+    fn english(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Msg::Welcome => write!(f, "Connected to voyeurs"),
+            Msg::Connected(name) => write!(f, "{name}: connected"),
+            Msg::Disconnected(name) => write!(f, "{name}: disconnected"),
+            Msg::Lost(name) => write!(f, "{name}: connection lost, waiting for them"),
+            Msg::Rejoined(name) => write!(f, "{name}: reconnected"),
+            Msg::Away(name) => write!(f, "{name}: away"),
+            Msg::Back(name) => write!(f, "{name}: back"),
+            Msg::Renamed { from, to } => write!(f, "{from} is now called {to}"),
+            Msg::Whisper { from, text } => write!(f, "{from} (whisper): {text}"),
+            Msg::Host => write!(f, "host"),
+            Msg::MarkedAway => write!(f, "You are now marked as away"),
+            Msg::NowRole {
+                username: None,
+                role,
+            } => write!(f, "You are now a {role}"),
+            Msg::NowRole {
+                username: Some(username),
+                role,
+            } => write!(f, "{username} is now a {role}"),
+            Msg::PausedEmpty => write!(f, "Everyone left, paused until somebody is back"),
+            Msg::NotReady => write!(f, "Somebody isn't ready"),
+            Msg::Drifting(off) => {
+                let (off, way) = (off.abs(), if *off < 0.0 { "behind" } else { "ahead" });
+                write!(f, "You are {off:.1}s {way}, press Alt+r to resync")
+            }
+            Msg::Offset(off) => write!(f, "Your file is now {off:+.1}s off everyone else's"),
+            Msg::Locked(true) => write!(f, "Only the host and moderators control playback now"),
+            Msg::Locked(false) => write!(f, "Everyone controls playback again"),
+            Msg::HostOnly => write!(
+                f,
+                "Playback is locked, only the host and moderators can pause or seek"
+            ),
+            Msg::Goodbye => write!(f, "The host ended the session"),
+            Msg::Rejoining => write!(f, "Lost the server, trying to reconnect"),
+            Msg::Rejected(reason) => write!(f, "Server rejected the connection: {reason}"),
+            Msg::FilenameMismatch => write!(f, "filename does not match with server's filename"),
+            Msg::DurationMismatch(off) => write!(
+                f,
+                "duration does not match with server's duration ({off:+.2}s)"
+            ),
+            Msg::FileSizeMismatch => write!(f, "file size does not match with server's file size"),
+            Msg::NoPlaylistEntry(n) => {
+                write!(f, "The host is at entry {n}, the playlist is shorter")
+            }
+            Msg::NotStreaming => write!(f, "Server is not streaming from a valid url"),
+            Msg::Resyncing { username, off } => {
+                write!(f, "{username} was {off:+.2}s off, resyncing")
+            }
+            Msg::NewKey { username, key } => {
+                write!(f, "{username} has a new key, check it's {key} with them")
+            }
+            Msg::StartingServer(addr) => write!(f, "Starting server on {addr}"),
+            Msg::Connecting(addr) => write!(f, "Connecting to {addr}"),
+            Msg::PausedBy(name) => write!(f, "Paused by {name}"),
+            Msg::ResumedBy(name) => write!(f, "Resumed by {name}"),
+            Msg::Resumed => write!(f, "Playback resumed"),
+            Msg::NowPlaying(file) => write!(f, "Now playing {file}"),
+            Msg::Bookmark { name, time } => {
+                write!(f, "Bookmark \"{name}\" at {}", format_time(*time))
+            }
+            Msg::Summary { watched, over } => write!(
+                f,
+                "Session summary: watched {} in {}",
+                format_time(*watched),
+                format_time(*over)
+            ),
+            Msg::Pauses { count, by } => write!(f, "{count} pauses: {by}"),
+            Msg::Buffered { name, time } => {
+                write!(f, "{name}: buffered for {}", format_time(*time))
+            }
+            Msg::AverageLatency(ms) => write!(f, ", average latency {ms}ms"),
+            Msg::Jumped { name, time, off } => write!(
+                f,
+                "{name} jumped to {} ({})",
+                format_time(*time),
+                format_offset(*off)
+            ),
+        }
+    }
+
+    fn italian(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Msg::Welcome => write!(f, "Connesso a voyeurs"),
+            Msg::Connected(name) => write!(f, "{name}: connesso"),
+            Msg::Disconnected(name) => write!(f, "{name}: disconnesso"),
+            Msg::Lost(name) => write!(f, "{name}: connessione persa, in attesa"),
+            Msg::Rejoined(name) => write!(f, "{name}: riconnesso"),
+            Msg::Away(name) => write!(f, "{name}: assente"),
+            Msg::Back(name) => write!(f, "{name}: di nuovo qui"),
+            Msg::Renamed { from, to } => write!(f, "{from} ora si chiama {to}"),
+            Msg::Whisper { from, text } => write!(f, "{from} (sussurro): {text}"),
+            Msg::Host => write!(f, "host"),
+            Msg::MarkedAway => write!(f, "Ora risulti assente"),
+            Msg::NowRole { username, role } => {
+                let role = match role {
+                    Role::Viewer => "spettatore",
+                    Role::Moderator => "moderatore",
+                    Role::Host => "host",
+                };
+                match username {
+                    Some(username) => write!(f, "{username} ora è {role}"),
+                    None => write!(f, "Ora sei {role}"),
+                }
+            }
+            Msg::PausedEmpty => write!(f, "Sono usciti tutti, in pausa finché non torna qualcuno"),
+            Msg::NotReady => write!(f, "Qualcuno non è pronto"),
+            Msg::Drifting(off) => {
+                let (off, way) = (off.abs(), if *off < 0.0 { "indietro" } else { "avanti" });
+                write!(f, "Sei {off:.1}s {way}, premi Alt+r per risincronizzarti")
+            }
+            Msg::Offset(off) => write!(f, "Il tuo file ora è sfasato di {off:+.1}s dagli altri"),
+            Msg::Locked(true) => write!(f, "Ora solo l'host e i moderatori controllano la riproduzione"),
+            Msg::Locked(false) => write!(f, "Tutti possono di nuovo controllare la riproduzione"),
+            Msg::HostOnly => write!(
+                f,
+                "La riproduzione è bloccata, solo l'host e i moderatori possono mettere in pausa o spostarsi"
+            ),
+            Msg::Goodbye => write!(f, "L'host ha chiuso la sessione"),
+            Msg::Rejoining => write!(f, "Connessione al server persa, provo a riconnettermi"),
+            Msg::Rejected(reason) => {
+                let reason = match reason {
+                    RejectReason::InvalidUsername => "il nome utente è vuoto o non valido",
+                    RejectReason::UsernameTooLong => "il nome utente è troppo lungo",
+                    RejectReason::UsernameTaken => "il nome utente è già in uso",
+                    RejectReason::NotCertified => {
+                        "il nome utente non è quello del certificato client"
+                    }
+                    RejectReason::Kicked => "l'host ti ha buttato fuori",
+                    RejectReason::NoSuchRoom => "il server non ha questa stanza",
+                    RejectReason::NotTrusted => {
+                        "il server non si fida della tua chiave e non hai una password o un invito validi"
+                    }
+                    RejectReason::InviteExpired => "l'invito è scaduto, chiedine uno nuovo all'host",
+                };
+                write!(f, "Il server ha rifiutato la connessione: {reason}")
+            }
+            Msg::FilenameMismatch => write!(f, "il nome del file è diverso da quello del server"),
+            Msg::DurationMismatch(off) => {
+                write!(f, "la durata è diversa da quella del server ({off:+.2}s)")
+            }
+            Msg::FileSizeMismatch => {
+                write!(f, "la dimensione del file è diversa da quella del server")
+            }
+            Msg::NoPlaylistEntry(n) => {
+                write!(f, "L'host è alla voce {n}, la playlist è più corta")
+            }
+            Msg::NotStreaming => write!(f, "Il server non sta riproducendo un url valido"),
+            Msg::Resyncing { username, off } => {
+                write!(f, "{username} era fuori sync di {off:+.2}s, risincronizzo")
+            }
+            Msg::NewKey { username, key } => {
+                write!(
+                    f,
+                    "{username} ha una chiave nuova, verificate insieme che sia {key}"
+                )
+            }
+            Msg::StartingServer(addr) => write!(f, "Avvio il server su {addr}"),
+            Msg::Connecting(addr) => write!(f, "Connessione a {addr}"),
+            Msg::PausedBy(name) => write!(f, "Messo in pausa da {name}"),
+            Msg::ResumedBy(name) => write!(f, "Ripreso da {name}"),
+            Msg::Resumed => write!(f, "Riproduzione ripresa"),
+            Msg::NowPlaying(file) => write!(f, "In riproduzione: {file}"),
+            Msg::Bookmark { name, time } => {
+                write!(f, "Segnalibro \"{name}\" a {}", format_time(*time))
+            }
+            Msg::Summary { watched, over } => write!(
+                f,
+                "Riepilogo della sessione: visti {} in {}",
+                format_time(*watched),
+                format_time(*over)
+            ),
+            Msg::Pauses { count, by } => write!(f, "{count} pause: {by}"),
+            Msg::Buffered { name, time } => {
+                write!(f, "{name}: in buffering per {}", format_time(*time))
+            }
+            Msg::AverageLatency(ms) => write!(f, ", latenza media {ms}ms"),
+            Msg::Jumped { name, time, off } => write!(
+                f,
+                "{name} è passato a {} ({})",
+                format_time(*time),
+                format_offset(*off)
+            ),
+        }
+    }
+}
+
+// How far a jump went, like +3:05 or -0:10
+fn format_offset(off: f64) -> String {
+    let sign = if off < 0.0 { '-' } else { '+' };
+    format!("{sign}{}", format_time(off.abs()))
+}
+
+impl fmt::Display for Msg<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.render(*LOCALE.get_or_init(Locale::from_env), f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::i18n::{Locale, Msg};
+    use crate::proto::Role;
+    use std::fmt;
+
+    struct In(Locale, Msg<'static>);
+    impl fmt::Display for In {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            self.1.render(self.0, f)
+        }
+    }
+
+    #[test]
+    fn test_catalog() {
+        assert_eq!(
+            In(Locale::En, Msg::Connected("bob")).to_string(),
+            "bob: connected"
+        );
+        assert_eq!(
+            In(Locale::It, Msg::Connected("bob")).to_string(),
+            "bob: connesso"
+        );
+        assert_eq!(
+            In(
+                Locale::It,
+                Msg::Renamed {
+                    from: "bob",
+                    to: "bobby"
+                }
+            )
+            .to_string(),
+            "bob ora si chiama bobby"
+        );
+        assert_eq!(
+            In(
+                Locale::It,
+                Msg::Whisper {
+                    from: "bob",
+                    text: "ciao"
+                }
+            )
+            .to_string(),
+            "bob (sussurro): ciao"
+        );
+        assert_eq!(
+            In(
+                Locale::It,
+                Msg::NowRole {
+                    username: Some("bob"),
+                    role: Role::Moderator
+                }
+            )
+            .to_string(),
+            "bob ora è moderatore"
+        );
+        assert_eq!(
+            In(
+                Locale::En,
+                Msg::Resyncing {
+                    username: "bob",
+                    off: 2.5
+                }
+            )
+            .to_string(),
+            "bob was +2.50s off, resyncing"
+        );
+        assert_eq!(
+            In(Locale::En, Msg::DurationMismatch(-0.8)).to_string(),
+            "duration does not match with server's duration (-0.80s)"
+        );
+        assert_eq!(
+            In(Locale::En, Msg::Drifting(-3.24)).to_string(),
+            "You are 3.2s behind, press Alt+r to resync"
+        );
+        assert_eq!(
+            In(
+                Locale::En,
+                Msg::Jumped {
+                    name: "bob",
+                    time: 2530.0,
+                    off: 185.0
+                }
+            )
+            .to_string(),
+            "bob jumped to 42:10 (+3:05)"
+        );
+        assert_eq!(
+            In(
+                Locale::It,
+                Msg::Summary {
+                    watched: 100.0,
+                    over: 120.0
+                }
+            )
+            .to_string(),
+            "Riepilogo della sessione: visti 1:40 in 2:00"
+        );
+    }
+}
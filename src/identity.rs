@@ -0,0 +1,188 @@
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use rand_core::{OsRng, RngCore};
+use sha2::{Digest, Sha256};
+use std::{
+    env, fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+// Hex digits of a fingerprint, enough to tell friends apart but not to trust or ban by
+const FINGERPRINT_LEN: usize = 16;
+// Signed along with the challenge, so the signature can't be passed off as anything else
+const CONTEXT: &[u8] = b"voyeurs identity";
+// The same for an invite's expiry, so an invite can't pass for an answer or the other way round
+const INVITE_CONTEXT: &[u8] = b"voyeurs invite";
+
+/// The keypair a client proves who it is with, whatever username it picks
+#[derive(Clone)]
+pub struct Identity(SigningKey);
+
+impl Identity {
+    /// Read the key at `path`, making one on first run
+    pub fn load_or_create(path: &Path) -> io::Result<Self> {
+        match fs::read(path) {
+            Ok(bytes) => {
+                let secret = bytes
+                    .try_into()
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "not a voyeurs key"))?;
+                Ok(Self(SigningKey::from_bytes(&secret)))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let key = SigningKey::generate(&mut OsRng);
+                if let Some(dir) = path.parent() {
+                    fs::create_dir_all(dir)?;
+                }
+                write_secret(path, &key.to_bytes())?;
+                Ok(Self(key))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn public(&self) -> [u8; 32] {
+        self.0.verifying_key().to_bytes()
+    }
+
+    pub fn fingerprint(&self) -> String {
+        fingerprint(&self.public())
+    }
+
+    /// Answer the server's challenge
+    pub fn sign(&self, challenge: &[u8]) -> [u8; 64] {
+        self.0.sign(&[CONTEXT, challenge].concat()).to_bytes()
+    }
+
+    /// Vouch for whoever holds an invite good until `expires`
+    pub fn sign_invite(&self, expires: u64) -> [u8; 64] {
+        self.0
+            .sign(&[INVITE_CONTEXT, &expires.to_be_bytes()].concat())
+            .to_bytes()
+    }
+}
+
+// Keep the secret out of logs
+impl fmt::Debug for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Identity({})", self.fingerprint())
+    }
+}
+
+#[cfg(unix)]
+fn write_secret(path: &Path, secret: &[u8]) -> io::Result<()> {
+    use std::{io::Write, os::unix::fs::OpenOptionsExt};
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(secret)
+}
+
+#[cfg(not(unix))]
+fn write_secret(path: &Path, secret: &[u8]) -> io::Result<()> {
+    fs::write(path, secret)
+}
+
+/// Where the key is kept when --identity doesn't say
+pub fn default_path() -> PathBuf {
+    env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+        .unwrap_or_else(env::temp_dir)
+        .join("voyeurs")
+        .join("identity")
+}
+
+/// What the server sends a new connection to sign
+pub fn challenge() -> [u8; 32] {
+    let mut challenge = [0; 32];
+    OsRng.fill_bytes(&mut challenge);
+    challenge
+}
+
+/// Whether `signature` is `key`'s answer to `challenge`
+pub fn verify(key: &[u8; 32], challenge: &[u8], signature: &[u8; 64]) -> bool {
+    VerifyingKey::from_bytes(key).is_ok_and(|public| {
+        public
+            .verify(
+                &[CONTEXT, challenge].concat(),
+                &Signature::from_bytes(signature),
+            )
+            .is_ok()
+    })
+}
+
+/// Whether `key` signed an invite good until `expires`
+pub fn verify_invite(key: &[u8; 32], expires: u64, signature: &[u8; 64]) -> bool {
+    VerifyingKey::from_bytes(key).is_ok_and(|public| {
+        public
+            .verify(
+                &[INVITE_CONTEXT, &expires.to_be_bytes()].concat(),
+                &Signature::from_bytes(signature),
+            )
+            .is_ok()
+    })
+}
+
+/// A short name for a key, for people to read out to each other. Too short to trust or
+/// ban by, the whole key is for that
+pub fn fingerprint(key: &[u8; 32]) -> String {
+    let mut hex = format!("{:x}", Sha256::digest(key));
+    hex.truncate(FINGERPRINT_LEN);
+    hex
+}
+
+/// The whole key in hex, as --ban-key and --trusted-keys take it
+pub fn encode_key(key: &[u8; 32]) -> String {
+    key.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// clap value parser for a key, as logged when a peer joins
+pub fn parse_key(s: &str) -> Result<[u8; 32], String> {
+    let invalid = || "a key is 64 hex digits".to_owned();
+    if s.len() != 64 || !s.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(invalid());
+    }
+    let mut key = [0; 32];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).map_err(|_| invalid())?;
+    }
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::identity::{challenge, encode_key, parse_key, verify, Identity};
+
+    #[test]
+    fn test_identity() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("voyeurs").join("identity");
+        let alice = Identity::load_or_create(&path).unwrap();
+        // the same key on the next run
+        assert_eq!(
+            Identity::load_or_create(&path).unwrap().public(),
+            alice.public()
+        );
+
+        let nonce = challenge();
+        let signature = alice.sign(&nonce);
+        assert!(verify(&alice.public(), &nonce, &signature));
+        // an answer to another connection's challenge doesn't do
+        assert!(!verify(&alice.public(), &challenge(), &signature));
+
+        let mallory = Identity::load_or_create(&dir.path().join("mallory")).unwrap();
+        assert!(!verify(&mallory.public(), &nonce, &signature));
+    }
+
+    #[test]
+    fn test_parse_key() {
+        let key: [u8; 32] = std::array::from_fn(|i| i as u8 * 8);
+        assert_eq!(parse_key(&encode_key(&key)), Ok(key));
+        assert_eq!(parse_key(&encode_key(&key).to_ascii_uppercase()), Ok(key));
+        // a fingerprint is only the start of one
+        assert!(parse_key("0123456789abcdef").is_err());
+        assert!(parse_key(&"g".repeat(64)).is_err());
+        assert!(parse_key(&"é".repeat(32)).is_err());
+    }
+}
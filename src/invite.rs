@@ -0,0 +1,210 @@
+use rand_core::{OsRng, RngCore};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    identity::{self, Identity},
+    proto::{RejectReason, VoyeursCommand},
+};
+
+/// How long an invite is good for when the host doesn't say, about a movie night
+pub const DEFAULT_MINUTES: u64 = 180;
+const SCHEME: &str = "voyeurs://";
+const QUERY: &str = "/?invite=";
+// What join codes are made of, without the letters and digits that sound or look alike
+const CODE_ALPHABET: &[u8] = b"ACDEFHJKMNPRTUVWXY345679";
+const CODE_LEN: usize = 8;
+/// How long a code nobody used stays good
+const CODE_LIFETIME: Duration = Duration::from_secs(24 * 3600);
+/// Codes kept at most, minting one more drops the oldest
+const MAX_CODES: usize = 64;
+
+/// A way into a server started with --invite-only until it expires, signed with the
+/// server's key so that nobody can move the date
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Invite {
+    /// seconds since the epoch
+    pub expires: u64,
+    pub sig: [u8; 64],
+}
+
+impl Invite {
+    /// An invite good for `minutes` from `now`, in seconds since the epoch
+    pub fn mint(identity: &Identity, now: u64, minutes: u64) -> Invite {
+        let expires = now + minutes * 60;
+        Invite {
+            expires,
+            sig: identity.sign_invite(expires),
+        }
+    }
+
+    /// Why the server holding `identity` turns it away as of `now`, if it does
+    pub fn check(&self, identity: &Identity, now: u64) -> Result<(), RejectReason> {
+        if !identity::verify_invite(&identity.public(), self.expires, &self.sig) {
+            return Err(RejectReason::NotTrusted);
+        }
+        if self.expires <= now {
+            return Err(RejectReason::InviteExpired);
+        }
+        Ok(())
+    }
+
+    /// What the client shows the server it holds
+    pub fn command(&self) -> VoyeursCommand {
+        VoyeursCommand::Invite {
+            expires: self.expires,
+            sig: self.sig,
+        }
+    }
+
+    /// The link to hand out, to the server at `address`
+    pub fn link(&self, address: &str) -> String {
+        let token: String = self
+            .expires
+            .to_be_bytes()
+            .iter()
+            .chain(&self.sig)
+            .map(|b| format!("{b:02x}"))
+            .collect();
+        format!("{SCHEME}{address}{QUERY}{token}")
+    }
+}
+
+/// The server's address and the invite in a link, None when `address` is a plain address
+pub fn parse_link(address: &str) -> Result<Option<(String, Invite)>, String> {
+    let Some(link) = address.strip_prefix(SCHEME) else {
+        return Ok(None);
+    };
+    let (address, token) = link
+        .split_once(QUERY)
+        .ok_or("the link has no invite in it")?;
+    let bytes = (0..token.len())
+        .step_by(2)
+        .map(|i| {
+            token
+                .get(i..i + 2)
+                .and_then(|b| u8::from_str_radix(b, 16).ok())
+        })
+        .collect::<Option<Vec<u8>>>()
+        .filter(|bytes| bytes.len() == 8 + 64)
+        .ok_or("the invite in the link is mangled, copy it again")?;
+    let invite = Invite {
+        expires: u64::from_be_bytes(bytes[..8].try_into().expect("8 bytes")),
+        sig: bytes[8..].try_into().expect("64 bytes"),
+    };
+    Ok(Some((address.to_owned(), invite)))
+}
+
+/// Short codes the host can read out over voice chat, each letting in one person within a
+/// day, by when they were minted
+#[derive(Debug, Default)]
+pub struct JoinCodes(HashMap<String, Instant>);
+
+impl JoinCodes {
+    /// A new code, like `MXK4-9TRA`
+    pub fn mint(&mut self, now: Instant) -> String {
+        self.0
+            .retain(|_, minted| now.duration_since(*minted) < CODE_LIFETIME);
+        if self.0.len() >= MAX_CODES {
+            let oldest = self.0.iter().min_by_key(|(_, minted)| **minted);
+            if let Some(oldest) = oldest.map(|(code, _)| code.clone()) {
+                self.0.remove(&oldest);
+            }
+        }
+        let mut picks = [0; CODE_LEN];
+        OsRng.fill_bytes(&mut picks);
+        let mut code = String::with_capacity(CODE_LEN + 1);
+        for (i, pick) in picks.iter().enumerate() {
+            if i == CODE_LEN / 2 {
+                code.push('-');
+            }
+            code.push(CODE_ALPHABET[*pick as usize % CODE_ALPHABET.len()] as char);
+        }
+        self.0.insert(normalize(&code), now);
+        code
+    }
+
+    /// Whether `code` lets someone in, it's gone once used. Coming back after losing the
+    /// connection is up to --reconnect-grace and the identity key
+    pub fn redeem(&mut self, code: &str, now: Instant) -> bool {
+        self.0
+            .remove(&normalize(code))
+            .is_some_and(|minted| now.duration_since(minted) < CODE_LIFETIME)
+    }
+}
+
+/// Read aloud and typed back, case and dashes don't matter
+fn normalize(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::identity::Identity;
+    use crate::invite::{parse_link, Invite, JoinCodes};
+    use crate::proto::RejectReason;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_invite() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = Identity::load_or_create(&dir.path().join("server")).unwrap();
+        let other = Identity::load_or_create(&dir.path().join("other")).unwrap();
+        let now = 1_700_000_000;
+        let invite = Invite::mint(&server, now, 180);
+
+        let link = invite.link("movies.example.com:8998");
+        assert!(link.starts_with("voyeurs://movies.example.com:8998/?invite="));
+        let (address, parsed) = parse_link(&link).unwrap().unwrap();
+        assert_eq!(address, "movies.example.com:8998");
+        assert_eq!(parsed, invite);
+
+        assert_eq!(parsed.check(&server, now + 60), Ok(()));
+        // a leaked link is no good the week after
+        assert_eq!(
+            parsed.check(&server, now + 7 * 24 * 3600),
+            Err(RejectReason::InviteExpired)
+        );
+        // nor is one with the date moved, or for another server
+        let moved = Invite {
+            expires: invite.expires + 3600,
+            ..invite
+        };
+        assert_eq!(moved.check(&server, now), Err(RejectReason::NotTrusted));
+        assert_eq!(invite.check(&other, now), Err(RejectReason::NotTrusted));
+
+        assert_eq!(parse_link("movies.example.com:8998"), Ok(None));
+        assert!(parse_link("voyeurs://movies.example.com:8998").is_err());
+        assert!(parse_link(&link[..link.len() - 2]).is_err());
+    }
+
+    #[test]
+    fn test_join_codes() {
+        let now = Instant::now();
+        let mut codes = JoinCodes::default();
+        let code = codes.mint(now);
+        assert_eq!(code.len(), 9);
+        assert!(!codes.redeem("AAAA-AAAA", now));
+        // typed back any which way
+        assert!(codes.redeem(&code.to_lowercase().replace('-', " "), now));
+        // used up, whoever tries it again
+        assert!(!codes.redeem(&code, now));
+
+        // one nobody used is no good the day after
+        let stale = codes.mint(now);
+        assert!(!codes.redeem(&stale, now + Duration::from_secs(25 * 3600)));
+
+        // minting on and on only keeps the latest
+        let first = codes.mint(now);
+        for i in 1..=64 {
+            codes.mint(now + Duration::from_secs(i));
+        }
+        assert_eq!(codes.0.len(), 64);
+        assert!(!codes.redeem(&first, now + Duration::from_secs(65)));
+    }
+}
@@ -0,0 +1,218 @@
+use serde_json::{json, Map, Value};
+use std::{
+    io::{stdout, Write},
+    net::SocketAddr,
+};
+
+use crate::{
+    player::PlayerEvent,
+    proto::VoyeursCommand,
+    record::{hex, Direction},
+    time::get_timestamp,
+};
+
+/// Print an event as a line of JSON, for wrappers driving voyeurs with --json-events
+pub fn emit(kind: &str, mut event: Map<String, Value>) {
+    event.insert("ts".to_owned(), json!(get_timestamp()));
+    event.insert("type".to_owned(), json!(kind));
+    let mut stdout = stdout().lock();
+    // nobody reading isn't a reason to stop
+    let _ = writeln!(stdout, "{}", Value::Object(event));
+    let _ = stdout.flush();
+}
+
+/// A packet, `peer` being none for broadcasts
+pub fn packet(
+    direction: Direction,
+    peer: Option<SocketAddr>,
+    username: &str,
+    command: &VoyeursCommand,
+) -> Map<String, Value> {
+    let mut event = object(json!({
+        "dir": match direction {
+            Direction::In => "in",
+            Direction::Out => "out",
+        },
+        "peer": peer.map(|addr| addr.to_string()),
+        "username": username,
+    }));
+    event.extend(self::command(command));
+    event
+}
+
+fn command(command: &VoyeursCommand) -> Map<String, Value> {
+    object(match command {
+        VoyeursCommand::NewConnection(username) => {
+            json!({"command": "new_connection", "username": username})
+        }
+        VoyeursCommand::Ready(ready) => json!({"command": "ready", "ready": ready}),
+        VoyeursCommand::Seek(time) => json!({"command": "seek", "time": time}),
+        VoyeursCommand::Filename(filename) => {
+            json!({"command": "filename", "filename": filename})
+        }
+        VoyeursCommand::Duration(duration) => {
+            json!({"command": "duration", "duration": duration})
+        }
+        VoyeursCommand::StreamName(url) => json!({"command": "stream_name", "url": url}),
+        VoyeursCommand::GetStreamName => json!({"command": "get_stream_name"}),
+        VoyeursCommand::Away(away) => json!({"command": "away", "away": away}),
+        VoyeursCommand::Chat { from, text } => {
+            json!({"command": "chat", "from": from, "text": text})
+        }
+        // Between the two of them, not whoever reads the log
+        VoyeursCommand::Whisper { to, .. } => json!({"command": "whisper", "to": to}),
+        VoyeursCommand::WhisperFrom { from, .. } => json!({"command": "whisper", "from": from}),
+        VoyeursCommand::Typing(typing) => json!({"command": "typing", "typing": typing}),
+        VoyeursCommand::TypingFrom { from, typing } => {
+            json!({"command": "typing", "typing": typing, "from": from})
+        }
+        VoyeursCommand::Reaction(emoji) => json!({"command": "reaction", "emoji": emoji}),
+        VoyeursCommand::Rejected(reason) => {
+            json!({"command": "rejected", "reason": reason.to_string()})
+        }
+        VoyeursCommand::Extensions(names) => {
+            json!({"command": "extensions", "extensions": names})
+        }
+        VoyeursCommand::Position { time, paused } => {
+            json!({"command": "position", "time": time, "paused": paused})
+        }
+        VoyeursCommand::PlaylistPos(pos) => {
+            json!({"command": "playlist_pos", "position": pos})
+        }
+        VoyeursCommand::LiveLag(lag) => json!({"command": "live_lag", "lag": lag}),
+        VoyeursCommand::ServerMessage(text) => {
+            json!({"command": "server_message", "text": text})
+        }
+        VoyeursCommand::Bookmark { name, time } => {
+            json!({"command": "bookmark", "name": name, "time": time})
+        }
+        VoyeursCommand::Room(name) => json!({"command": "room", "room": name}),
+        VoyeursCommand::FileSize(size) => json!({"command": "file_size", "size": size}),
+        VoyeursCommand::SkipSegments(segments) => {
+            json!({"command": "skip_segments", "segments": segments})
+        }
+        VoyeursCommand::Lock(locked) => json!({"command": "lock", "locked": locked}),
+        VoyeursCommand::ReadyFrom { username, ready } => {
+            json!({"command": "ready", "ready": ready, "from": username})
+        }
+        VoyeursCommand::SeekFrom { username, time } => {
+            json!({"command": "seek", "time": time, "from": username})
+        }
+        VoyeursCommand::Goodbye => json!({"command": "goodbye"}),
+        VoyeursCommand::Challenge(challenge) => {
+            json!({"command": "challenge", "challenge": hex(challenge)})
+        }
+        VoyeursCommand::Identity { key, sig } => {
+            json!({"command": "identity", "key": hex(key), "signature": hex(sig)})
+        }
+        VoyeursCommand::Password(answer) => {
+            json!({"command": "password", "answer": hex(answer)})
+        }
+        VoyeursCommand::ResumeAt { time, at } => {
+            json!({"command": "resume_at", "time": time, "at": at})
+        }
+        VoyeursCommand::GetState => json!({"command": "get_state"}),
+        VoyeursCommand::State { time, paused } => {
+            json!({"command": "state", "time": time, "paused": paused})
+        }
+        VoyeursCommand::ServerInfo(info) => json!({
+            "command": "server_info",
+            "version": info.version,
+            "protocol": info.protocol,
+            "uptime": info.uptime,
+            "room": info.room,
+            "peers": info.peers,
+        }),
+        VoyeursCommand::Peers(peers) => {
+            let peers: Vec<_> = peers
+                .iter()
+                .map(|peer| {
+                    json!({
+                        "username": peer.username,
+                        "role": peer.role.to_string(),
+                        "ready": peer.ready,
+                        "away": peer.away,
+                    })
+                })
+                .collect();
+            json!({"command": "peers", "peers": peers})
+        }
+        VoyeursCommand::Kick(username) => json!({"command": "kick", "username": username}),
+        VoyeursCommand::Rename(username) => json!({"command": "rename", "username": username}),
+        // The code itself would let whoever reads the log in
+        VoyeursCommand::JoinCode(_) => json!({"command": "join_code"}),
+        VoyeursCommand::Auth(answer) => json!({"command": "auth", "answer": hex(answer)}),
+        VoyeursCommand::Invite { expires, sig } => {
+            json!({"command": "invite", "expires": expires, "signature": hex(sig)})
+        }
+        VoyeursCommand::Extension { code, args } => {
+            json!({"command": "extension", "code": code, "args": hex(args)})
+        }
+    })
+}
+
+pub fn player(event: &PlayerEvent) -> Map<String, Value> {
+    object(match event {
+        PlayerEvent::Pause(paused) => json!({"event": "pause", "paused": paused}),
+        PlayerEvent::Seeking(seeking) => json!({"event": "seeking", "seeking": seeking}),
+        PlayerEvent::Focused(focused) => json!({"event": "focused", "focused": focused}),
+        PlayerEvent::Reaction(emoji) => json!({"event": "reaction", "emoji": emoji}),
+        PlayerEvent::Bookmark(name) => json!({"event": "bookmark", "name": name}),
+        PlayerEvent::Resync => json!({"event": "resync"}),
+        PlayerEvent::Nudge(by) => json!({"event": "nudge", "by": by}),
+        PlayerEvent::PlaylistPos(pos) => json!({"event": "playlist_pos", "position": pos}),
+        PlayerEvent::FileLoaded(filename) => {
+            json!({"event": "file_loaded", "filename": filename})
+        }
+        PlayerEvent::EndFile => json!({"event": "end_file"}),
+        PlayerEvent::Restarted => json!({"event": "restarted"}),
+        PlayerEvent::Shutdown => json!({"event": "shutdown"}),
+        PlayerEvent::Other => json!({"event": "other"}),
+    })
+}
+
+pub fn log(level: &str, message: &str) -> Map<String, Value> {
+    object(json!({"level": level, "message": message}))
+}
+
+fn object(value: Value) -> Map<String, Value> {
+    match value {
+        Value::Object(map) => map,
+        _ => unreachable!("events are built from object literals"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::json_events::{packet, player};
+    use crate::player::PlayerEvent;
+    use crate::proto::VoyeursCommand;
+    use crate::record::Direction;
+    use serde_json::json;
+
+    #[test]
+    fn test_events() {
+        let chat = VoyeursCommand::Chat {
+            from: "bob".to_owned(),
+            text: "hi".to_owned(),
+        };
+        let event = packet(
+            Direction::In,
+            Some("127.0.0.1:8998".parse().unwrap()),
+            "bob",
+            &chat,
+        );
+        assert_eq!(event["dir"], "in");
+        assert_eq!(event["peer"], "127.0.0.1:8998");
+        assert_eq!(event["command"], "chat");
+        assert_eq!(event["text"], "hi");
+
+        let event = packet(Direction::Out, None, "", &VoyeursCommand::Seek(1.5));
+        assert_eq!(event["peer"], json!(null));
+        assert_eq!(event["time"], 1.5);
+
+        let event = player(&PlayerEvent::Pause(true));
+        assert_eq!(event["event"], "pause");
+        assert_eq!(event["paused"], true);
+    }
+}
@@ -0,0 +1,119 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, LineWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::time::get_timestamp;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Info,
+    /// every packet and player event, for tracking down desyncs
+    Debug,
+}
+
+impl Level {
+    fn name(self) -> &'static str {
+        match self {
+            Level::Info => "info",
+            Level::Debug => "debug",
+        }
+    }
+}
+
+/// Writes log lines to the console up to a verbosity, and everything to an optional log file
+pub struct Logger {
+    /// most verbose level echoed to the console, none when quiet
+    console: Option<Level>,
+    file: Option<LineWriter<File>>,
+    path: Option<PathBuf>,
+}
+
+fn open(path: &Path) -> io::Result<LineWriter<File>> {
+    Ok(LineWriter::new(
+        OpenOptions::new().create(true).append(true).open(path)?,
+    ))
+}
+
+impl Logger {
+    pub fn new(console: Option<Level>, path: Option<&Path>) -> io::Result<Logger> {
+        let file = match path {
+            Some(path) => Some(open(path)?),
+            None => None,
+        };
+        Ok(Logger {
+            console,
+            file,
+            path: path.map(Path::to_owned),
+        })
+    }
+
+    /// Open the log file again, after it was rotated
+    #[cfg(unix)]
+    pub fn reopen(&mut self) -> io::Result<()> {
+        if let Some(path) = &self.path {
+            self.file = Some(open(path)?);
+        }
+        Ok(())
+    }
+
+    /// Whether a line at `level` goes anywhere at all
+    pub fn enabled(&self, level: Level) -> bool {
+        self.file.is_some() || self.console.is_some_and(|max| level <= max)
+    }
+
+    pub fn write(&mut self, level: Level, line: &str, console: bool) {
+        if console && self.console.is_some_and(|max| level <= max) {
+            println!("{line}");
+        }
+        if let Some(file) = &mut self.file {
+            // a broken log file shouldn't take the session down with it
+            let _ = writeln!(file, "{}", format_line(get_timestamp(), level, line));
+        }
+    }
+}
+
+impl Default for Logger {
+    fn default() -> Self {
+        Logger {
+            console: Some(Level::Info),
+            file: None,
+            path: None,
+        }
+    }
+}
+
+// logfmt, so it can be grepped and parsed alike
+fn format_line(timestamp: u64, level: Level, line: &str) -> String {
+    format!("ts={timestamp} level={} msg={line:?}", level.name())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::logging::{format_line, Level, Logger};
+    use std::fs;
+
+    #[test]
+    fn test_format_line() {
+        assert_eq!(
+            format_line(1234, Level::Debug, r#"bob -> Chat { text: "hi" }"#),
+            r#"ts=1234 level=debug msg="bob -> Chat { text: \"hi\" }""#
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("voyeurs.log");
+        let mut logger = Logger::new(None, Some(&path)).unwrap();
+        logger.write(Level::Info, "before", false);
+        // what logrotate does
+        fs::rename(&path, dir.path().join("voyeurs.log.1")).unwrap();
+        logger.reopen().unwrap();
+        logger.write(Level::Info, "after", false);
+        let log = fs::read_to_string(&path).unwrap();
+        assert!(log.contains("after") && !log.contains("before"));
+    }
+}
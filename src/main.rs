@@ -1,28 +1,134 @@
+mod acl;
+mod afk;
+mod bookmarks;
 mod client_message_handler;
+#[cfg(unix)]
+mod control;
+#[cfg(unix)]
+mod daemon;
+#[cfg(feature = "notify")]
+mod desktop;
+mod edit_list;
+mod filename;
+mod headless;
+mod health;
+mod i18n;
+mod identity;
+mod invite;
+mod json_events;
+mod logging;
+#[cfg(all(unix, feature = "mpris"))]
+mod mpris;
 mod mpv_event_handler;
+#[cfg(any(windows, test))]
+mod mpv_pipe;
+mod osd;
+mod outbox;
+mod panel;
+mod player;
+mod plugin;
 mod proto;
+mod record;
+#[cfg(unix)]
+mod remote;
+mod replay;
+mod rewrite;
+mod rooms;
+#[cfg(feature = "scripting")]
+mod scripting;
+mod selftest;
+mod session;
+mod simulate;
+mod source;
+mod sponsorblock;
+mod stats;
+mod stdin;
+mod supervised;
+mod telemetry;
 mod time;
+#[cfg(feature = "tls")]
+mod tls;
+mod traffic;
+mod trust;
+#[cfg(feature = "tui")]
+mod tui;
+mod username;
+mod vlc;
+#[cfg(feature = "wasm")]
+mod wasm_plugin;
+mod webhook;
 
-use clap::Parser;
+use acl::{parse_cidr, Acl, Bans, Cidr, BAN_DURATION};
+use afk::watch_idle;
+use bookmarks::Bookmarks;
+use clap::{ArgAction, CommandFactory, Parser, Subcommand};
+use clap_complete::Shell;
+use clap_mangen::Man;
 use client_message_handler::*;
+use edit_list::EditList;
+use filename::FilenameMatch;
+use headless::Headless;
+use i18n::{Locale, Msg};
+use identity::Identity;
+use invite::{Invite, JoinCodes};
+use logging::{Level, Logger};
 use mpv_event_handler::*;
-use mpvipc::*;
+use osd::{notify, Category, Durations};
+use outbox::{Outbox, SendError};
+use player::{PlayerBackend, PlayerConnection, PlayerError, PlayerKind};
+use plugin::Plugins;
 use proto::*;
+use record::{Direction, Recorder};
+use rewrite::Rewrites;
+use rooms::{parse_room, Room, Rooms};
+use session::SessionEvent;
+use simulate::{parse_conditions, Conditions};
+use stats::Stats;
 use std::collections::VecDeque;
+use std::error::Error;
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
 use std::vec;
-use std::{collections::HashMap, process::Command, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    io::stdout,
+    iter,
+    process::{self, Child, Command},
+    sync::Arc,
+    thread,
+};
+use telemetry::run_telemetry;
 use tempfile::tempdir;
-use time::set_time_delta;
+use time::{get_timestamp, refine_time_delta, set_time_delta, watch_clock};
 use tokio::{
-    io::AsyncWriteExt,
-    net::{lookup_host, tcp::OwnedWriteHalf, TcpListener, TcpStream},
-    sync::Mutex,
+    io::{AsyncRead, AsyncWrite},
+    net::{lookup_host, TcpListener, TcpStream},
+    sync::{
+        broadcast,
+        mpsc::{self, UnboundedSender},
+        Mutex, Semaphore,
+    },
+    task::AbortHandle,
 };
+use traffic::Traffic;
+use trust::TrustList;
+use url::Url;
+use vlc::start_vlc;
 
 #[derive(Parser)]
-#[command(about, version)]
+#[command(
+    about,
+    version,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Commands>,
+
     /// become a server, if not set you are a client
     #[arg(short, long)]
     serve: bool,
@@ -31,14 +137,98 @@ struct Cli {
     #[arg(short, long, conflicts_with = "serve")]
     accept_source: bool,
 
+    /// rewrite the uri got from the server with the rules in this file, one
+    /// `REGEX -> REPLACEMENT` per line
+    #[arg(long, value_name = "FILE", requires = "accept_source")]
+    rewrite_rules: Option<PathBuf>,
+
     /// username that will be sent to the server
-    #[arg(short, long, default_value = "user")]
+    #[arg(short, long, default_value = "user", value_parser = username::sanitize)]
     username: String,
 
+    /// go by a made-up name, and send only a hash of the filename when hosting,
+    /// for syncing over semi-public servers
+    #[arg(long, conflicts_with = "username")]
+    anonymous: bool,
+
+    /// the key proving to servers it's you whatever your username, and signing the invites
+    /// of a server with --invite-only, made on first run. By default identity in ~/.config/voyeurs
+    #[arg(long, value_name = "PATH", conflicts_with = "anonymous")]
+    identity: Option<PathBuf>,
+
     /// username that will be sent to the server
     #[arg(long)]
     standalone: bool,
 
+    /// never block playback: always report ready and follow every resume
+    #[arg(long, conflicts_with = "standalone")]
+    auto_ready: bool,
+
+    /// pause when the last client leaves, so that you don't watch ahead of them alone
+    #[arg(long, requires = "serve")]
+    pause_when_empty: bool,
+
+    /// neither follow nor send these, can be repeated
+    #[arg(long, value_enum, value_name = "COMMAND", conflicts_with = "serve")]
+    no_sync: Vec<SyncClass>,
+
+    /// follow the session without ever pausing or seeking it, for a TV mirroring it
+    #[arg(long, conflicts_with_all = ["serve", "no_sync"])]
+    follow_only: bool,
+
+    /// join without a player, only to follow the session and pause, seek, chat or kick from
+    /// the terminal or voyeursctl
+    #[arg(long, conflicts_with_all = ["serve", "player", "follow_only"])]
+    controller: bool,
+
+    /// seconds our file is ahead of everyone else's, negative when it's behind
+    #[arg(
+        long,
+        value_name = "SECS",
+        allow_hyphen_values = true,
+        default_value_t = 0.0
+    )]
+    offset: f64,
+
+    /// where stretches of everyone else's file are in ours, for a different cut of it,
+    /// one `START-END OFFSET` per line
+    #[arg(long, value_name = "FILE")]
+    edit_list: Option<PathBuf>,
+
+    /// keep the event log off the console
+    #[arg(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// also print every packet and player event
+    #[arg(short, long)]
+    verbose: bool,
+
+    /// print a JSON object per packet, player event and log line instead of the usual text,
+    /// for wrappers and GUIs
+    #[arg(long, conflicts_with_all = ["quiet", "verbose"])]
+    json_events: bool,
+
+    /// append timestamped logs of everything, including what --verbose prints
+    #[arg(long, value_name = "PATH")]
+    log_file: Option<PathBuf>,
+
+    /// take commands from voyeursctl on this unix socket, by default voyeurs.sock in
+    /// $XDG_RUNTIME_DIR
+    #[cfg(unix)]
+    #[arg(long, value_name = "PATH", num_args = 0..=1)]
+    control_socket: Option<Option<PathBuf>>,
+
+    /// serve a page on this port to play, pause, seek and chat from a phone on the same
+    /// network. The address to open, key included, is in the log
+    #[cfg(unix)]
+    #[arg(long, value_name = "PORT")]
+    remote_port: Option<u16>,
+
+    /// write every packet sent and received to this file as JSON lines, to debug desyncs
+    /// or replay the session later
+    #[arg(long, value_name = "PATH")]
+    record_session: Option<PathBuf>,
+
     /// use system time instead of ntp (not reccomended)
     #[arg(short, long)]
     trust_system_time: bool,
@@ -51,183 +241,1767 @@ struct Cli {
     )]
     ntp_server: String,
 
-    /// address:port to connect/bind to  
-    #[arg(value_name = "ADDRESS")]
-    address: String,
+    /// seek to the exact frame instead of the nearest keyframe, so every peer lands on the same position
+    #[arg(long, default_value_t = true, action = ArgAction::Set)]
+    exact_seek: bool,
+
+    /// keep within a frame or two of the others, for comparing details rather than
+    /// watching: seeks make up for the network delay, and a host with it too has
+    /// everyone resume at the same instant
+    #[arg(long)]
+    precise: bool,
+
+    /// when hosting, seek peers that drift further than this many seconds back in sync
+    #[arg(long, value_name = "SECS")]
+    max_desync: Option<f64>,
+
+    /// tell us when we are this many seconds off the host, 0 never does
+    #[arg(long, value_name = "SECS", default_value_t = 2.0)]
+    drift_alert: f64,
+
+    /// how many seconds the duration can differ from the server's before warning about it,
+    /// container padding alone makes identical files differ a bit
+    #[arg(long, value_name = "SECS", default_value_t = 0.5)]
+    duration_tolerance: f64,
+
+    /// how alike the filename has to be to the server's not to warn about it
+    #[arg(long, value_enum, default_value_t = FilenameMatch::Fuzzy)]
+    filename_match: FilenameMatch,
+
+    /// show a terminal interface with the peers and the event log
+    #[cfg(feature = "tui")]
+    #[arg(long, conflicts_with = "json_events")]
+    tui: bool,
+
+    /// also show joins, leaves and chat as desktop notifications
+    #[cfg(feature = "notify")]
+    #[arg(long)]
+    desktop_notifications: bool,
+
+    /// count packets and bytes by peer and command, logging a summary every SECS
+    /// (60 by default) and answering voyeursctl stats
+    #[arg(
+        long,
+        value_name = "SECS",
+        num_args = 0..=1,
+        default_missing_value = "60",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    stats: Option<u64>,
+
+    /// mark yourself as away after this many minutes paused without any input
+    #[arg(long, value_name = "MINUTES")]
+    afk_timeout: Option<u64>,
+
+    /// seconds a new connection gets to complete the handshake
+    #[arg(long, value_name = "SECONDS", default_value_t = 10)]
+    handshake_timeout: u64,
+
+    /// seconds a peer whose connection dropped has to come back as if it never left,
+    /// 0 to let it go right away
+    #[arg(long, value_name = "SECONDS", default_value_t = 15)]
+    reconnect_grace: u64,
+
+    /// seconds to keep trying to get back to a server that dropped us without ending
+    /// the session, like when the host reboots. 0 gives up right away
+    #[arg(long, value_name = "SECONDS", default_value_t = 120)]
+    rejoin_window: u64,
+
+    /// maximum number of connections still in the handshake at the same time
+    #[arg(long, value_name = "N", default_value_t = 16)]
+    max_pending: usize,
+
+    /// only accept connections from this range, e.g. your tailscale subnet. Can be repeated
+    #[arg(long, value_name = "CIDR", requires = "serve", value_parser = parse_cidr)]
+    allow: Vec<Cidr>,
+
+    /// refuse connections from this range, even when allowed. Can be repeated
+    #[arg(long, value_name = "CIDR", requires = "serve", value_parser = parse_cidr)]
+    deny: Vec<Cidr>,
+
+    /// refuse whoever holds this key, as logged when they join, under any username. Can be
+    /// repeated
+    #[arg(long, value_name = "KEY", requires = "serve", value_parser = identity::parse_key)]
+    ban_key: Vec<[u8; 32]>,
+
+    /// only let in the keys listed in this file, one `KEY NAME` per line. New keys
+    /// get added once they give --password-file, an invite or a join code
+    #[arg(long, value_name = "PATH", requires = "serve")]
+    trusted_keys: Option<PathBuf>,
+
+    /// only let in peers with an invite link from voyeursctl invite, a join code, or the
+    /// password when there is one. Links stop working once they expire
+    #[arg(long, requires = "serve")]
+    invite_only: bool,
+
+    /// print a short code that lets one person in, to read out over voice chat. Implies
+    /// --invite-only, voyeursctl code makes more
+    #[arg(long, requires = "serve")]
+    gen_code: bool,
+
+    /// join code the host gave you
+    #[arg(long, value_name = "CODE", conflicts_with = "serve")]
+    code: Option<String>,
+
+    /// address the invite links point to, by default the first one the server listens on
+    #[arg(long, value_name = "ADDRESS", requires = "invite_only")]
+    public_address: Option<String>,
+
+    /// file holding the password the server asks of peers it doesn't trust yet. Only a
+    /// proof of it goes over the network
+    #[arg(long, value_name = "PATH")]
+    password_file: Option<PathBuf>,
+
+    /// file holding a second, stronger password that makes whoever proves it a
+    /// moderator, whether or not they know the room's
+    #[arg(long, value_name = "PATH")]
+    admin_password_file: Option<PathBuf>,
+
+    /// largest packet accepted from a peer, in bytes, up to the 65535 its length field holds
+    #[arg(
+        long,
+        value_name = "BYTES",
+        default_value_t = Limits::default().max_packet as LenSize,
+        value_parser = clap::value_parser!(LenSize).range(1..)
+    )]
+    max_packet_size: LenSize,
+
+    /// file holding a secret shared by everyone in the session. Every packet then carries an
+    /// HMAC of it, so forged or tampered ones are rejected. Nothing gets encrypted
+    #[arg(long, value_name = "PATH")]
+    psk_file: Option<PathBuf>,
+
+    /// also encrypt what packets carry with --psk-file, so that whatever forwards them,
+    /// like a relay or a tunnel on someone else's server, can't read chat, urls or filenames
+    #[arg(long, requires = "psk_file")]
+    encrypt: bool,
+
+    /// for development: send everything through a bad network,
+    /// e.g. latency=250ms,jitter=80ms,loss=1%
+    #[arg(long, value_name = "CONDITIONS", value_parser = parse_conditions)]
+    simulate: Option<Conditions>,
+
+    /// PEM certificate to present. With --tls-key and --tls-ca this turns on mutual tls:
+    /// the server only lets in clients whose certificate is signed by the CA and issued
+    /// to their username
+    #[cfg(feature = "tls")]
+    #[arg(long, value_name = "PEM", requires_all = ["tls_key", "tls_ca"])]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key of --tls-cert
+    #[cfg(feature = "tls")]
+    #[arg(long, value_name = "PEM", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// PEM certificate of the CA signing the server's and the clients' certificates
+    #[cfg(feature = "tls")]
+    #[arg(long, value_name = "PEM", requires = "tls_cert")]
+    tls_ca: Option<PathBuf>,
+
+    /// for live streams: keep everyone as far from the live edge as the host, instead of syncing positions
+    #[arg(long)]
+    live: bool,
+
+    /// welcome message shown to everyone who joins
+    #[arg(long, requires = "serve")]
+    motd: Option<String>,
+
+    /// POST a JSON event here when someone joins or leaves, pauses or resumes, or the
+    /// file changes. Discord webhook URLs work as they are
+    #[arg(long, value_name = "URL", requires = "serve")]
+    webhook: Option<Url>,
+
+    /// run the on_join, on_chat, on_tick... hooks of this rhai script as things happen
+    #[cfg(feature = "scripting")]
+    #[arg(long, value_name = "FILE")]
+    script: Option<PathBuf>,
+
+    /// start the plugins in this file, one `NAME CODE[,CODE...] COMMAND [ARGS...]` per
+    /// line, handling the extension commands with those codes
+    #[arg(long, value_name = "FILE")]
+    plugins: Option<PathBuf>,
+
+    /// start with only the host controlling playback, voyeursctl lock and unlock toggle it
+    #[arg(long, requires = "serve")]
+    lock: bool,
+
+    /// skip the sponsored parts of youtube videos for everyone, as marked on SponsorBlock
+    #[arg(long, requires = "serve")]
+    sponsorblock: bool,
+
+    /// skip START-END in every file for everyone, like an intro at 0:00-1:30. Can be repeated
+    #[arg(long = "skip", value_name = "START-END", requires = "serve", value_parser = sponsorblock::parse_segment)]
+    skips: Vec<sponsorblock::Segment>,
+
+    /// run as a service: mpv plays headless and comes back when it quits, keeping the
+    /// peers connected. The address is ignored when systemd passes a socket
+    #[arg(long, requires = "serve")]
+    daemon: bool,
+
+    /// answer health checks at http://ADDRESS/healthz, with the version, uptime and peers
+    #[arg(long, value_name = "ADDRESS", requires = "serve")]
+    healthz: Option<String>,
+
+    /// shut the server down once nobody has joined for this many minutes. With --rooms,
+    /// a room left empty that long unloads its file until they all are
+    #[arg(long, value_name = "MINUTES", requires = "serve")]
+    idle_timeout: Option<u64>,
+
+    /// serve several rooms, each with its own player and peers, as names separated by commas.
+    /// Clients that don't pick one with --room join the first
+    #[arg(
+        long,
+        value_name = "NAMES",
+        requires = "serve",
+        value_delimiter = ',',
+        value_parser = parse_room
+    )]
+    rooms: Vec<String>,
+
+    /// room of the server to join
+    #[arg(long, value_name = "NAME", conflicts_with = "serve", value_parser = parse_room)]
+    room: Option<String>,
+
+    /// write the process id here
+    #[arg(long, value_name = "PATH")]
+    pid_file: Option<PathBuf>,
+
+    /// don't show any messages on the video
+    #[arg(long)]
+    no_osd: bool,
+
+    /// also show the session summary on the video when it ends, needs mpv's --keep-open
+    #[arg(long)]
+    osd_summary: bool,
+
+    /// how long a kind of message stays on the video, as CATEGORY=MS with the category
+    /// one of presence, chat, reaction or warning. 0 hides them
+    #[arg(long, value_name = "CATEGORY=MS", value_parser = osd::parse_duration)]
+    osd_duration: Vec<(Category, u32)>,
+
+    /// where messages go on the video, away from the subtitles
+    #[arg(long, value_enum, default_value_t = osd::Position::TopLeft)]
+    osd_position: osd::Position,
+
+    /// size of the messages, by default mpv's --osd-font-size
+    #[arg(long, value_name = "SIZE")]
+    osd_font_size: Option<u32>,
+
+    /// draw a dark box behind the messages
+    #[arg(long)]
+    osd_box: bool,
+
+    /// language of the messages, defaults to the one of the environment
+    #[arg(long, value_enum)]
+    lang: Option<Locale>,
+
+    /// listening party: no video window, and playlists advance together
+    #[arg(long)]
+    audio_only: bool,
+
+    /// media player to drive. vlc can't show messages on the video and only seeks to whole seconds
+    #[arg(long, value_enum, default_value_t = PlayerKind::Mpv)]
+    player: PlayerKind,
 
-    // arguments that will get passed to mpv
-    #[arg(value_name = "MPV_ARGS")]
+    /// address:port to connect/bind to. A server can listen on several, separated by commas
+    #[arg(value_name = "ADDRESS", required = true)]
+    address: Option<String>,
+
+    // arguments that will get passed to the player
+    #[arg(value_name = "PLAYER_ARGS")]
     mpv_args: Vec<String>,
 }
 
+#[derive(Subcommand)]
+enum Commands {
+    /// print the completion script for a shell
+    Completions { shell: Shell },
+    /// print the man page
+    Man,
+    /// check that this install works, with a server and two clients talking over loopback
+    Selftest,
+    /// play a session recorded with --record-session back on mpv, with the original timing
+    Replay {
+        recording: PathBuf,
+        /// arguments passed to mpv, usually the file that was watched
+        #[arg(last = true)]
+        player_args: Vec<String>,
+    },
+}
+
+/// Completions and the man page come from the built binary rather than from build.rs: the
+/// command line is made of value parsers and types from all over the crate, which a build
+/// script would have to compile a second time. Packagers run `voyeurs man` after the build
+fn generate(command: Commands) {
+    let mut cmd = Cli::command();
+    match command {
+        Commands::Completions { shell } => {
+            clap_complete::generate(shell, &mut cmd, "voyeurs", &mut stdout())
+        }
+        Commands::Man => Man::new(cmd)
+            .render(&mut stdout())
+            .expect("Couldn't write the man page"),
+        Commands::Selftest | Commands::Replay { .. } => {
+            unreachable!("only completions and the man page are generated")
+        }
+    }
+}
+
+/// Where a peer is in the handshake. Until it has joined, a peer can't
+/// influence playback and doesn't receive broadcasts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HandshakeState {
+    /// the preamble checked out, waiting for NewConnection
+    AwaitingHello,
+    Joined,
+}
+
+/// What's kept of a peer whose connection dropped, until it comes back or
+/// the grace period is over
+pub struct Departed {
+    ready: bool,
+    away: bool,
+    role: Role,
+    /// the identity it held, only the same one gets the role back
+    key: Option<[u8; 32]>,
+    latency: VecDeque<u64>,
+    left: Instant,
+}
+
 pub struct Peer {
-    tx: OwnedWriteHalf,
+    tx: Outbox,
+    /// the task writing `tx` out, stopped when the peer can't keep up
+    writer: AbortHandle,
+    handshake: HandshakeState,
     username: String,
     ready: bool,
+    away: bool,
     latency: VecDeque<u64>,
+    last_reaction: Option<Instant>,
+    /// extensions both ends support
+    extensions: Vec<String>,
+    /// seconds we are ahead of this peer, from its latest position report
+    drift: Option<f64>,
+    last_position: Option<Instant>,
+    /// when the server last sent this peer a corrective seek
+    last_resync: Option<Instant>,
+    /// our player restarted playback since its last position report, which may have
+    /// left us a little off it
+    recheck: bool,
+    /// the key it signed the challenge with, if it has one
+    key: Option<[u8; 32]>,
+    /// it proved knowing the password, or brought an invite that hasn't expired
+    vouched: bool,
+    /// what it gave for a join code, redeemed once it says who it is
+    join_code: Option<String>,
+    /// what the host lets it do, the server we are a client of is the host
+    role: Role,
 }
 
+const MAX_EVENTS: usize = 200;
+/// How often a client knocks on a server it lost
+const REJOIN_INTERVAL: Duration = Duration::from_secs(2);
+/// How long a kicked peer's connection stays open for it to hear why it's closing
+const KICK_GRACE: Duration = Duration::from_secs(1);
+/// How far ahead the host schedules a resume with --precise, time for peers to seek
+const RESUME_LEAD: Duration = Duration::from_millis(750);
+const COMPANION_SCRIPT: &str = include_str!("../scripts/voyeurs.lua");
+
 pub struct Shared {
     peers: HashMap<SocketAddr, Peer>,
+    /// peers that lost their connection, by username
+    departed: HashMap<String, Departed>,
+    /// removed when the session ends, with --pid-file
+    pid_file: Option<PathBuf>,
     ignore_next: bool,
+    /// we asked the host for its state, to go right where it is
+    resyncing: bool,
     is_ready: bool,
+    away: bool,
+    last_activity: Instant,
+    events: VecDeque<String>,
+    tui: bool,
+    logger: Logger,
+    bans: Bans,
+    /// what happened in the session, for the webhook and anyone else subscribed
+    session: broadcast::Sender<SessionEvent>,
+    recorder: Option<Recorder>,
+    bookmarks: Bookmarks,
+    stats: Stats,
+    json_events: bool,
+    /// stretches of the video everyone skips
+    segments: Vec<sponsorblock::Segment>,
+    /// the host's own skip ranges, kept from a file to the next
+    skips: Vec<sponsorblock::Segment>,
+    /// only the host and moderators can pause, resume and seek
+    locked: bool,
+    /// the room this is the state of, with --rooms
+    room: Option<String>,
+    /// where to send the address of the server a client should move to
+    switch: Option<UnboundedSender<String>>,
+    /// the server ended the session or turned us away, no point in rejoining
+    goodbye: bool,
+    /// what the server we joined runs, as it told us
+    server_info: Option<ServerInfo>,
+    /// what the host lets us do, as a client
+    role: Role,
+    /// who's in the session, their roles and whether they're ready, as the server last told us
+    roster: Vec<RosterEntry>,
+    /// what we go by since voyeursctl rename, as a client, instead of --username
+    renamed: Option<String>,
+    /// who's writing a chat message right now
+    typing: HashSet<String>,
+    /// the last few chat lines and who they're from, for the panel
+    recent_chat: VecDeque<(String, String)>,
+    /// keys banned with voyeursctl ban, until the server restarts
+    banned_keys: HashSet<[u8; 32]>,
+    /// the codes handed out with --gen-code and voyeursctl code, until they're used
+    join_codes: JoinCodes,
+    /// packets and bytes by peer and command, with --stats
+    traffic: Option<Traffic>,
+    /// how our file's timeline maps to the session's, with --offset or --edit-list
+    timeline: EditList,
+    /// what peers take, for what we send them
+    limits: Limits,
+}
+
+/// Turn down what we'd send with a field longer than peers accept, as they'd drop the
+/// connection over it
+fn fits(limits: &Limits, command: &VoyeursCommand) -> Result<(), String> {
+    limits.check(command).map_err(|e| e.to_string())
+}
+
+/// Where we'll be a moment from now, playing on from `position`, and when: peers start
+/// playing right as we get there
+fn resume_at(position: f64) -> VoyeursCommand {
+    VoyeursCommand::ResumeAt {
+        time: position + RESUME_LEAD.as_secs_f64(),
+        at: get_timestamp() + RESUME_LEAD.as_millis() as TsSize,
+    }
 }
 
 impl Shared {
     fn new() -> Self {
         Shared {
             peers: HashMap::new(),
+            departed: HashMap::new(),
+            pid_file: None,
             ignore_next: false,
+            resyncing: false,
             is_ready: false,
+            away: false,
+            last_activity: Instant::now(),
+            events: VecDeque::with_capacity(MAX_EVENTS),
+            tui: false,
+            logger: Logger::default(),
+            bans: Bans::default(),
+            session: session::channel(),
+            recorder: None,
+            bookmarks: Bookmarks::default(),
+            stats: Stats::new(Instant::now()),
+            json_events: false,
+            segments: vec![],
+            skips: vec![],
+            locked: false,
+            room: None,
+            switch: None,
+            goodbye: false,
+            server_info: None,
+            role: Role::Viewer,
+            roster: vec![],
+            renamed: None,
+            typing: HashSet::new(),
+            recent_chat: VecDeque::with_capacity(panel::CHAT_LINES),
+            banned_keys: HashSet::new(),
+            join_codes: JoinCodes::default(),
+            traffic: None,
+            timeline: EditList::default(),
+            limits: Limits::default(),
         }
     }
 
-    async fn send(&mut self, addr: SocketAddr, command: VoyeursCommand) {
+    /// The name we go by, the one we were started with until renamed
+    fn username<'a>(&'a self, settings: &'a Settings) -> &'a str {
+        self.renamed.as_deref().unwrap_or(&settings.username)
+    }
+
+    /// Tell the peers we are leaving on purpose, so that they don't wait for us to come back
+    async fn say_goodbye(&mut self) {
+        self.broadcast(VoyeursCommand::Goodbye).await;
+    }
+
+    /// Print how the session went and quit
+    fn end_session(&mut self) -> ! {
+        #[cfg(feature = "tui")]
+        crate::tui::restore_terminal();
+        // the terminal is back to normal, the summary can go there
+        self.tui = false;
+        for line in self.stats.summary(Instant::now()) {
+            self.log(line);
+        }
+        if let Some(path) = &self.pid_file {
+            let _ = fs::remove_file(path);
+        }
+        std::process::exit(0)
+    }
+
+    /// Tell the subscribers, if there are any
+    fn announce(&self, event: SessionEvent) {
+        let _ = self.session.send(event);
+    }
+
+    /// Hear about joins, pauses, seeks, chat and the like from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<SessionEvent> {
+        self.session.subscribe()
+    }
+
+    /// Record a line in the event log, echoing it unless the tui owns the terminal
+    fn log(&mut self, line: impl Into<String>) {
+        let line = match &self.room {
+            Some(room) => format!("[{room}] {}", line.into()),
+            None => line.into(),
+        };
+        self.logger.write(Level::Info, &line, !self.tui);
+        if self.json_events {
+            json_events::emit("log", json_events::log("info", &line));
+        }
+        if self.events.len() == MAX_EVENTS {
+            self.events.pop_front();
+        }
+        self.events.push_back(line);
+    }
+
+    /// Log the nitty-gritty, only with --verbose or --log-file
+    fn debug(&mut self, line: impl FnOnce() -> String) {
+        if self.logger.enabled(Level::Debug) {
+            self.logger.write(Level::Debug, &line(), !self.tui);
+        }
+    }
+
+    /// Count a failed handshake against the peer's address, banning it after too many
+    fn strike(&mut self, addr: SocketAddr) {
+        if self.bans.strike(addr.ip(), Instant::now()) {
+            self.log(format!(
+                "banned {} for {} minutes, too many failed handshakes",
+                addr.ip(),
+                BAN_DURATION.as_secs() / 60
+            ));
+        }
+    }
+
+    /// Whether every joined peer that isn't away is ready to play
+    fn peers_ready(&self) -> bool {
+        self.joined_peers().all(|(_, p)| p.ready || p.away)
+    }
+
+    fn joined_peers(&self) -> impl Iterator<Item = (&SocketAddr, &Peer)> {
         self.peers
-            .get_mut(&addr)
-            .unwrap()
-            .tx
-            .write_all(&command.craft_packet().compile())
-            .await
-            .unwrap();
+            .iter()
+            .filter(|(_, p)| p.handshake == HandshakeState::Joined)
+    }
+
+    /// Bookmark `time` as `name` for everyone
+    async fn add_bookmark(&mut self, name: String, time: f64) {
+        if name.is_empty() {
+            self.log("Bookmark names can't be empty".to_owned());
+            return;
+        }
+        let bookmark = VoyeursCommand::Bookmark {
+            name: name.clone(),
+            time,
+        };
+        if let Err(e) = fits(&self.limits, &bookmark) {
+            self.log(e);
+            return;
+        }
+        self.bookmarks.set(name.clone(), time);
+        self.log(Msg::Bookmark { name: &name, time }.to_string());
+        self.broadcast(bookmark).await;
+    }
+
+    /// Move our file `by` seconds further ahead of everyone else's, and the player along
+    /// with it so that we stay on the same scene
+    fn nudge(&mut self, player: &dyn PlayerBackend, by: f64, exact: bool) -> String {
+        let offset = self.timeline.nudge(by);
+        if let Some(position) = player.position() {
+            // Only we moved, nobody else needs to follow
+            self.ignore_next = true;
+            let _ = player.seek(position + by, exact);
+        }
+        let line = Msg::Offset(offset).to_string();
+        self.log(line.clone());
+        line
+    }
+
+    /// Have the peers that keep frame-accurate time start playing where we'll be a
+    /// moment from now, right as we get there. We're playing from `position` already
+    async fn schedule_resume(&mut self, position: f64) {
+        let resume = resume_at(position);
+        let precise: Vec<_> = self
+            .joined_peers()
+            .filter(|(_, peer)| peer.extensions.iter().any(|name| name == PRECISE))
+            .map(|(addr, _)| *addr)
+            .collect();
+        for addr in precise {
+            self.send(addr, resume.clone()).await;
+        }
+    }
+
+    /// The big red button: everyone snaps to where we are, and plays or stays paused
+    /// along with us whether they were ready or not
+    async fn sync_everyone(&mut self, username: &str, position: f64, paused: bool) {
+        if paused {
+            let username = username.to_owned();
+            self.broadcast(VoyeursCommand::ReadyFrom {
+                username: username.clone(),
+                ready: false,
+            })
+            .await;
+            self.broadcast(VoyeursCommand::SeekFrom {
+                username,
+                time: position,
+            })
+            .await;
+        } else {
+            self.is_ready = true;
+            for peer in self.peers.values_mut() {
+                peer.ready = true;
+            }
+            self.broadcast(resume_at(position)).await;
+        }
+    }
+
+    /// Ask the host where it is and whether it's paused, going right there when `snap`.
+    /// The answer also stands in for a position report that's late
+    async fn query_state(&mut self, snap: bool) {
+        self.resyncing |= snap;
+        self.broadcast(VoyeursCommand::GetState).await;
+    }
+
+    /// Tell everyone who's here, what they may do and whether they're ready or away,
+    /// us as `host` first
+    async fn broadcast_peers(&mut self, host: &str) {
+        let host = RosterEntry {
+            username: host.to_owned(),
+            role: Role::Host,
+            ready: self.is_ready,
+            away: self.away,
+        };
+        let peers = iter::once(host)
+            .chain(self.joined_peers().map(|(_, peer)| RosterEntry {
+                username: peer.username.clone(),
+                role: peer.role,
+                ready: peer.ready,
+                away: peer.away,
+            }))
+            .collect();
+        self.broadcast(VoyeursCommand::Peers(peers)).await;
+    }
+
+    /// What we run and how we're doing, for peers to tell builds apart
+    fn server_info(&self) -> ServerInfo {
+        ServerInfo {
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            protocol: PROTOCOL_VERSION,
+            uptime: self.stats.uptime(Instant::now()).as_secs(),
+            room: self.room.clone().unwrap_or_default(),
+            peers: self.joined_peers().count() as u32,
+        }
+    }
+
+    /// Say something to everyone, as `from`
+    async fn chat(&mut self, from: String, text: String) -> Result<(), String> {
+        let chat = VoyeursCommand::Chat {
+            from: from.clone(),
+            text: text.clone(),
+        };
+        fits(&self.limits, &chat)?;
+        let line = format!("{from}: {text}");
+        self.remember_chat(from.clone(), line.clone());
+        self.log(line);
+        self.broadcast(chat).await;
+        self.announce(SessionEvent::Chat { from, text });
+        Ok(())
+    }
+
+    /// Keep `line`, from `from`, for the panel along with the last few others
+    fn remember_chat(&mut self, from: String, line: String) {
+        if self.recent_chat.len() == panel::CHAT_LINES {
+            self.recent_chat.pop_front();
+        }
+        self.recent_chat.push_back((from, line));
+    }
+
+    /// Say something to `to` alone, as `from`. Clients hand it to the server, which
+    /// knows where everyone is
+    async fn whisper(
+        &mut self,
+        from: String,
+        to: String,
+        text: String,
+        serving: bool,
+    ) -> Result<(), String> {
+        if !serving {
+            let whisper = VoyeursCommand::Whisper { to, text };
+            fits(&self.limits, &whisper)?;
+            self.broadcast(whisper).await;
+            return Ok(());
+        }
+        let whisper = VoyeursCommand::WhisperFrom { from, text };
+        fits(&self.limits, &whisper)?;
+        let addr = self
+            .joined_peers()
+            .find(|(_, peer)| peer.username == to)
+            .map(|(addr, _)| *addr)
+            .ok_or(format!("nobody called {to} is here"))?;
+        self.send(addr, whisper).await;
+        Ok(())
+    }
+
+    /// Let the others know whether we're writing something in the tui, as `username`
+    #[cfg(feature = "tui")]
+    async fn set_typing(&mut self, username: &str, typing: bool, serving: bool) {
+        let command = if serving {
+            VoyeursCommand::TypingFrom {
+                from: username.to_owned(),
+                typing,
+            }
+        } else {
+            VoyeursCommand::Typing(typing)
+        };
+        self.broadcast(command).await;
+    }
+
+    /// Add a packet to the session recording, when there's one
+    fn record(&mut self, direction: Direction, addr: SocketAddr, packet: &Packet) {
+        let username = self.peers.get(&addr).map_or("", |p| p.username.as_str());
+        if self.json_events {
+            let event = json_events::packet(direction, Some(addr), username, &packet.command);
+            json_events::emit("packet", event);
+        }
+        if let Some(recorder) = &mut self.recorder {
+            recorder.record(direction, addr, username, packet);
+        }
+    }
+
+    /// Add a packet to --stats, when it's on
+    fn count(
+        &mut self,
+        direction: Direction,
+        addr: SocketAddr,
+        command: &VoyeursCommand,
+        bytes: usize,
+    ) {
+        if let Some(traffic) = &mut self.traffic {
+            let username = self.peers.get(&addr).map_or("", |p| p.username.as_str());
+            traffic.count(
+                direction,
+                &traffic_label(addr, username),
+                command.name(),
+                bytes,
+            );
+        }
+    }
+
+    async fn send(&mut self, addr: SocketAddr, command: VoyeursCommand) {
+        // Gone already, there's nobody to tell
+        if !self.peers.contains_key(&addr) {
+            return;
+        }
+        self.debug(|| format!("{addr} <- {command:?}"));
+        let packet = command
+            .retimed(|time| self.timeline.to_session(time))
+            .craft_packet();
+        self.record(Direction::Out, addr, &packet);
+        match self.peers[&addr].tx.send(&packet) {
+            Ok(bytes) => self.count(Direction::Out, addr, &packet.command, bytes),
+            Err(SendError::Overflow) => self.drop_slow(vec![addr]),
+            // Nothing wrong with the peer, only with the packet
+            Err(SendError::Frame(e)) => {
+                self.log(format!("couldn't send {}: {e}", packet.command.name()))
+            }
+        }
     }
 
     async fn broadcast(&mut self, command: VoyeursCommand) {
-        dbg!(&command);
+        self.debug(|| format!("everyone <- {command:?}"));
+        if self.json_events {
+            let event = json_events::packet(Direction::Out, None, "", &command);
+            json_events::emit("packet", event);
+        }
+        let packet = command
+            .retimed(|time| self.timeline.to_session(time))
+            .craft_packet();
+        let (mut slow, mut unframed) = (vec![], None);
         for peer in self.peers.iter_mut() {
-            peer.1
-                .tx
-                .write_all(&command.clone().craft_packet().compile())
-                .await
-                .unwrap();
+            if peer.1.handshake != HandshakeState::Joined {
+                continue;
+            }
+            if let Some(recorder) = &mut self.recorder {
+                recorder.record(Direction::Out, *peer.0, &peer.1.username, &packet);
+            }
+            let bytes = match peer.1.tx.send(&packet) {
+                Ok(bytes) => bytes,
+                Err(SendError::Overflow) => {
+                    slow.push(*peer.0);
+                    continue;
+                }
+                // It won't go to anyone else either
+                Err(SendError::Frame(e)) => {
+                    unframed = Some(e);
+                    break;
+                }
+            };
+            if let Some(traffic) = &mut self.traffic {
+                let label = traffic_label(*peer.0, &peer.1.username);
+                traffic.count(Direction::Out, &label, packet.command.name(), bytes);
+            }
+        }
+        if let Some(e) = unframed {
+            self.log(format!("couldn't send {}: {e}", packet.command.name()));
         }
+        self.drop_slow(slow);
     }
 
     async fn broadcast_excluding(&mut self, command: VoyeursCommand, addr: SocketAddr) {
-        dbg!(&command, addr);
+        self.debug(|| format!("everyone but {addr} <- {command:?}"));
+        if self.json_events {
+            let event = json_events::packet(Direction::Out, None, "", &command);
+            json_events::emit("packet", event);
+        }
+        let packet = command
+            .retimed(|time| self.timeline.to_session(time))
+            .craft_packet();
+        let (mut slow, mut unframed) = (vec![], None);
         for peer in self.peers.iter_mut() {
-            if *peer.0 != addr {
-                peer.1
-                    .tx
-                    .write_all(&command.clone().craft_packet().compile())
-                    .await
-                    .unwrap();
+            if *peer.0 != addr && peer.1.handshake == HandshakeState::Joined {
+                if let Some(recorder) = &mut self.recorder {
+                    recorder.record(Direction::Out, *peer.0, &peer.1.username, &packet);
+                }
+                let bytes = match peer.1.tx.send(&packet) {
+                    Ok(bytes) => bytes,
+                    Err(SendError::Overflow) => {
+                        slow.push(*peer.0);
+                        continue;
+                    }
+                    Err(SendError::Frame(e)) => {
+                        unframed = Some(e);
+                        break;
+                    }
+                };
+                if let Some(traffic) = &mut self.traffic {
+                    let label = traffic_label(*peer.0, &peer.1.username);
+                    traffic.count(Direction::Out, &label, packet.command.name(), bytes);
+                }
             }
         }
+        if let Some(e) = unframed {
+            self.log(format!("couldn't send {}: {e}", packet.command.name()));
+        }
+        self.drop_slow(slow);
+    }
+
+    /// Let go of a peer, telling it why when there's a reason to give. Its connection
+    /// closes once what's queued is out. Evicting a peer that's gone already does nothing
+    async fn evict(&mut self, addr: SocketAddr, reason: Option<RejectReason>) -> Option<Peer> {
+        if let Some(reason) = reason {
+            self.send(addr, VoyeursCommand::Rejected(reason)).await;
+        }
+        self.peers.remove(&addr)
+    }
+
+    /// Show a peer the door, out of the session right away whether it goes along or not.
+    /// Its username, for the others to hear it left, unless it was gone already
+    async fn kick(&mut self, addr: SocketAddr) -> Option<String> {
+        let Peer {
+            username, writer, ..
+        } = self.evict(addr, Some(RejectReason::Kicked)).await?;
+        // Time for the Rejected to get out, so that it doesn't knock again, but one that
+        // stopped reading doesn't get to keep its connection
+        tokio::spawn(async move {
+            tokio::time::sleep(KICK_GRACE).await;
+            writer.abort();
+        });
+        Some(username)
+    }
+
+    /// Let go of peers whose outbox filled up, rather than have the room wait for them
+    fn drop_slow(&mut self, slow: Vec<SocketAddr>) {
+        for addr in slow {
+            let Some(peer) = self.peers.get_mut(&addr) else {
+                continue;
+            };
+            // A writer stuck on a full socket never finishes by itself. Stopping it ends
+            // the connection, which lets the peer go without waiting for it to come back
+            peer.tx.close();
+            peer.writer.abort();
+            let label = traffic_label(addr, &peer.username);
+            self.log(format!("{label} can't keep up, dropping them"));
+        }
+    }
+}
+
+/// Who --stats counts a packet for: the peer's username, its address before the
+/// handshake and for the server we are a client of
+fn traffic_label(addr: SocketAddr, username: &str) -> String {
+    if username.is_empty() {
+        addr.to_string()
+    } else {
+        username.to_owned()
     }
 }
-#[derive(Clone, Debug)]
+
+/// What a client can keep to itself with --no-sync, neither following nor sending it
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum SyncClass {
+    /// seeks, to scrub back on your own
+    Seek,
+    /// pausing and resuming
+    Pause,
+    /// moving along the playlist
+    Playlist,
+}
+
+#[derive(Clone, Debug, Default)]
 pub struct Settings {
     is_serving: bool,
     username: String,
+    anonymous: bool,
+    /// what a client signs the server's challenge with, and a server its invites
+    identity: Option<Identity>,
+    invite_only: bool,
+    /// the link a client was started with, for the server to let it in
+    invite: Option<Invite>,
+    join_code: Option<String>,
+    /// where the server's invite links point to
+    public_address: String,
+    /// keys never let in
+    banned_keys: Vec<[u8; 32]>,
+    trusted_keys: Option<TrustList>,
+    /// what the server asks of peers it doesn't trust, and clients answer with
+    password: Option<Psk>,
+    /// what makes a peer a moderator, and clients that have it answer with
+    admin_password: Option<Psk>,
     accept_source: bool,
+    rewrites: Rewrites,
     standalone: bool,
+    auto_ready: bool,
+    pause_when_empty: bool,
+    no_sync: Vec<SyncClass>,
+    follow_only: bool,
+    exact_seek: bool,
+    precise: bool,
+    max_desync: Option<f64>,
+    drift_alert: f64,
+    duration_tolerance: f64,
+    filename_match: FilenameMatch,
+    audio_only: bool,
+    live: bool,
+    osd: Durations,
+    osd_style: osd::Style,
+    motd: Option<String>,
+    handshake_timeout: Duration,
+    reconnect_grace: Duration,
+    rejoin_window: Duration,
+    limits: Limits,
+    psk: Option<Psk>,
+    simulate: Option<Conditions>,
+    osd_summary: bool,
+    daemon: bool,
+    sponsorblock: bool,
+    rooms: Rooms,
+    /// the room a client asks for
+    room: Option<String>,
+    /// extensions this build knows how to handle
+    extensions: Vec<String>,
+    plugins: Plugins,
+    #[cfg(feature = "notify")]
+    desktop_notifications: bool,
 }
 
-#[tokio::main]
-async fn main() {
-    let args = Cli::parse();
+impl Settings {
+    /// Whether we follow and send `class`, as opposed to keeping it to ourselves
+    fn syncs(&self, class: SyncClass) -> bool {
+        !self.no_sync.contains(&class)
+    }
+
+    /// Whether our own player's `class` events go to the others
+    fn sends(&self, class: SyncClass) -> bool {
+        !self.follow_only && self.syncs(class)
+    }
+}
+
+fn main() {
+    // Clearing the environment isn't safe once the runtime's threads may be reading it
+    #[cfg(unix)]
+    let inherited = daemon::inherited_listener();
+    #[cfg(not(unix))]
+    let inherited = None;
+    tokio::runtime::Runtime::new()
+        .expect("Couldn't start the runtime")
+        .block_on(run(inherited));
+}
 
-    if !args.trust_system_time {
-        set_time_delta(args.ntp_server);
+/// Everything but the systemd socket, if it passed one
+async fn run(inherited: Option<std::net::TcpListener>) {
+    let args = Cli::parse();
+    match args.command {
+        Some(Commands::Selftest) => {
+            let passed = selftest::run().await;
+            std::process::exit(if passed { 0 } else { 1 });
+        }
+        Some(Commands::Replay {
+            recording,
+            player_args,
+        }) => {
+            return tokio::task::spawn_blocking(move || replay_session(&recording, player_args))
+                .await
+                .unwrap()
+        }
+        Some(command) => return generate(command),
+        None => {}
     }
+    let address = args.address.expect("clap requires an address");
+    // Clients can be given the invite link instead
+    let (address, invite) =
+        match invite::parse_link(&address).expect("Couldn't read the invite link") {
+            Some((address, invite)) if !args.serve => (address, Some(invite)),
+            _ => (address, None),
+        };
 
-    let state = Arc::new(Mutex::new(Shared::new()));
+    i18n::set_locale(args.lang.unwrap_or_else(Locale::from_env));
+    let mut shared = Shared::new();
+    let console = match (args.quiet || args.json_events, args.verbose) {
+        (true, _) => None,
+        (_, true) => Some(Level::Debug),
+        _ => Some(Level::Info),
+    };
+    shared.logger =
+        Logger::new(console, args.log_file.as_deref()).expect("Couldn't open the log file");
+    let clock = (!args.trust_system_time).then(|| set_time_delta(&args.ntp_server, &mut shared));
+    // Not sending our pauses, we never hold the others back either
+    let holds_nobody_back =
+        args.auto_ready || args.follow_only || args.no_sync.contains(&SyncClass::Pause);
+    // A controller has nothing to load, it's ready until it pauses everyone
+    let initially_ready = holds_nobody_back || args.controller;
+    shared.is_ready = initially_ready;
+    shared.json_events = args.json_events;
+    shared.locked = args.lock;
+    shared.traffic = args.stats.map(|_| Traffic::default());
+    shared.timeline = match &args.edit_list {
+        Some(path) => EditList::load(path, args.offset).expect("Couldn't read the edit list"),
+        None => EditList::offset(args.offset),
+    };
+    #[cfg(feature = "tui")]
+    {
+        shared.tui = args.tui;
+    }
+    if let Some(path) = &args.record_session {
+        shared.recorder =
+            Some(Recorder::create(path, args.serve).expect("Couldn't create the recording"));
+    }
+    let state = Arc::new(Mutex::new(shared));
+    if let Some(url) = args.webhook {
+        let events = state.lock().await.subscribe();
+        webhook::spawn(url, Arc::clone(&state), events);
+    }
+    if let Some(path) = &args.pid_file {
+        fs::write(path, process::id().to_string()).expect("Couldn't write the pid file");
+        state.lock().await.pid_file = Some(path.clone());
+    }
+    #[cfg(unix)]
+    {
+        tokio::spawn(daemon::reopen_logs_on_sighup(Arc::clone(&state)));
+        tokio::spawn(daemon::quit_on_sigterm(Arc::clone(&state)));
+    }
 
     let cloned_state = Arc::clone(&state);
-    let mpv_socket =
-        start_mpv(args.accept_source, args.mpv_args).expect("Coudln't start or connect to mpv");
+    let mut osd = if args.no_osd {
+        Durations::none()
+    } else {
+        Durations::default()
+    };
+    for (category, duration) in args.osd_duration {
+        osd.set(category, duration);
+    }
 
-    let settings = Settings {
+    let password = args.password_file.map(|path| {
+        let secret = fs::read(path).expect("Couldn't read the password");
+        let secret = secret.trim_ascii_end();
+        assert!(!secret.is_empty(), "The password is empty");
+        Psk::from_password(secret)
+    });
+    let admin_password = args.admin_password_file.map(|path| {
+        let secret = fs::read(path).expect("Couldn't read the admin password");
+        let secret = secret.trim_ascii_end();
+        assert!(!secret.is_empty(), "The admin password is empty");
+        Psk::from_password(secret)
+    });
+    let psk = args.psk_file.map(|path| {
+        let secret = fs::read(path).expect("Couldn't read the pre-shared key");
+        let secret = secret.trim_ascii_end();
+        assert!(!secret.is_empty(), "The pre-shared key is empty");
+        let psk = Psk::new(secret);
+        if args.encrypt {
+            psk.encrypting()
+        } else {
+            psk
+        }
+    });
+
+    let mut settings = Settings {
         is_serving: args.serve,
-        username: args.username,
+        username: if args.anonymous {
+            username::pseudonym()
+        } else {
+            args.username
+        },
+        anonymous: args.anonymous,
+        // An anonymous client would be anything but, and the server only signs invites
+        identity: (args.invite_only || args.gen_code || !args.anonymous && !args.serve).then(
+            || {
+                let path = args.identity.unwrap_or_else(identity::default_path);
+                Identity::load_or_create(&path).expect("Couldn't read or create the identity key")
+            },
+        ),
+        invite_only: args.invite_only || args.gen_code,
+        invite,
+        join_code: args.code,
+        public_address: args.public_address.unwrap_or_else(|| {
+            let first = address.split(',').next().unwrap_or_default();
+            first.trim().to_owned()
+        }),
+        banned_keys: args.ban_key,
+        trusted_keys: args
+            .trusted_keys
+            .map(|path| TrustList::load(&path).expect("Couldn't read the trusted keys")),
+        password,
+        admin_password,
         accept_source: args.accept_source,
+        rewrites: args
+            .rewrite_rules
+            .map(|path| Rewrites::load(&path).expect("Couldn't read the rewrite rules"))
+            .unwrap_or_default(),
         standalone: args.standalone,
+        auto_ready: holds_nobody_back,
+        pause_when_empty: args.pause_when_empty,
+        no_sync: args.no_sync.clone(),
+        follow_only: args.follow_only,
+        exact_seek: args.exact_seek || args.precise,
+        precise: args.precise,
+        max_desync: args.max_desync,
+        drift_alert: args.drift_alert,
+        duration_tolerance: args.duration_tolerance,
+        filename_match: args.filename_match,
+        audio_only: args.audio_only,
+        live: args.live,
+        osd,
+        osd_style: osd::Style {
+            position: args.osd_position,
+            font_size: args.osd_font_size,
+            boxed: args.osd_box,
+        },
+        motd: args.motd,
+        handshake_timeout: Duration::from_secs(args.handshake_timeout),
+        reconnect_grace: Duration::from_secs(args.reconnect_grace),
+        rejoin_window: Duration::from_secs(args.rejoin_window),
+        limits: Limits {
+            max_packet: args.max_packet_size.into(),
+            ..Default::default()
+        },
+        psk,
+        simulate: args.simulate,
+        osd_summary: args.osd_summary,
+        daemon: args.daemon,
+        sponsorblock: args.sponsorblock,
+        rooms: Rooms::default(),
+        room: args.room,
+        extensions: vec![],
+        plugins: args
+            .plugins
+            .map(|path| Plugins::load(&path).expect("Couldn't start the plugins"))
+            .unwrap_or_default(),
+        #[cfg(feature = "notify")]
+        desktop_notifications: args.desktop_notifications,
     };
+    let too_long = settings
+        .motd
+        .iter()
+        .map(|motd| VoyeursCommand::ServerMessage(motd.clone()))
+        .chain(
+            args.rooms
+                .iter()
+                .chain(&settings.room)
+                .map(|name| VoyeursCommand::Room(name.clone())),
+        )
+        .find_map(|command| fits(&settings.limits, &command).err());
+    if let Some(e) = too_long {
+        Cli::command()
+            .error(clap::error::ErrorKind::ValueValidation, e)
+            .exit();
+    }
+    // Peers get the commands of the plugins they have too
+    settings.extensions = settings.plugins.names().map(str::to_owned).collect();
+    if settings.precise {
+        settings.extensions.push(PRECISE.to_owned());
+    }
+    // For the host to check against what it sees when we first join
+    if let Some(identity) = &settings.identity {
+        let key = identity::encode_key(&identity.public());
+        let line = format!("your key is {key} ({})", identity.fingerprint());
+        state.lock().await.log(line);
+    }
+    if args.gen_code {
+        let mut s = state.lock().await;
+        let code = s.join_codes.mint(Instant::now());
+        s.log(format!("join code {code}, good for one person"));
+    }
+    #[cfg(feature = "tls")]
+    let tls = args.tls_cert.map(|cert| tls::TlsFiles {
+        cert,
+        key: args.tls_key.expect("clap requires a key"),
+        ca: args.tls_ca.expect("clap requires a CA"),
+    });
+
+    let player_conn = match args.player {
+        _ if args.controller => PlayerConnection::Headless(Headless::default()),
+        PlayerKind::Mpv => {
+            start_mpv(&settings, args.mpv_args).expect("Couldn't start or connect to mpv")
+        }
+        PlayerKind::Vlc => PlayerConnection::Vlc(
+            start_vlc(args.mpv_args).expect("Couldn't start or connect to vlc"),
+        ),
+    };
+
+    {
+        let mut s = state.lock().await;
+        s.skips = args.skips.clone();
+        s.segments = args.skips.clone();
+        s.limits = settings.limits.clone();
+    }
+
+    // The first room is the one the player above and everything else belong to
+    if let Some((first, others)) = args.rooms.split_first() {
+        state.lock().await.room = Some(first.clone());
+        let mut rooms = HashMap::from([(
+            first.clone(),
+            Room {
+                state: Arc::clone(&state),
+                player: player_conn.clone(),
+            },
+        )]);
+        for name in others {
+            let mut shared = Shared::new();
+            shared.logger =
+                Logger::new(console, args.log_file.as_deref()).expect("Couldn't open the log file");
+            shared.is_ready = initially_ready;
+            shared.json_events = args.json_events;
+            shared.locked = args.lock;
+            shared.tui = state.lock().await.tui;
+            shared.room = Some(name.clone());
+            shared.skips = args.skips.clone();
+            shared.segments = args.skips.clone();
+            shared.traffic = args.stats.map(|_| Traffic::default());
+            shared.timeline = state.lock().await.timeline.clone();
+            shared.limits = settings.limits.clone();
+            let room = open_room(name, shared, &settings);
+            if let Some(secs) = args.stats {
+                tokio::spawn(traffic::report(
+                    Arc::clone(&room.state),
+                    Duration::from_secs(secs),
+                ));
+            }
+            rooms.insert(name.clone(), room);
+        }
+        settings.rooms = Rooms::new(rooms);
+    }
+
+    tokio::spawn(watch_clock(Arc::clone(&state)));
+    #[cfg(feature = "scripting")]
+    if let Some(path) = &args.script {
+        let script = scripting::Script::load(path).expect("Couldn't load the script");
+        let events = state.lock().await.subscribe();
+        scripting::spawn(
+            script,
+            player_conn.connect(),
+            Arc::clone(&state),
+            events,
+            settings.clone(),
+        );
+    }
+    if let Some(secs) = args.stats {
+        tokio::spawn(traffic::report(
+            Arc::clone(&state),
+            Duration::from_secs(secs),
+        ));
+    }
+    {
+        let player = player_conn.connect();
+        tokio::spawn(run_telemetry(player, Arc::clone(&state), settings.clone()));
+        let player = player_conn.connect();
+        tokio::spawn(sponsorblock::run(
+            player,
+            Arc::clone(&state),
+            settings.clone(),
+        ));
+    }
+    #[cfg(unix)]
+    if let Some(path) = args.control_socket {
+        let path = path.unwrap_or_else(control::default_socket);
+        let player = player_conn.connect();
+        tokio::spawn(control::serve(
+            path,
+            player,
+            Arc::clone(&state),
+            settings.clone(),
+        ));
+    }
+    #[cfg(unix)]
+    if let Some(port) = args.remote_port {
+        let player = player_conn.connect();
+        tokio::spawn(remote::serve(
+            port,
+            player,
+            Arc::clone(&state),
+            settings.clone(),
+        ));
+    }
+    #[cfg(all(unix, feature = "mpris"))]
+    {
+        let player = player_conn.connect();
+        let state = Arc::clone(&state);
+        let exact_seek = settings.exact_seek;
+        tokio::spawn(async move {
+            if let Err(e) = mpris::serve(player, exact_seek).await {
+                state.lock().await.log(format!(
+                    "media keys won't work, couldn't register with MPRIS: {e}"
+                ));
+            }
+        });
+    }
+    if let Some(clock) = clock {
+        tokio::spawn(refine_time_delta(
+            args.ntp_server,
+            clock,
+            Arc::clone(&state),
+        ));
+    }
+
+    if let Some(minutes) = args.afk_timeout {
+        let player = player_conn.connect();
+        let state = Arc::clone(&state);
+        tokio::spawn(watch_idle(
+            player,
+            state,
+            Duration::from_secs(minutes * 60),
+            settings.clone(),
+        ));
+    }
+
+    // The tui reads the terminal itself, and a service has none
+    if !state.lock().await.tui && !settings.daemon {
+        let player = player_conn.connect();
+        tokio::spawn(stdin::run(player, Arc::clone(&state), settings.clone()));
+    }
+
+    #[cfg(feature = "tui")]
+    if args.tui {
+        let player = player_conn.connect();
+        let state = Arc::clone(&state);
+        let settings = settings.clone();
+        tokio::task::spawn_blocking(move || tui::run_tui(player, state, settings));
+    }
 
     // Handle server
     if args.serve {
-        let listener = TcpListener::bind(&args.address)
-            .await
-            .expect("Couldn't bind address");
-        println!("Starting server on {}", args.address);
-        let mpv = Mpv::connect(mpv_socket.as_str()).expect("Task coudln't attach to mpv socket");
-        tokio::task::spawn_blocking(move || handle_mpv_event(mpv, cloned_state, args.standalone));
+        // with socket activation systemd already bound it
+        let mut listeners = vec![];
+        match inherited {
+            Some(listener) => listeners
+                .push(TcpListener::from_std(listener).expect("Couldn't use the inherited socket")),
+            // Say on the LAN and on a VPN, everyone ends up in the same session
+            None => {
+                for address in address.split(',').map(str::trim) {
+                    let listener = TcpListener::bind(address)
+                        .await
+                        .expect("Couldn't bind address");
+                    listeners.push(listener);
+                    state
+                        .lock()
+                        .await
+                        .log(Msg::StartingServer(address).to_string());
+                }
+            }
+        }
+        let (incoming_tx, mut incoming) = mpsc::unbounded_channel();
+        for listener in listeners {
+            let incoming_tx = incoming_tx.clone();
+            tokio::spawn(async move {
+                while let Ok(connection) = listener.accept().await {
+                    if incoming_tx.send(connection).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(incoming_tx);
+        // The server's own room first, it's the one that gets to shut everything down
+        let mut rooms = vec![Room {
+            state: Arc::clone(&state),
+            player: player_conn.clone(),
+        }];
+        rooms.extend(
+            settings
+                .rooms
+                .iter()
+                .filter(|(_, room)| !Arc::ptr_eq(&room.state, &state))
+                .map(|(_, room)| room.clone()),
+        );
+        if let Some(address) = args.healthz {
+            tokio::spawn(health::serve(address, rooms.clone()));
+        }
+        if let Some(minutes) = args.idle_timeout {
+            let rooms = rooms
+                .into_iter()
+                .map(|room| {
+                    let player = room.player.connect();
+                    (room, player)
+                })
+                .collect();
+            tokio::spawn(health::watch_empty(
+                rooms,
+                Duration::from_secs(minutes * 60),
+            ));
+        }
+        let player = player_conn.connect();
+        let cloned_settings = settings.clone();
+        tokio::task::spawn_blocking(move || {
+            handle_mpv_event(player, cloned_state, cloned_settings)
+        });
+        let pending = Arc::new(Semaphore::new(args.max_pending));
+        let acl = Acl {
+            allow: args.allow,
+            deny: args.deny,
+        };
+        #[cfg(feature = "tls")]
+        let acceptor = tls.map(|tls| tls.acceptor().expect("Couldn't load the tls certificates"));
         loop {
-            let (stream, addr) = listener.accept().await.unwrap();
+            let (stream, addr) = incoming.recv().await.unwrap();
             // Asynchronously wait for an inbound TcpStream.
 
+            // Only in the debug log, port scanners would flood the event log otherwise
+            {
+                let mut s = state.lock().await;
+                if s.bans.is_banned(addr.ip(), Instant::now()) {
+                    s.debug(|| format!("refused {addr}, it's banned"));
+                    continue;
+                }
+                if !acl.allows(addr.ip()) {
+                    s.debug(|| format!("refused {addr}, it isn't allowed to connect"));
+                    continue;
+                }
+            }
+
+            // Don't let half-open connections pile up
+            let Ok(permit) = Arc::clone(&pending).try_acquire_owned() else {
+                state
+                    .lock()
+                    .await
+                    .log(format!("too many pending handshakes, dropped {addr}"));
+                continue;
+            };
+
             // Clone a handle to the `Shared` state for the new connection.
             let state: Arc<Mutex<Shared>> = Arc::clone(&state);
 
-            let mpv =
-                Mpv::connect(mpv_socket.as_str()).expect("Task coudln't attach to mpv socket");
+            let player = player_conn.connect();
 
             // Spawn our handler to be run asynchronously.
             let cloned_settings = settings.clone();
+            #[cfg(feature = "tls")]
+            let acceptor = acceptor.clone();
             tokio::spawn(async move {
-                handle_connection(mpv, addr, stream, state, cloned_settings).await
+                #[cfg(feature = "tls")]
+                if let Some(acceptor) = acceptor {
+                    let limit = cloned_settings.handshake_timeout;
+                    match tls::accept(&acceptor, stream, limit).await {
+                        Ok((stream, name)) => {
+                            handle_connection(
+                                player,
+                                addr,
+                                stream,
+                                state,
+                                cloned_settings,
+                                Some(permit),
+                                Some(name),
+                            )
+                            .await
+                        }
+                        Err(e) => {
+                            let mut s = state.lock().await;
+                            s.log(format!("dropped connection from {addr}: {e}"));
+                            s.strike(addr);
+                        }
+                    }
+                    return;
+                }
+                handle_connection(
+                    player,
+                    addr,
+                    stream,
+                    state,
+                    cloned_settings,
+                    Some(permit),
+                    None,
+                )
+                .await
             });
         }
     }
     // Handle client
     else {
-        println!("Connecting to {}", args.address);
-        let addr = lookup_host(args.address)
-            .await
-            .expect("Server lookup failed")
-            .next()
-            .expect("Couldn't create SockAddr from the given address and port");
-
-        let stream = TcpStream::connect(addr)
-            .await
-            .expect("Could not connect to server");
-        let mpv = Mpv::connect(mpv_socket.as_str()).expect("Task coudln't attach to mpv socket");
-        let communication_task =
-            tokio::spawn(
-                async move { handle_connection(mpv, addr, stream, state, settings).await },
-            );
-        let mpv = Mpv::connect(mpv_socket.as_str()).expect("Task coudln't attach to mpv socket");
-        tokio::task::spawn_blocking(move || handle_mpv_event(mpv, cloned_state, args.standalone));
-        let _ = tokio::join!(communication_task);
+        let player = player_conn.connect();
+        let cloned_settings = settings.clone();
+        tokio::task::spawn_blocking(move || {
+            handle_mpv_event(player, cloned_state, cloned_settings)
+        });
+
+        // `connect ADDRESS` moves us to another server, with the same mpv
+        let (switch, mut switches) = mpsc::unbounded_channel();
+        state.lock().await.switch = Some(switch);
+        let mut address = address;
+        let mut previous = None;
+        // Until when to keep knocking on a server that went away without saying goodbye
+        let mut rejoin_until = None;
+        loop {
+            if rejoin_until.is_none() {
+                state
+                    .lock()
+                    .await
+                    .log(Msg::Connecting(&address).to_string());
+            }
+            #[cfg(feature = "tls")]
+            let connection = connect(&address, tls.as_ref()).await;
+            #[cfg(not(feature = "tls"))]
+            let connection = connect(&address).await;
+            let (addr, stream) = match (connection, previous.take(), rejoin_until) {
+                (Ok(connection), _, _) => connection,
+                // Better the server we were on than none at all
+                (Err(e), Some(back), _) => {
+                    state
+                        .lock()
+                        .await
+                        .log(format!("couldn't connect to {address}: {e}"));
+                    address = back;
+                    continue;
+                }
+                // The host may be rebooting
+                (Err(_), None, Some(until)) if Instant::now() < until => {
+                    tokio::time::sleep(REJOIN_INTERVAL).await;
+                    continue;
+                }
+                (Err(e), None, Some(_)) => {
+                    let mut s = state.lock().await;
+                    s.log(format!("the server didn't come back: {e}"));
+                    s.end_session()
+                }
+                (Err(e), None, None) => panic!("Could not connect to server: {e}"),
+            };
+            if rejoin_until.take().is_some() {
+                let host = Msg::Host.to_string();
+                state.lock().await.log(Msg::Rejoined(&host).to_string());
+            }
+
+            let player = player_conn.connect();
+            let cloned_state = Arc::clone(&state);
+            let cloned_settings = settings.clone();
+            let communication_task = tokio::spawn(async move {
+                handle_connection(
+                    player,
+                    addr,
+                    stream,
+                    cloned_state,
+                    cloned_settings,
+                    None,
+                    None,
+                )
+                .await
+            });
+            let _ = tokio::join!(communication_task);
+
+            // What the old server said doesn't hold on the new one, nor on a restarted one
+            let mut s = state.lock().await;
+            s.locked = false;
+            s.segments.clear();
+            s.server_info = None;
+            if let Ok(next) = switches.try_recv() {
+                previous = Some(std::mem::replace(&mut address, next));
+            } else if s.goodbye || settings.rejoin_window.is_zero() {
+                break;
+            } else {
+                s.log(Msg::Rejoining.to_string());
+                rejoin_until = Some(Instant::now() + settings.rejoin_window);
+            }
+        }
+    }
+}
+
+/// Open a connection to the server at `address`, over tls when we have certificates
+async fn connect(
+    address: &str,
+    #[cfg(feature = "tls")] tls: Option<&tls::TlsFiles>,
+) -> Result<
+    (
+        SocketAddr,
+        impl AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    ),
+    Box<dyn Error + Sync + Send>,
+> {
+    let addr = lookup_host(address)
+        .await?
+        .next()
+        .ok_or("the address doesn't resolve to anything")?;
+    let stream = TcpStream::connect(addr).await?;
+    #[cfg(feature = "tls")]
+    let stream: Box<dyn tls::Stream> = match tls {
+        Some(tls) => {
+            let name = tls::server_name(address)?;
+            Box::new(tls.connector()?.connect(name, stream).await?)
+        }
+        None => Box::new(stream),
+    };
+    Ok((addr, stream))
+}
+
+fn replay_session(recording: &Path, player_args: Vec<String>) {
+    let (serving, entries) = replay::load(recording).expect("Couldn't read the recording");
+    let settings = Settings {
+        osd: Durations::default(),
+        ..Default::default()
+    };
+    let player = start_mpv(&settings, player_args)
+        .expect("Couldn't start or connect to mpv")
+        .connect();
+    replay::run(&*player, &settings.osd, serving, &entries).expect("Lost the connection to mpv");
+}
+
+/// Start the player of another room and what keeps it in sync, for --rooms
+fn open_room(name: &str, shared: Shared, settings: &Settings) -> Room {
+    // the room starts empty, waiting for a file to be dropped on its window
+    let mut args = vec![format!("--title=voyeurs: {name}"), "--idle=yes".to_owned()];
+    if !settings.daemon {
+        args.push("--force-window=yes".to_owned());
     }
+    let player = start_mpv(settings, args).expect("Couldn't start or connect to mpv");
+    let state = Arc::new(Mutex::new(shared));
+    tokio::spawn(run_telemetry(
+        player.connect(),
+        Arc::clone(&state),
+        settings.clone(),
+    ));
+    tokio::spawn(sponsorblock::run(
+        player.connect(),
+        Arc::clone(&state),
+        settings.clone(),
+    ));
+    let (mpv, cloned_state, settings) = (player.connect(), Arc::clone(&state), settings.clone());
+    tokio::task::spawn_blocking(move || handle_mpv_event(mpv, cloned_state, settings));
+    Room { state, player }
 }
 
-fn start_mpv(accept_source: bool, mpv_args: Vec<String>) -> Result<String, Error> {
+fn start_mpv(settings: &Settings, mpv_args: Vec<String>) -> Result<PlayerConnection, PlayerError> {
     // generate temp path for the socket
-    let binding = tempdir()
+    let dir = tempdir()
         .expect("Failed to create a tmp directory for the mpv socket")
-        .into_path()
-        .join("mpv.sock");
-    let mpv_socket = binding
+        .into_path();
+    // on windows mpv's ipc server is a named pipe instead, one for each mpv --rooms starts
+    #[cfg(windows)]
+    let mpv_socket = {
+        static STARTED: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+        let n = STARTED.fetch_add(1, Ordering::Relaxed);
+        format!(r"\\.\pipe\voyeurs-{}-{n}", std::process::id())
+    };
+    #[cfg(not(windows))]
+    let mpv_socket = dir
+        .join("mpv.sock")
         .to_str()
         .expect("Path isn't valid unicode")
         .to_owned();
 
+    // the companion script is shipped inside the binary
+    let script = dir.join("voyeurs.lua");
+    fs::write(&script, COMPANION_SCRIPT).expect("Failed to write the companion script");
+
     // start mpv
     let mut gui_mode_args = vec![];
-    if accept_source {
+    if settings.accept_source {
         gui_mode_args.push("--player-operation-mode=pseudo-gui".to_string());
     }
+    // local seeks are broadcast as-is, so they should be as precise as the remote ones
+    if settings.exact_seek {
+        gui_mode_args.push("--hr-seek=yes".to_string());
+    }
+    if settings.audio_only {
+        gui_mode_args.push("--no-video".to_string());
+        gui_mode_args.push("--force-window=no".to_string());
+    }
+    // nobody watches on a daemon, mpv is only there to keep the time
+    if settings.daemon {
+        gui_mode_args.extend(
+            ["--idle=yes", "--vo=null", "--ao=null", "--force-window=no"].map(String::from),
+        );
+    }
 
-    Command::new("mpv")
+    let mut command = Command::new("mpv");
+    command
         .arg(format!("--input-ipc-server={}", mpv_socket))
+        .arg(format!("--script={}", script.display()))
+        .args(settings.osd_style.mpv_args())
         .args(gui_mode_args)
-        .args(mpv_args)
-        .spawn()
-        .expect("failed to execute mpv");
+        .args(mpv_args);
+    let mut child = command.spawn().expect("failed to execute mpv");
+    let conn = PlayerConnection::Mpv(mpv_socket.clone());
+    // Reaped once it quits, and with --daemon brought back
+    if settings.daemon {
+        let conn = conn.clone();
+        thread::spawn(move || restart_mpv(command, child, conn));
+    } else {
+        thread::spawn(move || child.wait());
+    }
 
     // enstabilish a connection to the mpv socket
-    let mut mpv: Result<Mpv, Error> = Err(mpvipc::Error(mpvipc::ErrorCode::ConnectError(
-        "Not yet connected".to_owned(),
-    )));
-    while mpv.is_err() {
-        mpv = Mpv::connect(mpv_socket.as_str());
-    }
-    let mpv = mpv?;
-    mpv.pause()?;
+    let mpv = loop {
+        if let Ok(mpv) = conn.try_connect() {
+            break mpv;
+        }
+    };
+    mpv.set_paused(true)?;
 
-    mpv.run_command_raw("show-text", &["Connected to voyeurs", "5000"])?;
+    notify(
+        &*mpv,
+        &settings.osd,
+        Category::Presence,
+        &Msg::Welcome.to_string(),
+    )?;
 
-    Ok(mpv_socket)
+    if settings.daemon {
+        return Ok(PlayerConnection::Supervised(mpv_socket));
+    }
+    Ok(conn)
+}
+
+/// Bring mpv back every time it quits, for --daemon
+fn restart_mpv(mut command: Command, mut child: Child, conn: PlayerConnection) {
+    loop {
+        let _ = child.wait();
+        child = command.spawn().expect("failed to restart mpv");
+        let mpv = loop {
+            if let Ok(mpv) = conn.try_connect() {
+                break mpv;
+            }
+            thread::sleep(Duration::from_millis(100));
+        };
+        let _ = mpv.set_paused(true);
+        supervised::RESTARTS.fetch_add(1, Ordering::SeqCst);
+    }
 }
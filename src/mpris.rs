@@ -0,0 +1,232 @@
+use std::{collections::HashMap, process, time::Duration};
+use tokio::time::interval;
+use zbus::{
+    dbus_interface, fdo,
+    zvariant::{ObjectPath, OwnedValue, Value},
+    ConnectionBuilder,
+};
+
+use crate::player::{PlayerBackend, PlayerError};
+
+const PATH: &str = "/org/mpris/MediaPlayer2";
+const TRACK_ID: &str = "/org/voyeurs/CurrentTrack";
+// How often desktop widgets are told about pauses that happened elsewhere
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+fn failed(e: PlayerError) -> fdo::Error {
+    fdo::Error::Failed(e.to_string())
+}
+
+fn playback_status(paused: bool) -> &'static str {
+    if paused {
+        "Paused"
+    } else {
+        "Playing"
+    }
+}
+
+/// Where a relative MPRIS seek lands, kept inside the file
+fn seek_target(position: f64, offset_us: i64, duration: Option<f64>) -> f64 {
+    let target = (position + offset_us as f64 / 1e6).max(0.0);
+    duration.map_or(target, |duration| target.min(duration))
+}
+
+struct Root;
+
+#[dbus_interface(name = "org.mpris.MediaPlayer2")]
+impl Root {
+    fn raise(&self) {}
+
+    fn quit(&self) {}
+
+    #[dbus_interface(property)]
+    fn can_quit(&self) -> bool {
+        false
+    }
+
+    #[dbus_interface(property)]
+    fn can_raise(&self) -> bool {
+        false
+    }
+
+    #[dbus_interface(property)]
+    fn has_track_list(&self) -> bool {
+        false
+    }
+
+    #[dbus_interface(property)]
+    fn identity(&self) -> String {
+        "voyeurs".to_owned()
+    }
+
+    #[dbus_interface(property)]
+    fn supported_uri_schemes(&self) -> Vec<String> {
+        vec![]
+    }
+
+    #[dbus_interface(property)]
+    fn supported_mime_types(&self) -> Vec<String> {
+        vec![]
+    }
+}
+
+/// Drives the local player like the user would, so what media keys do reaches the
+/// peers through the player events, the same way as pausing in mpv does
+struct Player {
+    player: Box<dyn PlayerBackend>,
+    exact_seek: bool,
+}
+
+#[dbus_interface(name = "org.mpris.MediaPlayer2.Player")]
+impl Player {
+    fn play(&self) -> fdo::Result<()> {
+        self.player.set_paused(false).map_err(failed)
+    }
+
+    fn pause(&self) -> fdo::Result<()> {
+        self.player.set_paused(true).map_err(failed)
+    }
+
+    fn play_pause(&self) -> fdo::Result<()> {
+        let paused = self.player.is_paused().map_err(failed)?;
+        self.player.set_paused(!paused).map_err(failed)
+    }
+
+    // Nothing to stop without leaving the session
+    fn stop(&self) -> fdo::Result<()> {
+        self.pause()
+    }
+
+    fn next(&self) {}
+
+    fn previous(&self) {}
+
+    fn seek(&self, offset: i64) -> fdo::Result<()> {
+        let position = self.player.position().unwrap_or_default();
+        let target = seek_target(position, offset, self.player.duration());
+        self.player.seek(target, self.exact_seek).map_err(failed)
+    }
+
+    fn set_position(&self, _track_id: ObjectPath<'_>, position: i64) -> fdo::Result<()> {
+        let target = seek_target(0.0, position, self.player.duration());
+        self.player.seek(target, self.exact_seek).map_err(failed)
+    }
+
+    fn open_uri(&self, _uri: String) -> fdo::Result<()> {
+        Err(fdo::Error::NotSupported(
+            "everyone has to open the same file".to_owned(),
+        ))
+    }
+
+    #[dbus_interface(property)]
+    fn playback_status(&self) -> String {
+        playback_status(self.player.is_paused().unwrap_or(true)).to_owned()
+    }
+
+    #[dbus_interface(property)]
+    fn position(&self) -> i64 {
+        (self.player.position().unwrap_or_default() * 1e6) as i64
+    }
+
+    #[dbus_interface(property)]
+    fn metadata(&self) -> HashMap<String, OwnedValue> {
+        let mut metadata = HashMap::new();
+        let track = ObjectPath::from_static_str_unchecked(TRACK_ID);
+        metadata.insert("mpris:trackid".to_owned(), Value::from(track).into());
+        if let Some(duration) = self.player.duration() {
+            let length = Value::from((duration * 1e6) as i64);
+            metadata.insert("mpris:length".to_owned(), length.into());
+        }
+        if let Some(filename) = self.player.filename() {
+            metadata.insert("xesam:title".to_owned(), Value::from(filename).into());
+        }
+        metadata
+    }
+
+    #[dbus_interface(property)]
+    fn rate(&self) -> f64 {
+        1.0
+    }
+
+    #[dbus_interface(property)]
+    fn minimum_rate(&self) -> f64 {
+        1.0
+    }
+
+    #[dbus_interface(property)]
+    fn maximum_rate(&self) -> f64 {
+        1.0
+    }
+
+    #[dbus_interface(property)]
+    fn can_go_next(&self) -> bool {
+        false
+    }
+
+    #[dbus_interface(property)]
+    fn can_go_previous(&self) -> bool {
+        false
+    }
+
+    #[dbus_interface(property)]
+    fn can_play(&self) -> bool {
+        true
+    }
+
+    #[dbus_interface(property)]
+    fn can_pause(&self) -> bool {
+        true
+    }
+
+    #[dbus_interface(property)]
+    fn can_seek(&self) -> bool {
+        true
+    }
+
+    #[dbus_interface(property)]
+    fn can_control(&self) -> bool {
+        true
+    }
+}
+
+/// Show up as a media player on the session bus until voyeurs exits
+pub async fn serve(player: Box<dyn PlayerBackend>, exact_seek: bool) -> zbus::Result<()> {
+    let conn = ConnectionBuilder::session()?
+        .name(format!(
+            "org.mpris.MediaPlayer2.voyeurs.instance{}",
+            process::id()
+        ))?
+        .serve_at(PATH, Root)?
+        .serve_at(PATH, Player { player, exact_seek })?
+        .build()
+        .await?;
+
+    // Pauses from the peers don't go through us, widgets still have to hear about them
+    let iface = conn.object_server().interface::<_, Player>(PATH).await?;
+    let mut last = None;
+    let mut ticker = interval(POLL_INTERVAL);
+    loop {
+        ticker.tick().await;
+        let player = iface.get().await;
+        let paused = player.player.is_paused().ok();
+        if paused != last {
+            last = paused;
+            player
+                .playback_status_changed(iface.signal_context())
+                .await?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mpris::seek_target;
+
+    #[test]
+    fn test_seek_target() {
+        assert_eq!(seek_target(60.0, 10_000_000, Some(120.0)), 70.0);
+        assert_eq!(seek_target(5.0, -10_000_000, Some(120.0)), 0.0);
+        assert_eq!(seek_target(115.0, 10_000_000, Some(120.0)), 120.0);
+        assert_eq!(seek_target(0.0, 30_000_000, None), 30.0);
+    }
+}
@@ -1,75 +1,380 @@
-use mpvipc::*;
-use std::process::exit;
 use std::sync::Arc;
-use tokio::runtime::Runtime;
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::runtime::{Handle, Runtime};
 use tokio::sync::Mutex;
 
-use crate::{proto::*, Shared};
+use crate::{
+    i18n::Msg,
+    json_events,
+    osd::{notify, Category},
+    player::{PlayerBackend, PlayerEvent},
+    proto::*,
+    session::SessionEvent,
+    Settings, Shared, SyncClass,
+};
 
-pub fn handle_mpv_event(mut mpv: Mpv, state: Arc<Mutex<Shared>>, standalone: bool) {
-    // setup necessary property observers
-    mpv.observe_property(0, "pause").unwrap();
-    mpv.observe_property(1, "seeking").unwrap();
+const SUMMARY_DURATION: Duration = Duration::from_secs(10);
+
+pub fn handle_mpv_event(
+    mut player: Box<dyn PlayerBackend>,
+    state: Arc<Mutex<Shared>>,
+    settings: Settings,
+) {
+    player.observe().unwrap();
 
     let rt = Runtime::new().unwrap();
     let handle = rt.handle();
 
     loop {
-        let event = mpv.event_listen().unwrap();
+        let event = player.next_event().unwrap();
         let mut s = handle.block_on(state.lock());
-        if dbg!(s.ignore_next) {
+        s.debug(|| format!("player: {event:?}"));
+        if s.json_events {
+            json_events::emit("player", json_events::player(&event));
+        }
+        if s.ignore_next {
+            s.debug(|| "ignoring it, we caused it".to_owned());
             s.ignore_next = false;
             continue;
         }
-        match event {
-            Event::Shutdown => exit(0),
-            Event::EndFile => exit(0),
-            Event::PropertyChange { id: _, property } => match property {
-                Property::Path(_) => todo!(),
-                Property::Pause(p) => {
-                    if standalone {
-                        handle.block_on(s.broadcast(VoyeursCommand::Ready(!p)));
-                    } else {
-                        s.is_ready = !p;
-                        match s.is_ready {
-                            false => {
-                                handle.block_on(s.broadcast(VoyeursCommand::Ready(false)));
-                            }
-                            true => {
-                                if s.peers.values().any(|r| !r.ready) {
-                                    mpv.run_command_raw(
-                                        "show-text",
-                                        &["Somebody isn't ready", "2000"],
-                                    )
-                                    .unwrap();
-                                    s.ignore_next = true;
-                                    mpv.pause().unwrap();
-                                }
-                                handle.block_on(s.broadcast(VoyeursCommand::Ready(true)));
-                            }
+        // In audio-only mode the playlist goes on to the next track, so does it when
+        // there's one left. A daemon's mpv idles until the next file instead
+        let more = matches!(
+            (player.playlist_pos(), player.playlist_count()),
+            (Some(pos), Some(count)) if pos + 1 < count
+        );
+        let end_of_file =
+            event == PlayerEvent::EndFile && !settings.audio_only && !settings.daemon && !more;
+        if end_of_file && settings.osd_summary {
+            let summary = s.stats.summary(Instant::now()).join("\n");
+            let _ = player.show_text(&summary, SUMMARY_DURATION.as_millis() as u32);
+            // Time to read it before mpv quits, without holding up the connections
+            drop(s);
+            thread::sleep(SUMMARY_DURATION);
+            s = handle.block_on(state.lock());
+        }
+        if event == PlayerEvent::Shutdown || end_of_file {
+            handle.block_on(s.say_goodbye());
+            s.end_session()
+        }
+        handle_event(&*player, &mut s, &settings, handle, event);
+    }
+}
+
+pub fn handle_event(
+    player: &dyn PlayerBackend,
+    s: &mut Shared,
+    settings: &Settings,
+    handle: &Handle,
+    event: PlayerEvent,
+) {
+    match event {
+        PlayerEvent::Pause(_) if !settings.sends(SyncClass::Pause) => mark_active(s, handle),
+        // Resuming still says we are ready, when the host pauses or resumes is up to them
+        PlayerEvent::Pause(p)
+            if s.locked
+                && !settings.is_serving
+                && s.role < Role::Moderator
+                && (p || settings.standalone) =>
+        {
+            mark_active(s, handle);
+            notify(
+                player,
+                &settings.osd,
+                Category::Warning,
+                &Msg::HostOnly.to_string(),
+            )
+            .unwrap();
+            s.ignore_next = true;
+            player.set_paused(!p).unwrap();
+        }
+        PlayerEvent::Pause(p) => {
+            mark_active(s, handle);
+            if p {
+                let username = s.username(settings).to_owned();
+                s.stats.paused_by(&username);
+                s.announce(SessionEvent::Pause(username));
+            }
+            if settings.standalone {
+                if !p {
+                    s.announce(SessionEvent::Resume);
+                }
+                handle.block_on(s.broadcast(ready(s, settings, !p)));
+            } else if settings.auto_ready && p {
+                // Auto-ready peers never hold the others back
+            } else {
+                s.is_ready = !p;
+                match s.is_ready {
+                    false => {
+                        handle.block_on(s.broadcast(ready(s, settings, false)));
+                    }
+                    true => {
+                        if !s.peers_ready() {
+                            notify(
+                                player,
+                                &settings.osd,
+                                Category::Warning,
+                                &Msg::NotReady.to_string(),
+                            )
+                            .unwrap();
+                            s.ignore_next = true;
+                            player.set_paused(true).unwrap();
+                        } else {
+                            s.announce(SessionEvent::Resume);
+                        }
+                        handle.block_on(s.broadcast(ready(s, settings, true)));
+                        if settings.is_serving && s.peers_ready() {
+                            let position = player.position().unwrap_or_default();
+                            handle.block_on(s.schedule_resume(position));
                         }
                     }
                 }
-                Property::Unknown { name, data } => match name.as_str() {
-                    "seeking" => {
-                        println!("seeking:{:?}", data);
-                        match data {
-                            MpvDataType::Bool(false) => {
-                                let current_time =
-                                    mpv.get_property("playback-time").unwrap_or_default();
-                                handle.block_on(s.broadcast(VoyeursCommand::Seek(current_time)));
-                            }
-                            MpvDataType::Bool(true) => {
-                                println!("Houston we have a buffering problem");
-                            }
-                            _ => {}
-                        }
+            }
+        }
+        PlayerEvent::Seeking(seeking) => {
+            mark_active(s, handle);
+            if seeking {
+                s.log("Houston we have a buffering problem");
+            } else if !settings.live && settings.sends(SyncClass::Seek) {
+                // The host sends us back where we were
+                if s.locked && !settings.is_serving && s.role < Role::Moderator {
+                    notify(
+                        player,
+                        &settings.osd,
+                        Category::Warning,
+                        &Msg::HostOnly.to_string(),
+                    )
+                    .unwrap();
+                }
+                let current_time = player.position().unwrap_or_default();
+                let username = s.username(settings).to_owned();
+                s.announce(SessionEvent::Seek {
+                    by: Some(username.clone()),
+                    time: current_time,
+                });
+                let seek = if settings.is_serving {
+                    VoyeursCommand::SeekFrom {
+                        username,
+                        time: current_time,
                     }
-                    _ => todo!(),
-                },
-                _ => todo!(),
-            },
-            _ => {}
+                } else {
+                    VoyeursCommand::Seek(current_time)
+                };
+                handle.block_on(s.broadcast(seek));
+            }
+        }
+        PlayerEvent::Focused(true) => mark_active(s, handle),
+        PlayerEvent::Reaction(emoji) => {
+            notify(player, &settings.osd, Category::Reaction, &emoji).unwrap();
+            handle.block_on(s.broadcast(VoyeursCommand::Reaction(emoji)));
+        }
+        PlayerEvent::Bookmark(name) => {
+            let time = player.position().unwrap_or_default();
+            let line = Msg::Bookmark { name: &name, time }.to_string();
+            notify(player, &settings.osd, Category::Chat, &line).unwrap();
+            handle.block_on(s.add_bookmark(name, time));
+        }
+        PlayerEvent::Resync => {
+            let Some(position) = player.position() else {
+                return;
+            };
+            if settings.is_serving {
+                // Everyone comes here, like with voyeursctl resync
+                handle.block_on(s.broadcast(VoyeursCommand::Seek(position)));
+            } else {
+                // Where the host is right now, not as of its last report
+                handle.block_on(s.query_state(true));
+            }
+        }
+        PlayerEvent::Nudge(by) => {
+            let line = s.nudge(player, by, settings.exact_seek);
+            notify(player, &settings.osd, Category::Warning, &line).unwrap();
+        }
+        PlayerEvent::PlaylistPos(pos)
+            if settings.audio_only && settings.sends(SyncClass::Playlist) =>
+        {
+            handle.block_on(s.broadcast(VoyeursCommand::PlaylistPos(pos)));
+        }
+        // Otherwise only the host moves the playlist
+        PlayerEvent::PlaylistPos(pos) if settings.is_serving => {
+            hold_for_next_entry(player, s, settings);
+            handle.block_on(s.broadcast(VoyeursCommand::PlaylistPos(pos)));
+        }
+        PlayerEvent::FileLoaded(filename) => {
+            // Joined before the file was ready, the seek from the host may not have taken
+            let late = s
+                .joined_peers()
+                .any(|(_, host)| host.last_position.is_none());
+            if !settings.is_serving && !settings.live && late {
+                handle.block_on(s.query_state(true));
+            }
+            s.announce(SessionEvent::FileChange(filename));
+        }
+        // Checked against the next position report, there's no telling from here
+        PlayerEvent::Restarted if !settings.live => {
+            for peer in s.peers.values_mut() {
+                peer.recheck = true;
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Saying we're ready or not: from the host, clients get to see it was us
+pub fn ready(s: &Shared, settings: &Settings, ready: bool) -> VoyeursCommand {
+    if settings.is_serving {
+        VoyeursCommand::ReadyFrom {
+            username: s.username(settings).to_owned(),
+            ready,
         }
+    } else {
+        VoyeursCommand::Ready(ready)
+    }
+}
+
+/// Pause on a new playlist entry until everyone is ready to start it
+pub fn hold_for_next_entry(player: &dyn PlayerBackend, s: &mut Shared, settings: &Settings) {
+    if !player.is_paused().unwrap_or(true) {
+        s.ignore_next = true;
+        player.set_paused(true).unwrap();
+    }
+    s.is_ready = settings.auto_ready;
+    for peer in s.peers.values_mut() {
+        peer.ready = false;
+    }
+}
+
+fn mark_active(s: &mut Shared, handle: &Handle) {
+    s.last_activity = Instant::now();
+    if s.away {
+        s.away = false;
+        handle.block_on(s.broadcast(VoyeursCommand::Away(false)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mpv_event_handler::{handle_event, hold_for_next_entry};
+    use crate::player::{FakePlayer, PlayerBackend, PlayerEvent};
+    use crate::session::SessionEvent;
+    use crate::{Settings, Shared};
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn test_handle_event() {
+        let rt = Runtime::new().unwrap();
+        let player = FakePlayer::default();
+        let settings = Settings::default();
+        let mut s = Shared::new();
+        s.tui = true;
+
+        handle_event(
+            &player,
+            &mut s,
+            &settings,
+            rt.handle(),
+            PlayerEvent::Pause(false),
+        );
+        assert!(s.is_ready);
+        assert!(!player.is_paused().unwrap());
+
+        handle_event(
+            &player,
+            &mut s,
+            &settings,
+            rt.handle(),
+            PlayerEvent::Pause(true),
+        );
+        assert!(!s.is_ready);
+
+        s.away = true;
+        handle_event(
+            &player,
+            &mut s,
+            &settings,
+            rt.handle(),
+            PlayerEvent::Focused(true),
+        );
+        assert!(!s.away);
+
+        let reaction = PlayerEvent::Reaction("😂".to_owned());
+        handle_event(&player, &mut s, &settings, rt.handle(), reaction);
+        assert_eq!(*player.shown.lock().unwrap(), ["😂"]);
+    }
+
+    #[test]
+    fn test_renamed() {
+        let rt = Runtime::new().unwrap();
+        let player = FakePlayer::default();
+        let settings = Settings {
+            username: "alice".to_owned(),
+            ..Default::default()
+        };
+        let mut s = Shared::new();
+        s.tui = true;
+        s.renamed = Some("alicia".to_owned());
+        let mut events = s.subscribe();
+
+        // what we do goes under the name we go by now
+        player.set_paused(true).unwrap();
+        handle_event(
+            &player,
+            &mut s,
+            &settings,
+            rt.handle(),
+            PlayerEvent::Pause(true),
+        );
+        assert_eq!(
+            events.try_recv().unwrap(),
+            SessionEvent::Pause("alicia".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_locked() {
+        let rt = Runtime::new().unwrap();
+        let player = FakePlayer::default();
+        let settings = Settings::default();
+        let mut s = Shared::new();
+        s.tui = true;
+        s.locked = true;
+        s.is_ready = true;
+
+        // We paused, but only the host gets to
+        player.set_paused(true).unwrap();
+        handle_event(
+            &player,
+            &mut s,
+            &settings,
+            rt.handle(),
+            PlayerEvent::Pause(true),
+        );
+        assert!(!player.is_paused().unwrap());
+        assert!(s.ignore_next);
+        assert!(s.is_ready);
+    }
+
+    #[test]
+    fn test_hold_for_next_entry() {
+        let player = FakePlayer::default();
+        let mut s = Shared::new();
+        s.is_ready = true;
+        player.set_paused(false).unwrap();
+
+        hold_for_next_entry(&player, &mut s, &Settings::default());
+        assert!(player.is_paused().unwrap());
+        assert!(s.ignore_next);
+        assert!(!s.is_ready);
+
+        // Already paused, so no event is coming to ignore
+        s.ignore_next = false;
+        let auto_ready = Settings {
+            auto_ready: true,
+            ..Default::default()
+        };
+        hold_for_next_entry(&player, &mut s, &auto_ready);
+        assert!(!s.ignore_next);
+        assert!(s.is_ready);
     }
 }
@@ -0,0 +1,295 @@
+use serde_json::{json, Value};
+use std::{
+    collections::VecDeque,
+    io::{BufRead, BufReader, Read, Write},
+    sync::Mutex,
+};
+
+use crate::{
+    osd,
+    player::{PlayerBackend, PlayerError, PlayerEvent},
+    source,
+};
+
+/// mpv's json ipc spoken over any byte stream, for the platforms mpvipc
+/// doesn't support. On Windows `--input-ipc-server` is a named pipe.
+pub struct MpvPipe {
+    conn: Mutex<Conn>,
+}
+
+struct Conn {
+    reader: BufReader<Box<dyn Read + Send>>,
+    writer: Box<dyn Write + Send>,
+    // events that showed up while waiting for a reply
+    events: VecDeque<Value>,
+    next_id: u64,
+}
+
+impl Conn {
+    fn read(&mut self) -> Result<Value, PlayerError> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err("mpv closed the ipc connection".into());
+        }
+        Ok(serde_json::from_str(&line)?)
+    }
+}
+
+#[cfg(windows)]
+pub fn connect(pipe: &str) -> Result<MpvPipe, PlayerError> {
+    let pipe = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(pipe)?;
+    Ok(MpvPipe::new(pipe.try_clone()?, pipe))
+}
+
+impl MpvPipe {
+    pub fn new(reader: impl Read + Send + 'static, writer: impl Write + Send + 'static) -> MpvPipe {
+        MpvPipe {
+            conn: Mutex::new(Conn {
+                reader: BufReader::new(Box::new(reader)),
+                writer: Box::new(writer),
+                events: VecDeque::new(),
+                next_id: 0,
+            }),
+        }
+    }
+
+    /// Run an mpv command and return whatever data it answered with
+    fn command(&self, args: Value) -> Result<Value, PlayerError> {
+        let mut conn = self.conn.lock().unwrap();
+        conn.next_id += 1;
+        let id = conn.next_id;
+        writeln!(
+            conn.writer,
+            "{}",
+            json!({ "command": args, "request_id": id })
+        )?;
+        conn.writer.flush()?;
+        loop {
+            let mut msg = conn.read()?;
+            if msg.get("event").is_some() {
+                conn.events.push_back(msg);
+                continue;
+            }
+            if msg["request_id"] != id {
+                continue;
+            }
+            if msg["error"] != "success" {
+                return Err(format!("mpv answered {}", msg["error"]).into());
+            }
+            return Ok(msg["data"].take());
+        }
+    }
+
+    fn get(&self, property: &str) -> Result<Value, PlayerError> {
+        self.command(json!(["get_property", property]))
+    }
+
+    fn set(&self, property: &str, value: Value) -> Result<(), PlayerError> {
+        self.command(json!(["set_property", property, value]))
+            .map(drop)
+    }
+
+    fn next_message(&self) -> Result<Value, PlayerError> {
+        let mut conn = self.conn.lock().unwrap();
+        match conn.events.pop_front() {
+            Some(event) => Ok(event),
+            None => conn.read(),
+        }
+    }
+}
+
+impl PlayerBackend for MpvPipe {
+    fn is_paused(&self) -> Result<bool, PlayerError> {
+        Ok(self.get("pause")?.as_bool().ok_or("pause isn't a bool")?)
+    }
+
+    fn set_paused(&self, paused: bool) -> Result<(), PlayerError> {
+        self.set("pause", json!(paused))
+    }
+
+    fn seek(&self, t: f64, exact: bool) -> Result<(), PlayerError> {
+        let flags = if exact { "absolute+exact" } else { "absolute" };
+        self.command(json!(["seek", t, flags])).map(drop)
+    }
+
+    fn position(&self) -> Option<f64> {
+        self.get("playback-time").ok()?.as_f64()
+    }
+
+    fn duration(&self) -> Option<f64> {
+        self.get("duration").ok()?.as_f64()
+    }
+
+    fn file_size(&self) -> Option<u64> {
+        self.get("file-size").ok()?.as_u64()
+    }
+
+    fn live_lag(&self) -> Option<f64> {
+        let cached = self.get("demuxer-cache-time").ok()?.as_f64()?;
+        Some(cached - self.position()?)
+    }
+
+    fn set_speed(&self, speed: f64) -> Result<(), PlayerError> {
+        self.set("speed", json!(speed))
+    }
+
+    fn filename(&self) -> Option<String> {
+        self.get("filename").ok()?.as_str().map(str::to_owned)
+    }
+
+    fn path(&self) -> Option<String> {
+        self.get("path").ok()?.as_str().map(str::to_owned)
+    }
+
+    fn stream_source(&self) -> Option<String> {
+        let opened = self.get("stream-open-filename").ok();
+        let ytdl = self.get("user-data/mpv/ytdl/json-subprocess-result").ok();
+        let stdout = ytdl.as_ref().and_then(|result| result["stdout"].as_str());
+        source::shareable(
+            &self.path()?,
+            opened.as_ref().and_then(Value::as_str),
+            stdout,
+        )
+    }
+
+    fn playlist_pos(&self) -> Option<u64> {
+        self.get("playlist-pos").ok()?.as_u64()
+    }
+
+    fn playlist_count(&self) -> Option<u64> {
+        self.get("playlist-count").ok()?.as_u64()
+    }
+
+    fn set_playlist_pos(&self, pos: u64) -> Result<(), PlayerError> {
+        self.set("playlist-pos", json!(pos))
+    }
+
+    fn load(&mut self, url: &str) -> Result<(), PlayerError> {
+        self.command(json!(["loadfile", url, "replace"]))?;
+        while self.next_message()?["event"] != "file-loaded" {}
+        Ok(())
+    }
+
+    fn show_text(&self, text: &str, duration: u32) -> Result<(), PlayerError> {
+        let message = format!("{duration} {}", osd::ass_escape(text));
+        self.set("user-data/voyeurs/osd", json!(message))
+    }
+
+    fn show_from(&self, username: &str, line: &str, duration: u32) -> Result<(), PlayerError> {
+        let message = format!("{duration} {}", osd::ass_line(username, line));
+        self.set("user-data/voyeurs/osd", json!(message))
+    }
+
+    fn set_sync_status(&self, drift: &str, health: &str) {
+        let _ = self.set("user-data/voyeurs/drift", json!(drift));
+        let _ = self.set("user-data/voyeurs/health", json!(health));
+    }
+
+    fn set_panel(&self, panel: &str) {
+        let _ = self.set("user-data/voyeurs/panel", json!(panel));
+    }
+
+    fn stop(&self) {
+        let _ = self.command(json!(["stop"]));
+    }
+
+    fn quit(&self) {
+        let _ = self.command(json!(["quit"]));
+    }
+
+    fn observe(&mut self) -> Result<(), PlayerError> {
+        let properties = [
+            "pause",
+            "seeking",
+            "focused",
+            "user-data/voyeurs/reaction",
+            "playlist-pos",
+            "filename",
+            "user-data/voyeurs/bookmark",
+            "user-data/voyeurs/resync",
+            "user-data/voyeurs/nudge",
+        ];
+        for (id, property) in properties.into_iter().enumerate() {
+            self.command(json!(["observe_property", id, property]))?;
+        }
+        Ok(())
+    }
+
+    fn next_event(&mut self) -> Result<PlayerEvent, PlayerError> {
+        let msg = self.next_message()?;
+        let event = match msg["event"].as_str() {
+            Some("shutdown") => PlayerEvent::Shutdown,
+            Some("end-file") => PlayerEvent::EndFile,
+            Some("playback-restart" | "audio-reconfig") => PlayerEvent::Restarted,
+            Some("property-change") => match (msg["name"].as_str(), &msg["data"]) {
+                (Some("pause"), Value::Bool(p)) => PlayerEvent::Pause(*p),
+                (Some("seeking"), Value::Bool(b)) => PlayerEvent::Seeking(*b),
+                (Some("focused"), Value::Bool(b)) => PlayerEvent::Focused(*b),
+                (Some("playlist-pos"), pos) if pos.is_u64() => {
+                    PlayerEvent::PlaylistPos(pos.as_u64().unwrap())
+                }
+                (Some("filename"), Value::String(name)) => PlayerEvent::FileLoaded(name.clone()),
+                (Some("user-data/voyeurs/reaction"), Value::String(emoji)) if !emoji.is_empty() => {
+                    // Clear it, so that the same reaction twice in a row is still a change
+                    self.set("user-data/voyeurs/reaction", json!(""))?;
+                    PlayerEvent::Reaction(emoji.clone())
+                }
+                (Some("user-data/voyeurs/bookmark"), Value::String(name)) if !name.is_empty() => {
+                    self.set("user-data/voyeurs/bookmark", json!(""))?;
+                    PlayerEvent::Bookmark(name.clone())
+                }
+                (Some("user-data/voyeurs/resync"), Value::String(asked)) if !asked.is_empty() => {
+                    self.set("user-data/voyeurs/resync", json!(""))?;
+                    PlayerEvent::Resync
+                }
+                (Some("user-data/voyeurs/nudge"), Value::String(by)) if !by.is_empty() => {
+                    self.set("user-data/voyeurs/nudge", json!(""))?;
+                    by.parse().map_or(PlayerEvent::Other, PlayerEvent::Nudge)
+                }
+                _ => PlayerEvent::Other,
+            },
+            _ => PlayerEvent::Other,
+        };
+        Ok(event)
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use crate::mpv_pipe::MpvPipe;
+    use crate::player::{PlayerBackend, PlayerEvent};
+    use serde_json::{json, Value};
+    use std::{
+        io::{BufRead, BufReader, Write},
+        os::unix::net::UnixStream,
+        thread,
+    };
+
+    #[test]
+    fn test_ipc() {
+        let (ours, mut theirs) = UnixStream::pair().unwrap();
+        thread::spawn(move || {
+            let requests = BufReader::new(theirs.try_clone().unwrap()).lines();
+            for request in requests {
+                let request: Value = serde_json::from_str(&request.unwrap()).unwrap();
+                let id = &request["request_id"];
+                let reply = match request["command"][1].as_str() {
+                    Some("pause") => json!({ "request_id": id, "error": "success", "data": true }),
+                    _ => json!({ "request_id": id, "error": "property unavailable" }),
+                };
+                // an event sneaking in before the reply
+                let event = json!({ "event": "property-change", "name": "seeking", "data": false });
+                writeln!(theirs, "{event}\n{reply}").unwrap();
+            }
+        });
+
+        let mut mpv = MpvPipe::new(ours.try_clone().unwrap(), ours);
+        assert!(mpv.is_paused().unwrap());
+        assert_eq!(mpv.position(), None);
+        assert_eq!(mpv.next_event().unwrap(), PlayerEvent::Seeking(false));
+        assert_eq!(mpv.next_event().unwrap(), PlayerEvent::Seeking(false));
+    }
+}
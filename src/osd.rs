@@ -0,0 +1,308 @@
+#[cfg(unix)]
+use mpvipc::{Error, Mpv};
+
+use crate::player::{PlayerBackend, PlayerError};
+
+/// What an OSD message is about, each kind stays up for its own duration
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Category {
+    /// peers joining, leaving or going away
+    Presence,
+    Chat,
+    Reaction,
+    /// anything that may need the user to do something
+    Warning,
+}
+
+/// How long each category stays on screen, in milliseconds. Zero hides it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Durations {
+    pub presence: u32,
+    pub chat: u32,
+    pub reaction: u32,
+    pub warning: u32,
+}
+
+impl Default for Durations {
+    fn default() -> Self {
+        Durations {
+            presence: 2000,
+            chat: 5000,
+            reaction: 1500,
+            warning: 2000,
+        }
+    }
+}
+
+impl Durations {
+    pub fn none() -> Self {
+        Durations {
+            presence: 0,
+            chat: 0,
+            reaction: 0,
+            warning: 0,
+        }
+    }
+
+    pub fn get(&self, category: Category) -> u32 {
+        match category {
+            Category::Presence => self.presence,
+            Category::Chat => self.chat,
+            Category::Reaction => self.reaction,
+            Category::Warning => self.warning,
+        }
+    }
+
+    pub fn set(&mut self, category: Category, duration: u32) {
+        match category {
+            Category::Presence => self.presence = duration,
+            Category::Chat => self.chat = duration,
+            Category::Reaction => self.reaction = duration,
+            Category::Warning => self.warning = duration,
+        }
+    }
+}
+
+/// Where messages go on the video
+#[derive(Debug, Clone, Copy, PartialEq, Default, clap::ValueEnum)]
+pub enum Position {
+    #[default]
+    TopLeft,
+    Top,
+    TopRight,
+    BottomLeft,
+    Bottom,
+    BottomRight,
+}
+
+/// How messages look, handed to the companion script as its script-opts
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Style {
+    pub position: Position,
+    /// mpv's --osd-font-size when None
+    pub font_size: Option<u32>,
+    /// a dark box behind the messages, to read them over bright scenes
+    pub boxed: bool,
+}
+
+impl Style {
+    /// The mpv arguments passing it on to the companion script
+    pub fn mpv_args(&self) -> Vec<String> {
+        let position = match self.position {
+            Position::TopLeft => "top-left",
+            Position::Top => "top",
+            Position::TopRight => "top-right",
+            Position::BottomLeft => "bottom-left",
+            Position::Bottom => "bottom",
+            Position::BottomRight => "bottom-right",
+        };
+        let font_size = self.font_size.unwrap_or_default();
+        let boxed = if self.boxed { "yes" } else { "no" };
+        [
+            format!("position={position}"),
+            format!("font_size={font_size}"),
+            format!("box={boxed}"),
+        ]
+        .map(|opt| format!("--script-opts-append=voyeurs-{opt}"))
+        .to_vec()
+    }
+}
+
+/// Show `text` on the player, for as long as its category is configured to stay up
+pub fn notify(
+    player: &dyn PlayerBackend,
+    durations: &Durations,
+    category: Category,
+    text: &str,
+) -> Result<(), PlayerError> {
+    match durations.get(category) {
+        0 => Ok(()),
+        duration => player.show_text(text, duration),
+    }
+}
+
+/// Show `line`, about what `username` said or did, in their color when the player can
+pub fn notify_from(
+    player: &dyn PlayerBackend,
+    durations: &Durations,
+    category: Category,
+    username: &str,
+    line: &str,
+) -> Result<(), PlayerError> {
+    match durations.get(category) {
+        0 => Ok(()),
+        duration => player.show_from(username, line, duration),
+    }
+}
+
+// Easy to tell apart on most videos, in ASS's BGR order
+const PALETTE: [&str; 8] = [
+    "6B6BFF", "47B3FF", "6DE6FF", "95E47B", "C4CD4E", "E2AD5D", "FF8CB3", "C68CFF",
+];
+
+/// The color `username` gets, the same on every peer's screen and every session
+pub fn color(username: &str) -> &'static str {
+    // FNV-1a, std's hashers are seeded differently on every run
+    let hash = username.bytes().fold(0xcbf29ce484222325u64, |hash, b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    });
+    PALETTE[(hash % PALETTE.len() as u64) as usize]
+}
+
+/// `line` as an ASS event, with `username` in their color where it starts the line,
+/// or all of it otherwise
+pub fn ass_line(username: &str, line: &str) -> String {
+    let color = color(username);
+    match line.strip_prefix(username) {
+        Some(rest) if !username.is_empty() => format!(
+            "{{\\1c&H{color}&}}{}{{\\1c&HFFFFFF&}}{}",
+            ass_escape(username),
+            ass_escape(rest)
+        ),
+        _ => format!("{{\\1c&H{color}&}}{}", ass_escape(line)),
+    }
+}
+
+/// `text` drawn as it is, without peers sneaking their own override tags in
+pub fn ass_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            // a zero width no-break space keeps it from starting an escape
+            '\\' => escaped.push_str("\\\u{feff}"),
+            '{' => escaped.push_str("\\{"),
+            '\n' => escaped.push_str("\\N"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Parse a `category=milliseconds` command line argument
+pub fn parse_duration(arg: &str) -> Result<(Category, u32), String> {
+    let (category, ms) = arg
+        .split_once('=')
+        .ok_or("expected category=milliseconds")?;
+    let category = match category {
+        "presence" => Category::Presence,
+        "chat" => Category::Chat,
+        "reaction" => Category::Reaction,
+        "warning" => Category::Warning,
+        other => {
+            return Err(format!(
+                "unknown category {other}, expected presence, chat, reaction or warning"
+            ))
+        }
+    };
+    let ms = ms.parse().map_err(|e| format!("invalid duration: {e}"))?;
+    Ok((category, ms))
+}
+
+/// Show `text` on mpv's OSD for `duration` milliseconds
+#[cfg(unix)]
+pub fn show_text(mpv: &Mpv, text: &str, duration: u32) -> Result<(), Error> {
+    show_ass(mpv, &ass_escape(text), duration)
+}
+
+/// Show `line` with `username` in their color, for `duration` milliseconds
+#[cfg(unix)]
+pub fn show_from(mpv: &Mpv, username: &str, line: &str, duration: u32) -> Result<(), Error> {
+    show_ass(mpv, &ass_line(username, line), duration)
+}
+
+/// Hand the panel's contents over to the companion script, for when it's shown
+#[cfg(unix)]
+pub fn set_panel(mpv: &Mpv, panel: &str) -> Result<(), Error> {
+    mpv.run_command_raw("set", &["user-data/voyeurs/panel", escape(panel).as_str()])
+}
+
+// The companion script draws it in its own overlay, away from the subtitles
+#[cfg(unix)]
+fn show_ass(mpv: &Mpv, ass: &str, duration: u32) -> Result<(), Error> {
+    let message = format!("{duration} {ass}");
+    mpv.run_command_raw("set", &["user-data/voyeurs/osd", escape(&message).as_str()])
+}
+
+// mpvipc pastes command arguments into its json request as they are,
+// so anything coming from other peers has to be escaped first
+#[cfg(unix)]
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '"' | '\\' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '\n' => escaped.push_str("\\n"),
+            c if c.is_control() => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::osd::{ass_line, color, parse_duration, Category, Position, Style};
+
+    #[cfg(unix)]
+    #[test]
+    fn test_escape() {
+        use crate::osd::escape;
+        assert_eq!(escape("plain"), "plain");
+        assert_eq!(escape(r#"say "hi" \o/"#), r#"say \"hi\" \\o/"#);
+        assert_eq!(escape("two\nlines\u{7}"), "two\\nlines");
+    }
+
+    #[test]
+    fn test_ass_line() {
+        // the same wherever it's drawn
+        assert_eq!(color("alice"), color("alice"));
+        let alice = color("alice");
+        assert_eq!(
+            ass_line("alice", "alice: hi"),
+            format!("{{\\1c&H{alice}&}}alice{{\\1c&HFFFFFF&}}: hi")
+        );
+        assert_eq!(ass_line("alice", "ciao"), format!("{{\\1c&H{alice}&}}ciao"));
+        // no override tags from peers
+        assert_eq!(
+            ass_line("alice", "alice: {\\fs200}big\nline"),
+            format!("{{\\1c&H{alice}&}}alice{{\\1c&HFFFFFF&}}: \\{{\\\u{feff}fs200}}big\\Nline")
+        );
+    }
+
+    #[test]
+    fn test_style() {
+        assert_eq!(
+            Style::default().mpv_args(),
+            [
+                "--script-opts-append=voyeurs-position=top-left",
+                "--script-opts-append=voyeurs-font_size=0",
+                "--script-opts-append=voyeurs-box=no",
+            ]
+        );
+        let style = Style {
+            position: Position::BottomRight,
+            font_size: Some(28),
+            boxed: true,
+        };
+        assert_eq!(
+            style.mpv_args(),
+            [
+                "--script-opts-append=voyeurs-position=bottom-right",
+                "--script-opts-append=voyeurs-font_size=28",
+                "--script-opts-append=voyeurs-box=yes",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("chat=8000"), Ok((Category::Chat, 8000)));
+        assert_eq!(parse_duration("presence=0"), Ok((Category::Presence, 0)));
+        assert!(parse_duration("chat").is_err());
+        assert!(parse_duration("subtitles=100").is_err());
+        assert!(parse_duration("warning=-1").is_err());
+    }
+}
@@ -0,0 +1,169 @@
+use bytes::{Bytes, BytesMut};
+use std::collections::VecDeque;
+use tokio::{
+    io::{self, AsyncWrite, AsyncWriteExt},
+    sync::mpsc::{self, error::TrySendError},
+};
+
+use crate::proto::{frame, Packet, Psk, VoyeursCommand};
+
+/// Packets a peer may have waiting before it counts as too slow to keep
+pub const MAX_QUEUED: usize = 256;
+
+/// A packet as it goes on the wire
+pub struct Queued {
+    bytes: Bytes,
+    // set for commands the next one of the same kind replaces
+    kind: Option<&'static str>,
+}
+
+/// What the shared state holds of a connection: the sending end of a queue the
+/// connection's own task writes from, so nobody waits on a peer with a full tcp buffer
+pub struct Outbox {
+    psk: Option<Psk>,
+    /// the largest packet the peer takes
+    max_packet: usize,
+    tx: Option<mpsc::Sender<Queued>>,
+}
+
+/// Why a packet didn't make it into the outbox
+#[derive(Debug)]
+pub enum SendError {
+    /// The peer fell so far behind that its queue is full
+    Overflow,
+    /// The packet can't go on the wire at all, to this peer or any other
+    Frame(io::Error),
+}
+
+/// An outbox, and the queue for `write_packets` to empty
+pub fn channel(psk: Option<Psk>, max_packet: usize) -> (Outbox, mpsc::Receiver<Queued>) {
+    let (tx, rx) = mpsc::channel(MAX_QUEUED);
+    let outbox = Outbox {
+        psk,
+        max_packet,
+        tx: Some(tx),
+    };
+    (outbox, rx)
+}
+
+impl Outbox {
+    /// Queue a packet, returning how many bytes it will take on the wire
+    pub fn send(&self, packet: &Packet) -> Result<usize, SendError> {
+        let mut buf = BytesMut::new();
+        frame(packet, self.psk.as_ref(), self.max_packet, &mut buf).map_err(SendError::Frame)?;
+        let len = buf.len();
+        let queued = Queued {
+            bytes: buf.freeze(),
+            kind: superseded(&packet.command).then(|| packet.command.name()),
+        };
+        match self.tx.as_ref().map(|tx| tx.try_send(queued)) {
+            Some(Err(TrySendError::Full(_))) => Err(SendError::Overflow),
+            // A broken connection ends with the writing task, which the connection sees
+            _ => Ok(len),
+        }
+    }
+
+    /// Close the connection once what's queued is out
+    pub fn close(&mut self) {
+        self.tx = None;
+    }
+}
+
+/// Commands the next one of the same kind replaces
+fn superseded(command: &VoyeursCommand) -> bool {
+    matches!(
+        command,
+        VoyeursCommand::Position { .. } | VoyeursCommand::LiveLag(_)
+    )
+}
+
+/// Write out what's queued until the outbox is closed or dropped, then close the
+/// connection. Run by the connection, which owns the sending half of the socket
+pub async fn write_packets(
+    mut queue: mpsc::Receiver<Queued>,
+    mut inner: impl AsyncWrite + Send + Unpin,
+) -> io::Result<()> {
+    let mut batch = VecDeque::new();
+    while let Some(first) = queue.recv().await {
+        batch.push_back(first);
+        while let Ok(next) = queue.try_recv() {
+            batch.push_back(next);
+        }
+        // Where a peer was a while ago is old news once there's something newer
+        let mut kept = VecDeque::with_capacity(batch.len());
+        while let Some(queued) = batch.pop_front() {
+            let stale = queued.kind.is_some()
+                && batch.iter().any(|newer: &Queued| newer.kind == queued.kind);
+            if !stale {
+                kept.push_back(queued);
+            }
+        }
+        for queued in kept {
+            inner.write_all(&queued.bytes).await?;
+        }
+    }
+    inner.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::outbox::{channel, write_packets, SendError, MAX_QUEUED};
+    use crate::proto::{Limits, PacketReader, VoyeursCommand};
+    use tokio::io::duplex;
+
+    #[tokio::test]
+    async fn test_outbox() {
+        let (mut outbox, queue) = channel(None, Limits::default().max_packet);
+        let position = |time| VoyeursCommand::Position {
+            time,
+            paused: false,
+        };
+        for time in 0..MAX_QUEUED - 1 {
+            assert!(outbox.send(&position(time as f64).craft_packet()).is_ok());
+        }
+        assert!(outbox
+            .send(&VoyeursCommand::Seek(4.2).craft_packet())
+            .is_ok());
+        // nobody is writing yet, as with a peer whose connection stalled
+        assert!(outbox
+            .send(&VoyeursCommand::Seek(4.2).craft_packet())
+            .is_err());
+        outbox.close();
+
+        let (tx, rx) = duplex(1024);
+        tokio::spawn(write_packets(queue, tx));
+        let mut reader = PacketReader::new(rx, Limits::default(), None);
+        // only the latest position is still worth sending
+        let packet = reader.read_packet().await.unwrap();
+        assert_eq!(packet.command, position((MAX_QUEUED - 2) as f64));
+        let packet = reader.read_packet().await.unwrap();
+        assert_eq!(packet.command, VoyeursCommand::Seek(4.2));
+        assert!(reader.read_packet().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_broken_connection() {
+        let (outbox, queue) = channel(None, Limits::default().max_packet);
+        let (tx, rx) = duplex(1024);
+        drop(rx);
+        let writer = tokio::spawn(write_packets(queue, tx));
+        // queueing still works, the connection learns of the failure from the writer
+        assert!(outbox
+            .send(&VoyeursCommand::Seek(4.2).craft_packet())
+            .is_ok());
+        assert!(writer.await.unwrap().is_err());
+    }
+
+    #[test]
+    fn test_unframed() {
+        let (outbox, _queue) = channel(None, Limits::default().max_packet);
+        // the peer is fine, the packet is too large for anyone
+        let huge = VoyeursCommand::ServerMessage("a".repeat(70_000)).craft_packet();
+        assert!(matches!(outbox.send(&huge), Err(SendError::Frame(_))));
+        // more than peers take by default, fine for peers told to take more
+        let large = VoyeursCommand::ServerMessage("a".repeat(20_000)).craft_packet();
+        assert!(matches!(outbox.send(&large), Err(SendError::Frame(_))));
+        let (outbox, _queue) = channel(None, 32 * 1024);
+        assert!(outbox.send(&large).is_ok());
+    }
+}
@@ -0,0 +1,119 @@
+use crate::{osd::ass_line, proto::Role, Settings, Shared};
+
+/// How many chat lines the panel keeps
+pub const CHAT_LINES: usize = 5;
+
+/// What the Alt+p panel shows, as ASS: everyone in the session, whether they're ready and
+/// how far off they are, then the last few chat lines
+pub fn render(s: &Shared, settings: &Settings) -> String {
+    let username = s.username(settings);
+    let role = if settings.is_serving {
+        Role::Host
+    } else {
+        s.role
+    };
+    let mut lines = vec!["{\\b1}Peers{\\b0}".to_owned()];
+    lines.push(ass_line(
+        username,
+        &format!("{username}  {role}  {}", status(s.is_ready, s.away, false)),
+    ));
+
+    let mut peers: Vec<_> = s.joined_peers().map(|(_, peer)| peer).collect();
+    peers.sort_by(|a, b| a.username.cmp(&b.username));
+    for peer in &peers {
+        let status = status(peer.ready, peer.away, s.typing.contains(&peer.username));
+        let drift = peer.drift.map_or("-".to_owned(), |d| format!("{d:+.2}s"));
+        let line = format!("{}  {}  {status}  {drift}", peer.username, peer.role);
+        lines.push(ass_line(&peer.username, &line));
+    }
+    // The ones a client only hears about from the server
+    for other in &s.roster {
+        let name = &other.username;
+        if name == username || peers.iter().any(|peer| peer.username == *name) {
+            continue;
+        }
+        let status = status(other.ready, other.away, s.typing.contains(name));
+        lines.push(ass_line(name, &format!("{name}  {}  {status}", other.role)));
+    }
+
+    if !s.recent_chat.is_empty() {
+        lines.push(String::new());
+        lines.push("{\\b1}Chat{\\b0}".to_owned());
+        for (from, line) in &s.recent_chat {
+            lines.push(ass_line(from, line));
+        }
+    }
+    lines.join("\\N")
+}
+
+fn status(ready: bool, away: bool, typing: bool) -> &'static str {
+    match (ready, away, typing) {
+        (_, _, true) => "typing…",
+        (_, true, _) => "away",
+        (true, _, _) => "ready",
+        (false, _, _) => "not ready",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::osd::ass_line;
+    use crate::panel::render;
+    use crate::proto::{Role, RosterEntry};
+    use crate::{Settings, Shared};
+
+    #[test]
+    fn test_render() {
+        let mut s = Shared::new();
+        s.role = Role::Moderator;
+        s.is_ready = true;
+        s.roster = vec![
+            RosterEntry {
+                username: "host".to_owned(),
+                role: Role::Host,
+                ready: true,
+                away: false,
+            },
+            RosterEntry {
+                username: "alice".to_owned(),
+                role: Role::Moderator,
+                ready: true,
+                away: false,
+            },
+            RosterEntry {
+                username: "bob".to_owned(),
+                role: Role::Viewer,
+                ready: false,
+                away: false,
+            },
+            RosterEntry {
+                username: "carol".to_owned(),
+                role: Role::Viewer,
+                ready: false,
+                away: true,
+            },
+        ];
+        s.typing.insert("bob".to_owned());
+        s.remember_chat("bob".to_owned(), "bob: popcorn?".to_owned());
+        let settings = Settings {
+            username: "alice".to_owned(),
+            ..Default::default()
+        };
+
+        let panel = render(&s, &settings);
+        let lines: Vec<_> = panel.split("\\N").collect();
+        assert_eq!(
+            lines,
+            [
+                "{\\b1}Peers{\\b0}".to_owned(),
+                ass_line("alice", "alice  moderator  ready"),
+                ass_line("host", "host  host  ready"),
+                ass_line("bob", "bob  viewer  typing…"),
+                ass_line("carol", "carol  viewer  away"),
+                "".to_owned(),
+                "{\\b1}Chat{\\b0}".to_owned(),
+                ass_line("bob", "bob: popcorn?"),
+            ]
+        );
+    }
+}
@@ -0,0 +1,379 @@
+#[cfg(unix)]
+use mpvipc::{Event, Mpv, MpvCommand, MpvDataType, PlaylistAddOptions, Property, SeekOptions};
+use std::{
+    collections::VecDeque,
+    error::Error,
+    sync::{Arc, Mutex},
+};
+
+#[cfg(unix)]
+use crate::osd;
+use crate::{headless::Headless, source, supervised::Supervised, vlc::Vlc};
+
+pub type PlayerError = Box<dyn Error + Sync + Send>;
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum PlayerKind {
+    Mpv,
+    Vlc,
+}
+
+/// How tasks reach the player voyeurs started
+#[derive(Clone)]
+pub enum PlayerConnection {
+    Mpv(String),
+    Vlc(Vlc),
+    /// an mpv restarted whenever it quits, with --daemon
+    Supervised(String),
+    /// no player at all, with --controller
+    Headless(Headless),
+}
+
+impl PlayerConnection {
+    /// Open another connection to the player, every task gets its own
+    pub fn connect(&self) -> Box<dyn PlayerBackend> {
+        self.try_connect()
+            .expect("Task coudln't attach to the player")
+    }
+
+    pub fn try_connect(&self) -> Result<Box<dyn PlayerBackend>, PlayerError> {
+        match self {
+            #[cfg(unix)]
+            PlayerConnection::Mpv(socket) => Ok(Box::new(Mpv::connect(socket)?)),
+            #[cfg(windows)]
+            PlayerConnection::Mpv(pipe) => Ok(Box::new(crate::mpv_pipe::connect(pipe)?)),
+            PlayerConnection::Vlc(vlc) => Ok(Box::new(vlc.clone())),
+            PlayerConnection::Supervised(socket) => Ok(Box::new(Supervised::connect(socket)?)),
+            PlayerConnection::Headless(headless) => Ok(Box::new(headless.clone())),
+        }
+    }
+}
+
+/// What the player tells voyeurs about
+#[derive(Debug, Clone, PartialEq)]
+pub enum PlayerEvent {
+    Pause(bool),
+    /// `true` while seeking or buffering, `false` once playback can go on
+    Seeking(bool),
+    Focused(bool),
+    /// the local user picked a reaction
+    Reaction(String),
+    /// the local user bookmarked the current position, with this name
+    Bookmark(String),
+    /// the local user asked to get back in sync
+    Resync,
+    /// the local user moved their file this many seconds further ahead of everyone's
+    Nudge(f64),
+    /// moved to another entry of the playlist
+    PlaylistPos(u64),
+    /// a file was loaded, with this name
+    FileLoaded(String),
+    /// the current file is over, there may be more in the playlist
+    EndFile,
+    /// playback started again after a seek, a stall or a switch of audio device
+    Restarted,
+    Shutdown,
+    /// anything voyeurs doesn't care about
+    Other,
+}
+
+/// Everything voyeurs needs from a media player
+pub trait PlayerBackend: Send + Sync {
+    fn is_paused(&self) -> Result<bool, PlayerError>;
+    fn set_paused(&self, paused: bool) -> Result<(), PlayerError>;
+    /// Seek to `t` seconds, on the exact frame rather than the nearest keyframe if `exact`
+    fn seek(&self, t: f64, exact: bool) -> Result<(), PlayerError>;
+    /// Playback position in seconds, if anything is loaded
+    fn position(&self) -> Option<f64>;
+    fn duration(&self) -> Option<f64>;
+    /// Size in bytes of the current file, none for streams
+    fn file_size(&self) -> Option<u64>;
+    /// How many seconds of a live stream are buffered past the playback position
+    fn live_lag(&self) -> Option<f64>;
+    fn set_speed(&self, speed: f64) -> Result<(), PlayerError>;
+    fn filename(&self) -> Option<String>;
+    /// Where the current file was opened from
+    fn path(&self) -> Option<String>;
+    /// The url accept-source clients can open the current file from, none for local files
+    fn stream_source(&self) -> Option<String> {
+        source::shareable(&self.path()?, None, None)
+    }
+    fn playlist_pos(&self) -> Option<u64>;
+    fn playlist_count(&self) -> Option<u64>;
+    fn set_playlist_pos(&self, pos: u64) -> Result<(), PlayerError>;
+    /// Replace the current file and wait for it to load
+    fn load(&mut self, url: &str) -> Result<(), PlayerError>;
+    /// Show `text` on top of the video for `duration` milliseconds
+    fn show_text(&self, text: &str, duration: u32) -> Result<(), PlayerError>;
+    /// Show `line`, about what `username` said or did, in their color if the player can
+    /// draw one
+    fn show_from(&self, _username: &str, line: &str, duration: u32) -> Result<(), PlayerError> {
+        self.show_text(line, duration)
+    }
+    /// Publish the drift and health for the sync indicator, if the player can draw one
+    fn set_sync_status(&self, _drift: &str, _health: &str) {}
+    /// Publish what the panel shows, as ASS, if the player can draw one
+    fn set_panel(&self, _panel: &str) {}
+    /// Unload the file, keeping the player open for the next one
+    fn stop(&self) {}
+    /// Close the player, when voyeurs quits
+    fn quit(&self);
+    /// Subscribe to the events `next_event` reports
+    fn observe(&mut self) -> Result<(), PlayerError>;
+    /// Block until the player has something to report
+    fn next_event(&mut self) -> Result<PlayerEvent, PlayerError>;
+}
+
+#[cfg(unix)]
+impl PlayerBackend for Mpv {
+    fn is_paused(&self) -> Result<bool, PlayerError> {
+        Ok(self.get_property("pause")?)
+    }
+
+    fn set_paused(&self, paused: bool) -> Result<(), PlayerError> {
+        Ok(self.set_property("pause", paused)?)
+    }
+
+    fn seek(&self, t: f64, exact: bool) -> Result<(), PlayerError> {
+        if exact {
+            // mpvipc can't pass seek flags, and hr-seek may have been turned off by the user
+            self.run_command_raw("seek", &[&t.to_string(), "absolute+exact"])?;
+        } else {
+            Mpv::seek(self, t, SeekOptions::Absolute)?;
+        }
+        Ok(())
+    }
+
+    fn position(&self) -> Option<f64> {
+        self.get_property("playback-time").ok()
+    }
+
+    fn duration(&self) -> Option<f64> {
+        self.get_property("duration").ok()
+    }
+
+    fn file_size(&self) -> Option<u64> {
+        self.get_property::<usize>("file-size")
+            .ok()
+            .map(|size| size as u64)
+    }
+
+    fn live_lag(&self) -> Option<f64> {
+        let cached: f64 = self.get_property("demuxer-cache-time").ok()?;
+        Some(cached - PlayerBackend::position(self)?)
+    }
+
+    fn set_speed(&self, speed: f64) -> Result<(), PlayerError> {
+        Ok(self.set_property("speed", speed)?)
+    }
+
+    fn filename(&self) -> Option<String> {
+        self.get_property("filename").ok()
+    }
+
+    fn path(&self) -> Option<String> {
+        self.get_property_string("path").ok()
+    }
+
+    fn stream_source(&self) -> Option<String> {
+        let opened: Option<String> = self.get_property("stream-open-filename").ok();
+        // Set by the ytdl hook of mpv 0.36 and later
+        let ytdl: Option<std::collections::HashMap<String, MpvDataType>> = self
+            .get_property("user-data/mpv/ytdl/json-subprocess-result")
+            .ok();
+        let stdout = match ytdl.as_ref().and_then(|result| result.get("stdout")) {
+            Some(MpvDataType::String(stdout)) => Some(stdout.as_str()),
+            _ => None,
+        };
+        source::shareable(&PlayerBackend::path(self)?, opened.as_deref(), stdout)
+    }
+
+    fn playlist_pos(&self) -> Option<u64> {
+        self.get_property::<usize>("playlist-pos")
+            .ok()
+            .map(|pos| pos as u64)
+    }
+
+    fn playlist_count(&self) -> Option<u64> {
+        self.get_property::<usize>("playlist-count")
+            .ok()
+            .map(|count| count as u64)
+    }
+
+    fn set_playlist_pos(&self, pos: u64) -> Result<(), PlayerError> {
+        Ok(self.playlist_play_id(pos as usize)?)
+    }
+
+    fn load(&mut self, url: &str) -> Result<(), PlayerError> {
+        self.run_command(MpvCommand::LoadFile {
+            file: url.to_string(),
+            option: PlaylistAddOptions::Replace,
+        })?;
+        while !matches!(self.event_listen()?, Event::FileLoaded) {}
+        Ok(())
+    }
+
+    fn show_text(&self, text: &str, duration: u32) -> Result<(), PlayerError> {
+        Ok(osd::show_text(self, text, duration)?)
+    }
+
+    fn show_from(&self, username: &str, line: &str, duration: u32) -> Result<(), PlayerError> {
+        Ok(osd::show_from(self, username, line, duration)?)
+    }
+
+    fn set_sync_status(&self, drift: &str, health: &str) {
+        let _ = self.run_command_raw("set", &["user-data/voyeurs/drift", drift]);
+        let _ = self.run_command_raw("set", &["user-data/voyeurs/health", health]);
+    }
+
+    fn set_panel(&self, panel: &str) {
+        let _ = osd::set_panel(self, panel);
+    }
+
+    fn stop(&self) {
+        let _ = self.run_command_raw("stop", &[]);
+    }
+
+    fn quit(&self) {
+        let _ = self.run_command(MpvCommand::Quit);
+    }
+
+    fn observe(&mut self) -> Result<(), PlayerError> {
+        self.observe_property(0, "pause")?;
+        self.observe_property(1, "seeking")?;
+        self.observe_property(2, "focused")?;
+        self.observe_property(3, "user-data/voyeurs/reaction")?;
+        self.observe_property(4, "playlist-pos")?;
+        self.observe_property(5, "filename")?;
+        self.observe_property(6, "user-data/voyeurs/bookmark")?;
+        self.observe_property(7, "user-data/voyeurs/resync")?;
+        self.observe_property(8, "user-data/voyeurs/nudge")?;
+        Ok(())
+    }
+
+    fn next_event(&mut self) -> Result<PlayerEvent, PlayerError> {
+        let event = match self.event_listen()? {
+            Event::Shutdown => PlayerEvent::Shutdown,
+            Event::EndFile => PlayerEvent::EndFile,
+            Event::PlaybackRestart | Event::AudioReconfig => PlayerEvent::Restarted,
+            Event::PropertyChange {
+                property: Property::Pause(p),
+                ..
+            } => PlayerEvent::Pause(p),
+            Event::PropertyChange {
+                property: Property::Unknown { name, data },
+                ..
+            } => match (name.as_str(), data) {
+                ("seeking", MpvDataType::Bool(b)) => PlayerEvent::Seeking(b),
+                ("focused", MpvDataType::Bool(b)) => PlayerEvent::Focused(b),
+                ("playlist-pos", MpvDataType::Usize(pos)) => PlayerEvent::PlaylistPos(pos as u64),
+                ("filename", MpvDataType::String(name)) => PlayerEvent::FileLoaded(name),
+                ("user-data/voyeurs/reaction", MpvDataType::String(emoji)) if !emoji.is_empty() => {
+                    // Clear it, so that the same reaction twice in a row is still a change
+                    self.run_command_raw("set", &["user-data/voyeurs/reaction", ""])?;
+                    PlayerEvent::Reaction(emoji)
+                }
+                ("user-data/voyeurs/bookmark", MpvDataType::String(name)) if !name.is_empty() => {
+                    self.run_command_raw("set", &["user-data/voyeurs/bookmark", ""])?;
+                    PlayerEvent::Bookmark(name)
+                }
+                ("user-data/voyeurs/resync", MpvDataType::String(asked)) if !asked.is_empty() => {
+                    self.run_command_raw("set", &["user-data/voyeurs/resync", ""])?;
+                    PlayerEvent::Resync
+                }
+                ("user-data/voyeurs/nudge", MpvDataType::String(by)) if !by.is_empty() => {
+                    self.run_command_raw("set", &["user-data/voyeurs/nudge", ""])?;
+                    by.parse().map_or(PlayerEvent::Other, PlayerEvent::Nudge)
+                }
+                _ => PlayerEvent::Other,
+            },
+            _ => PlayerEvent::Other,
+        };
+        Ok(event)
+    }
+}
+
+/// A player that only remembers what it's told, for the tests and the selftest.
+/// Clones share the same state, like connections to the same mpv would.
+#[derive(Clone, Default)]
+pub struct FakePlayer {
+    pub paused: Arc<Mutex<bool>>,
+    pub position: Arc<Mutex<Option<f64>>>,
+    pub shown: Arc<Mutex<Vec<String>>>,
+    pub events: VecDeque<PlayerEvent>,
+}
+
+impl PlayerBackend for FakePlayer {
+    fn is_paused(&self) -> Result<bool, PlayerError> {
+        Ok(*self.paused.lock().unwrap())
+    }
+
+    fn set_paused(&self, paused: bool) -> Result<(), PlayerError> {
+        *self.paused.lock().unwrap() = paused;
+        Ok(())
+    }
+
+    fn seek(&self, t: f64, _exact: bool) -> Result<(), PlayerError> {
+        *self.position.lock().unwrap() = Some(t);
+        Ok(())
+    }
+
+    fn position(&self) -> Option<f64> {
+        *self.position.lock().unwrap()
+    }
+
+    fn duration(&self) -> Option<f64> {
+        None
+    }
+
+    fn file_size(&self) -> Option<u64> {
+        None
+    }
+
+    fn live_lag(&self) -> Option<f64> {
+        None
+    }
+
+    fn set_speed(&self, _speed: f64) -> Result<(), PlayerError> {
+        Ok(())
+    }
+
+    fn filename(&self) -> Option<String> {
+        None
+    }
+
+    fn path(&self) -> Option<String> {
+        None
+    }
+
+    fn playlist_pos(&self) -> Option<u64> {
+        None
+    }
+
+    fn playlist_count(&self) -> Option<u64> {
+        None
+    }
+
+    fn set_playlist_pos(&self, _pos: u64) -> Result<(), PlayerError> {
+        Ok(())
+    }
+
+    fn load(&mut self, _url: &str) -> Result<(), PlayerError> {
+        Ok(())
+    }
+
+    fn show_text(&self, text: &str, _duration: u32) -> Result<(), PlayerError> {
+        self.shown.lock().unwrap().push(text.to_owned());
+        Ok(())
+    }
+
+    fn quit(&self) {}
+
+    fn observe(&mut self) -> Result<(), PlayerError> {
+        Ok(())
+    }
+
+    fn next_event(&mut self) -> Result<PlayerEvent, PlayerError> {
+        Ok(self.events.pop_front().unwrap_or(PlayerEvent::Shutdown))
+    }
+}
@@ -0,0 +1,305 @@
+use async_trait::async_trait;
+use serde_json::{json, Value};
+use std::{error::Error, fmt, fs, io, path::Path, process::Stdio, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    process::{Child, ChildStdin, ChildStdout, Command},
+    sync::Mutex,
+    time::timeout,
+};
+
+use crate::proto::{CmdSize, EXTENSION_CODES};
+
+type PluginError = Box<dyn Error + Sync + Send>;
+
+// How long an external plugin gets to answer a hook before it's taken as no answer
+const HOOK_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Something voyeurs doesn't do itself, like trivia overlays or house rules for who may
+/// join. A plugin is an extension peers negotiate by name, handling some of the
+/// extension command codes, and gets a say in what happens to the session
+#[async_trait]
+pub trait Plugin: Send + Sync {
+    /// The extension it goes by, only peers that have it get its commands
+    fn name(&self) -> &str;
+
+    /// The extension command codes it handles
+    fn codes(&self) -> &[CmdSize];
+
+    /// A command from `from` with one of its codes, and what to show for it if anything
+    async fn command(&self, from: &str, code: CmdSize, args: &[u8]) -> Option<String>;
+
+    /// Whether to follow a seek to `time`, by `by` when we know who
+    async fn pre_seek(&self, _by: Option<&str>, _time: f64) -> bool {
+        true
+    }
+
+    /// `username` just got through the handshake, an error turns it away
+    async fn post_join(&self, _username: &str) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// The plugins loaded at startup, asked in order
+#[derive(Clone, Default)]
+pub struct Plugins(Arc<Vec<Box<dyn Plugin>>>);
+
+impl Plugins {
+    /// Start the plugins in a file with a `NAME CODE[,CODE...] COMMAND [ARGS...]` per
+    /// line, each a program that answers hooks as JSON lines on its stdin and stdout, or
+    /// a `.wasm` module run in a sandbox
+    pub fn load(path: &Path) -> Result<Plugins, PluginError> {
+        let mut plugins = vec![];
+        for (n, line) in fs::read_to_string(path)?.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let plugin = load_plugin(line).map_err(|e| format!("line {}: {e}", n + 1))?;
+            plugins.push(plugin);
+        }
+        Ok(Plugins(Arc::new(plugins)))
+    }
+
+    /// The names to offer peers along with the extensions built in
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.0.iter().map(|plugin| plugin.name())
+    }
+
+    /// The plugin for extension command `code`, among the extensions the peer shares
+    pub fn handling(&self, code: CmdSize, shared: &[String]) -> Option<&dyn Plugin> {
+        self.0
+            .iter()
+            .find(|plugin| {
+                plugin.codes().contains(&code) && shared.iter().any(|s| s == plugin.name())
+            })
+            .map(|plugin| &**plugin)
+    }
+
+    /// Whether there are none to ask
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Whether every plugin lets the seek happen
+    pub async fn pre_seek(&self, by: Option<&str>, time: f64) -> bool {
+        for plugin in self.0.iter() {
+            if !plugin.pre_seek(by, time).await {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// The first plugin's reason to turn `username` away, if any has one
+    pub async fn post_join(&self, username: &str) -> Result<(), String> {
+        for plugin in self.0.iter() {
+            plugin.post_join(username).await?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Plugins {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.names()).finish()
+    }
+}
+
+/// A plugin living out of tree, as a program of its own. Every hook is a JSON object
+/// on a line of its stdin, `{"hook": "command", "from": NAME, "code": CODE, "args": HEX}`,
+/// `{"hook": "pre_seek", "by": NAME, "time": SECS}` or `{"hook": "post_join",
+/// "username": NAME}`, and gets one line back: `{"show": TEXT}`, `{"allow": false}` and
+/// `{"reject": REASON}` respectively, or `{}` to go on as usual. One that takes longer
+/// than HOOK_TIMEOUT is taken as `{}`, and its late answer thrown away
+struct External {
+    name: String,
+    codes: Vec<CmdSize>,
+    /// and how many answers it still owes to hooks that timed out
+    io: Mutex<(ChildStdin, BufReader<ChildStdout>, usize)>,
+    // it sees its stdin close when voyeurs exits
+    _child: Child,
+}
+
+fn load_plugin(line: &str) -> Result<Box<dyn Plugin>, PluginError> {
+    let mut words = line.split_whitespace();
+    let (Some(name), Some(codes), Some(program)) = (words.next(), words.next(), words.next())
+    else {
+        return Err("it isn't NAME CODE[,CODE...] COMMAND [ARGS...]".into());
+    };
+    let codes = codes
+        .split(',')
+        .map(|code| parse_code(code.trim()))
+        .collect::<Result<_, _>>()?;
+    if program.ends_with(".wasm") {
+        #[cfg(feature = "wasm")]
+        return Ok(Box::new(crate::wasm_plugin::Wasm::load(
+            name,
+            codes,
+            Path::new(program),
+        )?));
+        #[cfg(not(feature = "wasm"))]
+        return Err("build voyeurs with --features wasm for wasm plugins".into());
+    }
+    Ok(Box::new(External::spawn(name, codes, program, words)?))
+}
+
+impl External {
+    fn spawn<'a>(
+        name: &str,
+        codes: Vec<CmdSize>,
+        program: &str,
+        args: impl Iterator<Item = &'a str>,
+    ) -> Result<External, PluginError> {
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| format!("couldn't start {program}: {e}"))?;
+        let stdin = child.stdin.take().expect("piped");
+        let stdout = BufReader::new(child.stdout.take().expect("piped"));
+        Ok(External {
+            name: name.to_owned(),
+            codes,
+            io: Mutex::new((stdin, stdout, 0)),
+            _child: child,
+        })
+    }
+
+    /// Ask the plugin, an empty answer when it doesn't make sense of the question or
+    /// doesn't answer in time
+    async fn ask(&self, hook: Value) -> Value {
+        let mut io = self.io.lock().await;
+        let (stdin, stdout, owed) = &mut *io;
+        let asked = timeout(HOOK_TIMEOUT, async {
+            let mut answer = String::new();
+            // out of date by now
+            while *owed > 0 {
+                stdout.read_line(&mut answer).await?;
+                answer.clear();
+                *owed -= 1;
+            }
+            stdin.write_all(format!("{hook}\n").as_bytes()).await?;
+            stdin.flush().await?;
+            *owed += 1;
+            stdout.read_line(&mut answer).await?;
+            *owed -= 1;
+            Ok::<_, io::Error>(answer)
+        });
+        match asked.await {
+            Ok(Ok(answer)) => serde_json::from_str(&answer).unwrap_or_default(),
+            _ => Value::Null,
+        }
+    }
+}
+
+#[async_trait]
+impl Plugin for External {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn codes(&self) -> &[CmdSize] {
+        &self.codes
+    }
+
+    async fn command(&self, from: &str, code: CmdSize, args: &[u8]) -> Option<String> {
+        let args: String = args.iter().map(|b| format!("{b:02x}")).collect();
+        let hook = json!({"hook": "command", "from": from, "code": code, "args": args});
+        let answer = self.ask(hook).await;
+        answer["show"].as_str().map(str::to_owned)
+    }
+
+    async fn pre_seek(&self, by: Option<&str>, time: f64) -> bool {
+        let answer = self
+            .ask(json!({"hook": "pre_seek", "by": by, "time": time}))
+            .await;
+        answer["allow"].as_bool().unwrap_or(true)
+    }
+
+    async fn post_join(&self, username: &str) -> Result<(), String> {
+        let answer = self
+            .ask(json!({"hook": "post_join", "username": username}))
+            .await;
+        match answer["reject"].as_str() {
+            Some(reason) => Err(reason.to_owned()),
+            None => Ok(()),
+        }
+    }
+}
+
+fn parse_code(code: &str) -> Result<CmdSize, PluginError> {
+    let parsed = match code.strip_prefix("0x") {
+        Some(hex) => CmdSize::from_str_radix(hex, 16)?,
+        None => code.parse()?,
+    };
+    if !EXTENSION_CODES.contains(&parsed) {
+        return Err(
+            format!("{code} isn't an extension command code, those are 0xe0 to 0xff").into(),
+        );
+    }
+    Ok(parsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::plugin::{parse_code, Plugins};
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_external() {
+        let dir = tempfile::tempdir().unwrap();
+        let script = dir.path().join("rules.sh");
+        // no seeking past the end credits, and no mallory
+        std::fs::write(
+            &script,
+            "while read -r hook; do\n\
+               case \"$hook\" in\n\
+                 *pre_seek*5400*) echo '{\"allow\": false}' ;;\n\
+                 *slowpoke*) sleep 0.7; echo '{\"allow\": false}' ;;\n\
+                 *mallory*) echo '{\"reject\": \"not mallory\"}' ;;\n\
+                 *command*) echo '{\"show\": \"42 points\"}' ;;\n\
+                 *) echo '{}' ;;\n\
+               esac\n\
+             done\n",
+        )
+        .unwrap();
+        let list = dir.path().join("plugins");
+        std::fs::write(
+            &list,
+            format!("# house rules\nrules 0xe0,225 sh {}\n", script.display()),
+        )
+        .unwrap();
+        let plugins = Plugins::load(&list).unwrap();
+        assert_eq!(plugins.names().collect::<Vec<_>>(), ["rules"]);
+
+        assert!(plugins.pre_seek(Some("alice"), 60.0).await);
+        assert!(!plugins.pre_seek(None, 5400.0).await);
+        // too late to count, and thrown away rather than taken for the next answer
+        assert!(plugins.pre_seek(Some("slowpoke"), 60.0).await);
+        assert_eq!(plugins.post_join("alice").await, Ok(()));
+        assert_eq!(
+            plugins.post_join("mallory").await,
+            Err("not mallory".to_owned())
+        );
+
+        // only for peers that have the plugin too
+        assert!(plugins.handling(0xE1, &[]).is_none());
+        let plugin = plugins.handling(0xE1, &["rules".to_owned()]).unwrap();
+        assert_eq!(
+            plugin.command("alice", 0xE1, b"answer").await,
+            Some("42 points".to_owned())
+        );
+        assert!(plugins.handling(0xE2, &["rules".to_owned()]).is_none());
+    }
+
+    #[test]
+    fn test_parse_code() {
+        assert_eq!(parse_code("0xe0").unwrap(), 0xE0);
+        assert_eq!(parse_code("255").unwrap(), 0xFF);
+        assert!(parse_code("0x08").is_err());
+        assert!(parse_code("trivia").is_err());
+    }
+}
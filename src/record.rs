@@ -0,0 +1,117 @@
+use bytes::BytesMut;
+use serde_json::{json, Value};
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, LineWriter, Write},
+    net::SocketAddr,
+    path::Path,
+};
+
+use crate::{proto::Packet, time::get_timestamp};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    /// received from the peer
+    In,
+    /// sent to the peer
+    Out,
+}
+
+/// Writes every packet going through the session to a JSON lines file, for debugging
+/// desyncs after the fact and for replaying the session
+pub struct Recorder {
+    file: LineWriter<File>,
+    buf: BytesMut,
+}
+
+impl Recorder {
+    /// Start a recording. Its first line says which end it was made on, the host's
+    /// `Ready(true)` starts playback while a client's only says it's ready
+    pub fn create(path: &Path, serving: bool) -> io::Result<Recorder> {
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(path)?;
+        let mut file = LineWriter::new(file);
+        writeln!(file, "{}", json!({ "serving": serving }))?;
+        Ok(Recorder {
+            file,
+            buf: BytesMut::new(),
+        })
+    }
+
+    pub fn record(
+        &mut self,
+        direction: Direction,
+        addr: SocketAddr,
+        username: &str,
+        packet: &Packet,
+    ) {
+        // one too large to encode never went out either
+        let Some(line) = self.line(get_timestamp(), direction, addr, username, packet) else {
+            return;
+        };
+        // a full disk shouldn't take the session down with it
+        let _ = writeln!(self.file, "{line}");
+    }
+
+    fn line(
+        &mut self,
+        at: u64,
+        direction: Direction,
+        addr: SocketAddr,
+        username: &str,
+        packet: &Packet,
+    ) -> Option<Value> {
+        self.buf.clear();
+        packet.encode(&mut self.buf).ok()?;
+        Some(json!({
+            "at": at,
+            "dir": match direction {
+                Direction::In => "in",
+                Direction::Out => "out",
+            },
+            "peer": addr.to_string(),
+            "username": username,
+            "command": format!("{:?}", packet.command),
+            // the packet as it went on the wire, minus the mac, for the replay
+            "packet": hex(&self.buf),
+        }))
+    }
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::proto::{Packet, VoyeursCommand};
+    use crate::record::{Direction, Recorder};
+
+    #[test]
+    fn test_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut recorder = Recorder::create(&dir.path().join("session.jsonl"), true).unwrap();
+        let packet = Packet {
+            timestamp: 1,
+            command: VoyeursCommand::Ready(true),
+        };
+        let line = recorder
+            .line(
+                5,
+                Direction::In,
+                "127.0.0.1:8998".parse().unwrap(),
+                "alice",
+                &packet,
+            )
+            .unwrap();
+        assert_eq!(line["at"], 5);
+        assert_eq!(line["dir"], "in");
+        assert_eq!(line["peer"], "127.0.0.1:8998");
+        assert_eq!(line["username"], "alice");
+        assert_eq!(line["command"], "Ready(true)");
+        assert_eq!(line["packet"], "000000000000000101000101");
+    }
+}
@@ -0,0 +1,312 @@
+use rand_core::{OsRng, RngCore};
+use serde_json::{json, Value};
+use std::{
+    iter,
+    net::{IpAddr, UdpSocket},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpListener,
+    sync::Mutex,
+    time::timeout,
+};
+
+use crate::{control, player::PlayerBackend, Settings, Shared};
+
+const PAGE: &str = include_str!("../scripts/remote.html");
+// How long a phone gets to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+// A command is a line, anything longer isn't one
+const MAX_BODY: usize = 4096;
+// The request line and each header, real browsers stay far below
+const MAX_LINE: u64 = 8192;
+// What the page's buttons send, the rest of voyeursctl's commands stay off the wifi
+const COMMANDS: [&str; 4] = ["play", "pause", "seek", "chat"];
+
+/// What the page asked for
+struct Request {
+    method: String,
+    path: String,
+    body: String,
+}
+
+/// Serve the web remote to the phones on the LAN on `port`. It lives under a random path
+/// that only the log tells, so that the neighbours on the same wifi can't pause the movie
+pub async fn serve(
+    port: u16,
+    player: Box<dyn PlayerBackend>,
+    state: Arc<Mutex<Shared>>,
+    settings: Settings,
+) {
+    let listener = match TcpListener::bind(("0.0.0.0", port)).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            state
+                .lock()
+                .await
+                .log(format!("couldn't serve the web remote on port {port}: {e}"));
+            return;
+        }
+    };
+    let key = Arc::new(new_key());
+    let host = local_ip().map_or("localhost".to_owned(), |ip| ip.to_string());
+    state
+        .lock()
+        .await
+        .log(format!("web remote at http://{host}:{port}/{key}"));
+    let player = Arc::new(Mutex::new(player));
+    while let Ok((stream, _)) = listener.accept().await {
+        let (player, state, settings, key) = (
+            Arc::clone(&player),
+            Arc::clone(&state),
+            settings.clone(),
+            Arc::clone(&key),
+        );
+        // Browsers open connections they only use later, those mustn't hold up the rest
+        tokio::spawn(async move {
+            let (rx, mut tx) = stream.into_split();
+            let Ok(Ok(request)) = timeout(REQUEST_TIMEOUT, read_request(rx)).await else {
+                return;
+            };
+            let mut player = player.lock().await;
+            let mut s = state.lock().await;
+            let response = respond(&mut **player, &mut s, &settings, &key, &request).await;
+            drop(s);
+            let _ = tx.write_all(response.as_bytes()).await;
+        });
+    }
+}
+
+async fn read_request(rx: impl AsyncRead + Unpin) -> std::io::Result<Request> {
+    let mut rx = BufReader::new(rx);
+    let mut line = String::new();
+    (&mut rx).take(MAX_LINE).read_line(&mut line).await?;
+    let mut words = line.split_whitespace();
+    let method = words.next().unwrap_or_default().to_owned();
+    let path = words.next().unwrap_or_default().to_owned();
+    let mut length = 0;
+    let mut header = String::new();
+    while (&mut rx).take(MAX_LINE).read_line(&mut header).await? > 2 {
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse().unwrap_or_default();
+            }
+        }
+        header.clear();
+    }
+    let mut body = vec![0; length.min(MAX_BODY)];
+    rx.read_exact(&mut body).await?;
+    Ok(Request {
+        method,
+        path,
+        body: String::from_utf8_lossy(&body).into_owned(),
+    })
+}
+
+/// The HTTP response to `request`: the page, where the session is, or what a command did.
+/// Commands are the ones voyeursctl sends, as long as the page has a button for them
+async fn respond(
+    player: &mut dyn PlayerBackend,
+    s: &mut Shared,
+    settings: &Settings,
+    key: &str,
+    request: &Request,
+) -> String {
+    const TEXT: &str = "text/plain; charset=utf-8";
+    let (status, kind, body) = match (request.method.as_str(), route(&request.path, key)) {
+        ("GET", Some("" | "/")) => ("200 OK", "text/html; charset=utf-8", PAGE.to_owned()),
+        ("GET", Some("/state")) => (
+            "200 OK",
+            "application/json",
+            format!("{}\n", session(player, s, settings)),
+        ),
+        ("POST", Some("/command")) => {
+            let command = request.body.trim();
+            s.debug(|| format!("remote: {command}"));
+            let name = command.split(' ').next().unwrap_or_default();
+            if !COMMANDS.contains(&name) {
+                (
+                    "403 Forbidden",
+                    TEXT,
+                    format!("{name} isn't for the remote"),
+                )
+            } else {
+                match control::execute(player, s, settings, command).await {
+                    Ok(reply) => ("200 OK", TEXT, reply),
+                    Err(e) => ("400 Bad Request", TEXT, e),
+                }
+            }
+        }
+        _ => ("404 Not Found", TEXT, "not found".to_owned()),
+    };
+    format!(
+        "HTTP/1.1 {status}\r\n\
+         Content-Type: {kind}\r\n\
+         Content-Length: {}\r\n\
+         Cache-Control: no-store\r\n\
+         Connection: close\r\n\r\n\
+         {body}",
+        body.len()
+    )
+}
+
+/// What's left of `path` after `/{key}`, if it starts with the key. The key is compared in
+/// constant time so that how long a wrong guess takes doesn't tell how much of it was right
+fn route<'a>(path: &'a str, key: &str) -> Option<&'a str> {
+    let path = path.strip_prefix('/')?;
+    let (given, rest) = path.split_at(path.find('/').unwrap_or(path.len()));
+    let diff = given
+        .bytes()
+        .zip(key.bytes())
+        .fold(0, |diff, (a, b)| diff | (a ^ b));
+    (given.len() == key.len() && diff == 0).then_some(rest)
+}
+
+/// What the page shows: whether we play, where, who's there and the last chat lines
+fn session(player: &dyn PlayerBackend, s: &Shared, settings: &Settings) -> Value {
+    let username = s.username(settings);
+    let peers: Vec<&str> = if settings.is_serving || s.roster.is_empty() {
+        iter::once(username)
+            .chain(s.joined_peers().map(|(_, peer)| peer.username.as_str()))
+            .collect()
+    } else {
+        s.roster.iter().map(|peer| peer.username.as_str()).collect()
+    };
+    let chat: Vec<&str> = s
+        .recent_chat
+        .iter()
+        .map(|(_, line)| line.as_str())
+        .collect();
+    json!({
+        "paused": player.is_paused().unwrap_or(true),
+        "position": player.position(),
+        "duration": player.duration(),
+        "title": player.filename(),
+        "peers": peers,
+        "chat": chat,
+    })
+}
+
+fn new_key() -> String {
+    let mut key = [0; 8];
+    OsRng.fill_bytes(&mut key);
+    key.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// The address phones on the LAN reach us at, as far as the routing table knows
+fn local_ip() -> Option<IpAddr> {
+    let socket = UdpSocket::bind("0.0.0.0:0").ok()?;
+    // Nothing is sent, connecting only picks the interface
+    socket.connect("192.0.2.1:9").ok()?;
+    Some(socket.local_addr().ok()?.ip())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::player::{FakePlayer, PlayerBackend};
+    use crate::remote::{read_request, respond, Request, MAX_LINE};
+    use crate::{Settings, Shared};
+
+    #[tokio::test]
+    async fn test_respond() {
+        let mut player = FakePlayer::default();
+        let mut s = Shared::new();
+        s.tui = true;
+        let settings = Settings {
+            is_serving: true,
+            username: "host".to_owned(),
+            ..Default::default()
+        };
+        let request = |method: &str, path: &str, body: &str| Request {
+            method: method.to_owned(),
+            path: path.to_owned(),
+            body: body.to_owned(),
+        };
+
+        let page = respond(
+            &mut player,
+            &mut s,
+            &settings,
+            "k3y",
+            &request("GET", "/k3y", ""),
+        )
+        .await;
+        assert!(page.starts_with("HTTP/1.1 200 OK\r\nContent-Type: text/html"));
+        // anyone guessing at paths gets nothing
+        for path in ["/", "/k3", "/k3yy", "/state", "/k3y/secret"] {
+            let wrong = respond(
+                &mut player,
+                &mut s,
+                &settings,
+                "k3y",
+                &request("GET", path, ""),
+            )
+            .await;
+            assert!(wrong.starts_with("HTTP/1.1 404"), "{path}");
+        }
+
+        let pause = request("POST", "/k3y/command", "pause\n");
+        let paused = respond(&mut player, &mut s, &settings, "k3y", &pause).await;
+        assert!(paused.starts_with("HTTP/1.1 200 OK"));
+        assert!(paused.ends_with("\r\n\r\npaused"));
+        assert_eq!(player.is_paused().ok(), Some(true));
+        let rewind = request("POST", "/k3y/command", "seek back");
+        let bad = respond(&mut player, &mut s, &settings, "k3y", &rewind).await;
+        assert!(bad.starts_with("HTTP/1.1 400"));
+        // only what the page has buttons for
+        for command in [
+            "dance",
+            "kick alice",
+            "load http://example.com/x.mkv",
+            "sync",
+        ] {
+            let other = request("POST", "/k3y/command", command);
+            let forbidden = respond(&mut player, &mut s, &settings, "k3y", &other).await;
+            assert!(forbidden.starts_with("HTTP/1.1 403"), "{command}");
+        }
+
+        // within what a request may hold, yet more than the peers would take
+        let rant = format!("chat {}", "a".repeat(4000));
+        let rant = request("POST", "/k3y/command", &rant);
+        let refused = respond(&mut player, &mut s, &settings, "k3y", &rant).await;
+        assert!(refused.starts_with("HTTP/1.1 400"));
+        assert!(
+            refused.ends_with("The chat message is 4000 bytes long, more than the limit of 1024")
+        );
+        let chat = request("POST", "/k3y/command", "chat popcorn?");
+        respond(&mut player, &mut s, &settings, "k3y", &chat).await;
+        player.seek(90.0, true).unwrap();
+        let state = respond(
+            &mut player,
+            &mut s,
+            &settings,
+            "k3y",
+            &request("GET", "/k3y/state", ""),
+        )
+        .await;
+        assert!(state.ends_with(
+            "\r\n\r\n{\"chat\":[\"host: popcorn?\"],\"duration\":null,\"paused\":true,\"peers\":[\"host\"],\"position\":90.0,\"title\":null}\n"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_read_request() {
+        let raw = "POST /k3y/command HTTP/1.1\r\nHost: tv\r\nContent-Length: 5\r\n\r\npause";
+        let request = read_request(raw.as_bytes()).await.unwrap();
+        assert_eq!(
+            (
+                request.method.as_str(),
+                request.path.as_str(),
+                request.body.as_str()
+            ),
+            ("POST", "/k3y/command", "pause")
+        );
+
+        // a request line that never ends is cut short
+        let endless = format!("GET /{}", "a".repeat(1 << 20));
+        let request = read_request(endless.as_bytes()).await.unwrap();
+        assert_eq!(request.path.len() as u64, MAX_LINE - "GET ".len() as u64);
+    }
+}
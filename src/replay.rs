@@ -0,0 +1,205 @@
+use bytes::Bytes;
+use serde_json::Value;
+use std::{
+    error::Error,
+    fs,
+    path::Path,
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    i18n::Msg,
+    osd::{notify, Category, Durations},
+    player::{PlayerBackend, PlayerError},
+    proto::{Packet, VoyeursCommand},
+    record::Direction,
+};
+
+type ReplayError = Box<dyn Error + Sync + Send>;
+
+/// A packet of a recording
+#[derive(Debug)]
+pub struct Entry {
+    /// when it went through, in ms since the epoch
+    at: u64,
+    direction: Direction,
+    username: String,
+    packet: Packet,
+}
+
+/// Read a recording made with --record-session, returning whether it was made by the
+/// host and its packets
+pub fn load(path: &Path) -> Result<(bool, Vec<Entry>), ReplayError> {
+    parse(&fs::read_to_string(path)?)
+}
+
+fn parse(recording: &str) -> Result<(bool, Vec<Entry>), ReplayError> {
+    let mut lines = recording.lines().filter(|line| !line.trim().is_empty());
+    let header: Value = serde_json::from_str(lines.next().ok_or("the recording is empty")?)?;
+    let serving = header["serving"]
+        .as_bool()
+        .ok_or("the recording doesn't start with its header")?;
+
+    let mut entries = vec![];
+    let mut last_sent = None;
+    for (n, line) in lines.enumerate() {
+        let line: Value = serde_json::from_str(line)?;
+        let malformed = || format!("line {} of the recording is malformed", n + 2);
+        let direction = match line["dir"].as_str() {
+            Some("in") => Direction::In,
+            Some("out") => Direction::Out,
+            _ => return Err(malformed().into()),
+        };
+        let packet = line["packet"].as_str().ok_or_else(malformed)?;
+        // a broadcast is recorded once per peer, it only has to be replayed once
+        if direction == Direction::Out {
+            if last_sent.as_deref() == Some(packet) {
+                continue;
+            }
+            last_sent = Some(packet.to_owned());
+        }
+        entries.push(Entry {
+            at: line["at"].as_u64().ok_or_else(malformed)?,
+            direction,
+            username: line["username"].as_str().unwrap_or_default().to_owned(),
+            packet: Packet::decode(unhex(packet).ok_or_else(malformed)?)?,
+        });
+    }
+    Ok((serving, entries))
+}
+
+fn unhex(s: &str) -> Option<Bytes> {
+    // an odd length leaves a last digit on its own, and `get` returns None for it
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()
+        .map(Bytes::from)
+}
+
+/// Play the recording back on `player`, keeping the original timing
+pub fn run(
+    player: &dyn PlayerBackend,
+    osd: &Durations,
+    serving: bool,
+    entries: &[Entry],
+) -> Result<(), PlayerError> {
+    let Some(first) = entries.first() else {
+        return Ok(());
+    };
+    let start = Instant::now();
+    for entry in entries {
+        let due = start + Duration::from_millis(entry.at.saturating_sub(first.at));
+        thread::sleep(due.saturating_duration_since(Instant::now()));
+        println!(
+            "{:>8.1}s {:?}",
+            entry.at.saturating_sub(first.at) as f64 / 1000.0,
+            entry.packet.command
+        );
+        apply(player, osd, serving, entry)?;
+    }
+    Ok(())
+}
+
+// Do to the player what the packet did to it during the session
+fn apply(
+    player: &dyn PlayerBackend,
+    osd: &Durations,
+    serving: bool,
+    entry: &Entry,
+) -> Result<(), PlayerError> {
+    let from_host = serving == (entry.direction == Direction::Out);
+    match &entry.packet.command {
+        // A client being ready doesn't start anything, the host says when playback starts
+        VoyeursCommand::Ready(true) | VoyeursCommand::ReadyFrom { ready: true, .. }
+            if from_host =>
+        {
+            player.set_paused(false)
+        }
+        VoyeursCommand::Ready(false) | VoyeursCommand::ReadyFrom { ready: false, .. } => {
+            player.set_paused(true)
+        }
+        VoyeursCommand::Seek(t) | VoyeursCommand::SeekFrom { time: t, .. } => player.seek(*t, true),
+        VoyeursCommand::Chat { from, text } => {
+            notify(player, osd, Category::Chat, &format!("{from}: {text}"))
+        }
+        VoyeursCommand::Reaction(emoji) => notify(player, osd, Category::Reaction, emoji),
+        VoyeursCommand::ServerMessage(text) => notify(player, osd, Category::Chat, text),
+        VoyeursCommand::NewConnection(_) if serving => notify(
+            player,
+            osd,
+            Category::Presence,
+            &Msg::Connected(&entry.username).to_string(),
+        ),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::osd::Durations;
+    use crate::player::{FakePlayer, PlayerBackend};
+    use crate::proto::VoyeursCommand;
+    use crate::record::hex;
+    use crate::replay::{apply, parse, run, unhex};
+    use bytes::BytesMut;
+
+    fn line(at: u64, dir: &str, command: VoyeursCommand) -> String {
+        let mut buf = BytesMut::new();
+        command.craft_packet().encode(&mut buf).unwrap();
+        format!(
+            r#"{{"at":{at},"dir":"{dir}","peer":"127.0.0.1:1","username":"bob","packet":"{}"}}"#,
+            hex(&buf)
+        )
+    }
+
+    #[test]
+    fn test_unhex() {
+        assert_eq!(unhex("00ff10").unwrap().as_ref(), [0x00, 0xff, 0x10]);
+        assert!(unhex("0").is_none());
+        assert!(unhex("zz").is_none());
+    }
+
+    #[test]
+    fn test_replay() {
+        let ready = line(1000, "out", VoyeursCommand::Ready(true));
+        let recording = [
+            r#"{"serving":true}"#.to_owned(),
+            line(0, "in", VoyeursCommand::Ready(true)),
+            ready.clone(),
+            // the same broadcast, to another peer
+            ready,
+            line(2500, "in", VoyeursCommand::Seek(60.0)),
+        ]
+        .join("\n");
+        let (serving, entries) = parse(&recording).unwrap();
+        assert!(serving);
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[2].at, 2500);
+
+        let player = FakePlayer::default();
+        player.set_paused(true).unwrap();
+        let osd = Durations::default();
+        apply(&player, &osd, serving, &entries[0]).unwrap();
+        assert!(player.is_paused().unwrap());
+        apply(&player, &osd, serving, &entries[1]).unwrap();
+        assert!(!player.is_paused().unwrap());
+        apply(&player, &osd, serving, &entries[2]).unwrap();
+        assert_eq!(player.position(), Some(60.0));
+
+        // a clock stepped back while recording doesn't stop the replay
+        let stepped = [
+            r#"{"serving":false}"#.to_owned(),
+            line(10, "in", VoyeursCommand::Seek(30.0)),
+            line(0, "in", VoyeursCommand::Seek(60.0)),
+        ]
+        .join("\n");
+        let (serving, entries) = parse(&stepped).unwrap();
+        run(&player, &osd, serving, &entries).unwrap();
+        assert_eq!(player.position(), Some(60.0));
+
+        assert!(parse("").is_err());
+        assert!(parse(r#"{"at":0}"#).is_err());
+    }
+}
@@ -0,0 +1,69 @@
+use regex::Regex;
+use std::{error::Error, fs, path::Path};
+
+type RewriteError = Box<dyn Error + Sync + Send>;
+
+/// Rules turning the host's stream urls into ones that are quicker to reach from here,
+/// like a local mirror or the same file on the NAS
+#[derive(Debug, Clone, Default)]
+pub struct Rewrites(Vec<(Regex, String)>);
+
+impl Rewrites {
+    /// Read the rules from a file with a `REGEX -> REPLACEMENT` per line, replacements
+    /// can refer to the groups of the regex as ${1}, ${2}...
+    pub fn load(path: &Path) -> Result<Rewrites, RewriteError> {
+        parse(&fs::read_to_string(path)?)
+    }
+
+    /// The url after the first rule that matches it, none when no rule does
+    pub fn apply(&self, url: &str) -> Option<String> {
+        self.0
+            .iter()
+            .find(|(pattern, _)| pattern.is_match(url))
+            .map(|(pattern, replacement)| pattern.replace(url, replacement.as_str()).into_owned())
+    }
+}
+
+fn parse(rules: &str) -> Result<Rewrites, RewriteError> {
+    let mut parsed = vec![];
+    for (n, line) in rules.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (pattern, replacement) = line
+            .split_once(" -> ")
+            .ok_or_else(|| format!("line {} isn't REGEX -> REPLACEMENT", n + 1))?;
+        let pattern = Regex::new(pattern.trim()).map_err(|e| format!("line {}: {e}", n + 1))?;
+        parsed.push((pattern, replacement.trim().to_owned()));
+    }
+    Ok(Rewrites(parsed))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rewrite::parse;
+
+    #[test]
+    fn test_rewrites() {
+        let rules = parse(
+            "# the host streams from its seedbox, we have the files too\n\
+             ^https://seedbox\\.example\\.com/(.*)$ -> /mnt/nas/${1}\n\
+             \n\
+             ^http://(.*) -> https://${1}\n",
+        )
+        .unwrap();
+        assert_eq!(
+            rules.apply("https://seedbox.example.com/show/e01.mkv"),
+            Some("/mnt/nas/show/e01.mkv".to_owned())
+        );
+        assert_eq!(
+            rules.apply("http://example.com/live"),
+            Some("https://example.com/live".to_owned())
+        );
+        assert_eq!(rules.apply("https://youtu.be/abc"), None);
+
+        assert!(parse("no arrow here").is_err());
+        assert!(parse("(unclosed -> x").is_err());
+    }
+}
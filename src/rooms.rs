@@ -0,0 +1,63 @@
+use std::{collections::HashMap, fmt, sync::Arc};
+use tokio::sync::Mutex;
+
+use crate::{player::PlayerConnection, Shared};
+
+/// A room of a server started with --rooms: its own player, and the peers watching it
+#[derive(Clone)]
+pub struct Room {
+    pub state: Arc<Mutex<Shared>>,
+    pub player: PlayerConnection,
+}
+
+/// The rooms of the server by name, empty unless started with --rooms
+#[derive(Clone, Default)]
+pub struct Rooms(Arc<HashMap<String, Room>>);
+
+impl Rooms {
+    pub fn new(rooms: HashMap<String, Room>) -> Rooms {
+        Rooms(Arc::new(rooms))
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Room> {
+        self.0.get(name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Room)> {
+        self.0.iter()
+    }
+}
+
+impl fmt::Debug for Rooms {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_set().entries(self.0.keys()).finish()
+    }
+}
+
+/// Check a room name given on the command line. How long it may be is up to the peers'
+/// limits, checked once those are known
+pub fn parse_room(name: &str) -> Result<String, String> {
+    if name.is_empty() {
+        return Err("room names can't be empty".to_owned());
+    }
+    if name.chars().any(char::is_control) {
+        return Err("room names can't have control characters".to_owned());
+    }
+    Ok(name.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rooms::parse_room;
+
+    #[test]
+    fn test_parse_room() {
+        assert_eq!(parse_room("anime"), Ok("anime".to_owned()));
+        assert_eq!(
+            parse_room("film del venerdì"),
+            Ok("film del venerdì".to_owned())
+        );
+        assert!(parse_room("").is_err());
+        assert!(parse_room("a\nb").is_err());
+    }
+}
@@ -0,0 +1,218 @@
+use rhai::{Array, Dynamic, Engine, FuncArgs, Scope, AST};
+use std::{
+    error::Error,
+    path::Path,
+    sync::{Arc, Mutex as SyncMutex},
+    time::{Duration, Instant},
+};
+use tokio::{
+    sync::{broadcast, Mutex},
+    time::interval,
+};
+
+use crate::{
+    client_message_handler::leave, player::PlayerBackend, session::SessionEvent, Settings, Shared,
+};
+
+type ScriptError = Box<dyn Error + Sync + Send>;
+
+// How often on_tick runs
+const TICK: Duration = Duration::from_secs(60);
+
+/// What a script asked for, done once it's back
+#[derive(Debug, PartialEq)]
+enum Action {
+    Chat(String),
+    Kick(String),
+}
+
+/// A rhai script with hooks for the session's events, `on_join(name)`, `on_leave(name)`,
+/// `on_pause(name)`, `on_resume()`, `on_seek(time)`, `on_chat(from, text)`,
+/// `on_file(name)` and `on_tick(paused_secs)` every minute. It can `chat(text)`,
+/// `kick(name)` and list the peers that are `away()`. Hooks it doesn't define are skipped
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+    actions: Arc<SyncMutex<Vec<Action>>>,
+    away: Arc<SyncMutex<Vec<String>>>,
+}
+
+impl Script {
+    pub fn load(path: &Path) -> Result<Script, ScriptError> {
+        let actions = Arc::new(SyncMutex::new(vec![]));
+        let away = Arc::new(SyncMutex::new(vec![]));
+        let mut engine = Engine::new();
+        let queue = Arc::clone(&actions);
+        engine.register_fn("chat", move |text: &str| {
+            queue.lock().unwrap().push(Action::Chat(text.to_owned()))
+        });
+        let queue = Arc::clone(&actions);
+        engine.register_fn("kick", move |name: &str| {
+            queue.lock().unwrap().push(Action::Kick(name.to_owned()))
+        });
+        let names = Arc::clone(&away);
+        engine.register_fn("away", move || -> Array {
+            names
+                .lock()
+                .unwrap()
+                .iter()
+                .cloned()
+                .map(Dynamic::from)
+                .collect()
+        });
+        let ast = engine.compile_file(path.to_owned())?;
+        Ok(Script {
+            engine,
+            ast,
+            actions,
+            away,
+        })
+    }
+
+    fn defines(&self, hook: &str) -> bool {
+        self.ast.iter_functions().any(|f| f.name == hook)
+    }
+
+    /// Run `hook` if the script has it, returning what it asked for
+    fn run(
+        &self,
+        hook: &str,
+        args: impl FuncArgs,
+        away: Vec<String>,
+    ) -> Result<Vec<Action>, ScriptError> {
+        if !self.defines(hook) {
+            return Ok(vec![]);
+        }
+        *self.away.lock().unwrap() = away;
+        // What a hook returns doesn't matter, the actions it took do
+        let _ = self
+            .engine
+            .call_fn::<Dynamic>(&mut Scope::new(), &self.ast, hook, args)?;
+        Ok(self.actions.lock().unwrap().drain(..).collect())
+    }
+}
+
+/// Run the script's hooks as things happen, speaking as the host
+pub fn spawn(
+    script: Script,
+    player: Box<dyn PlayerBackend>,
+    state: Arc<Mutex<Shared>>,
+    mut events: broadcast::Receiver<SessionEvent>,
+    settings: Settings,
+) {
+    tokio::spawn(async move {
+        let mut ticker = interval(TICK);
+        ticker.tick().await;
+        let mut paused_since = None;
+        loop {
+            let ran = tokio::select! {
+                event = events.recv() => {
+                    let event = match event {
+                        Ok(event) => event,
+                        Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    };
+                    let away = away(&*state.lock().await);
+                    match event {
+                        SessionEvent::Join(name) => script.run("on_join", (name,), away),
+                        SessionEvent::Leave(name) => script.run("on_leave", (name,), away),
+                        SessionEvent::Pause(name) => {
+                            paused_since.get_or_insert_with(Instant::now);
+                            script.run("on_pause", (name,), away)
+                        }
+                        SessionEvent::Resume => {
+                            paused_since = None;
+                            script.run("on_resume", (), away)
+                        }
+                        SessionEvent::Seek { time, .. } => script.run("on_seek", (time,), away),
+                        SessionEvent::Chat { from, text } => {
+                            // Its own messages would set off a greeting loop
+                            if from == state.lock().await.username(&settings) {
+                                continue;
+                            }
+                            script.run("on_chat", (from, text), away)
+                        }
+                        SessionEvent::FileChange(name) => script.run("on_file", (name,), away),
+                    }
+                }
+                _ = ticker.tick() => {
+                    let paused = paused_since.map_or(0, |since: Instant| since.elapsed().as_secs());
+                    let away = away(&*state.lock().await);
+                    script.run("on_tick", (paused as i64,), away)
+                }
+            };
+            let mut s = state.lock().await;
+            let actions = match ran {
+                Ok(actions) => actions,
+                Err(e) => {
+                    s.log(format!("the script failed: {e}"));
+                    continue;
+                }
+            };
+            for action in actions {
+                match action {
+                    Action::Chat(text) => {
+                        let username = s.username(&settings).to_owned();
+                        if let Err(e) = s.chat(username, text).await {
+                            s.log(format!("the script can't chat: {e}"));
+                        }
+                    }
+                    Action::Kick(name) => {
+                        let Some(addr) = s
+                            .joined_peers()
+                            .find(|(_, peer)| peer.username == name)
+                            .map(|(addr, _)| *addr)
+                        else {
+                            continue;
+                        };
+                        s.log(format!("the script kicked {name}"));
+                        if let Some(name) = s.kick(addr).await {
+                            leave(&*player, &mut s, &settings, name).await;
+                        }
+                    }
+                }
+            }
+        }
+    });
+}
+
+/// Who's away, for the script to ask
+fn away(s: &Shared) -> Vec<String> {
+    s.joined_peers()
+        .filter(|(_, peer)| peer.away)
+        .map(|(_, peer)| peer.username.clone())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::scripting::{Action, Script};
+
+    #[test]
+    fn test_script() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("hooks.rhai");
+        std::fs::write(
+            &path,
+            "fn on_join(name) { chat(\"welcome \" + name); }\n\
+             fn on_tick(paused) { if paused > 600 { for name in away() { kick(name); } } }\n",
+        )
+        .unwrap();
+        let script = Script::load(&path).unwrap();
+        assert_eq!(
+            script
+                .run("on_join", ("alice".to_owned(),), vec![])
+                .unwrap(),
+            [Action::Chat("welcome alice".to_owned())]
+        );
+        // not every hook has to be there
+        assert!(script.run("on_resume", (), vec![]).unwrap().is_empty());
+
+        let away = || vec!["bob".to_owned()];
+        assert!(script.run("on_tick", (60_i64,), away()).unwrap().is_empty());
+        assert_eq!(
+            script.run("on_tick", (900_i64,), away()).unwrap(),
+            [Action::Kick("bob".to_owned())]
+        );
+    }
+}
@@ -0,0 +1,293 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    runtime::Handle,
+    sync::Mutex,
+    task::{self, JoinHandle},
+    time::sleep,
+};
+
+#[cfg(test)]
+use crate::proto::{exchange_preamble, frame, Limits, VoyeursCommand};
+use crate::{
+    client_message_handler::handle_connection,
+    logging::Logger,
+    mpv_event_handler::handle_event,
+    player::{FakePlayer, PlayerBackend, PlayerEvent},
+    Settings, Shared,
+};
+#[cfg(test)]
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+const PATIENCE: Duration = Duration::from_secs(3);
+
+/// A voyeurs instance driving a fake player instead of mpv
+pub(crate) struct Node {
+    pub player: FakePlayer,
+    pub state: Arc<Mutex<Shared>>,
+    pub settings: Settings,
+}
+
+impl Node {
+    pub fn new(is_serving: bool, username: &str) -> Node {
+        let mut shared = Shared::new();
+        shared.logger = Logger::new(None, None).expect("a quiet logger opens no file");
+        let player = FakePlayer::default();
+        player.set_paused(true).unwrap();
+        Node {
+            player,
+            state: Arc::new(Mutex::new(shared)),
+            settings: Settings {
+                is_serving,
+                username: username.to_owned(),
+                handshake_timeout: Duration::from_secs(5),
+                reconnect_grace: Duration::from_secs(5),
+                ..Default::default()
+            },
+        }
+    }
+
+    pub async fn serve(&self) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (player, state, settings) = self.parts();
+        tokio::spawn(async move {
+            while let Ok((stream, addr)) = listener.accept().await {
+                tokio::spawn(handle_connection(
+                    Box::new(player.clone()),
+                    addr,
+                    stream,
+                    Arc::clone(&state),
+                    settings.clone(),
+                    None,
+                    None,
+                ));
+            }
+        });
+        addr
+    }
+
+    pub async fn connect(&self, addr: SocketAddr) -> JoinHandle<()> {
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (player, state, settings) = self.parts();
+        tokio::spawn(handle_connection(
+            Box::new(player),
+            addr,
+            stream,
+            state,
+            settings,
+            None,
+            None,
+        ))
+    }
+
+    /// The user did something in the player
+    async fn event(&self, event: PlayerEvent) {
+        let (player, state, settings) = self.parts();
+        task::spawn_blocking(move || {
+            let handle = Handle::current();
+            let mut s = handle.block_on(state.lock());
+            // a real player would have echoed back what voyeurs did to it by now
+            s.ignore_next = false;
+            handle_event(&player, &mut s, &settings, &handle, event);
+        })
+        .await
+        .unwrap();
+    }
+
+    fn parts(&self) -> (FakePlayer, Arc<Mutex<Shared>>, Settings) {
+        (
+            self.player.clone(),
+            Arc::clone(&self.state),
+            self.settings.clone(),
+        )
+    }
+
+    fn paused(&self) -> bool {
+        self.player.is_paused().unwrap()
+    }
+
+    pub fn joined_peers(&self) -> Option<usize> {
+        Some(self.state.try_lock().ok()?.joined_peers().count())
+    }
+}
+
+pub(crate) async fn eventually(check: impl Fn() -> bool) -> bool {
+    let mut waited = Duration::ZERO;
+    while waited < PATIENCE {
+        if check() {
+            return true;
+        }
+        sleep(POLL_INTERVAL).await;
+        waited += POLL_INTERVAL;
+    }
+    check()
+}
+
+/// A peer that says who it is, then only ever talks and never listens
+#[cfg(test)]
+pub(crate) async fn join_deaf(addr: SocketAddr, username: &str) -> TcpStream {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    exchange_preamble(&mut stream).await.unwrap();
+    let hello = VoyeursCommand::NewConnection(username.to_owned());
+    say(&mut stream, hello).await;
+    stream
+}
+
+#[cfg(test)]
+pub(crate) async fn say(stream: &mut TcpStream, command: VoyeursCommand) {
+    let mut buf = bytes::BytesMut::new();
+    let max = Limits::default().max_packet;
+    frame(&command.craft_packet(), None, max, &mut buf).unwrap();
+    // the server may well have hung up on it already
+    let _ = stream.write_all(&buf).await;
+}
+
+/// Have a peer `server` showed the door try to seek anyway. Once the server hangs up on
+/// it, the peer is out of the shared state and anything it sends goes unread
+#[cfg(test)]
+pub(crate) async fn assert_ignored(server: &Node, mut stream: TcpStream, username: &str) {
+    say(&mut stream, VoyeursCommand::Seek(1234.0)).await;
+    let mut rest = vec![];
+    let hung_up = tokio::time::timeout(PATIENCE, stream.read_to_end(&mut rest)).await;
+    assert!(hung_up.is_ok(), "{username} is still connected");
+    assert_ne!(server.player.position(), Some(1234.0));
+    let s = server.state.lock().await;
+    assert!(s.peers.values().all(|peer| peer.username != username));
+}
+
+fn report(scenario: &str, passed: bool) -> bool {
+    println!("{scenario:.<40} {}", if passed { "ok" } else { "FAILED" });
+    passed
+}
+
+/// Run a server and two clients against fake players over loopback, returns whether
+/// every scenario passed
+pub async fn run() -> bool {
+    let server = Node::new(true, "host");
+    let alice = Node::new(false, "alice");
+    let bob = Node::new(false, "bob");
+
+    let addr = server.serve().await;
+    alice.connect(addr).await;
+    let bob_conn = bob.connect(addr).await;
+    let joined = eventually(|| server.joined_peers() == Some(2)).await;
+    if !report("clients join", joined) {
+        return false;
+    }
+
+    // Nobody plays until everyone is ready
+    server.player.set_paused(false).unwrap();
+    server.event(PlayerEvent::Pause(false)).await;
+    alice.player.set_paused(false).unwrap();
+    alice.event(PlayerEvent::Pause(false)).await;
+    let waiting = eventually(|| server.paused()).await;
+    bob.player.set_paused(false).unwrap();
+    bob.event(PlayerEvent::Pause(false)).await;
+    let playing = eventually(|| !server.paused() && !alice.paused() && !bob.paused()).await;
+    if !report("everyone ready starts playback", waiting && playing) {
+        return false;
+    }
+
+    server.player.seek(120.0, true).unwrap();
+    server.event(PlayerEvent::Seeking(false)).await;
+    let seeked = eventually(|| {
+        alice.player.position() == Some(120.0) && bob.player.position() == Some(120.0)
+    })
+    .await;
+    if !report("seek follows the host", seeked) {
+        return false;
+    }
+
+    alice.player.set_paused(true).unwrap();
+    alice.event(PlayerEvent::Pause(true)).await;
+    let paused = eventually(|| server.paused() && bob.paused()).await;
+    if !report("a client pausing pauses everyone", paused) {
+        return false;
+    }
+
+    // Dropping both halves of the connection is what closes it
+    bob_conn.abort();
+    bob.state.lock().await.peers.clear();
+    let left = eventually(|| server.joined_peers() == Some(1)).await;
+    let bob = Node::new(false, "bob");
+    bob.connect(addr).await;
+    // within the grace period, so bob picks up where it left off
+    let rejoined = eventually(|| {
+        server.joined_peers() == Some(2)
+            && server.state.try_lock().is_ok_and(|s| s.departed.is_empty())
+    })
+    .await;
+    report("a client reconnects", left && rejoined)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use crate::identity::Identity;
+    use crate::invite::Invite;
+    use crate::proto::VoyeursCommand;
+    use crate::selftest::{eventually, join_deaf, run, Node};
+    use crate::time::get_timestamp;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_selftest() {
+        assert!(run().await);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_invite_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let identity = Identity::load_or_create(&dir.path().join("identity")).unwrap();
+        let mut server = Node::new(true, "host");
+        server.settings.invite_only = true;
+        server.settings.identity = Some(identity.clone());
+        let addr = server.serve().await;
+
+        let mut alice = Node::new(false, "alice");
+        alice.settings.invite = Some(Invite::mint(&identity, get_timestamp() / 1000, 60));
+        alice.connect(addr).await;
+        assert!(eventually(|| server.joined_peers() == Some(1)).await);
+
+        let mut bob = Node::new(false, "bob");
+        bob.settings.join_code = Some(server.state.lock().await.join_codes.mint(Instant::now()));
+        bob.connect(addr).await;
+        assert!(eventually(|| server.joined_peers() == Some(2)).await);
+
+        // neither an invite nor a code, and bob's code is used up
+        let mallory = Node::new(false, "mallory");
+        let mallory = mallory.connect(addr).await;
+        let mut eve = Node::new(false, "eve");
+        eve.settings.join_code = bob.settings.join_code.clone();
+        let eve = eve.connect(addr).await;
+        assert!(eventually(|| mallory.is_finished() && eve.is_finished()).await);
+        assert_eq!(server.state.lock().await.joined_peers().count(), 2);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_slow_peer() {
+        let server = Node::new(true, "host");
+        let addr = server.serve().await;
+        // joins, then never reads a thing
+        let _stream = join_deaf(addr, "slowpoke").await;
+        assert!(eventually(|| server.joined_peers() == Some(1)).await);
+
+        // more than the socket buffers and the outbox together hold
+        let line = "popcorn? ".repeat(1000);
+        for _ in 0..4000 {
+            let message = VoyeursCommand::ServerMessage(line.clone());
+            server.state.lock().await.broadcast(message).await;
+        }
+        // gone for good, not waiting to come back like a dropped connection
+        let gone = eventually(|| {
+            server
+                .state
+                .try_lock()
+                .is_ok_and(|s| s.peers.is_empty() && s.departed.is_empty())
+        })
+        .await;
+        assert!(gone);
+    }
+}
@@ -0,0 +1,59 @@
+use tokio::sync::broadcast;
+
+// Events kept for a subscriber that falls behind, it misses the older ones
+const BACKLOG: usize = 64;
+
+/// Something that happened in the session, for whoever subscribed with
+/// `Shared::subscribe`: the webhook, or a frontend or bot built on top of voyeurs
+#[derive(Debug, Clone, PartialEq)]
+pub enum SessionEvent {
+    Join(String),
+    Leave(String),
+    /// who paused
+    Pause(String),
+    Resume,
+    /// who seeked when we know, and where to
+    Seek {
+        by: Option<String>,
+        time: f64,
+    },
+    Chat {
+        from: String,
+        text: String,
+    },
+    FileChange(String),
+}
+
+/// Where session events go, with nobody listening yet
+pub fn channel() -> broadcast::Sender<SessionEvent> {
+    broadcast::channel(BACKLOG).0
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::session::SessionEvent;
+    use crate::Shared;
+
+    #[test]
+    fn test_subscribe() {
+        let s = Shared::new();
+        // nobody listening is fine
+        s.announce(SessionEvent::Resume);
+
+        let mut bot = s.subscribe();
+        let mut gui = s.subscribe();
+        s.announce(SessionEvent::Join("alice".to_owned()));
+        s.announce(SessionEvent::Pause("alice".to_owned()));
+        for events in [&mut bot, &mut gui] {
+            assert_eq!(
+                events.try_recv().unwrap(),
+                SessionEvent::Join("alice".to_owned())
+            );
+            assert_eq!(
+                events.try_recv().unwrap(),
+                SessionEvent::Pause("alice".to_owned())
+            );
+            assert!(events.try_recv().is_err());
+        }
+    }
+}
@@ -0,0 +1,250 @@
+use std::{
+    collections::hash_map::RandomState,
+    future::Future,
+    hash::{BuildHasher, Hasher},
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    sync::{
+        mpsc::{unbounded_channel, UnboundedSender},
+        oneshot,
+    },
+    time::{sleep_until, Instant},
+};
+
+// What a lost segment costs over tcp: the minimum retransmission timeout
+const RETRANSMIT_DELAY: Duration = Duration::from_millis(200);
+
+/// A bad network to put outgoing packets through
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Conditions {
+    pub latency: Duration,
+    /// the latency varies up to this much either way
+    pub jitter: Duration,
+    /// fraction of packets that are lost and have to be retransmitted
+    pub loss: f64,
+}
+
+/// clap value parser for `latency=250ms,jitter=80ms,loss=1%`, every part being optional
+pub fn parse_conditions(s: &str) -> Result<Conditions, String> {
+    let mut conditions = Conditions::default();
+    for part in s.split(',') {
+        let (key, value) = part
+            .split_once('=')
+            .ok_or(format!("expected KEY=VALUE, got {part}"))?;
+        match key.trim() {
+            "latency" => conditions.latency = parse_ms(value)?,
+            "jitter" => conditions.jitter = parse_ms(value)?,
+            "loss" => {
+                let percent: f64 = value
+                    .trim()
+                    .trim_end_matches('%')
+                    .parse()
+                    .map_err(|_| format!("{value} isn't a percentage"))?;
+                if !(0.0..=100.0).contains(&percent) {
+                    return Err(format!("{value} isn't a percentage"));
+                }
+                conditions.loss = percent / 100.0;
+            }
+            key => {
+                return Err(format!(
+                    "unknown condition {key}, try latency, jitter or loss"
+                ))
+            }
+        }
+    }
+    Ok(conditions)
+}
+
+fn parse_ms(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let (number, unit) = match value.strip_suffix("ms") {
+        Some(number) => (number, 1.0),
+        None => (value.strip_suffix('s').unwrap_or(value), 1000.0),
+    };
+    let ms: f64 = number
+        .parse()
+        .map_err(|_| format!("{value} isn't a duration, try 250ms"))?;
+    if ms < 0.0 {
+        return Err(format!("{value} is negative"));
+    }
+    Ok(Duration::from_secs_f64(ms * unit / 1000.0))
+}
+
+impl Conditions {
+    /// How long a packet takes to get there, given two random numbers in [0, 1)
+    fn delay(&self, jitter_roll: f64, loss_roll: f64) -> Duration {
+        let jitter = self.jitter.as_secs_f64() * (jitter_roll * 2.0 - 1.0);
+        let mut delay = (self.latency.as_secs_f64() + jitter).max(0.0);
+        if loss_roll < self.loss {
+            delay += RETRANSMIT_DELAY.as_secs_f64();
+        }
+        Duration::from_secs_f64(delay)
+    }
+}
+
+// Good enough for simulating a network, every RandomState is keyed differently
+fn random() -> f64 {
+    let bits = RandomState::new().build_hasher().finish();
+    (bits >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// What goes through the simulated network, in order
+enum Segment {
+    Data(Instant, Vec<u8>),
+    /// everything before it is out, flushed, or shut down for good when `shutdown`
+    Marker {
+        shutdown: bool,
+        done: oneshot::Sender<io::Result<()>>,
+    },
+}
+
+/// Wraps a writer so that what's written goes out late, as if it went through a bad
+/// network. Order is kept like tcp would: a packet held back holds back the ones after it.
+pub struct Simulated {
+    tx: UnboundedSender<Segment>,
+    conditions: Conditions,
+    // when the previous write goes out, nothing can overtake it
+    last_due: Instant,
+    // the flush or shutdown waiting for the writes before it
+    pending: Option<oneshot::Receiver<io::Result<()>>>,
+}
+
+impl Simulated {
+    pub fn new(
+        mut inner: impl AsyncWrite + Send + Unpin + 'static,
+        conditions: Conditions,
+    ) -> Self {
+        let (tx, mut rx) = unbounded_channel();
+        tokio::spawn(async move {
+            while let Some(segment) = rx.recv().await {
+                match segment {
+                    Segment::Data(due, buf) => {
+                        sleep_until(due).await;
+                        if inner.write_all(&buf).await.is_err() {
+                            break;
+                        }
+                    }
+                    Segment::Marker { shutdown, done } => {
+                        let result = if shutdown {
+                            inner.shutdown().await
+                        } else {
+                            inner.flush().await
+                        };
+                        let _ = done.send(result);
+                        if shutdown {
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+        Self {
+            tx,
+            conditions,
+            last_due: Instant::now(),
+            pending: None,
+        }
+    }
+
+    /// Wait for what was written so far to go out, then flush or shut down the writer
+    fn poll_marker(&mut self, cx: &mut Context<'_>, shutdown: bool) -> Poll<io::Result<()>> {
+        let done = match &mut self.pending {
+            Some(done) => done,
+            None => {
+                let (done, rx) = oneshot::channel();
+                let marker = Segment::Marker { shutdown, done };
+                if self.tx.send(marker).is_err() {
+                    return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+                }
+                self.pending.insert(rx)
+            }
+        };
+        let result = ready!(Pin::new(done).poll(cx));
+        self.pending = None;
+        // The writer broke before getting to it
+        Poll::Ready(result.unwrap_or_else(|_| Err(io::ErrorKind::BrokenPipe.into())))
+    }
+}
+
+impl AsyncWrite for Simulated {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let due = Instant::now() + self.conditions.delay(random(), random());
+        let due = due.max(self.last_due);
+        self.last_due = due;
+        match self.tx.send(Segment::Data(due, buf.to_vec())) {
+            Ok(()) => Poll::Ready(Ok(buf.len())),
+            Err(_) => Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_marker(cx, false)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_marker(cx, true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simulate::{parse_conditions, Conditions, Simulated, RETRANSMIT_DELAY};
+    use std::time::{Duration, Instant};
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn test_parse_conditions() {
+        assert_eq!(
+            parse_conditions("latency=250ms,jitter=80ms,loss=1%"),
+            Ok(Conditions {
+                latency: Duration::from_millis(250),
+                jitter: Duration::from_millis(80),
+                loss: 0.01,
+            })
+        );
+        assert_eq!(
+            parse_conditions("latency=1s").unwrap().latency,
+            Duration::from_secs(1)
+        );
+        assert!(parse_conditions("latency=fast").is_err());
+        assert!(parse_conditions("loss=120%").is_err());
+        assert!(parse_conditions("bandwidth=1mbit").is_err());
+    }
+
+    #[test]
+    fn test_delay() {
+        let conditions = parse_conditions("latency=100ms,jitter=50ms,loss=10%").unwrap();
+        assert_eq!(conditions.delay(0.0, 0.5), Duration::from_millis(50));
+        assert_eq!(conditions.delay(0.5, 0.5), Duration::from_millis(100));
+        assert_eq!(
+            conditions.delay(0.5, 0.05),
+            Duration::from_millis(100) + RETRANSMIT_DELAY
+        );
+    }
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        let (tx, mut rx) = duplex(64);
+        let conditions = parse_conditions("latency=50ms").unwrap();
+        let mut simulated = Simulated::new(tx, conditions);
+        let start = Instant::now();
+        simulated.write_all(b"bye").await.unwrap();
+        // only done once the write went through the network
+        simulated.flush().await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        // the other end hears it's over while we still hold on to the writer
+        simulated.shutdown().await.unwrap();
+        let mut received = vec![];
+        rx.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"bye");
+    }
+}
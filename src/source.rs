@@ -0,0 +1,70 @@
+use serde_json::Value;
+use url::Url;
+
+// Schemes another peer can open too, unlike file://, edl:// or memory://
+const NETWORK_SCHEMES: &[&str] = &[
+    "http", "https", "ytdl", "rtmp", "rtmps", "rtsp", "rtp", "srt", "udp", "ftp", "sftp",
+];
+
+/// Where accept-source clients can play what we are playing from, if anywhere.
+/// `path` is what was opened, `opened` the stream-open-filename the hooks resolved it
+/// to, and `ytdl` what youtube-dl said about it.
+pub fn shareable(path: &str, opened: Option<&str>, ytdl: Option<&str>) -> Option<String> {
+    // What youtube-dl resolves to is often tied to our address and expires, the page
+    // it came from lets the client's youtube-dl do the same
+    if let Some(page) = ytdl.and_then(webpage_url) {
+        return Some(page);
+    }
+    [Some(path), opened]
+        .into_iter()
+        .flatten()
+        .find(|url| is_network(url))
+        .map(str::to_owned)
+}
+
+fn is_network(url: &str) -> bool {
+    // A windows path like C:\video.mkv parses as an url with the c scheme
+    Url::parse(url).is_ok_and(|url| NETWORK_SCHEMES.contains(&url.scheme()))
+}
+
+/// The page in youtube-dl's json output
+fn webpage_url(output: &str) -> Option<String> {
+    let info: Value = serde_json::from_str(output).ok()?;
+    let page = info["webpage_url"].as_str()?;
+    is_network(page).then(|| page.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::source::shareable;
+
+    #[test]
+    fn test_shareable() {
+        let page = "https://www.youtube.com/watch?v=dQw4w9WgXcQ";
+        assert_eq!(shareable(page, None, None).as_deref(), Some(page));
+        // youtube-dl resolved a short link, and mpv opened the video it found
+        let ytdl = format!(r#"{{"id": "dQw4w9WgXcQ", "webpage_url": "{page}"}}"#);
+        assert_eq!(
+            shareable(
+                "https://youtu.be/dQw4w9WgXcQ",
+                Some("edl://!no_clip;%123%https://rr1.googlevideo.com/..."),
+                Some(&ytdl)
+            )
+            .as_deref(),
+            Some(page)
+        );
+        // a local playlist pointing to a stream
+        assert_eq!(
+            shareable(
+                "/home/me/radio.m3u",
+                Some("https://radio.example.com/live.mp3"),
+                None
+            )
+            .as_deref(),
+            Some("https://radio.example.com/live.mp3")
+        );
+        assert_eq!(shareable("/home/me/video.mkv", None, None), None);
+        assert_eq!(shareable(r"C:\video.mkv", None, None), None);
+        assert_eq!(shareable("file:///home/me/video.mkv", None, None), None);
+    }
+}
@@ -0,0 +1,176 @@
+use serde_json::Value;
+use std::{error::Error, sync::Arc, time::Duration};
+use tokio::{sync::Mutex, time::interval};
+use url::Url;
+
+use crate::{
+    bookmarks::{format_time, parse_time},
+    player::PlayerBackend,
+    proto::*,
+    Settings, Shared,
+};
+
+const API: &str = "https://sponsor.ajay.app/api/skipSegments";
+const CATEGORIES: &str = r#"["sponsor","selfpromo","interaction"]"#;
+/// How often the position is checked, about how late a skip can be
+const SKIP_INTERVAL: Duration = Duration::from_millis(250);
+
+/// A stretch of the video everyone skips, start and end in seconds
+pub type Segment = (f64, f64);
+
+/// A range to skip in every file, like 0:00-1:30
+pub fn parse_segment(range: &str) -> Result<Segment, String> {
+    let (start, end) = range
+        .split_once(['-', '–'])
+        .ok_or("skip ranges look like START-END")?;
+    let time = |t: &str| parse_time(t).ok_or(format!("{t:?} isn't a time like 1:30"));
+    let (start, end) = (time(start)?, time(end)?);
+    if start >= end {
+        return Err("a skip range has to end after it starts".to_owned());
+    }
+    Ok((start, end))
+}
+
+/// The id of the youtube video at `url`
+fn video_id(url: &str) -> Option<String> {
+    let url = Url::parse(url).ok()?;
+    let host = url
+        .host_str()?
+        .trim_start_matches("www.")
+        .trim_start_matches("m.");
+    let id = match host {
+        "youtu.be" => url.path_segments()?.next()?.to_owned(),
+        "youtube.com" | "music.youtube.com" => match url.path_segments()?.collect::<Vec<_>>()[..] {
+            ["watch"] => url.query_pairs().find(|(k, _)| k == "v")?.1.into_owned(),
+            ["shorts" | "embed" | "live", id, ..] => id.to_owned(),
+            _ => return None,
+        },
+        _ => return None,
+    };
+    (!id.is_empty()).then_some(id)
+}
+
+/// The segments in SponsorBlock's answer, sorted
+fn parse(answer: &Value) -> Vec<Segment> {
+    let mut segments: Vec<Segment> = answer
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| {
+            let segment = entry["segment"].as_array()?;
+            Some((segment.first()?.as_f64()?, segment.get(1)?.as_f64()?))
+        })
+        .filter(|(start, end)| start < end)
+        .collect();
+    segments.sort_by(|a, b| a.0.total_cmp(&b.0));
+    segments
+}
+
+async fn fetch(video: &str) -> Result<Vec<Segment>, Box<dyn Error + Sync + Send>> {
+    let url = Url::parse_with_params(API, [("videoID", video), ("categories", CATEGORIES)])?;
+    let response = reqwest::Client::new().get(url).send().await?;
+    // 404 is how it says nobody submitted any
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(vec![]);
+    }
+    Ok(parse(&response.error_for_status()?.json::<Value>().await?))
+}
+
+/// Where to skip to from `time`, if it's in a segment
+fn skip_to(segments: &[Segment], time: f64) -> Option<f64> {
+    segments
+        .iter()
+        .find(|(start, end)| (*start..*end).contains(&time))
+        .map(|(_, end)| *end)
+}
+
+/// Skip the shared segments when playback gets to one, on every peer at the same time.
+/// The host also looks them up whenever it opens another youtube video.
+pub async fn run(player: Box<dyn PlayerBackend>, state: Arc<Mutex<Shared>>, settings: Settings) {
+    let mut ticker = interval(SKIP_INTERVAL);
+    let mut last_path = None;
+    loop {
+        ticker.tick().await;
+        if settings.is_serving && settings.sponsorblock {
+            let path = player.path();
+            if path != last_path {
+                last_path = path;
+                let segments = match player.stream_source().as_deref().and_then(video_id) {
+                    Some(video) => match fetch(&video).await {
+                        Ok(segments) => segments,
+                        // Not worth bothering anyone on the video about
+                        Err(e) => {
+                            let line = format!("couldn't get the segments from SponsorBlock: {e}");
+                            state.lock().await.log(line);
+                            vec![]
+                        }
+                    },
+                    None => vec![],
+                };
+                let mut s = state.lock().await;
+                // The host's own ranges are for every file
+                s.segments = s.skips.iter().copied().chain(segments).collect();
+                s.segments.sort_by(|a, b| a.0.total_cmp(&b.0));
+                let segments = s.segments.clone();
+                s.broadcast(VoyeursCommand::SkipSegments(segments)).await;
+            }
+        }
+
+        // Don't keep asking the player for nothing
+        if state.lock().await.segments.is_empty() {
+            continue;
+        }
+        let Some(time) = player.position() else {
+            continue;
+        };
+        let mut s = state.lock().await;
+        if let Some(end) = skip_to(&s.segments, time) {
+            // Everybody skips on their own, no need to tell them
+            s.ignore_next = true;
+            if player.seek(end, settings.exact_seek).is_ok() {
+                s.log(format!("skipped a segment, to {}", format_time(end)));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sponsorblock::{parse, parse_segment, skip_to, video_id};
+    use serde_json::json;
+
+    #[test]
+    fn test_video_id() {
+        let id = Some("dQw4w9WgXcQ".to_owned());
+        assert_eq!(
+            video_id("https://www.youtube.com/watch?v=dQw4w9WgXcQ&t=1"),
+            id
+        );
+        assert_eq!(video_id("https://youtu.be/dQw4w9WgXcQ"), id);
+        assert_eq!(video_id("https://m.youtube.com/shorts/dQw4w9WgXcQ"), id);
+        assert_eq!(video_id("https://www.youtube.com/feed/trending"), None);
+        assert_eq!(video_id("https://vimeo.com/123"), None);
+    }
+
+    #[test]
+    fn test_parse_segment() {
+        assert_eq!(parse_segment("00:00-01:30"), Ok((0.0, 90.0)));
+        assert_eq!(parse_segment("21:10–22:40"), Ok((1270.0, 1360.0)));
+        assert!(parse_segment("1:30-0:10").is_err());
+        assert!(parse_segment("1:30").is_err());
+    }
+
+    #[test]
+    fn test_segments() {
+        let answer = json!([
+            {"category": "selfpromo", "segment": [300.5, 320.0]},
+            {"category": "sponsor", "segment": [10.0, 42.25]},
+            {"category": "sponsor", "segment": [50.0]},
+        ]);
+        let segments = parse(&answer);
+        assert_eq!(segments, [(10.0, 42.25), (300.5, 320.0)]);
+        assert_eq!(skip_to(&segments, 10.0), Some(42.25));
+        assert_eq!(skip_to(&segments, 42.25), None);
+        assert_eq!(skip_to(&segments, 5.0), None);
+    }
+}
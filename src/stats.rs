@@ -0,0 +1,147 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::{Duration, Instant},
+};
+
+use crate::i18n::Msg;
+
+// A playhead falling behind the clock by less than this is just jitter in the reports
+const STALL_THRESHOLD: Duration = Duration::from_millis(500);
+
+/// Counters kept over the whole session, summed up when it ends
+#[derive(Debug)]
+pub struct Stats {
+    started: Instant,
+    /// how long the video was playing, pauses not included
+    pub watched: Duration,
+    /// pauses, by who caused them
+    pauses: BTreeMap<String, u32>,
+    /// time spent buffering, by peer
+    buffering: BTreeMap<String, Duration>,
+    /// latency samples by peer, summed up and counted
+    latency: BTreeMap<String, (u64, u64)>,
+    /// where each playhead was at the last report, while playing
+    playheads: HashMap<String, (Instant, f64)>,
+}
+
+impl Stats {
+    pub fn new(now: Instant) -> Stats {
+        Stats {
+            started: now,
+            watched: Duration::ZERO,
+            pauses: BTreeMap::new(),
+            buffering: BTreeMap::new(),
+            latency: BTreeMap::new(),
+            playheads: HashMap::new(),
+        }
+    }
+
+    /// How long since the session started
+    pub fn uptime(&self, now: Instant) -> Duration {
+        now.duration_since(self.started)
+    }
+
+    pub fn paused_by(&mut self, username: &str) {
+        *self.pauses.entry(username.to_owned()).or_default() += 1;
+    }
+
+    pub fn latency(&mut self, username: &str, ms: u64) {
+        let (sum, count) = self.latency.entry(username.to_owned()).or_default();
+        *sum += ms;
+        *count += 1;
+    }
+
+    /// Take note of where a playhead is. One that advanced slower than the clock while
+    /// playing was buffering.
+    pub fn position(&mut self, username: &str, time: f64, paused: bool, now: Instant) {
+        if paused {
+            self.playheads.remove(username);
+            return;
+        }
+        let last = self.playheads.insert(username.to_owned(), (now, time));
+        let Some((then, last_time)) = last else {
+            return;
+        };
+        let advanced = time - last_time;
+        // going back means a seek
+        if advanced < 0.0 {
+            return;
+        }
+        let elapsed = now.duration_since(then);
+        let stalled = elapsed.saturating_sub(Duration::from_secs_f64(advanced));
+        if stalled > STALL_THRESHOLD {
+            *self.buffering.entry(username.to_owned()).or_default() += stalled;
+        }
+    }
+
+    pub fn summary(&self, now: Instant) -> Vec<String> {
+        let mut lines = vec![Msg::Summary {
+            watched: self.watched.as_secs_f64(),
+            over: now.duration_since(self.started).as_secs_f64(),
+        }
+        .to_string()];
+        let pauses: u32 = self.pauses.values().sum();
+        if pauses > 0 {
+            let by: Vec<_> = self
+                .pauses
+                .iter()
+                .map(|(name, n)| format!("{name} {n}"))
+                .collect();
+            let (count, by) = (pauses, by.join(", "));
+            lines.push(Msg::Pauses { count, by: &by }.to_string());
+        }
+        let peers = self.buffering.keys().chain(self.latency.keys());
+        let mut peers: Vec<_> = peers.collect();
+        peers.sort();
+        peers.dedup();
+        for peer in peers {
+            let buffering = self.buffering.get(peer).copied().unwrap_or_default();
+            let time = buffering.as_secs_f64();
+            let mut line = Msg::Buffered { name: peer, time }.to_string();
+            if let Some((sum, count)) = self.latency.get(peer) {
+                line += &Msg::AverageLatency(sum / count).to_string();
+            }
+            lines.push(line);
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::i18n::{set_locale, Locale};
+    use crate::stats::Stats;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_stats() {
+        set_locale(Locale::En);
+        let now = Instant::now();
+        let mut stats = Stats::new(now);
+        stats.watched = Duration::from_secs(100);
+        stats.paused_by("alice");
+        stats.paused_by("alice");
+        stats.paused_by("bob");
+        stats.latency("bob", 40);
+        stats.latency("bob", 60);
+
+        let secs = |s| now + Duration::from_secs(s);
+        stats.position("bob", 10.0, false, secs(0));
+        stats.position("bob", 12.0, false, secs(2));
+        // stuck for 3 of these 4 seconds
+        stats.position("bob", 13.0, false, secs(6));
+        // a seek back isn't buffering
+        stats.position("bob", 1.0, false, secs(8));
+        stats.position("bob", 1.0, true, secs(10));
+        stats.position("bob", 1.0, false, secs(20));
+
+        assert_eq!(
+            stats.summary(secs(120)),
+            [
+                "Session summary: watched 1:40 in 2:00",
+                "3 pauses: alice 2, bob 1",
+                "bob: buffered for 0:03, average latency 50ms",
+            ]
+        );
+    }
+}
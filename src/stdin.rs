@@ -0,0 +1,131 @@
+use std::{io::BufRead, sync::Arc, thread};
+use tokio::sync::{mpsc, Mutex};
+
+#[cfg(unix)]
+use crate::control;
+use crate::{i18n::Msg, player::PlayerBackend, Settings, Shared};
+
+/// Chat and /commands typed on the terminal voyeurs was started from, for whoever
+/// doesn't have the chat script in mpv
+pub async fn run(
+    mut player: Box<dyn PlayerBackend>,
+    state: Arc<Mutex<Shared>>,
+    settings: Settings,
+) {
+    // tokio's stdin would keep the runtime from shutting down while it waits for a line
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    thread::spawn(move || {
+        for line in std::io::stdin().lock().lines() {
+            let Ok(line) = line else { break };
+            if tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    while let Some(line) = rx.recv().await {
+        let mut s = state.lock().await;
+        handle(&mut *player, &mut s, &settings, line.trim()).await;
+    }
+}
+
+async fn handle(player: &mut dyn PlayerBackend, s: &mut Shared, settings: &Settings, line: &str) {
+    let Some(command) = line.strip_prefix('/') else {
+        if line.is_empty() {
+            return;
+        }
+        if let Err(e) = s
+            .chat(s.username(settings).to_owned(), line.to_owned())
+            .await
+        {
+            s.log(e);
+        }
+        return;
+    };
+    let (name, arg) = command.split_once(' ').unwrap_or((command, ""));
+    let arg = arg.trim();
+    let result = match name {
+        "play" => player.set_paused(false),
+        "pause" => player.set_paused(true),
+        "quit" => {
+            player.quit();
+            s.say_goodbye().await;
+            s.end_session()
+        }
+        "marks" => {
+            let lines: Vec<_> = s
+                .bookmarks
+                .iter()
+                .map(|(name, time)| Msg::Bookmark { name, time }.to_string())
+                .collect();
+            lines.into_iter().for_each(|line| s.log(line));
+            Ok(())
+        }
+        "mark" => {
+            let time = player.position().unwrap_or_default();
+            s.add_bookmark(arg.to_owned(), time).await;
+            Ok(())
+        }
+        // Seeking locally is broadcast like any other seek
+        "goto" => match s.bookmarks.get(arg) {
+            Some(time) => player.seek(time, settings.exact_seek),
+            None => {
+                s.log(format!("No bookmark called {arg}"));
+                Ok(())
+            }
+        },
+        // The rest is what voyeursctl can do
+        #[cfg(unix)]
+        _ => {
+            match control::execute(player, s, settings, command).await {
+                Ok(reply) => reply.lines().for_each(|line| s.log(line.to_owned())),
+                Err(e) => s.log(e),
+            }
+            Ok(())
+        }
+        #[cfg(not(unix))]
+        _ => {
+            s.log(format!("Unknown command: /{name}"));
+            Ok(())
+        }
+    };
+    if let Err(e) = result {
+        s.log(format!("/{name} failed: {e}"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::player::{FakePlayer, PlayerBackend};
+    use crate::stdin::handle;
+    use crate::{Settings, Shared};
+
+    #[tokio::test]
+    async fn test_handle() {
+        let mut player = FakePlayer::default();
+        let mut s = Shared::new();
+        s.tui = true;
+        let settings = Settings {
+            username: "alice".to_owned(),
+            ..Default::default()
+        };
+
+        handle(&mut player, &mut s, &settings, "/pause").await;
+        assert_eq!(player.is_paused().ok(), Some(true));
+
+        player.seek(90.0, true).unwrap();
+        handle(&mut player, &mut s, &settings, "/mark intro").await;
+        player.seek(300.0, true).unwrap();
+        handle(&mut player, &mut s, &settings, "/goto intro").await;
+        assert_eq!(player.position(), Some(90.0));
+
+        handle(&mut player, &mut s, &settings, "hi all").await;
+        assert_eq!(s.events.back().map(String::as_str), Some("alice: hi all"));
+        // more than peers take, it stays here instead of dropping them
+        handle(&mut player, &mut s, &settings, &"a".repeat(2000)).await;
+        assert_eq!(
+            s.events.back().map(String::as_str),
+            Some("The chat message is 2000 bytes long, more than the limit of 1024")
+        );
+        assert_eq!(s.recent_chat.len(), 1);
+    }
+}
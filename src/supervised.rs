@@ -0,0 +1,158 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex, MutexGuard,
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::player::{PlayerBackend, PlayerConnection, PlayerError, PlayerEvent};
+
+/// Bumped every time --daemon brings the player back after it quit
+pub static RESTARTS: AtomicUsize = AtomicUsize::new(0);
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A connection to an mpv that gets restarted when it quits. Every call goes to the
+/// latest instance, so the tasks holding one don't notice the restart and the peers stay.
+pub struct Supervised {
+    socket: String,
+    /// the connection, and how many restarts ago it was opened
+    current: Mutex<(usize, Box<dyn PlayerBackend>)>,
+    observing: bool,
+}
+
+impl Supervised {
+    pub fn connect(socket: &str) -> Result<Supervised, PlayerError> {
+        let restarts = RESTARTS.load(Ordering::SeqCst);
+        let player = PlayerConnection::Mpv(socket.to_owned()).try_connect()?;
+        Ok(Supervised {
+            socket: socket.to_owned(),
+            current: Mutex::new((restarts, player)),
+            observing: false,
+        })
+    }
+
+    fn player(&self) -> MutexGuard<'_, (usize, Box<dyn PlayerBackend>)> {
+        let mut current = self.current.lock().unwrap();
+        let restarts = RESTARTS.load(Ordering::SeqCst);
+        if current.0 != restarts {
+            // the restart only gets counted once mpv takes connections
+            if let Ok(player) = PlayerConnection::Mpv(self.socket.clone()).try_connect() {
+                *current = (restarts, player);
+            }
+        }
+        current
+    }
+}
+
+impl PlayerBackend for Supervised {
+    fn is_paused(&self) -> Result<bool, PlayerError> {
+        self.player().1.is_paused()
+    }
+
+    fn set_paused(&self, paused: bool) -> Result<(), PlayerError> {
+        self.player().1.set_paused(paused)
+    }
+
+    fn seek(&self, t: f64, exact: bool) -> Result<(), PlayerError> {
+        self.player().1.seek(t, exact)
+    }
+
+    fn position(&self) -> Option<f64> {
+        self.player().1.position()
+    }
+
+    fn duration(&self) -> Option<f64> {
+        self.player().1.duration()
+    }
+
+    fn file_size(&self) -> Option<u64> {
+        self.player().1.file_size()
+    }
+
+    fn live_lag(&self) -> Option<f64> {
+        self.player().1.live_lag()
+    }
+
+    fn set_speed(&self, speed: f64) -> Result<(), PlayerError> {
+        self.player().1.set_speed(speed)
+    }
+
+    fn filename(&self) -> Option<String> {
+        self.player().1.filename()
+    }
+
+    fn path(&self) -> Option<String> {
+        self.player().1.path()
+    }
+
+    fn stream_source(&self) -> Option<String> {
+        self.player().1.stream_source()
+    }
+
+    fn playlist_pos(&self) -> Option<u64> {
+        self.player().1.playlist_pos()
+    }
+
+    fn playlist_count(&self) -> Option<u64> {
+        self.player().1.playlist_count()
+    }
+
+    fn set_playlist_pos(&self, pos: u64) -> Result<(), PlayerError> {
+        self.player().1.set_playlist_pos(pos)
+    }
+
+    fn load(&mut self, url: &str) -> Result<(), PlayerError> {
+        self.player().1.load(url)
+    }
+
+    fn show_text(&self, text: &str, duration: u32) -> Result<(), PlayerError> {
+        self.player().1.show_text(text, duration)
+    }
+
+    fn show_from(&self, username: &str, line: &str, duration: u32) -> Result<(), PlayerError> {
+        self.player().1.show_from(username, line, duration)
+    }
+
+    fn set_sync_status(&self, drift: &str, health: &str) {
+        self.player().1.set_sync_status(drift, health)
+    }
+
+    fn set_panel(&self, panel: &str) {
+        self.player().1.set_panel(panel)
+    }
+
+    fn stop(&self) {
+        self.player().1.stop()
+    }
+
+    fn quit(&self) {
+        self.player().1.quit()
+    }
+
+    fn observe(&mut self) -> Result<(), PlayerError> {
+        self.observing = true;
+        self.player().1.observe()
+    }
+
+    // The player quitting isn't the end of the session, wait for it to come back instead
+    fn next_event(&mut self) -> Result<PlayerEvent, PlayerError> {
+        loop {
+            let event = self.player().1.next_event();
+            match event {
+                Ok(PlayerEvent::Shutdown) | Err(_) => {
+                    let seen = self.current.get_mut().unwrap().0;
+                    while RESTARTS.load(Ordering::SeqCst) == seen {
+                        thread::sleep(POLL_INTERVAL);
+                    }
+                    if self.observing {
+                        self.player().1.observe()?;
+                    }
+                }
+                event => return event,
+            }
+        }
+    }
+}
@@ -0,0 +1,153 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{sync::Mutex, time::interval};
+
+use crate::{
+    i18n::Msg,
+    osd::{notify, Category},
+    panel,
+    player::{PlayerBackend, PlayerError},
+    proto::*,
+    time::get_weighted_latency,
+    Peer, Settings, Shared,
+};
+
+pub const TELEMETRY_INTERVAL: Duration = Duration::from_secs(2);
+/// Give a corrected peer time to finish seeking before judging its drift again
+pub const RESYNC_COOLDOWN: Duration = Duration::from_secs(6);
+/// Seconds off a peer we put up with after the player restarted playback
+pub const RESTART_TOLERANCE: f64 = 0.2;
+/// How long to leave the user alone after telling them they drifted
+const DRIFT_ALERT_INTERVAL: Duration = Duration::from_secs(30);
+
+// Thresholds for the health indicator, drift in seconds and latency in milliseconds
+const GOOD_DRIFT: f64 = 0.5;
+const BAD_DRIFT: f64 = 2.0;
+const GOOD_LATENCY: u64 = 150;
+const BAD_LATENCY: u64 = 500;
+
+// Following a live stream: close enough, how much faster or slower to play to get there,
+// and past which distance it's better to just seek
+const LIVE_TOLERANCE: f64 = 0.3;
+const LIVE_CATCHUP: f64 = 0.05;
+const LIVE_JUMP: f64 = 5.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Health {
+    Good,
+    Degraded,
+    Bad,
+}
+
+impl Health {
+    pub fn color(self) -> &'static str {
+        match self {
+            Health::Good => "green",
+            Health::Degraded => "yellow",
+            Health::Bad => "red",
+        }
+    }
+}
+
+/// Catch up with the host's distance from the live edge, given how many seconds we are ahead of it
+pub fn follow_live_edge(player: &dyn PlayerBackend, ahead: f64) -> Result<(), PlayerError> {
+    if ahead.abs() > LIVE_JUMP {
+        // Too far to get there by changing speed, skip the difference
+        let Some(position) = player.position() else {
+            return Ok(());
+        };
+        player.seek(position - ahead, false)?;
+        player.set_speed(1.0)
+    } else if ahead > LIVE_TOLERANCE {
+        player.set_speed(1.0 - LIVE_CATCHUP)
+    } else if ahead < -LIVE_TOLERANCE {
+        player.set_speed(1.0 + LIVE_CATCHUP)
+    } else {
+        player.set_speed(1.0)
+    }
+}
+
+/// How well we're keeping up with a peer, judging from its latest position report
+pub fn peer_health(peer: &Peer) -> Health {
+    let stale = peer
+        .last_position
+        .is_none_or(|t| t.elapsed() > TELEMETRY_INTERVAL * 3);
+    let latency = if peer.latency.is_empty() {
+        0
+    } else {
+        get_weighted_latency(&peer.latency)
+    };
+    match peer.drift.map(f64::abs) {
+        _ if stale || latency > BAD_LATENCY => Health::Bad,
+        Some(drift) if drift > BAD_DRIFT => Health::Bad,
+        Some(drift) if drift <= GOOD_DRIFT && latency <= GOOD_LATENCY => Health::Good,
+        _ => Health::Degraded,
+    }
+}
+
+/// Periodically tell the peers where we are, and publish how far off they are
+/// to the player's sync indicator.
+/// Positions mean nothing across live streams, there peers compare how far they are from the live edge.
+pub async fn run_telemetry(
+    player: Box<dyn PlayerBackend>,
+    state: Arc<Mutex<Shared>>,
+    settings: Settings,
+) {
+    let mut ticker = interval(TELEMETRY_INTERVAL);
+    let mut alerted: Option<Instant> = None;
+    loop {
+        ticker.tick().await;
+        let paused = player.is_paused().unwrap_or_default();
+        let position = player.position();
+        let report = if settings.live {
+            player.live_lag().map(VoyeursCommand::LiveLag)
+        } else {
+            position.map(|time| VoyeursCommand::Position { time, paused })
+        };
+
+        let mut s = state.lock().await;
+        if !paused {
+            s.stats.watched += TELEMETRY_INTERVAL;
+        }
+        if let Some(time) = position {
+            let username = s.username(&settings).to_owned();
+            s.stats.position(&username, time, paused, Instant::now());
+        }
+        player.set_panel(&panel::render(&s, &settings));
+        let Some(report) = report else {
+            continue;
+        };
+        s.broadcast(report).await;
+        // Don't wait on a host whose reports stopped coming, ask it
+        let stale = s.joined_peers().any(|(_, host)| {
+            host.last_position
+                .is_some_and(|t| t.elapsed() > TELEMETRY_INTERVAL * 2)
+        });
+        if !settings.is_serving && !settings.live && stale {
+            s.query_state(false).await;
+        }
+
+        let worst_drift = s
+            .joined_peers()
+            .filter_map(|(_, p)| p.drift)
+            .max_by(|a, b| a.abs().total_cmp(&b.abs()));
+        let health = s.joined_peers().map(|(_, p)| peer_health(p)).max();
+        let drift = worst_drift.map_or("-".to_owned(), |d| format!("{d:+.2}s"));
+        let color = health.map_or("", Health::color);
+        player.set_sync_status(&drift, color);
+
+        // Speak up before a scene spoils itself, clients only hear about the host
+        let alert = settings.drift_alert;
+        let drifting = worst_drift.filter(|d| alert > 0.0 && d.abs() > alert);
+        if let (false, Some(drift)) = (settings.is_serving, drifting) {
+            if alerted.is_none_or(|t| t.elapsed() > DRIFT_ALERT_INTERVAL) {
+                alerted = Some(Instant::now());
+                let line = Msg::Drifting(drift).to_string();
+                let _ = notify(&*player, &settings.osd, Category::Warning, &line);
+                s.log(line);
+            }
+        }
+    }
+}
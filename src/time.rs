@@ -1,36 +1,144 @@
 use lazy_static::lazy_static;
 use rsntp::SntpClient;
 use std::collections::VecDeque;
+use std::error::Error;
 use std::sync::atomic::AtomicI64;
 use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::Mutex;
+use tokio::time::interval;
 
 use crate::proto::TsSize;
+use crate::Shared;
 
 lazy_static! {
     pub static ref TIME_DELTA: Arc<AtomicI64> = Arc::new(AtomicI64::new(0));
+    // The wall clock is read only once, everything after that is measured on the
+    // monotonic clock, so a system clock jump mid-session can't skew timestamps
+    static ref ANCHOR: (Instant, i64) = (Instant::now(), system_millis());
 }
 
-pub fn get_timestamp() -> TsSize {
+const CLOCK_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+const CLOCK_RESYNC_INTERVAL: Duration = Duration::from_secs(5 * 60);
+const INITIAL_CLOCK_SAMPLES: usize = 3;
+const MAX_CLOCK_SAMPLES: usize = 9;
+const MAX_CLOCK_JUMP: i64 = 1000;
+
+fn system_millis() -> i64 {
     SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .expect("Couldn't get system time")
-        .as_millis() as TsSize
-        + TIME_DELTA.load(std::sync::atomic::Ordering::SeqCst) as TsSize
+        .as_millis() as i64
+}
+
+/// Milliseconds since the epoch, as seen by the monotonic clock
+fn local_millis() -> i64 {
+    ANCHOR.1 + ANCHOR.0.elapsed().as_millis() as i64
+}
+
+pub fn get_timestamp() -> TsSize {
+    (local_millis() + TIME_DELTA.load(std::sync::atomic::Ordering::SeqCst)) as TsSize
+}
+
+/// Filtered estimate of the offset between the local clock and the ntp server.
+/// It's the median of the last few measurements, so a single noisy one can't skew the session.
+#[derive(Debug, Default)]
+pub struct ClockModel {
+    samples: VecDeque<i64>,
+}
+
+impl ClockModel {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_sample(&mut self, offset: i64) {
+        if self.samples.len() == MAX_CLOCK_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(offset);
+    }
+
+    pub fn offset(&self) -> i64 {
+        let mut sorted: Vec<i64> = self.samples.iter().copied().collect();
+        sorted.sort_unstable();
+        match sorted.len() {
+            0 => 0,
+            n if n % 2 == 0 => (sorted[n / 2 - 1] + sorted[n / 2]) / 2,
+            n => sorted[n / 2],
+        }
+    }
 }
 
-pub fn set_time_delta(ntp_server: String) {
-    let client = SntpClient::new();
-    let result = client
-        .synchronize(ntp_server)
-        .expect("Coudn't syncronize time with ntp server");
-    let delta: i64 = result.datetime().unix_timestamp().expect("msg").as_millis() as i64
-        - SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("Couldn't get system time")
-            .as_millis() as i64;
-    println!("Clock skew: {} ms", delta);
-    TIME_DELTA.store(delta, std::sync::atomic::Ordering::SeqCst);
+fn measure_offset(ntp_server: &str) -> Result<i64, Box<dyn Error + Sync + Send>> {
+    let result = SntpClient::new().synchronize(ntp_server)?;
+    let ntp_time = result
+        .datetime()
+        .unix_timestamp()
+        .map_err(|_| "ntp time is before the unix epoch")?;
+    Ok(ntp_time.as_millis() as i64 - local_millis())
+}
+
+/// Take a first few measurements, good enough to start the session with
+pub fn set_time_delta(ntp_server: &str, s: &mut Shared) -> ClockModel {
+    let mut model = ClockModel::new();
+    for _ in 0..INITIAL_CLOCK_SAMPLES {
+        match measure_offset(ntp_server) {
+            Ok(offset) => model.add_sample(offset),
+            Err(e) => s.log(format!("Couldn't syncronize time with ntp server: {e}")),
+        }
+    }
+    if model.samples.is_empty() {
+        panic!("Coudn't syncronize time with ntp server");
+    }
+    s.log(format!("Clock skew: {} ms", model.offset()));
+    TIME_DELTA.store(model.offset(), std::sync::atomic::Ordering::SeqCst);
+    model
+}
+
+/// Keep measuring the offset for the whole session, refining the estimate
+pub async fn refine_time_delta(
+    ntp_server: String,
+    mut model: ClockModel,
+    state: Arc<Mutex<Shared>>,
+) {
+    let mut ticker = interval(CLOCK_RESYNC_INTERVAL);
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        let server = ntp_server.clone();
+        match tokio::task::spawn_blocking(move || measure_offset(&server))
+            .await
+            .unwrap()
+        {
+            Ok(offset) => {
+                model.add_sample(offset);
+                TIME_DELTA.store(model.offset(), std::sync::atomic::Ordering::SeqCst);
+            }
+            Err(e) => state
+                .lock()
+                .await
+                .log(format!("Couldn't syncronize time with ntp server: {e}")),
+        }
+    }
+}
+
+/// Keep an eye on the system clock, reporting when it jumps away from the monotonic one.
+/// Timestamps aren't affected either way.
+pub async fn watch_clock(state: Arc<Mutex<Shared>>) {
+    let mut ticker = interval(CLOCK_CHECK_INTERVAL);
+    let mut last_offset = system_millis() - local_millis();
+    loop {
+        ticker.tick().await;
+        let offset = system_millis() - local_millis();
+        if (offset - last_offset).abs() > MAX_CLOCK_JUMP {
+            state.lock().await.log(format!(
+                "system clock jumped by {} ms, ignoring it",
+                offset - last_offset
+            ));
+        }
+        last_offset = offset;
+    }
 }
 
 pub static MAX_QUEUE_LATENCY: usize = 10;
@@ -43,3 +151,28 @@ pub fn get_weighted_latency(latency: &VecDeque<u64>) -> u64 {
         .sum::<u64>()
         / latency.len() as u64
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::time::{ClockModel, MAX_CLOCK_SAMPLES};
+
+    #[test]
+    fn test_clock_model() {
+        let mut model = ClockModel::new();
+        assert_eq!(model.offset(), 0);
+
+        model.add_sample(100);
+        model.add_sample(120);
+        assert_eq!(model.offset(), 110);
+
+        // a single bad measurement barely moves the estimate
+        model.add_sample(5000);
+        assert_eq!(model.offset(), 120);
+
+        // old samples age out
+        for _ in 0..MAX_CLOCK_SAMPLES {
+            model.add_sample(-40);
+        }
+        assert_eq!(model.offset(), -40);
+    }
+}
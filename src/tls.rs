@@ -0,0 +1,130 @@
+use std::{
+    error::Error,
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+    time::timeout,
+};
+use tokio_rustls::{
+    rustls::{
+        server::AllowAnyAuthenticatedClient, Certificate, ClientConfig, PrivateKey, RootCertStore,
+        ServerConfig, ServerName,
+    },
+    server, TlsAcceptor, TlsConnector,
+};
+
+type TlsError = Box<dyn Error + Sync + Send>;
+
+/// A connection to a peer, encrypted or not
+pub trait Stream: AsyncRead + AsyncWrite + Send + Unpin {}
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Stream for T {}
+
+/// Our certificate and key, and the CA the other end's certificate has to be signed by
+#[derive(Debug, Clone)]
+pub struct TlsFiles {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    pub ca: PathBuf,
+}
+
+impl TlsFiles {
+    /// Only clients presenting a certificate signed by the CA get past the handshake
+    pub fn acceptor(&self) -> Result<TlsAcceptor, TlsError> {
+        let verifier = AllowAnyAuthenticatedClient::new(self.roots()?).boxed();
+        let config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(load_certs(&self.cert)?, load_key(&self.key)?)?;
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+
+    pub fn connector(&self) -> Result<TlsConnector, TlsError> {
+        let config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(self.roots()?)
+            .with_client_auth_cert(load_certs(&self.cert)?, load_key(&self.key)?)?;
+        Ok(TlsConnector::from(Arc::new(config)))
+    }
+
+    fn roots(&self) -> Result<RootCertStore, TlsError> {
+        let mut roots = RootCertStore::empty();
+        for cert in load_certs(&self.ca)? {
+            roots.add(&cert)?;
+        }
+        Ok(roots)
+    }
+}
+
+fn load_certs(path: &Path) -> Result<Vec<Certificate>, TlsError> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))?;
+    if certs.is_empty() {
+        return Err(format!("no certificate in {}", path.display()).into());
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_key(path: &Path) -> Result<PrivateKey, TlsError> {
+    let mut reader = BufReader::new(File::open(path)?);
+    while let Some(item) = rustls_pemfile::read_one(&mut reader)? {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => {}
+        }
+    }
+    Err(format!("no private key in {}", path.display()).into())
+}
+
+/// The name the server's certificate has to be issued to, from an address:port
+pub fn server_name(address: &str) -> Result<ServerName, TlsError> {
+    Ok(ServerName::try_from(host(address))?)
+}
+
+fn host(address: &str) -> &str {
+    let host = address
+        .rsplit_once(':')
+        .map_or(address, |(host, _port)| host);
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+/// Complete the tls handshake with a client, returning the username its certificate
+/// was issued to
+pub async fn accept(
+    acceptor: &TlsAcceptor,
+    stream: TcpStream,
+    limit: Duration,
+) -> Result<(server::TlsStream<TcpStream>, String), TlsError> {
+    let stream = timeout(limit, acceptor.accept(stream))
+        .await
+        .map_err(|_| "timed out during the tls handshake")??;
+    let name = certified_name(&stream).ok_or("the client certificate has no common name")?;
+    Ok((stream, name))
+}
+
+// The common name of the client certificate
+fn certified_name(stream: &server::TlsStream<TcpStream>) -> Option<String> {
+    let (_, conn) = stream.get_ref();
+    let cert = conn.peer_certificates()?.first()?;
+    let (_, cert) = x509_parser::parse_x509_certificate(&cert.0).ok()?;
+    let name = cert.subject().iter_common_name().next()?.as_str().ok()?;
+    Some(name.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tls::host;
+
+    #[test]
+    fn test_host() {
+        assert_eq!(host("example.com:8998"), "example.com");
+        assert_eq!(host("127.0.0.1:8998"), "127.0.0.1");
+        assert_eq!(host("[::1]:8998"), "::1");
+    }
+}
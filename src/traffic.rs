@@ -0,0 +1,125 @@
+use std::{cmp::Reverse, collections::BTreeMap, sync::Arc, time::Duration};
+use tokio::{sync::Mutex, time::interval};
+
+use crate::{record::Direction, Shared};
+
+// Commands listed per peer in the summary, the ones moving the most bytes
+const TOP_COMMANDS: usize = 3;
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Count {
+    packets: u64,
+    bytes: u64,
+}
+
+impl Count {
+    fn add(&mut self, other: Count) {
+        self.packets += other.packets;
+        self.bytes += other.bytes;
+    }
+}
+
+/// Packets and bytes each way, by peer and command, for --stats
+#[derive(Debug, Default)]
+pub struct Traffic {
+    counts: BTreeMap<String, BTreeMap<(&'static str, bool), Count>>,
+}
+
+impl Traffic {
+    pub fn count(&mut self, direction: Direction, peer: &str, command: &'static str, bytes: usize) {
+        let sent = direction == Direction::Out;
+        let count = self
+            .counts
+            .entry(peer.to_owned())
+            .or_default()
+            .entry((command, sent))
+            .or_default();
+        count.packets += 1;
+        count.bytes += bytes as u64;
+    }
+
+    pub fn summary(&self) -> Vec<String> {
+        self.counts
+            .iter()
+            .map(|(peer, commands)| {
+                let mut sent = Count::default();
+                let mut received = Count::default();
+                let mut by_command: BTreeMap<&str, Count> = BTreeMap::new();
+                for (&(command, out), count) in commands {
+                    if out {
+                        sent.add(*count);
+                    } else {
+                        received.add(*count);
+                    }
+                    by_command.entry(command).or_default().add(*count);
+                }
+                let mut top: Vec<_> = by_command.into_iter().collect();
+                top.sort_by_key(|(_, count)| Reverse(count.bytes));
+                let top: Vec<_> = top
+                    .iter()
+                    .take(TOP_COMMANDS)
+                    .map(|(command, count)| {
+                        format!(
+                            "{command} {} ({})",
+                            count.packets,
+                            format_bytes(count.bytes)
+                        )
+                    })
+                    .collect();
+                format!(
+                    "{peer}: sent {} packets ({}), received {} ({}), mostly {}",
+                    sent.packets,
+                    format_bytes(sent.bytes),
+                    received.packets,
+                    format_bytes(received.bytes),
+                    top.join(", ")
+                )
+            })
+            .collect()
+    }
+}
+
+/// Log the counters every `every`, for hosts watching what a big room costs
+pub async fn report(state: Arc<Mutex<Shared>>, every: Duration) {
+    let mut ticker = interval(every);
+    // the first tick is right away, with nothing to say
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        let mut s = state.lock().await;
+        let lines = s.traffic.as_ref().map(Traffic::summary).unwrap_or_default();
+        lines.into_iter().for_each(|line| s.log(line));
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    match bytes {
+        0..=1023 => format!("{bytes} B"),
+        1024..=1048575 => format!("{:.1} KiB", bytes as f64 / 1024.0),
+        _ => format!("{:.1} MiB", bytes as f64 / 1048576.0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::record::Direction;
+    use crate::traffic::Traffic;
+
+    #[test]
+    fn test_traffic() {
+        let mut traffic = Traffic::default();
+        for _ in 0..100 {
+            traffic.count(Direction::In, "bob", "position", 30);
+        }
+        traffic.count(Direction::In, "bob", "chat", 60);
+        traffic.count(Direction::Out, "bob", "seek", 20);
+        traffic.count(Direction::Out, "bob", "ready", 12);
+        traffic.count(Direction::Out, "bob", "ready", 12);
+
+        assert_eq!(
+            traffic.summary(),
+            ["bob: sent 3 packets (44 B), received 101 (3.0 KiB), \
+              mostly position 100 (2.9 KiB), chat 1 (60 B), ready 2 (24 B)"]
+        );
+    }
+}
@@ -0,0 +1,112 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use crate::identity::{encode_key, parse_key};
+
+/// The keys the host knows, from a file with a `KEY NAME` per line. A key that isn't
+/// there yet gets added once the password, an invite or a join code vouched for it, like
+/// ssh's known_hosts
+#[derive(Clone)]
+pub struct TrustList {
+    path: PathBuf,
+    // shared by every room and connection
+    keys: Arc<Mutex<HashMap<[u8; 32], String>>>,
+}
+
+impl TrustList {
+    /// Read the list, an empty one when the file isn't there yet
+    pub fn load(path: &Path) -> io::Result<TrustList> {
+        let keys = match fs::read_to_string(path) {
+            Ok(list) => parse(&list).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(TrustList {
+            path: path.to_owned(),
+            keys: Arc::new(Mutex::new(keys)),
+        })
+    }
+
+    /// The name the key was first trusted under
+    pub fn name(&self, key: &[u8; 32]) -> Option<String> {
+        self.keys.lock().unwrap().get(key).cloned()
+    }
+
+    /// Trust `key` from now on, in this session and the next ones
+    pub fn add(&self, key: &[u8; 32], name: &str) -> io::Result<()> {
+        let mut keys = self.keys.lock().unwrap();
+        if keys.contains_key(key) {
+            return Ok(());
+        }
+        writeln!(
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?,
+            "{} {name}",
+            encode_key(key)
+        )?;
+        keys.insert(*key, name.to_owned());
+        Ok(())
+    }
+}
+
+impl fmt::Debug for TrustList {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "TrustList({})", self.path.display())
+    }
+}
+
+fn parse(list: &str) -> Result<HashMap<[u8; 32], String>, String> {
+    let mut keys = HashMap::new();
+    for (n, line) in list.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, name) = line.split_once(' ').unwrap_or((line, ""));
+        let key = parse_key(key).map_err(|e| format!("line {}: {e}", n + 1))?;
+        keys.insert(key, name.trim().to_owned());
+    }
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::identity::encode_key;
+    use crate::trust::TrustList;
+
+    #[test]
+    fn test_trust_list() {
+        let (alice, bob) = ([1; 32], [2; 32]);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("trusted");
+        let list = format!(
+            "# movie night\n{} alice\n\n",
+            encode_key(&alice).to_ascii_uppercase()
+        );
+        std::fs::write(&path, list).unwrap();
+        let list = TrustList::load(&path).unwrap();
+        assert_eq!(list.name(&alice), Some("alice".to_owned()));
+        assert_eq!(list.name(&bob), None);
+
+        list.add(&bob, "bob").unwrap();
+        assert_eq!(list.name(&bob), Some("bob".to_owned()));
+        // still there after a restart
+        let list = TrustList::load(&path).unwrap();
+        assert_eq!(list.name(&bob), Some("bob".to_owned()));
+
+        let list = TrustList::load(&dir.path().join("missing")).unwrap();
+        assert_eq!(list.name(&alice), None);
+
+        // a fingerprint alone is too short to go by
+        std::fs::write(&path, "0123456789abcdef alice\n").unwrap();
+        assert!(TrustList::load(&path).is_err());
+    }
+}
@@ -0,0 +1,240 @@
+use crossterm::{
+    event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+    execute,
+    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+};
+use ratatui::{
+    backend::{Backend, CrosstermBackend},
+    layout::{Constraint, Direction, Layout},
+    style::{Modifier, Style},
+    widgets::{Block, Borders, List, ListItem, Paragraph, Row, Table},
+    Frame, Terminal,
+};
+use std::{
+    io::stdout,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    runtime::{Handle, Runtime},
+    sync::Mutex,
+};
+
+use crate::{
+    i18n::Msg, player::PlayerBackend, time::get_weighted_latency, HandshakeState, Settings, Shared,
+};
+
+const REFRESH_INTERVAL: Duration = Duration::from_millis(250);
+
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+pub fn run_tui(player: Box<dyn PlayerBackend>, state: Arc<Mutex<Shared>>, settings: Settings) {
+    let rt = Runtime::new().unwrap();
+    let handle = rt.handle();
+
+    enable_raw_mode().expect("Couldn't put the terminal in raw mode");
+    execute!(stdout(), EnterAlternateScreen).expect("Couldn't enter the alternate screen");
+    ACTIVE.store(true, Ordering::SeqCst);
+    let mut terminal =
+        Terminal::new(CrosstermBackend::new(stdout())).expect("Couldn't start the tui");
+
+    let mut input = String::new();
+    let mut typing = false;
+    loop {
+        {
+            let s = handle.block_on(state.lock());
+            terminal.draw(|f| draw(f, &s, &input)).unwrap();
+        }
+
+        if !event::poll(REFRESH_INTERVAL).unwrap() {
+            continue;
+        }
+        let Event::Key(key) = event::read().unwrap() else {
+            continue;
+        };
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+        match key.code {
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                quit(&*player, &mut handle.block_on(state.lock()), handle)
+            }
+            KeyCode::Char(c) => input.push(c),
+            KeyCode::Backspace => {
+                input.pop();
+            }
+            KeyCode::Enter => {
+                let line = std::mem::take(&mut input);
+                let mut s = handle.block_on(state.lock());
+                match line.trim() {
+                    "" => {}
+                    "/play" => player.set_paused(false).unwrap(),
+                    "/pause" => player.set_paused(true).unwrap(),
+                    "/quit" => quit(&*player, &mut s, handle),
+                    "/marks" => {
+                        let lines: Vec<_> = s
+                            .bookmarks
+                            .iter()
+                            .map(|(name, time)| Msg::Bookmark { name, time }.to_string())
+                            .collect();
+                        lines.into_iter().for_each(|line| s.log(line));
+                    }
+                    cmd if cmd.starts_with("/mark ") => {
+                        let name = cmd["/mark ".len()..].trim().to_owned();
+                        let time = player.position().unwrap_or_default();
+                        handle.block_on(s.add_bookmark(name, time));
+                    }
+                    // Seeking locally is broadcast like any other seek
+                    cmd if cmd.starts_with("/goto ") => {
+                        let name = cmd["/goto ".len()..].trim();
+                        match s.bookmarks.get(name) {
+                            Some(time) => player.seek(time, settings.exact_seek).unwrap(),
+                            None => s.log(format!("No bookmark called {name}")),
+                        }
+                    }
+                    cmd if cmd.starts_with('/') => s.log(format!("Unknown command: {cmd}")),
+                    text => handle.block_on(send_chat(&mut s, &settings, text)),
+                }
+            }
+            _ => {}
+        }
+
+        // Commands aren't anything the others are waiting on
+        let now_typing = !input.is_empty() && !input.starts_with('/');
+        if now_typing != typing {
+            typing = now_typing;
+            let mut s = handle.block_on(state.lock());
+            let username = s.username(&settings).to_owned();
+            handle.block_on(s.set_typing(&username, typing, settings.is_serving));
+        }
+    }
+}
+
+async fn send_chat(s: &mut Shared, settings: &Settings, text: &str) {
+    if let Err(e) = s
+        .chat(s.username(settings).to_owned(), text.to_owned())
+        .await
+    {
+        s.log(e);
+    }
+}
+
+fn draw<B: Backend>(f: &mut Frame<B>, s: &Shared, input: &str) {
+    // A client is only connected to the host, the others come from the server's roster
+    let others: Vec<_> = s
+        .roster
+        .iter()
+        .filter(|other| !s.peers.values().any(|peer| peer.username == other.username))
+        .collect();
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Length((s.peers.len() + others.len()) as u16 + 3),
+            Constraint::Min(3),
+            Constraint::Length(3),
+        ])
+        .split(f.size());
+
+    let rows = s.peers.iter().map(|(addr, peer)| {
+        let status = if peer.handshake != HandshakeState::Joined {
+            "joining"
+        } else if s.typing.contains(&peer.username) {
+            "typing…"
+        } else if peer.away {
+            "away"
+        } else if peer.ready {
+            "ready"
+        } else {
+            "waiting"
+        };
+        let latency = if peer.latency.is_empty() {
+            "-".to_owned()
+        } else {
+            format!("{}ms", get_weighted_latency(&peer.latency))
+        };
+        let drift = peer.drift.map_or("-".to_owned(), |d| format!("{d:+.2}s"));
+        Row::new(vec![
+            peer.username.clone(),
+            peer.role.to_string(),
+            addr.to_string(),
+            status.to_owned(),
+            latency,
+            drift,
+        ])
+    });
+    let rows = rows.chain(others.into_iter().map(|other| {
+        let status = if s.typing.contains(&other.username) {
+            "typing…"
+        } else if other.away {
+            "away"
+        } else if other.ready {
+            "ready"
+        } else {
+            "waiting"
+        };
+        Row::new(vec![
+            other.username.clone(),
+            other.role.to_string(),
+            "-".to_owned(),
+            status.to_owned(),
+            "-".to_owned(),
+            "-".to_owned(),
+        ])
+    }));
+    let peers = Table::new(rows)
+        .header(
+            Row::new(vec![
+                "User", "Role", "Address", "Status", "Latency", "Drift",
+            ])
+            .style(Style::default().add_modifier(Modifier::BOLD)),
+        )
+        .block(Block::default().borders(Borders::ALL).title("Peers"))
+        .widths(&[
+            Constraint::Percentage(20),
+            Constraint::Percentage(12),
+            Constraint::Percentage(23),
+            Constraint::Percentage(15),
+            Constraint::Percentage(15),
+            Constraint::Percentage(15),
+        ]);
+    f.render_widget(peers, chunks[0]);
+
+    // Only the most recent events fit, newest at the bottom
+    let visible = chunks[1].height.saturating_sub(2) as usize;
+    let events: Vec<ListItem> = s
+        .events
+        .iter()
+        .skip(s.events.len().saturating_sub(visible))
+        .map(|e| ListItem::new(e.as_str()))
+        .collect();
+    let log = List::new(events).block(Block::default().borders(Borders::ALL).title("Events"));
+    f.render_widget(log, chunks[1]);
+
+    let prompt = Paragraph::new(input).block(
+        Block::default()
+            .borders(Borders::ALL)
+            .title("Chat (/play, /pause, /mark NAME, /marks, /goto NAME, /quit)"),
+    );
+    f.render_widget(prompt, chunks[2]);
+    f.set_cursor(
+        chunks[2].x + input.chars().count() as u16 + 1,
+        chunks[2].y + 1,
+    );
+}
+
+fn quit(player: &dyn PlayerBackend, s: &mut Shared, handle: &Handle) -> ! {
+    player.quit();
+    handle.block_on(s.say_goodbye());
+    s.end_session()
+}
+
+/// Give the terminal back to the shell, if the tui ever took it
+pub fn restore_terminal() {
+    if ACTIVE.swap(false, Ordering::SeqCst) {
+        let _ = disable_raw_mode();
+        let _ = execute!(stdout(), LeaveAlternateScreen);
+    }
+}
@@ -0,0 +1,117 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+};
+use unicode_normalization::UnicodeNormalization;
+
+use crate::proto::RejectReason;
+
+pub const MAX_USERNAME_LEN: usize = 32;
+
+/// Clean up a username: normalize it, drop control and invisible characters and check its length
+pub fn sanitize(name: &str) -> Result<String, RejectReason> {
+    let name: String = name.nfc().filter(|c| !is_invisible(*c)).collect();
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(RejectReason::InvalidUsername);
+    }
+    if name.chars().count() > MAX_USERNAME_LEN {
+        return Err(RejectReason::UsernameTooLong);
+    }
+    Ok(name.to_owned())
+}
+
+/// A made-up name for --anonymous, different every time
+pub fn pseudonym() -> String {
+    // Every RandomState is seeded differently, good enough for a name
+    let n = RandomState::new().build_hasher().finish();
+    format!("anon-{:04x}", n & 0xffff)
+}
+
+/// Sanitize a username and make sure it can't be mistaken for one of the `taken` ones
+pub fn validate<'a>(
+    name: &str,
+    mut taken: impl Iterator<Item = &'a str>,
+) -> Result<String, RejectReason> {
+    let name = sanitize(name)?;
+    let folded = skeleton(&name);
+    if taken.any(|other| skeleton(other) == folded) {
+        return Err(RejectReason::UsernameTaken);
+    }
+    Ok(name)
+}
+
+/// Fold away the differences that are hard to see on screen, so that
+/// "Alice", "alice", "ａｌｉｃｅ" and a cyrillic "аlice" all compare equal
+pub fn skeleton(name: &str) -> String {
+    name.nfkc()
+        .flat_map(char::to_lowercase)
+        .map(|c| match c {
+            'а' | 'α' => 'a',
+            'с' | 'ϲ' => 'c',
+            'е' | 'ε' => 'e',
+            'і' | 'ι' | 'l' | '1' | '|' => 'i',
+            'ј' => 'j',
+            'к' | 'κ' => 'k',
+            'о' | 'ο' | '0' => 'o',
+            'р' | 'ρ' => 'p',
+            'ѕ' => 's',
+            'т' | 'τ' => 't',
+            'у' | 'υ' => 'y',
+            'х' | 'χ' => 'x',
+            c => c,
+        })
+        .filter(|c| !c.is_whitespace() && !is_invisible(*c))
+        .collect()
+}
+
+fn is_invisible(c: char) -> bool {
+    c.is_control()
+        || matches!(c,
+            '\u{00AD}'
+            | '\u{200B}'..='\u{200F}'
+            | '\u{202A}'..='\u{202E}'
+            | '\u{2060}'..='\u{2064}'
+            | '\u{2066}'..='\u{2069}'
+            | '\u{FEFF}')
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{proto::RejectReason, username::*};
+
+    #[test]
+    fn test_sanitize() {
+        assert_eq!(sanitize("Jean-Luc Picard").unwrap(), "Jean-Luc Picard");
+        assert_eq!(sanitize("  ゆき\u{200B}\n").unwrap(), "ゆき");
+        assert_eq!(
+            sanitize("\u{202E}\u{7}"),
+            Err(RejectReason::InvalidUsername)
+        );
+        assert_eq!(
+            sanitize(&"a".repeat(MAX_USERNAME_LEN + 1)),
+            Err(RejectReason::UsernameTooLong)
+        );
+    }
+
+    #[test]
+    fn test_pseudonym() {
+        let name = pseudonym();
+        assert_eq!(sanitize(&name).as_ref(), Ok(&name));
+        assert!(name.starts_with("anon-"));
+    }
+
+    #[test]
+    fn test_confusables() {
+        let taken = ["alice", "bob"];
+        assert_eq!(
+            validate("ａｌｉｃｅ", taken.into_iter()),
+            Err(RejectReason::UsernameTaken)
+        );
+        assert_eq!(
+            validate("\u{0430}lice", taken.into_iter()),
+            Err(RejectReason::UsernameTaken)
+        );
+        assert_eq!(validate("carol", taken.into_iter()).unwrap(), "carol");
+    }
+}
@@ -0,0 +1,253 @@
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    path::PathBuf,
+    process::Command,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+use url::Url;
+
+use crate::player::{PlayerBackend, PlayerError, PlayerEvent};
+
+// The rc interface doesn't push events, so changes are found by polling
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+const READ_TIMEOUT: Duration = Duration::from_secs(2);
+// get_time only has a one second resolution, anything within that is playback going on
+const SEEK_THRESHOLD: f64 = 2.0;
+
+/// VLC driven through its rc interface.
+///
+/// VLC only takes one rc client at a time, so clones share the same connection.
+/// The rc interface can't show text on the video and only seeks to whole seconds.
+#[derive(Clone)]
+pub struct Vlc {
+    conn: Arc<Mutex<BufReader<TcpStream>>>,
+    // what the last poll saw, every clone polls on its own
+    last: Option<(bool, f64)>,
+}
+
+impl Vlc {
+    pub fn connect(addr: &str) -> Result<Vlc, PlayerError> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        let vlc = Vlc {
+            conn: Arc::new(Mutex::new(BufReader::new(stream))),
+            last: None,
+        };
+        // Skip past the greeting, it's over once a query gets its answer
+        vlc.command("is_playing")?;
+        {
+            let mut conn = vlc.conn.lock().unwrap();
+            while !matches!(read_answer(&mut conn)?.as_str(), "0" | "1") {}
+        }
+        Ok(vlc)
+    }
+
+    fn command(&self, cmd: &str) -> Result<(), PlayerError> {
+        let mut conn = self.conn.lock().unwrap();
+        conn.get_mut().write_all(format!("{cmd}\n").as_bytes())?;
+        Ok(())
+    }
+
+    /// Send `cmd` and return the first line of its answer
+    fn query(&self, cmd: &str) -> Result<String, PlayerError> {
+        let mut conn = self.conn.lock().unwrap();
+        conn.get_mut().write_all(format!("{cmd}\n").as_bytes())?;
+        read_answer(&mut conn)
+    }
+}
+
+fn read_answer(conn: &mut BufReader<TcpStream>) -> Result<String, PlayerError> {
+    loop {
+        let mut line = String::new();
+        if conn.read_line(&mut line)? == 0 {
+            return Err("vlc closed the rc connection".into());
+        }
+        // prompts aren't followed by a newline, so they end up in front of the answer
+        let line = line.trim_start_matches(['>', ' ']).trim_end();
+        if line.is_empty() || line.starts_with("status change:") || line.ends_with("(no error)") {
+            continue;
+        }
+        return Ok(line.to_owned());
+    }
+}
+
+impl PlayerBackend for Vlc {
+    fn is_paused(&self) -> Result<bool, PlayerError> {
+        Ok(self.query("is_playing")? != "1")
+    }
+
+    fn set_paused(&self, paused: bool) -> Result<(), PlayerError> {
+        // pause toggles, play doesn't resume from a pause
+        if self.is_paused()? != paused {
+            self.command("pause")?;
+        }
+        Ok(())
+    }
+
+    fn seek(&self, t: f64, _exact: bool) -> Result<(), PlayerError> {
+        self.command(&format!("seek {}", t.round() as u64))
+    }
+
+    fn position(&self) -> Option<f64> {
+        self.query("get_time").ok()?.parse().ok()
+    }
+
+    fn duration(&self) -> Option<f64> {
+        self.query("get_length").ok()?.parse().ok()
+    }
+
+    fn file_size(&self) -> Option<u64> {
+        // The rc interface doesn't tell
+        None
+    }
+
+    fn live_lag(&self) -> Option<f64> {
+        None
+    }
+
+    fn set_speed(&self, speed: f64) -> Result<(), PlayerError> {
+        self.command(&format!("rate {speed}"))
+    }
+
+    fn filename(&self) -> Option<String> {
+        // get_title is the media's title, the name mpv peers compare against is the file's
+        let input = self.path()?;
+        let path = match Url::parse(&input) {
+            Ok(url) if url.scheme() == "file" => url.to_file_path().ok()?,
+            _ => PathBuf::from(input),
+        };
+        Some(path.file_name()?.to_string_lossy().into_owned())
+    }
+
+    fn path(&self) -> Option<String> {
+        let status = self.query("status").ok()?;
+        let input = status.strip_prefix("( new input: ")?.strip_suffix(" )")?;
+        Some(input.to_owned())
+    }
+
+    fn playlist_pos(&self) -> Option<u64> {
+        // The rc interface doesn't say which entry is playing
+        None
+    }
+
+    fn playlist_count(&self) -> Option<u64> {
+        None
+    }
+
+    fn set_playlist_pos(&self, pos: u64) -> Result<(), PlayerError> {
+        // goto counts from one
+        self.command(&format!("goto {}", pos + 1))
+    }
+
+    fn load(&mut self, url: &str) -> Result<(), PlayerError> {
+        self.command("clear")?;
+        self.command(&format!("add {url}"))?;
+        while self.duration().unwrap_or_default() <= 0.0 {
+            thread::sleep(POLL_INTERVAL);
+        }
+        self.set_paused(true)
+    }
+
+    fn show_text(&self, _text: &str, _duration: u32) -> Result<(), PlayerError> {
+        // Nowhere to show it, the terminal still gets everything through the log
+        Ok(())
+    }
+
+    fn stop(&self) {
+        let _ = self.command("stop");
+    }
+
+    fn quit(&self) {
+        let _ = self.command("quit");
+    }
+
+    fn observe(&mut self) -> Result<(), PlayerError> {
+        Ok(())
+    }
+
+    fn next_event(&mut self) -> Result<PlayerEvent, PlayerError> {
+        loop {
+            thread::sleep(POLL_INTERVAL);
+            let (Ok(paused), position) = (self.is_paused(), self.position()) else {
+                return Ok(PlayerEvent::Shutdown);
+            };
+            let position = position.unwrap_or_default();
+            let last = self.last.replace((paused, position));
+            let Some((was_paused, last_position)) = last else {
+                continue;
+            };
+
+            if paused != was_paused {
+                return Ok(PlayerEvent::Pause(paused));
+            }
+            let expected = if paused {
+                last_position
+            } else {
+                last_position + POLL_INTERVAL.as_secs_f64()
+            };
+            if (position - expected).abs() > SEEK_THRESHOLD {
+                return Ok(PlayerEvent::Seeking(false));
+            }
+        }
+    }
+}
+
+/// Start vlc with its rc interface on a free local port, and connect to it
+pub fn start_vlc(vlc_args: Vec<String>) -> Result<Vlc, PlayerError> {
+    let addr = TcpListener::bind("127.0.0.1:0")?.local_addr()?.to_string();
+
+    let mut child = Command::new("vlc")
+        .args(["--extraintf", "rc", "--rc-host", &addr, "--start-paused"])
+        .args(vlc_args)
+        .spawn()
+        .expect("failed to execute vlc");
+    // Reaped once it quits, voyeurs may outlive it
+    thread::spawn(move || child.wait());
+
+    loop {
+        match Vlc::connect(&addr) {
+            Ok(vlc) => return Ok(vlc),
+            Err(_) => thread::sleep(POLL_INTERVAL),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::player::PlayerBackend;
+    use crate::vlc::Vlc;
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        thread,
+    };
+
+    #[test]
+    fn test_rc_answers() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(b"VLC media player\n").unwrap();
+            let mut lines = BufReader::new(stream.try_clone().unwrap()).lines();
+            while let Some(Ok(cmd)) = lines.next() {
+                let answer = match cmd.as_str() {
+                    "is_playing" => "> status change: ( play state: 3 )\n> 1\n",
+                    "get_time" => "> 42\n",
+                    "status" => "> ( new input: file:///movies/Night%20Of.mkv )\n",
+                    _ => "> \n",
+                };
+                stream.write_all(answer.as_bytes()).unwrap();
+            }
+        });
+
+        let vlc = Vlc::connect(&addr).unwrap();
+        assert!(!vlc.is_paused().unwrap());
+        assert_eq!(vlc.position(), Some(42.0));
+        assert_eq!(vlc.path().as_deref(), Some("file:///movies/Night%20Of.mkv"));
+        assert_eq!(vlc.filename().as_deref(), Some("Night Of.mkv"));
+    }
+}
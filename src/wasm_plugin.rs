@@ -0,0 +1,185 @@
+use async_trait::async_trait;
+use std::{error::Error, path::Path, sync::Mutex};
+use wasmtime::{
+    Config, Engine, Instance, Memory, Module, Store, StoreLimits, StoreLimitsBuilder, TypedFunc,
+    WasmParams, WasmResults,
+};
+
+use crate::{plugin::Plugin, proto::CmdSize};
+
+type WasmError = Box<dyn Error + Sync + Send>;
+
+// Instructions a hook may take, so a plugin stuck in a loop doesn't take the session
+// down with it
+const FUEL: u64 = 10_000_000;
+// Memory a plugin may grow to, so one allocating without end runs out before we do
+const MAX_MEMORY: usize = 64 << 20;
+// Longest string a plugin can hand back
+const MAX_ANSWER: usize = 4096;
+
+/// A plugin compiled to WebAssembly. It gets no imports, so it can't touch files, the
+/// network or anything else on the machine, only answer the hooks it exports:
+///
+/// - `alloc(len: i32) -> i32`, room in its `memory` for the strings it's given
+/// - `command(code: i32, from: i32, from_len: i32, args: i32, args_len: i32) -> i64`
+/// - `pre_seek(by: i32, by_len: i32, time: f64) -> i32`, 0 to refuse
+/// - `post_join(username: i32, username_len: i32) -> i64`
+///
+/// The i64s are a string to show or a reason to turn the peer away, as the pointer in
+/// the high half and the length in the low one, or 0 for none. Hooks it doesn't export
+/// go on as usual
+pub struct Wasm {
+    name: String,
+    codes: Vec<CmdSize>,
+    instance: Mutex<(Store<StoreLimits>, Instance)>,
+}
+
+impl Wasm {
+    pub fn load(name: &str, codes: Vec<CmdSize>, path: &Path) -> Result<Wasm, WasmError> {
+        let engine = Engine::new(Config::new().consume_fuel(true))?;
+        let module = Module::from_file(&engine, path)?;
+        let limits = StoreLimitsBuilder::new().memory_size(MAX_MEMORY).build();
+        let mut store = Store::new(&engine, limits);
+        store.limiter(|limits| limits);
+        let instance = Instance::new(&mut store, &module, &[])?;
+        Ok(Wasm {
+            name: name.to_owned(),
+            codes,
+            instance: Mutex::new((store, instance)),
+        })
+    }
+
+    /// Call `hook` with `args`, after copying `strings` into the plugin's memory and
+    /// passing each as a pointer and a length ahead of `args`. None when the plugin
+    /// doesn't have the hook or it fails
+    fn call<P: WasmParams, R: WasmResults>(
+        &self,
+        hook: &str,
+        strings: &[&[u8]],
+        args: impl FnOnce(Vec<i32>) -> P,
+        answer: impl FnOnce(&mut Store<StoreLimits>, Memory, R) -> Option<String>,
+    ) -> Option<String> {
+        let mut guard = self.instance.lock().unwrap();
+        let (store, instance) = &mut *guard;
+        let func: TypedFunc<P, R> = instance.get_typed_func(&mut *store, hook).ok()?;
+        let memory = instance.get_memory(&mut *store, "memory")?;
+        store.set_fuel(FUEL).ok()?;
+        let mut pointers = vec![];
+        if !strings.is_empty() {
+            let alloc: TypedFunc<i32, i32> = instance.get_typed_func(&mut *store, "alloc").ok()?;
+            for string in strings {
+                let ptr = alloc.call(&mut *store, string.len() as i32).ok()?;
+                memory.write(&mut *store, ptr as usize, string).ok()?;
+                pointers.extend([ptr, string.len() as i32]);
+            }
+        }
+        let result = func.call(&mut *store, args(pointers)).ok()?;
+        answer(store, memory, result)
+    }
+}
+
+/// The string an i64 answer points to
+fn read_answer(store: &mut Store<StoreLimits>, memory: Memory, packed: i64) -> Option<String> {
+    if packed == 0 {
+        return None;
+    }
+    let ptr = (packed as u64 >> 32) as usize;
+    let len = (packed as u64 & u32::MAX as u64) as usize;
+    let mut buf = vec![0; len.min(MAX_ANSWER)];
+    memory.read(&*store, ptr, &mut buf).ok()?;
+    Some(String::from_utf8_lossy(&buf).into_owned())
+}
+
+#[async_trait]
+impl Plugin for Wasm {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn codes(&self) -> &[CmdSize] {
+        &self.codes
+    }
+
+    async fn command(&self, from: &str, code: CmdSize, args: &[u8]) -> Option<String> {
+        self.call::<(i32, i32, i32, i32, i32), i64>(
+            "command",
+            &[from.as_bytes(), args],
+            |p| (code as i32, p[0], p[1], p[2], p[3]),
+            read_answer,
+        )
+    }
+
+    async fn pre_seek(&self, by: Option<&str>, time: f64) -> bool {
+        let refused = self.call::<(i32, i32, f64), i32>(
+            "pre_seek",
+            &[by.unwrap_or_default().as_bytes()],
+            |p| (p[0], p[1], time),
+            |_, _, allowed| (allowed == 0).then(String::new),
+        );
+        refused.is_none()
+    }
+
+    async fn post_join(&self, username: &str) -> Result<(), String> {
+        let reason = self.call::<(i32, i32), i64>(
+            "post_join",
+            &[username.as_bytes()],
+            |p| (p[0], p[1]),
+            read_answer,
+        );
+        match reason {
+            Some(reason) => Err(reason),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{plugin::Plugin, wasm_plugin::Wasm};
+
+    // No seeking past the end credits, and nobody whose name starts with an m
+    const RULES: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (data (i32.const 0) "not mallory")
+          (global $next (mut i32) (i32.const 1024))
+          (func (export "alloc") (param $len i32) (result i32)
+            (local $ptr i32)
+            (local.set $ptr (global.get $next))
+            (global.set $next (i32.add (global.get $next) (local.get $len)))
+            (local.get $ptr))
+          (func (export "pre_seek") (param i32 i32) (param $time f64) (result i32)
+            (f64.le (local.get $time) (f64.const 5400)))
+          (func (export "post_join") (param $name i32) (param i32) (result i64)
+            (if (result i64) (i32.eq (i32.load8_u (local.get $name)) (i32.const 109))
+              (then (i64.const 11))
+              (else (i64.const 0))))
+          (func (export "spin") (loop $forever (br $forever)))
+          (func (export "grow") (result i32) (memory.grow (i32.const 2048))))
+    "#;
+
+    #[tokio::test]
+    async fn test_wasm_plugin() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("rules.wat");
+        std::fs::write(&path, RULES).unwrap();
+        let plugin = Wasm::load("rules", vec![0xE0], &path).unwrap();
+
+        assert!(plugin.pre_seek(Some("alice"), 60.0).await);
+        assert!(!plugin.pre_seek(None, 5500.0).await);
+        assert_eq!(plugin.post_join("alice").await, Ok(()));
+        assert_eq!(
+            plugin.post_join("mallory").await,
+            Err("not mallory".to_owned())
+        );
+        // it doesn't handle commands, nothing to show
+        assert_eq!(plugin.command("alice", 0xE0, b"answer").await, None);
+        // running out of fuel is a failed hook, not a hung session
+        let spun = plugin.call::<(), ()>("spin", &[], |_| (), |_, _, _| Some(String::new()));
+        assert_eq!(spun, None);
+        // and 128MiB more memory is more than a plugin gets
+        let grown =
+            plugin.call::<(), i32>("grow", &[], |_| (), |_, _, grown| Some(grown.to_string()));
+        assert_eq!(grown.as_deref(), Some("-1"));
+    }
+}
@@ -0,0 +1,93 @@
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tokio::sync::{broadcast, Mutex};
+use url::Url;
+
+use crate::{i18n::Msg, session::SessionEvent, time::get_timestamp, Shared};
+
+/// The JSON posted to the webhook, for the events worth telling the outside world
+/// about. `content` is what Discord webhooks show, the rest is for bots.
+fn to_json(event: &SessionEvent, timestamp: u64) -> Option<Value> {
+    let (name, content) = match event {
+        SessionEvent::Join(name) => ("join", Msg::Connected(name)),
+        SessionEvent::Leave(name) => ("leave", Msg::Disconnected(name)),
+        SessionEvent::Pause(name) => ("pause", Msg::PausedBy(name)),
+        SessionEvent::Resume => ("resume", Msg::Resumed),
+        SessionEvent::FileChange(file) => ("file_change", Msg::NowPlaying(file)),
+        // too chatty for a channel
+        SessionEvent::Seek { .. } | SessionEvent::Chat { .. } => return None,
+    };
+    let mut body = json!({
+        "event": name,
+        "timestamp": timestamp,
+        "content": content.to_string(),
+    });
+    match event {
+        SessionEvent::Join(name) | SessionEvent::Leave(name) | SessionEvent::Pause(name) => {
+            body["username"] = json!(name)
+        }
+        SessionEvent::FileChange(file) => body["filename"] = json!(file),
+        _ => {}
+    }
+    Some(body)
+}
+
+/// Post the session's events to `url`, one after the other
+pub fn spawn(url: Url, state: Arc<Mutex<Shared>>, mut events: broadcast::Receiver<SessionEvent>) {
+    tokio::spawn(async move {
+        let client = reqwest::Client::new();
+        loop {
+            let event = match events.recv().await {
+                Ok(event) => event,
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    state
+                        .lock()
+                        .await
+                        .log(format!("the webhook is behind, skipped {missed} events"));
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            };
+            let Some(body) = to_json(&event, get_timestamp()) else {
+                continue;
+            };
+            let sent = client
+                .post(url.clone())
+                .json(&body)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+            if let Err(e) = sent {
+                state
+                    .lock()
+                    .await
+                    .log(format!("couldn't call the webhook: {e}"));
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::session::SessionEvent;
+    use crate::webhook::to_json;
+    use serde_json::json;
+
+    #[test]
+    fn test_to_json() {
+        let body = to_json(&SessionEvent::FileChange("movie.mkv".to_owned()), 1234).unwrap();
+        assert_eq!(body["event"], "file_change");
+        assert_eq!(body["filename"], "movie.mkv");
+        assert_eq!(body["timestamp"], 1234);
+        assert!(body["content"].as_str().unwrap().contains("movie.mkv"));
+        assert_eq!(
+            to_json(&SessionEvent::Resume, 0).unwrap()["event"],
+            json!("resume")
+        );
+        let chat = SessionEvent::Chat {
+            from: "alice".to_owned(),
+            text: "hi".to_owned(),
+        };
+        assert_eq!(to_json(&chat, 0), None);
+    }
+}